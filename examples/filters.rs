@@ -1,10 +1,8 @@
-#![feature(unboxed_closures)]
-
 extern crate synthrs;
 
 use synthrs::filter::*;
 use synthrs::synthesizer::{make_samples, quantize_samples};
-use synthrs::wave::sine_wave;
+use synthrs::wave::{sawtooth_wave, sine_wave};
 use synthrs::writer::write_wav_file;
 
 fn main() {
@@ -15,12 +13,12 @@ fn main() {
 
     let lowpass = lowpass_filter(cutoff_from_frequency(400.0, 44_100), 0.01);
     let mut lowpass_samples = quantize_samples::<i16>(&sample);
-    lowpass_samples.extend_from_slice(&*quantize_samples::<i16>(&convolve(&lowpass, &sample)));
+    lowpass_samples.extend_from_slice(&quantize_samples::<i16>(&convolve(&lowpass, &sample)));
     write_wav_file("out/lowpass.wav", 44_100, &lowpass_samples).expect("failed");
 
     let highpass = highpass_filter(cutoff_from_frequency(2000.0, 44_100), 0.01);
     let mut highpass_samples = quantize_samples::<i16>(&sample);
-    highpass_samples.extend_from_slice(&*quantize_samples::<i16>(&convolve(&highpass, &sample)));
+    highpass_samples.extend_from_slice(&quantize_samples::<i16>(&convolve(&highpass, &sample)));
     write_wav_file("out/highpass.wav", 44_100, &highpass_samples).expect("failed");
 
     let bandpass = bandpass_filter(
@@ -29,7 +27,7 @@ fn main() {
         0.01,
     );
     let mut bandpass_samples = quantize_samples::<i16>(&sample);
-    bandpass_samples.extend_from_slice(&*quantize_samples::<i16>(&convolve(&bandpass, &sample)));
+    bandpass_samples.extend_from_slice(&quantize_samples::<i16>(&convolve(&bandpass, &sample)));
     write_wav_file("out/bandpass.wav", 44_100, &bandpass_samples).expect("failed");
 
     let bandreject = bandreject_filter(
@@ -38,8 +36,7 @@ fn main() {
         0.01,
     );
     let mut bandreject_samples = quantize_samples::<i16>(&sample);
-    bandreject_samples
-        .extend_from_slice(&*quantize_samples::<i16>(&convolve(&bandreject, &sample)));
+    bandreject_samples.extend_from_slice(&quantize_samples::<i16>(&convolve(&bandreject, &sample)));
     write_wav_file("out/bandreject.wav", 44_100, &bandreject_samples).expect("failed");
 
     // Stateful filters
@@ -48,7 +45,7 @@ fn main() {
     write_wav_file(
         "out/comb.wav",
         44_100,
-        &*quantize_samples::<i16>(comb_samples.as_slice()),
+        &quantize_samples::<i16>(comb_samples.as_slice()),
     )
     .expect("failed");
 
@@ -61,7 +58,62 @@ fn main() {
     write_wav_file(
         "out/allpass.wav",
         44_100,
-        &*quantize_samples::<i16>(allpass_samples.as_slice()),
+        &quantize_samples::<i16>(allpass_samples.as_slice()),
+    )
+    .expect("failed");
+
+    // Biquad lowpass, sweeping the cutoff from 200Hz to 5kHz over a sawtooth
+    let saw = make_samples(2.0, 44_100, |t: f64| 0.4 * sawtooth_wave(110.0)(t));
+    let mut sweep = Biquad::new(BiquadCoefficients::lowpass(200.0, 4.0, 44_100));
+    let mut sweep_samples = saw.clone();
+    let blocks = sweep_samples.len() / 256;
+    for (i, block) in sweep_samples.chunks_mut(256).enumerate() {
+        let cutoff = 200.0 * 25.0f64.powf(i as f64 / blocks as f64);
+        sweep.set_coefficients(BiquadCoefficients::lowpass(cutoff, 4.0, 44_100));
+        sweep.process(block);
+    }
+    write_wav_file(
+        "out/biquad_sweep.wav",
+        44_100,
+        &quantize_samples::<i16>(sweep_samples.as_slice()),
+    )
+    .expect("failed");
+
+    // Subtractive synthesis: resonant filters with their cutoffs driven by an envelope
+    let envelope = Adsr::new(0.01, 0.4, 0.1, 0.5);
+    let cutoff_at =
+        |i: usize| 100.0 + 4000.0 * envelope.value((i % 22_050) as f64 / 44_100.0, None);
+
+    let mut svf = StateVariable::new(100.0, 5.0, 44_100);
+    let svf_samples: Vec<f64> = saw
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| {
+            svf.cutoff = cutoff_at(i);
+            svf.tick(s).lowpass
+        })
+        .collect();
+    write_wav_file(
+        "out/svf_envelope.wav",
+        44_100,
+        &quantize_samples::<i16>(svf_samples.as_slice()),
+    )
+    .expect("failed");
+
+    let mut ladder = MoogLadder::new(100.0, 0.8, 44_100);
+    ladder.drive = 2.0;
+    let ladder_samples: Vec<f64> = saw
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| {
+            ladder.cutoff = cutoff_at(i);
+            ladder.tick(s)
+        })
+        .collect();
+    write_wav_file(
+        "out/moog_ladder_envelope.wav",
+        44_100,
+        &quantize_samples::<i16>(ladder_samples.as_slice()),
     )
     .expect("failed");
 }
@@ -1,12 +1,15 @@
-#![feature(unboxed_closures)]
-
 extern crate synthrs;
 
 use synthrs::midi;
-use synthrs::sample;
-use synthrs::synthesizer::{make_samples_from_midi, make_samples_from_midi_file, quantize_samples};
+use synthrs::sample::{Sample, SampleInstrument, SampleZone};
+use synthrs::sf2::read_sf2_file;
+use synthrs::synthesizer::{
+    make_samples_from_midi, make_samples_from_midi_file, make_stereo_samples_from_midi_file,
+    make_stereo_samples_from_midi_with_sampler, make_stereo_samples_from_midi_with_soundfont,
+    quantize_samples,
+};
 use synthrs::wave;
-use synthrs::writer::write_wav_file;
+use synthrs::writer::{write_multichannel_wav_file, write_wav_file};
 
 fn main() {
     // `make_samples_from_midi_file` is a convenience function that parses and synthesises
@@ -46,11 +49,9 @@ fn main() {
 
     // Use a sample to generate music!
     // This is a YAMAHA SY35 sample grabbed from http://legowelt.org/samples/
-    let (piano_sample, piano_sample_len) =
-        sample::samples_from_wave_file("examples/assets/piano110hz.wav").unwrap();
-    // Give the samples, length of samples, the sample's frequency, and sample's "sample rate (usually 44100Hz)"
-    let piano_sampler =
-        |frequency: f64| wave::sampler(frequency, &piano_sample, piano_sample_len, 110.0, 44_100);
+    // Give the sample's frequency: the sample rate is read from the file
+    let piano_sample = Sample::from_wave_file("examples/assets/piano110hz.wav", 110.0).unwrap();
+    let piano_sampler = |frequency: f64| wave::sampler(frequency, &piano_sample, 44_100);
 
     write_wav_file(
         "out/octave_piano_sampler.wav",
@@ -68,17 +69,8 @@ fn main() {
     .expect("failed");
 
     // This is a YAMAHA SY35 sample grabbed from http://legowelt.org/samples/
-    let (clarinet_sample, clarinet_sample_len) =
-        sample::samples_from_wave_file("examples/assets/clarinet262.wav").unwrap();
-    let clarinet_sampler = |frequency: f64| {
-        wave::sampler(
-            frequency,
-            &clarinet_sample,
-            clarinet_sample_len,
-            262.0,
-            44_100,
-        )
-    };
+    let clarinet_sample = Sample::from_wave_file("examples/assets/clarinet262.wav", 262.0).unwrap();
+    let clarinet_sampler = |frequency: f64| clarinet_sample.sampler(frequency, 44_100);
 
     write_wav_file(
         "out/octave_clarinet_sampler.wav",
@@ -159,12 +151,13 @@ fn main() {
     .expect("failed");
 
     // Grieg - In the Hall of the Mountain King
+    // The band-limited square wave avoids the aliasing of `wave::square_wave` on high notes
     write_wav_file(
         "out/mountainking.wav",
         44_100,
         &quantize_samples::<i16>(
             &make_samples_from_midi_file(
-                wave::square_wave,
+                |frequency: f64| wave::bandlimited_square_wave(frequency, 44_100),
                 44_100,
                 true,
                 "examples/assets/mountainking.mid",
@@ -190,6 +183,68 @@ fn main() {
     )
     .expect("failed");
 
+    // Map several samples across the keyboard: piano below middle C and clarinet from middle C
+    // up, each played from its own root key to keep pitch shifting small
+    let mut piano_zone = SampleZone::new(piano_sample.clone(), 45);
+    piano_zone.keys = (0, 59);
+    let mut clarinet_zone = SampleZone::new(clarinet_sample.clone(), 60);
+    clarinet_zone.keys = (60, 127);
+    let split = SampleInstrument::new(vec![piano_zone, clarinet_zone]);
+
+    write_multichannel_wav_file(
+        "out/octave_split_sampler.wav",
+        44_100,
+        2,
+        &quantize_samples::<i16>(
+            &make_stereo_samples_from_midi_with_sampler(
+                &split,
+                44_100,
+                false,
+                midi::read_midi_file("examples/assets/octave.mid").unwrap(),
+            )
+            .unwrap(),
+        ),
+    )
+    .expect("failed");
+
+    // Stereo rendering places each MIDI channel using its pan (CC10) and volume (CC7) controllers
+    write_multichannel_wav_file(
+        "out/danube_stereo.wav",
+        44_100,
+        2,
+        &quantize_samples::<i16>(
+            &make_stereo_samples_from_midi_file(
+                wave::sine_wave,
+                44_100,
+                true,
+                "examples/assets/danube.mid",
+            )
+            .unwrap(),
+        ),
+    )
+    .expect("failed");
+
+    // General MIDI render with a SoundFont, if one is given:
+    // `cargo run --example midi -- path/to/GeneralUser.sf2`
+    if let Some(path) = std::env::args().nth(1) {
+        let soundfont = read_sf2_file(&path).expect("failed to read SoundFont");
+        write_multichannel_wav_file(
+            "out/mountainking_soundfont.wav",
+            44_100,
+            2,
+            &quantize_samples::<i16>(
+                &make_stereo_samples_from_midi_with_soundfont(
+                    &soundfont,
+                    44_100,
+                    false,
+                    midi::read_midi_file("examples/assets/mountainking.mid").unwrap(),
+                )
+                .unwrap(),
+            ),
+        )
+        .expect("failed");
+    }
+
     // Christian Sinding - Rustle of Spring (Frühlingsrauschen)
     write_wav_file(
         "out/rustle.wav",
@@ -1,12 +1,18 @@
-#![feature(unboxed_closures)]
-
 extern crate synthrs;
 
+use synthrs::additive::drawbar_organ;
+use synthrs::drums::DrumKit;
+use synthrs::fm::Patch;
+use synthrs::granular::Granular;
+use synthrs::noise::Colour;
+use synthrs::sample::Sample;
 use synthrs::synthesizer::{make_samples, peak_normalize, quantize_samples, SamplesIter};
+use synthrs::timestretch::{pitch_shift, time_stretch};
 use synthrs::wave::{
     bell, karplus_strong, noise, organ, rising_linear, sawtooth_wave, sine_wave, square_wave,
     tangent_wave, triangle_wave,
 };
+use synthrs::waveguide::PluckedString;
 use synthrs::writer::{write_pcm_file, write_wav_file};
 
 fn main() {
@@ -84,6 +90,43 @@ fn main() {
     )
     .expect("failed");
 
+    // Seeded noise is the same on every run
+    write_wav_file(
+        "out/pink_noise.wav",
+        44_100,
+        &quantize_samples::<i16>(&make_samples(
+            1.0,
+            44_100,
+            synthrs::noise::generator(Colour::Pink, 1234),
+        )),
+    )
+    .expect("failed");
+
+    // Analog-style snare drum, as played for General MIDI note 38
+    let snare = DrumKit::new().drum(38).unwrap();
+    write_wav_file(
+        "out/snare.wav",
+        44_100,
+        &quantize_samples::<i16>(&make_samples(
+            snare.length(),
+            44_100,
+            snare.generator(44_100, 1234),
+        )),
+    )
+    .expect("failed");
+
+    // Physically modelled plucked string, ringing for two seconds
+    write_wav_file(
+        "out/plucked_string.wav",
+        44_100,
+        &quantize_samples::<i16>(
+            &PluckedString::new(110.0, 44_100, 2.0, 0.5, 0.2, 1234)
+                .take(44_100 * 2)
+                .collect::<Vec<f64>>(),
+        ),
+    )
+    .expect("failed");
+
     write_wav_file(
         "out/organ.wav",
         44_100,
@@ -134,6 +177,66 @@ fn main() {
     )
     .expect("failed");
 
+    // Additive synthesis: a drawbar organ with the "888000000" registration
+    write_wav_file(
+        "out/drawbar_organ.wav",
+        44_100,
+        &quantize_samples::<i16>(&make_samples(
+            1.0,
+            44_100,
+            drawbar_organ(220.0, [8, 8, 8, 0, 0, 0, 0, 0, 0]),
+        )),
+    )
+    .expect("failed");
+
+    // FM synthesis: a patchable bell built from sine operators
+    write_wav_file(
+        "out/fm_bell.wav",
+        44_100,
+        &quantize_samples::<i16>(&make_samples(6.0, 44_100, Patch::bell().generator(200.0))),
+    )
+    .expect("failed");
+
+    // Granular synthesis: freeze a bell's strike into a cloud of grains
+    let mut cloud = Granular::new(
+        Sample::new(
+            make_samples(2.0, 44_100, bell(200.0, 0.003, 0.5)),
+            44_100,
+            200.0,
+        ),
+        44_100,
+        1234,
+    );
+    cloud.position = 0.1;
+    cloud.speed = 0.0;
+    cloud.grain_size = 0.1;
+    cloud.density = 50.0;
+    cloud.position_jitter = 0.05;
+    cloud.pitch_jitter = 0.2;
+    write_wav_file(
+        "out/granular_cloud.wav",
+        44_100,
+        &quantize_samples::<i16>(&peak_normalize(
+            &cloud.take(44_100 * 5).collect::<Vec<f64>>(),
+        )),
+    )
+    .expect("failed");
+
+    // The same bell, twice as long at the same pitch, and a fifth up at the same length
+    let bell_samples = make_samples(2.0, 44_100, bell(200.0, 0.003, 0.5));
+    write_wav_file(
+        "out/bell_stretched.wav",
+        44_100,
+        &quantize_samples::<i16>(&time_stretch(&bell_samples, 2.0, 44_100)),
+    )
+    .expect("failed");
+    write_wav_file(
+        "out/bell_fifth.wav",
+        44_100,
+        &quantize_samples::<i16>(&pitch_shift(&bell_samples, 7.0, 44_100)),
+    )
+    .expect("failed");
+
     // Karplus-Strong introduces decay to the waveform
     write_wav_file(
         "out/karplus_strong.wav",
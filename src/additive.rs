@@ -0,0 +1,187 @@
+//! Additive synthesis: tones built from a list of sine partials.
+//!
+//! Each `Partial` is a sine at a ratio of the fundamental frequency, with its own amplitude,
+//! starting phase and optional envelope. Partials can be stretched away from exact harmonics
+//! with an inharmonicity coefficient `B`, as in stiff strings such as piano strings:
+//! partial ratio `r` is played at `r * sqrt(1 + B * r^2)`.
+//!
+//! ```
+//! use synthrs::additive::{additive, Partial};
+//! use synthrs::filter::Adsr;
+//! use synthrs::synthesizer::make_samples;
+//!
+//! // A plucked tone whose upper partials die away faster
+//! let partials: Vec<Partial> = (1..8)
+//!     .map(|harmonic| {
+//!         let mut partial = Partial::new(harmonic as f64, 0.5 / harmonic as f64);
+//!         partial.envelope = Some(Adsr::new(0.005, 2.0 / harmonic as f64, 0.0, 0.0));
+//!         partial
+//!     })
+//!     .collect();
+//!
+//! let samples = make_samples(2.0, 44_100, additive(220.0, partials, 0.0004));
+//! ```
+
+use std::f64::consts::PI;
+
+use crate::filter::Adsr;
+
+/// A single sine component of an additive tone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Partial {
+    /// Frequency as a multiple of the fundamental
+    pub ratio: f64,
+    pub amplitude: f64,
+    /// Starting phase in cycles, from `0.0` to `1.0`
+    pub phase: f64,
+    /// Envelope applied to this partial only, or `None` to sound at a constant amplitude
+    pub envelope: Option<Adsr>,
+}
+
+impl Partial {
+    /// Creates a partial starting at phase `0` with no envelope.
+    pub fn new(ratio: f64, amplitude: f64) -> Partial {
+        Partial {
+            ratio,
+            amplitude,
+            phase: 0.0,
+            envelope: None,
+        }
+    }
+
+    /// Returns the ratio after inharmonic stretching with coefficient `inharmonicity`.
+    ///
+    /// ```
+    /// use synthrs::additive::Partial;
+    ///
+    /// assert_eq!(Partial::new(3.0, 1.0).stretched_ratio(0.0), 3.0);
+    /// assert!(Partial::new(3.0, 1.0).stretched_ratio(0.001) > 3.0);
+    /// ```
+    pub fn stretched_ratio(&self, inharmonicity: f64) -> f64 {
+        self.ratio * (1.0 + inharmonicity * self.ratio * self.ratio).sqrt()
+    }
+
+    /// Amplitude of this partial at time `t` for a fundamental of `frequency`
+    pub fn value(&self, frequency: f64, t: f64, inharmonicity: f64) -> f64 {
+        let level = match self.envelope {
+            Some(envelope) => envelope.value(t, None),
+            None => 1.0,
+        };
+
+        if level == 0.0 {
+            return 0.0;
+        }
+
+        let cycles = t * frequency * self.stretched_ratio(inharmonicity) + self.phase;
+        self.amplitude * level * (2.0 * PI * cycles).sin()
+    }
+}
+
+/// Creates an additive generator summing `partials` over a fundamental `frequency`.
+///
+/// `partials` can be anything that can be viewed as a slice, such as a `Vec` or a fixed-size
+/// array (which avoids allocating when a generator is created for every note).
+pub fn additive<P: AsRef<[Partial]>>(
+    frequency: f64,
+    partials: P,
+    inharmonicity: f64,
+) -> impl Fn(f64) -> f64 {
+    move |t| {
+        partials
+            .as_ref()
+            .iter()
+            .map(|partial| partial.value(frequency, t, inharmonicity))
+            .sum()
+    }
+}
+
+/// Frequency ratios of the nine drawbars of a tonewheel organ, from 16' to 1'
+pub const DRAWBAR_RATIOS: [f64; 9] = [0.5, 1.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0];
+
+/// Returns the partials of a drawbar organ registration. Each drawbar goes from `0` (silent) to
+/// `8` (loudest), each step being about 3dB, in the usual order: 16', 5 1/3', 8', 4', 2 2/3',
+/// 2', 1 3/5', 1 1/3' and 1'. Amplitudes are scaled so that the output stays within `[-1, 1]`.
+pub fn drawbar_partials(drawbars: [u8; 9]) -> [Partial; 9] {
+    let levels: Vec<f64> = drawbars
+        .iter()
+        .map(|&drawbar| match drawbar.min(8) {
+            0 => 0.0,
+            drawbar => 10.0f64.powf(-3.0 * (8 - drawbar) as f64 / 20.0),
+        })
+        .collect();
+
+    let total: f64 = levels.iter().sum();
+    let scale = if total > 1.0 { 1.0 / total } else { 1.0 };
+
+    let mut partials = [Partial::new(0.0, 0.0); 9];
+    for (i, partial) in partials.iter_mut().enumerate() {
+        *partial = Partial::new(DRAWBAR_RATIOS[i], levels[i] * scale);
+    }
+    partials
+}
+
+/// Drawbar organ with a given registration, see `drawbar_partials`.
+///
+/// ```
+/// use synthrs::additive::drawbar_organ;
+/// use synthrs::synthesizer::make_samples;
+///
+/// // The classic "888000000" registration
+/// let samples = make_samples(1.0, 44_100, drawbar_organ(220.0, [8, 8, 8, 0, 0, 0, 0, 0, 0]));
+/// ```
+pub fn drawbar_organ(frequency: f64, drawbars: [u8; 9]) -> impl Fn(f64) -> f64 {
+    additive(frequency, drawbar_partials(drawbars), 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wave::sine_wave;
+
+    #[test]
+    fn test_single_partial_is_a_sine() {
+        let generator = additive(220.0, [Partial::new(2.0, 0.5)], 0.0);
+        for i in 0..1000 {
+            let t = i as f64 / 44_100.0;
+            assert!((generator(t) - 0.5 * sine_wave(440.0)(t)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_partial_phase_and_envelope() {
+        let mut partial = Partial::new(1.0, 1.0);
+        partial.phase = 0.25;
+        assert!((partial.value(100.0, 0.0, 0.0) - 1.0).abs() < 1e-12);
+
+        partial.envelope = Some(Adsr::new(0.0, 1.0, 0.0, 0.0));
+        assert!((partial.value(100.0, 0.5, 0.0) - 0.5 * (2.0 * PI * 50.25).sin()).abs() < 1e-9);
+        assert!(partial.value(100.0, 2.0, 0.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_inharmonicity_stretches_upper_partials_more() {
+        let b = 0.0005;
+        let second = Partial::new(2.0, 1.0).stretched_ratio(b) / 2.0;
+        let tenth = Partial::new(10.0, 1.0).stretched_ratio(b) / 10.0;
+        assert!(second > 1.0 && tenth > second);
+    }
+
+    #[test]
+    fn test_drawbars() {
+        let silent = drawbar_organ(440.0, [0; 9]);
+        assert!((0..100).all(|i| silent(i as f64 / 44_100.0) == 0.0));
+
+        let flute = drawbar_organ(440.0, [0, 0, 8, 0, 0, 0, 0, 0, 0]);
+        let full = drawbar_organ(440.0, [8; 9]);
+        for i in 0..1000 {
+            let t = i as f64 / 44_100.0;
+            assert!((flute(t) - sine_wave(440.0)(t)).abs() < 1e-9);
+            assert!(full(t).abs() <= 1.0);
+        }
+
+        // Each step is 3dB
+        let partials = drawbar_partials([0, 0, 8, 7, 0, 0, 0, 0, 0]);
+        let ratio = partials[3].amplitude / partials[2].amplitude;
+        assert!((ratio - 10.0f64.powf(-3.0 / 20.0)).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,615 @@
+//! Level and loudness analysis, and normalization to level targets.
+//!
+//! * RMS and sample peak levels
+//! * ITU-R BS.1770 (EBU R128) momentary, short-term and integrated loudness in LUFS
+//! * Oversampled true peak in dBTP
+//! * Spectra (FFT)
+//!
+//! Multichannel functions take interleaved samples (`[l0, r0, l1, r1, ...]`) and a channel count.
+//! Mono samples are analysed with `channels` set to `1`.
+//!
+//! ```
+//! use synthrs::analysis::{integrated_loudness, normalize_loudness};
+//! use synthrs::synthesizer::make_samples;
+//! use synthrs::wave::sine_wave;
+//!
+//! let samples = make_samples(1.0, 44_100, sine_wave(1000.0));
+//!
+//! // Normalize to the -23 LUFS EBU R128 broadcast target
+//! let normalized = normalize_loudness(&samples, 1, 44_100, -23.0);
+//! assert!((integrated_loudness(&normalized, 1, 44_100) + 23.0).abs() < 0.1);
+//! ```
+//!
+//! Silent input has a level of negative infinity, and is returned unchanged by the
+//! normalization functions.
+
+use std::f64::consts::PI;
+
+use num::Complex;
+
+use crate::filter::{blackman_window, Biquad, BiquadCoefficients};
+
+/// Converts a level in decibels into a linear gain.
+pub fn db_to_gain(db: f64) -> f64 {
+    10.0f64.powf(db / 20.0)
+}
+
+/// Converts a linear gain into a level in decibels. Returns negative infinity for silence.
+pub fn gain_to_db(gain: f64) -> f64 {
+    20.0 * gain.abs().log10()
+}
+
+/// Returns the absolute sample peak of `samples` as a linear value.
+pub fn peak(samples: &[f64]) -> f64 {
+    samples
+        .iter()
+        .fold(0.0f64, |acc, &sample| acc.max(sample.abs()))
+}
+
+/// Returns the root-mean-square level of `samples` as a linear value.
+///
+/// ```
+/// use synthrs::analysis::rms;
+///
+/// assert!((rms(&[1.0, -1.0, 1.0, -1.0]) - 1.0).abs() < 1e-12);
+/// assert_eq!(rms(&[]), 0.0);
+/// ```
+pub fn rms(samples: &[f64]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+
+    let sum_of_squares = samples
+        .iter()
+        .fold(0.0, |acc, &sample| acc + sample * sample);
+    (sum_of_squares / samples.len() as f64).sqrt()
+}
+
+/// Returns the true peak of interleaved `samples` as a linear value, estimated by 4x
+/// oversampling as described in ITU-R BS.1770 Annex 2. The true peak can be above the sample
+/// peak when the waveform peaks between samples.
+///
+/// ```
+/// use synthrs::analysis::{peak, true_peak};
+///
+/// // A quarter sample rate sine wave which is never sampled at its peak
+/// let samples: Vec<f64> = (0..1000)
+///     .map(|i| (i as f64 * std::f64::consts::PI / 2.0 + std::f64::consts::PI / 4.0).sin())
+///     .collect();
+///
+/// assert!(peak(&samples) < 0.71);
+/// assert!(true_peak(&samples, 1) > 0.99);
+/// ```
+pub fn true_peak(samples: &[f64], channels: usize) -> f64 {
+    let phases = oversampling_phases();
+
+    (0..channels).fold(0.0f64, |acc, channel| {
+        let channel_samples: Vec<f64> = samples
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .copied()
+            .collect();
+
+        let mut channel_peak = peak(&channel_samples);
+        for i in 0..channel_samples.len() {
+            for phase in &phases {
+                let interpolated = phase.iter().enumerate().fold(0.0, |acc, (j, tap)| {
+                    // Each phase is centered between input samples `i` and `i + 1`
+                    let index = i as isize + j as isize - (phase.len() / 2) as isize + 1;
+                    if index < 0 || index >= channel_samples.len() as isize {
+                        acc
+                    } else {
+                        acc + tap * channel_samples[index as usize]
+                    }
+                });
+                channel_peak = channel_peak.max(interpolated.abs());
+            }
+        }
+
+        acc.max(channel_peak)
+    })
+}
+
+/// Interpolation filters for each fractional offset used in true peak oversampling.
+/// 48 taps of a Blackman-windowed sinc are split into four phases of 12 taps.
+fn oversampling_phases() -> Vec<Vec<f64>> {
+    let factor = 4;
+    let taps_per_phase = 12;
+    let length = factor * taps_per_phase;
+    let window = blackman_window(length + 1);
+
+    (1..factor)
+        .map(|phase| {
+            (0..taps_per_phase)
+                .map(|tap| {
+                    let n = tap * factor + factor - phase;
+                    let x = (n as f64 - length as f64 / 2.0) / factor as f64;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (PI * x).sin() / (PI * x)
+                    };
+                    sinc * window[n]
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Creates the two-stage K-weighting filter (a high shelf modelling the head, followed by a
+/// highpass) from ITU-R BS.1770, designed for any sample rate.
+fn k_weighting(sample_rate: usize) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    // Stage 1: high shelf
+    let (f0, gain, q) = (
+        1_681.974_450_955_533,
+        3.999_843_853_973_347,
+        0.707_175_236_955_419_6,
+    );
+    let k = (PI * f0 / rate).tan();
+    let vh = 10.0f64.powf(gain / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(BiquadCoefficients::new(
+        [
+            vh + vb * k / q + k * k,
+            2.0 * (k * k - vh),
+            vh - vb * k / q + k * k,
+        ],
+        [a0, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+    ));
+
+    // Stage 2: highpass
+    let (f0, q) = (38.135_470_876_024_44, 0.500_327_037_323_877_3);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad::new(BiquadCoefficients {
+        b0: 1.0,
+        b1: -2.0,
+        b2: 1.0,
+        a1: 2.0 * (k * k - 1.0) / a0,
+        a2: (1.0 - k / q + k * k) / a0,
+    });
+
+    [shelf, highpass]
+}
+
+/// Channel weighting from ITU-R BS.1770. Surround channels (from the 4th onwards in 5 channel
+/// layouts, `Ls` and `Rs` in 5.1) are boosted by 1.5dB and the 5.1 LFE channel is excluded.
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (5, 3) | (5, 4) | (6, 4) | (6, 5) => 1.41,
+        (6, 3) => 0.0,
+        _ => 1.0,
+    }
+}
+
+/// Returns the mean square of the K-weighted signal for each block of `block_length` seconds,
+/// stepping by `step` seconds, summed across channels with their weights.
+fn block_powers(
+    samples: &[f64],
+    channels: usize,
+    sample_rate: usize,
+    block_length: f64,
+    step: f64,
+) -> Vec<f64> {
+    let frames = samples.len() / channels;
+    let block_frames = (block_length * sample_rate as f64).round() as usize;
+    let step_frames = (step * sample_rate as f64).round() as usize;
+
+    let weighted: Vec<Vec<f64>> = (0..channels)
+        .map(|channel| {
+            let [mut shelf, mut highpass] = k_weighting(sample_rate);
+            samples
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .map(|&sample| {
+                    let filtered = highpass.tick(shelf.tick(sample));
+                    filtered * filtered
+                })
+                .collect()
+        })
+        .collect();
+
+    if frames < block_frames || block_frames == 0 {
+        return Vec::new();
+    }
+
+    (0..=(frames - block_frames) / step_frames)
+        .map(|block| {
+            let start = block * step_frames;
+            weighted
+                .iter()
+                .enumerate()
+                .fold(0.0, |acc, (channel, squares)| {
+                    let sum: f64 = squares[start..start + block_frames].iter().sum();
+                    acc + channel_weight(channel, channels) * sum / block_frames as f64
+                })
+        })
+        .collect()
+}
+
+/// Converts a weighted mean square into LUFS
+fn power_to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// Returns the momentary loudness (400ms blocks) in LUFS for every 100ms step of the input.
+pub fn momentary_loudness(samples: &[f64], channels: usize, sample_rate: usize) -> Vec<f64> {
+    block_powers(samples, channels, sample_rate, 0.4, 0.1)
+        .into_iter()
+        .map(power_to_lufs)
+        .collect()
+}
+
+/// Returns the short-term loudness (3s blocks) in LUFS for every 100ms step of the input.
+///
+/// ```
+/// use synthrs::analysis::short_term_loudness;
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::wave::sine_wave;
+///
+/// let samples = make_samples(4.0, 44_100, sine_wave(1000.0));
+/// let loudness = short_term_loudness(&samples, 1, 44_100);
+/// assert_eq!(loudness.len(), 11);
+/// ```
+pub fn short_term_loudness(samples: &[f64], channels: usize, sample_rate: usize) -> Vec<f64> {
+    block_powers(samples, channels, sample_rate, 3.0, 0.1)
+        .into_iter()
+        .map(power_to_lufs)
+        .collect()
+}
+
+/// Returns the gated integrated loudness of the whole input in LUFS, as specified by
+/// ITU-R BS.1770 (EBU R128). Uses 400ms blocks overlapping by 75%, an absolute gate at -70 LUFS
+/// and a relative gate 10LU below the absolute-gated loudness.
+///
+/// Returns negative infinity for silent input or input shorter than a single block.
+///
+/// ```
+/// use synthrs::analysis::integrated_loudness;
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::wave::sine_wave;
+///
+/// // A full scale 1kHz sine on one channel measures -3.01 LUFS
+/// let samples = make_samples(1.0, 48_000, sine_wave(1000.0));
+/// assert!((integrated_loudness(&samples, 1, 48_000) + 3.01).abs() < 0.05);
+/// ```
+pub fn integrated_loudness(samples: &[f64], channels: usize, sample_rate: usize) -> f64 {
+    let powers = block_powers(samples, channels, sample_rate, 0.4, 0.1);
+
+    let gated_mean = |threshold: f64| -> Option<f64> {
+        let gated: Vec<f64> = powers
+            .iter()
+            .copied()
+            .filter(|&power| power_to_lufs(power) > threshold)
+            .collect();
+
+        if gated.is_empty() {
+            None
+        } else {
+            Some(gated.iter().sum::<f64>() / gated.len() as f64)
+        }
+    };
+
+    let absolute_gated = match gated_mean(-70.0) {
+        Some(power) => power,
+        None => return f64::NEG_INFINITY,
+    };
+    let relative_threshold = power_to_lufs(absolute_gated) - 10.0;
+
+    gated_mean(relative_threshold).map_or(f64::NEG_INFINITY, power_to_lufs)
+}
+
+/// In-place radix-2 fast Fourier transform. `buffer.len()` must be a power of two.
+///
+/// ```
+/// use num::Complex;
+/// use synthrs::analysis::{fft, ifft};
+///
+/// let mut buffer = vec![Complex::new(1.0, 0.0), Complex::new(0.0, 0.0), Complex::new(0.0, 0.0), Complex::new(0.0, 0.0)];
+/// fft(&mut buffer);
+/// assert!(buffer.iter().all(|bin| (bin.re - 1.0).abs() < 1e-12 && bin.im.abs() < 1e-12));
+///
+/// ifft(&mut buffer);
+/// assert!((buffer[0].re - 1.0).abs() < 1e-12);
+/// ```
+pub fn fft(buffer: &mut [Complex<f64>]) {
+    transform(buffer, -1.0);
+}
+
+/// In-place inverse of `fft`, including the `1 / n` scaling.
+pub fn ifft(buffer: &mut [Complex<f64>]) {
+    transform(buffer, 1.0);
+
+    let scale = 1.0 / buffer.len() as f64;
+    for bin in buffer.iter_mut() {
+        *bin *= scale;
+    }
+}
+
+/// Iterative Cooley-Tukey FFT, `sign` is the sign of the twiddle factor exponent
+fn transform(buffer: &mut [Complex<f64>], sign: f64) {
+    let n = buffer.len();
+    assert!(n.is_power_of_two(), "FFT length must be a power of two");
+
+    // Bit-reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            buffer.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n {
+        let angle = sign * 2.0 * PI / length as f64;
+        let twiddle = Complex::new(angle.cos(), angle.sin());
+
+        for start in (0..n).step_by(length) {
+            let mut w = Complex::new(1.0, 0.0);
+            for k in 0..length / 2 {
+                let even = buffer[start + k];
+                let odd = buffer[start + k + length / 2] * w;
+                buffer[start + k] = even + odd;
+                buffer[start + k + length / 2] = even - odd;
+                w *= twiddle;
+            }
+        }
+
+        length <<= 1;
+    }
+}
+
+/// Returns the magnitude spectrum of real `samples` (bins `0` to `n / 2` inclusive), after
+/// applying a Blackman window. `samples.len()` must be a power of two. Bin `k` is at
+/// `k * sample_rate / n` hertz.
+///
+/// ```
+/// use synthrs::analysis::magnitude_spectrum;
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::wave::sine_wave;
+///
+/// // 1024 samples at 1024Hz gives 1Hz bins
+/// let samples = make_samples(1.0, 1024, sine_wave(100.0));
+/// let spectrum = magnitude_spectrum(&samples);
+///
+/// let loudest = (0..spectrum.len()).max_by(|&a, &b| spectrum[a].partial_cmp(&spectrum[b]).unwrap());
+/// assert_eq!(loudest, Some(100));
+/// ```
+pub fn magnitude_spectrum(samples: &[f64]) -> Vec<f64> {
+    let window = blackman_window(samples.len());
+    let mut buffer: Vec<Complex<f64>> = samples
+        .iter()
+        .zip(window.iter())
+        .map(|(&sample, &w)| Complex::new(sample * w, 0.0))
+        .collect();
+
+    fft(&mut buffer);
+
+    buffer[..=samples.len() / 2]
+        .iter()
+        .map(|bin| bin.norm())
+        .collect()
+}
+
+/// Ratio of the power between the harmonics of a fundamental at `bin` to the power on them
+/// (within three bins of each harmonic), in the spectrum of `samples`. Measures aliasing of
+/// periodic signals in tests, which should use a fundamental on a bin centre.
+#[cfg(test)]
+pub(crate) fn aliasing_ratio(samples: &[f64], bin: usize) -> f64 {
+    let (mut harmonic, mut alias) = (0.0, 0.0);
+    for (k, magnitude) in magnitude_spectrum(samples).iter().enumerate().skip(1) {
+        let distance = k % bin;
+        if distance <= 3 || distance >= bin - 3 {
+            harmonic += magnitude * magnitude;
+        } else {
+            alias += magnitude * magnitude;
+        }
+    }
+    alias / harmonic
+}
+
+/// Scales `samples` by `gain`, leaving them unchanged when the gain cannot be computed
+/// (silent input).
+fn apply_gain(samples: &[f64], gain: f64) -> Vec<f64> {
+    if gain.is_finite() {
+        samples.iter().map(|&sample| sample * gain).collect()
+    } else {
+        samples.to_vec()
+    }
+}
+
+/// Scales `samples` so that their sample peak is at `target_db` dBFS.
+///
+/// ```
+/// use synthrs::analysis::{normalize_peak, peak};
+///
+/// let normalized = normalize_peak(&[0.25, -0.5], 0.0);
+/// assert_eq!(normalized, vec![0.5, -1.0]);
+/// assert_eq!(normalize_peak(&[0.0, 0.0], 0.0), vec![0.0, 0.0]);
+/// ```
+pub fn normalize_peak(samples: &[f64], target_db: f64) -> Vec<f64> {
+    apply_gain(samples, db_to_gain(target_db) / peak(samples))
+}
+
+/// Scales `samples` so that their RMS level is at `target_db` dBFS. The result may clip.
+///
+/// ```
+/// use synthrs::analysis::{normalize_rms, rms};
+///
+/// let normalized = normalize_rms(&[0.25, -0.25], -6.0);
+/// assert!((rms(&normalized) - 0.501).abs() < 0.001);
+/// ```
+pub fn normalize_rms(samples: &[f64], target_db: f64) -> Vec<f64> {
+    apply_gain(samples, db_to_gain(target_db) / rms(samples))
+}
+
+/// Scales interleaved `samples` so that their integrated loudness is `target_lufs`, for example
+/// -14 LUFS for streaming or -23 LUFS for EBU R128 broadcast. The result may clip; combine with
+/// `normalize_true_peak` or check `true_peak` if a ceiling is needed.
+pub fn normalize_loudness(
+    samples: &[f64],
+    channels: usize,
+    sample_rate: usize,
+    target_lufs: f64,
+) -> Vec<f64> {
+    let loudness = integrated_loudness(samples, channels, sample_rate);
+    apply_gain(samples, db_to_gain(target_lufs - loudness))
+}
+
+/// Scales interleaved `samples` so that their true peak is at `target_dbtp` dBTP.
+pub fn normalize_true_peak(samples: &[f64], channels: usize, target_dbtp: f64) -> Vec<f64> {
+    apply_gain(
+        samples,
+        db_to_gain(target_dbtp) / true_peak(samples, channels),
+    )
+}
+
+/// A level target for `normalize`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Normalization {
+    /// Sample peak in dBFS
+    Peak(f64),
+    /// RMS level in dBFS
+    Rms(f64),
+    /// Integrated loudness in LUFS
+    Loudness(f64),
+    /// True peak in dBTP
+    TruePeak(f64),
+}
+
+/// Normalizes interleaved `samples` to the given target.
+///
+/// ```
+/// use synthrs::analysis::{normalize, Normalization};
+///
+/// let normalized = normalize(&[0.25, -0.5], 1, 44_100, Normalization::Peak(0.0));
+/// assert_eq!(normalized, vec![0.5, -1.0]);
+/// ```
+pub fn normalize(
+    samples: &[f64],
+    channels: usize,
+    sample_rate: usize,
+    normalization: Normalization,
+) -> Vec<f64> {
+    match normalization {
+        Normalization::Peak(target) => normalize_peak(samples, target),
+        Normalization::Rms(target) => normalize_rms(samples, target),
+        Normalization::Loudness(target) => {
+            normalize_loudness(samples, channels, sample_rate, target)
+        }
+        Normalization::TruePeak(target) => normalize_true_peak(samples, channels, target),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthesizer::make_samples;
+    use crate::wave::sine_wave;
+
+    #[test]
+    fn test_fft_matches_dft() {
+        let input: Vec<Complex<f64>> = (0..16)
+            .map(|i| Complex::new((i as f64 * 0.7).sin(), (i as f64 * 0.3).cos()))
+            .collect();
+
+        let mut output = input.clone();
+        fft(&mut output);
+
+        for (k, bin) in output.iter().enumerate() {
+            let expected = input
+                .iter()
+                .enumerate()
+                .fold(Complex::new(0.0, 0.0), |acc, (n, x)| {
+                    let angle = -2.0 * PI * (k * n) as f64 / 16.0;
+                    acc + x * Complex::new(angle.cos(), angle.sin())
+                });
+            assert!((bin - expected).norm() < 1e-9);
+        }
+
+        ifft(&mut output);
+        for (a, b) in output.iter().zip(input.iter()) {
+            assert!((a - b).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_rms_of_sine() {
+        let samples = make_samples(1.0, 44_100, sine_wave(441.0));
+        assert!((rms(&samples) - 0.5f64.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_integrated_loudness_reference_levels() {
+        // BS.1770: a 0dBFS 1kHz sine in one channel reads -3.01 LUFS, in two channels 0 LUFS
+        let mono = make_samples(2.0, 48_000, sine_wave(1000.0));
+        assert!((integrated_loudness(&mono, 1, 48_000) + 3.01).abs() < 0.05);
+
+        let stereo: Vec<f64> = mono.iter().flat_map(|&s| vec![s, s]).collect();
+        assert!(integrated_loudness(&stereo, 2, 48_000).abs() < 0.05);
+
+        // -20dBFS reads 20LU quieter at any sample rate
+        let quiet = make_samples(2.0, 44_100, |t| 0.1 * sine_wave(1000.0)(t));
+        assert!((integrated_loudness(&quiet, 1, 44_100) + 23.01).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_integrated_loudness_gates_silence() {
+        let mut samples = make_samples(2.0, 48_000, sine_wave(1000.0));
+        samples.extend(vec![0.0; 48_000 * 4]);
+        // Only the blocks straddling the end of the tone pull the level down (ungated this
+        // would be around -8 LUFS)
+        assert!((integrated_loudness(&samples, 1, 48_000) + 3.01).abs() < 0.5);
+
+        assert_eq!(
+            integrated_loudness(&[0.0; 48_000], 1, 48_000),
+            f64::NEG_INFINITY
+        );
+        assert_eq!(
+            integrated_loudness(&[0.5; 100], 1, 48_000),
+            f64::NEG_INFINITY
+        );
+    }
+
+    #[test]
+    fn test_normalize_loudness_targets() {
+        let samples = make_samples(2.0, 44_100, |t| 0.3 * sine_wave(220.0)(t));
+
+        for &target in &[-14.0, -23.0] {
+            let normalized = normalize_loudness(&samples, 1, 44_100, target);
+            assert!((integrated_loudness(&normalized, 1, 44_100) - target).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn test_normalize_silence_is_unchanged() {
+        let silence = vec![0.0; 100];
+        assert_eq!(normalize_peak(&silence, 0.0), silence);
+        assert_eq!(normalize_rms(&silence, -20.0), silence);
+        assert_eq!(normalize_loudness(&silence, 1, 44_100, -23.0), silence);
+        assert_eq!(normalize_true_peak(&silence, 1, -1.0), silence);
+    }
+
+    #[test]
+    fn test_normalize_true_peak() {
+        let samples: Vec<f64> = (0..1000)
+            .map(|i| 0.5 * (i as f64 * PI / 2.0 + PI / 4.0).sin())
+            .collect();
+
+        let normalized = normalize_true_peak(&samples, 1, -1.0);
+        assert!((gain_to_db(true_peak(&normalized, 1)) + 1.0).abs() < 1e-9);
+        assert!(peak(&normalized) < db_to_gain(-1.0));
+    }
+}
@@ -0,0 +1,217 @@
+//! Dither and noise shaping for quantization.
+//!
+//! Quantizing low-level signals (such as fades) without dither produces distortion that is
+//! correlated with the signal. Adding a small amount of noise before rounding turns that
+//! distortion into a constant, benign noise floor. Noise shaping further moves the noise floor
+//! towards high frequencies where it is less audible.
+//!
+//! Dither noise comes from a seeded RNG, so output is deterministic for a given seed.
+//!
+//! ```
+//! use synthrs::dither::{Dither, NoiseShaping};
+//! use synthrs::synthesizer::{make_samples, quantize_samples_dithered};
+//! use synthrs::wave::sine_wave;
+//!
+//! let samples = make_samples(0.1, 44_100, |t| 0.001 * sine_wave(440.0)(t));
+//! let quantized = quantize_samples_dithered::<i16>(
+//!     &samples,
+//!     Dither::Triangular,
+//!     NoiseShaping::Lipshitz,
+//!     1234,
+//! );
+//! ```
+//!
+//! See: `crate::synthesizer::quantize_samples_dithered`
+
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
+
+/// Probability density of the dither noise added before rounding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dither {
+    /// No dither, samples are only rounded
+    None,
+    /// Rectangular (RPDF) dither, uniform noise of 1 LSB peak-to-peak. Removes the first moment of
+    /// the quantization error but leaves noise modulation.
+    Rectangular,
+    /// Triangular (TPDF) dither, the sum of two uniform noises (2 LSB peak-to-peak). Makes both
+    /// the mean and the power of the quantization error independent of the signal.
+    Triangular,
+}
+
+/// Error feedback filter used to shape the spectrum of the quantization noise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseShaping {
+    /// Flat (white) quantization noise
+    None,
+    /// First-order highpass shaping, noise transfer function `1 - z^-1`
+    FirstOrder,
+    /// Second-order highpass shaping, noise transfer function `(1 - z^-1)^2`
+    SecondOrder,
+    /// Lipshitz et al.'s 5-tap psychoacoustically (E-weighted) optimised filter for 44.1kHz
+    Lipshitz,
+}
+
+impl NoiseShaping {
+    /// Error feedback coefficients, applied to the errors of the previous samples
+    fn coefficients(self) -> &'static [f64] {
+        match self {
+            NoiseShaping::None => &[],
+            NoiseShaping::FirstOrder => &[1.0],
+            NoiseShaping::SecondOrder => &[2.0, -1.0],
+            NoiseShaping::Lipshitz => &[2.033, -2.165, 1.959, -1.590, 0.614_9],
+        }
+    }
+}
+
+/// Stateful ditherer which rounds samples to integer quantization levels.
+///
+/// Samples passed to `tick` are already scaled so that one LSB is `1.0`.
+///
+/// ```
+/// use synthrs::dither::{Dither, Ditherer, NoiseShaping};
+///
+/// let mut ditherer = Ditherer::new(Dither::Triangular, NoiseShaping::None, 42);
+/// let level = ditherer.tick(0.25, -128.0, 127.0);
+/// assert!(level >= -2.0 && level <= 2.0);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Ditherer {
+    pub dither: Dither,
+    pub noise_shaping: NoiseShaping,
+    rng: XorShiftRng,
+    /// Most recent quantization errors, newest first
+    errors: [f64; 5],
+}
+
+impl Ditherer {
+    /// Creates a ditherer with the dither noise generated from `seed`.
+    pub fn new(dither: Dither, noise_shaping: NoiseShaping, seed: u64) -> Ditherer {
+        Ditherer {
+            dither,
+            noise_shaping,
+            rng: XorShiftRng::seed_from_u64(seed),
+            errors: [0.0; 5],
+        }
+    }
+
+    /// Returns the dither noise for the next sample, in LSBs
+    fn noise(&mut self) -> f64 {
+        match self.dither {
+            Dither::None => 0.0,
+            Dither::Rectangular => self.rng.gen::<f64>() - 0.5,
+            Dither::Triangular => self.rng.gen::<f64>() - self.rng.gen::<f64>(),
+        }
+    }
+
+    /// Dithers and rounds a sample scaled to LSB units, clipping the result to `[min, max]`.
+    pub fn tick(&mut self, input: f64, min: f64, max: f64) -> f64 {
+        let feedback = self
+            .noise_shaping
+            .coefficients()
+            .iter()
+            .zip(self.errors.iter())
+            .fold(0.0, |acc, (coefficient, error)| acc + coefficient * error);
+
+        let shaped = input - feedback;
+        let output = (shaped + self.noise()).round().clamp(min, max);
+
+        // The error is taken after clipping and bounded, so that a signal held past full scale
+        // can't build up in the feedback loop and run away
+        let error = (output - shaped).clamp(-2.0, 2.0);
+        self.errors.rotate_right(1);
+        self.errors[0] = error;
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(ditherer: &mut Ditherer, input: &[f64]) -> Vec<f64> {
+        input
+            .iter()
+            .map(|&sample| ditherer.tick(sample, -128.0, 127.0))
+            .collect()
+    }
+
+    #[test]
+    fn test_dither_is_deterministic() {
+        let input = vec![0.3; 64];
+        let a = run(
+            &mut Ditherer::new(Dither::Triangular, NoiseShaping::None, 7),
+            &input,
+        );
+        let b = run(
+            &mut Ditherer::new(Dither::Triangular, NoiseShaping::None, 7),
+            &input,
+        );
+        let c = run(
+            &mut Ditherer::new(Dither::Triangular, NoiseShaping::None, 8),
+            &input,
+        );
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_dither_removes_bias() {
+        let input = vec![0.25; 20_000];
+
+        let undithered = run(
+            &mut Ditherer::new(Dither::None, NoiseShaping::None, 1),
+            &input,
+        );
+        assert!(undithered.iter().all(|&level| level == 0.0));
+
+        for &dither in &[Dither::Rectangular, Dither::Triangular] {
+            let dithered = run(&mut Ditherer::new(dither, NoiseShaping::None, 1), &input);
+            let mean = dithered.iter().sum::<f64>() / dithered.len() as f64;
+            assert!((mean - 0.25).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn test_noise_shaping_moves_noise_out_of_low_frequencies() {
+        let input: Vec<f64> = (0..20_000)
+            .map(|i| 10.0 * (i as f64 * 0.001).sin())
+            .collect();
+
+        // Error power after a 64-sample moving average (a crude lowpass)
+        let low_frequency_error = |shaping: NoiseShaping| -> f64 {
+            let output = run(&mut Ditherer::new(Dither::Triangular, shaping, 3), &input);
+            let errors: Vec<f64> = output
+                .iter()
+                .zip(input.iter())
+                .map(|(o, i)| o - i)
+                .collect();
+            errors
+                .windows(64)
+                .map(|window| (window.iter().sum::<f64>() / 64.0).powi(2))
+                .sum::<f64>()
+        };
+
+        let flat = low_frequency_error(NoiseShaping::None);
+        assert!(low_frequency_error(NoiseShaping::FirstOrder) < flat / 4.0);
+        assert!(low_frequency_error(NoiseShaping::SecondOrder) < flat / 4.0);
+    }
+
+    #[test]
+    fn test_noise_shaping_recovers_from_clipping() {
+        // Held far past full scale, then silent
+        let mut input = vec![1000.0; 1000];
+        input.extend(vec![0.0; 1000]);
+
+        for &shaping in &[
+            NoiseShaping::FirstOrder,
+            NoiseShaping::SecondOrder,
+            NoiseShaping::Lipshitz,
+        ] {
+            let output = run(&mut Ditherer::new(Dither::Triangular, shaping, 5), &input);
+            assert!(output[..1000].iter().all(|&level| level >= 126.0));
+            assert!(output[1100..].iter().all(|&level| level.abs() <= 8.0));
+        }
+    }
+}
@@ -0,0 +1,598 @@
+//! Analog-style percussion synthesis, mapped to General MIDI percussion notes.
+//!
+//! Every drum is a set of parameters with a `generator`, which creates a generator of `t` for one
+//! hit. Envelopes and pitch sweeps follow `t`, while noise and filters advance by one sample on
+//! every call, so generators must be called once per sample, in order, at their sample rate.
+//!
+//! * `Kick`: sine swept down in pitch, with a noise click
+//! * `Snare`: two decaying sines and highpassed noise
+//! * `HiHat`: a cluster of six detuned squares, highpassed, as in the TR-808 (also used for
+//!   cymbals)
+//! * `Clap`: a few quick bursts of bandpassed noise and a longer tail
+//! * `Tom`: a shorter, gentler pitch-swept sine
+//!
+//! ```
+//! use synthrs::drums::{DrumKit, Kick};
+//! use synthrs::synthesizer::make_samples;
+//!
+//! let mut kick = Kick::new();
+//! kick.decay = 0.8;
+//! let samples = make_samples(kick.length(), 44_100, kick.generator(44_100, 1234));
+//!
+//! // General MIDI acoustic snare
+//! let kit = DrumKit::new();
+//! let snare = kit.drum(38).unwrap();
+//! let samples = make_samples(snare.length(), 44_100, snare.generator(44_100, 1234));
+//! ```
+
+use std::cell::RefCell;
+use std::f64::consts::PI;
+
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
+
+/// `ln(1000)`: an exponential decay reaches -60dB after `decay` seconds at this rate
+const DECAY_RATE: f64 = 6.907_755_278_982_137;
+
+/// Exponential decay reaching -60dB after `decay` seconds, `0.0` before `t = 0`
+fn decay(t: f64, decay: f64) -> f64 {
+    if t < 0.0 {
+        0.0
+    } else {
+        (-DECAY_RATE * t / decay).exp()
+    }
+}
+
+/// Phase in cycles of a sine swept exponentially from `start` to `end` hertz with time constant
+/// `sweep` seconds
+fn swept_phase(t: f64, start: f64, end: f64, sweep: f64) -> f64 {
+    end * t + (start - end) * sweep * (1.0 - (-t / sweep).exp())
+}
+
+/// One-pole lowpass and highpass filter
+#[derive(Clone, Copy, Debug)]
+struct OnePole {
+    coefficient: f64,
+    state: f64,
+}
+
+impl OnePole {
+    fn new(cutoff: f64, sample_rate: usize) -> OnePole {
+        OnePole {
+            coefficient: 1.0 - (-2.0 * PI * cutoff / sample_rate as f64).exp(),
+            state: 0.0,
+        }
+    }
+
+    fn lowpass(&mut self, input: f64) -> f64 {
+        self.state += self.coefficient * (input - self.state);
+        self.state
+    }
+
+    fn highpass(&mut self, input: f64) -> f64 {
+        input - self.lowpass(input)
+    }
+}
+
+/// Bass drum: a sine swept down in pitch, with a short noise click for the beater.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Kick {
+    /// Frequency at the start of the hit, in hertz
+    pub start_frequency: f64,
+    /// Frequency the sweep settles on, in hertz
+    pub end_frequency: f64,
+    /// Time constant of the pitch sweep, in seconds
+    pub sweep: f64,
+    /// Time to decay by 60dB, in seconds
+    pub decay: f64,
+    /// Level of the beater click
+    pub click: f64,
+}
+
+impl Kick {
+    pub fn new() -> Kick {
+        Kick {
+            start_frequency: 160.0,
+            end_frequency: 50.0,
+            sweep: 0.03,
+            decay: 0.6,
+            click: 0.3,
+        }
+    }
+
+    /// Length of a hit, in seconds
+    pub fn length(&self) -> f64 {
+        self.decay
+    }
+
+    /// Creates a generator for one hit. `seed` seeds the click noise.
+    pub fn generator(&self, sample_rate: usize, seed: u64) -> impl Fn(f64) -> f64 {
+        let kick = *self;
+        let state = RefCell::new((
+            XorShiftRng::seed_from_u64(seed),
+            OnePole::new(5_000.0, sample_rate),
+        ));
+
+        move |t| {
+            let (ref mut rng, ref mut lowpass) = *state.borrow_mut();
+            let phase = swept_phase(t, kick.start_frequency, kick.end_frequency, kick.sweep);
+            let body = (2.0 * PI * phase).sin() * decay(t, kick.decay);
+            let click = lowpass.lowpass(rng.gen_range(-1.0, 1.0)) * decay(t, 0.01);
+
+            ((1.0 - kick.click) * body + kick.click * click).clamp(-1.0, 1.0)
+        }
+    }
+}
+
+impl Default for Kick {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tom: a pitch-swept sine, like a `Kick` with a shorter, gentler sweep.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tom {
+    /// Frequency the sweep settles on, in hertz
+    pub frequency: f64,
+    /// How far above `frequency` the hit starts, as a ratio (`0.5` starts at 1.5 times
+    /// `frequency`)
+    pub bend: f64,
+    /// Time to decay by 60dB, in seconds
+    pub decay: f64,
+}
+
+impl Tom {
+    pub fn new(frequency: f64) -> Tom {
+        Tom {
+            frequency,
+            bend: 0.5,
+            decay: 0.5,
+        }
+    }
+
+    /// Length of a hit, in seconds
+    pub fn length(&self) -> f64 {
+        self.decay
+    }
+
+    /// Creates a generator for one hit. `seed` seeds the stick noise.
+    pub fn generator(&self, sample_rate: usize, seed: u64) -> impl Fn(f64) -> f64 {
+        let tom = *self;
+        let state = RefCell::new((
+            XorShiftRng::seed_from_u64(seed),
+            OnePole::new(3_000.0, sample_rate),
+        ));
+
+        move |t| {
+            let (ref mut rng, ref mut lowpass) = *state.borrow_mut();
+            let start = tom.frequency * (1.0 + tom.bend);
+            let phase = swept_phase(t, start, tom.frequency, 0.05);
+            let body = (2.0 * PI * phase).sin() * decay(t, tom.decay);
+            let stick = lowpass.lowpass(rng.gen_range(-1.0, 1.0)) * decay(t, 0.02);
+
+            (0.9 * body + 0.2 * stick).clamp(-1.0, 1.0)
+        }
+    }
+}
+
+/// Snare drum: two decaying sines for the drum head and highpassed noise for the snares.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Snare {
+    /// Frequency of the lower head mode, in hertz. The upper one is 1.8 times higher.
+    pub tone_frequency: f64,
+    /// Level of the head tone
+    pub tone: f64,
+    /// Level of the snare noise
+    pub snappy: f64,
+    /// Time for the head tone to decay by 60dB, in seconds
+    pub tone_decay: f64,
+    /// Time for the snare noise to decay by 60dB, in seconds
+    pub decay: f64,
+}
+
+impl Snare {
+    pub fn new() -> Snare {
+        Snare {
+            tone_frequency: 185.0,
+            tone: 0.5,
+            snappy: 0.6,
+            tone_decay: 0.15,
+            decay: 0.3,
+        }
+    }
+
+    /// Length of a hit, in seconds
+    pub fn length(&self) -> f64 {
+        self.decay.max(self.tone_decay)
+    }
+
+    /// Creates a generator for one hit. `seed` seeds the snare noise.
+    pub fn generator(&self, sample_rate: usize, seed: u64) -> impl Fn(f64) -> f64 {
+        let snare = *self;
+        let state = RefCell::new((
+            XorShiftRng::seed_from_u64(seed),
+            OnePole::new(1_500.0, sample_rate),
+        ));
+
+        move |t| {
+            let (ref mut rng, ref mut highpass) = *state.borrow_mut();
+            let head = 0.6 * (2.0 * PI * snare.tone_frequency * t).sin()
+                + 0.4 * (2.0 * PI * 1.8 * snare.tone_frequency * t).sin();
+            let noise = highpass.highpass(rng.gen_range(-1.0, 1.0));
+
+            (snare.tone * head * decay(t, snare.tone_decay)
+                + snare.snappy * noise * decay(t, snare.decay))
+            .clamp(-1.0, 1.0)
+        }
+    }
+}
+
+impl Default for Snare {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frequencies of the six square oscillators of the TR-808 cymbal and hi-hat circuit, in hertz
+const METAL_FREQUENCIES: [f64; 6] = [205.3, 304.4, 369.6, 522.7, 540.0, 800.0];
+
+/// Hi-hat or cymbal: six inharmonically tuned squares, highpassed to a metallic hiss.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HiHat {
+    /// Time to decay by 60dB, in seconds
+    pub decay: f64,
+    /// Multiplier for the square oscillator frequencies
+    pub tune: f64,
+    /// Highpass cutoff, in hertz
+    pub cutoff: f64,
+}
+
+impl HiHat {
+    /// A tight closed hi-hat.
+    pub fn closed() -> HiHat {
+        HiHat {
+            decay: 0.08,
+            tune: 1.0,
+            cutoff: 7_000.0,
+        }
+    }
+
+    /// A hi-hat closed with the pedal: even shorter than `closed`.
+    pub fn pedal() -> HiHat {
+        HiHat {
+            decay: 0.05,
+            ..HiHat::closed()
+        }
+    }
+
+    /// An open hi-hat.
+    pub fn open() -> HiHat {
+        HiHat {
+            decay: 0.5,
+            ..HiHat::closed()
+        }
+    }
+
+    /// A crash cymbal: long, lower and wider than a hi-hat.
+    pub fn crash() -> HiHat {
+        HiHat {
+            decay: 1.6,
+            tune: 0.8,
+            cutoff: 4_000.0,
+        }
+    }
+
+    /// A ride cymbal.
+    pub fn ride() -> HiHat {
+        HiHat {
+            decay: 1.2,
+            tune: 1.2,
+            cutoff: 5_000.0,
+        }
+    }
+
+    /// Length of a hit, in seconds
+    pub fn length(&self) -> f64 {
+        self.decay
+    }
+
+    /// Creates a generator for one hit. The hi-hat is not random, so `seed` only sets the
+    /// starting phases of the squares.
+    pub fn generator(&self, sample_rate: usize, seed: u64) -> impl Fn(f64) -> f64 {
+        let hihat = *self;
+        let mut rng = XorShiftRng::seed_from_u64(seed);
+        let mut phases = [0.0; 6];
+        for phase in phases.iter_mut() {
+            *phase = rng.gen::<f64>();
+        }
+        let filters = RefCell::new([OnePole::new(hihat.cutoff, sample_rate); 2]);
+
+        move |t| {
+            let metal = METAL_FREQUENCIES
+                .iter()
+                .zip(phases.iter())
+                .map(|(frequency, phase)| {
+                    if (frequency * hihat.tune * t + phase).fract() < 0.5 {
+                        1.0
+                    } else {
+                        -1.0
+                    }
+                })
+                .sum::<f64>()
+                / 6.0;
+
+            let [ref mut first, ref mut second] = *filters.borrow_mut();
+            let filtered = second.highpass(first.highpass(metal));
+            (6.0 * filtered * decay(t, hihat.decay)).clamp(-1.0, 1.0)
+        }
+    }
+}
+
+/// Hand clap: a few quick bursts of bandpassed noise, then a longer tail.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Clap {
+    /// Number of bursts before the tail
+    pub bursts: usize,
+    /// Time between bursts, in seconds
+    pub spacing: f64,
+    /// Time for the tail to decay by 60dB, in seconds
+    pub decay: f64,
+}
+
+impl Clap {
+    pub fn new() -> Clap {
+        Clap {
+            bursts: 3,
+            spacing: 0.011,
+            decay: 0.25,
+        }
+    }
+
+    /// Length of a hit, in seconds
+    pub fn length(&self) -> f64 {
+        self.bursts as f64 * self.spacing + self.decay
+    }
+
+    /// Creates a generator for one hit. `seed` seeds the noise.
+    pub fn generator(&self, sample_rate: usize, seed: u64) -> impl Fn(f64) -> f64 {
+        let clap = *self;
+        let state = RefCell::new((
+            XorShiftRng::seed_from_u64(seed),
+            OnePole::new(800.0, sample_rate),
+            OnePole::new(2_500.0, sample_rate),
+        ));
+
+        move |t| {
+            let (ref mut rng, ref mut highpass, ref mut lowpass) = *state.borrow_mut();
+            let noise = lowpass.lowpass(highpass.highpass(rng.gen_range(-1.0, 1.0)));
+
+            let tail_start = clap.bursts as f64 * clap.spacing;
+            let level = (0..clap.bursts)
+                .map(|burst| decay(t - burst as f64 * clap.spacing, 0.02))
+                .chain(std::iter::once(0.7 * decay(t - tail_start, clap.decay)))
+                .fold(0.0, f64::max);
+
+            (3.0 * noise * level).clamp(-1.0, 1.0)
+        }
+    }
+}
+
+impl Default for Clap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Any one of the drums, as played by a `DrumKit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Drum {
+    Kick(Kick),
+    Snare(Snare),
+    HiHat(HiHat),
+    Clap(Clap),
+    Tom(Tom),
+}
+
+impl Drum {
+    /// Length of a hit, in seconds
+    pub fn length(&self) -> f64 {
+        match self {
+            Drum::Kick(kick) => kick.length(),
+            Drum::Snare(snare) => snare.length(),
+            Drum::HiHat(hihat) => hihat.length(),
+            Drum::Clap(clap) => clap.length(),
+            Drum::Tom(tom) => tom.length(),
+        }
+    }
+
+    /// Creates a generator for one hit of this drum.
+    pub fn generator(&self, sample_rate: usize, seed: u64) -> Box<dyn Fn(f64) -> f64> {
+        match self {
+            Drum::Kick(kick) => Box::new(kick.generator(sample_rate, seed)),
+            Drum::Snare(snare) => Box::new(snare.generator(sample_rate, seed)),
+            Drum::HiHat(hihat) => Box::new(hihat.generator(sample_rate, seed)),
+            Drum::Clap(clap) => Box::new(clap.generator(sample_rate, seed)),
+            Drum::Tom(tom) => Box::new(tom.generator(sample_rate, seed)),
+        }
+    }
+}
+
+/// A drum kit playing General MIDI percussion notes (MIDI channel 10).
+///
+/// | Notes          | Drum                                      |
+/// |----------------|-------------------------------------------|
+/// | 35, 36         | `kick`                                    |
+/// | 37, 38, 40     | `snare` (side stick is a quieter snare)   |
+/// | 39             | `clap`                                    |
+/// | 41, 43, 45, 47, 48, 50 | `toms`, from low floor to high    |
+/// | 42             | `closed_hihat`                            |
+/// | 44             | `pedal_hihat`                             |
+/// | 46             | `open_hihat`                              |
+/// | 49, 52, 55, 57 | `crash` (Chinese and splash included)     |
+/// | 51, 53, 59     | `ride`                                    |
+///
+/// Other notes have no drum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DrumKit {
+    pub kick: Kick,
+    pub snare: Snare,
+    pub clap: Clap,
+    pub closed_hihat: HiHat,
+    pub pedal_hihat: HiHat,
+    pub open_hihat: HiHat,
+    pub crash: HiHat,
+    pub ride: HiHat,
+    /// Low floor, high floor, low, low-mid, hi-mid and high toms
+    pub toms: [Tom; 6],
+}
+
+impl DrumKit {
+    pub fn new() -> DrumKit {
+        DrumKit {
+            kick: Kick::new(),
+            snare: Snare::new(),
+            clap: Clap::new(),
+            closed_hihat: HiHat::closed(),
+            pedal_hihat: HiHat::pedal(),
+            open_hihat: HiHat::open(),
+            crash: HiHat::crash(),
+            ride: HiHat::ride(),
+            toms: [
+                Tom::new(82.0),
+                Tom::new(98.0),
+                Tom::new(110.0),
+                Tom::new(131.0),
+                Tom::new(147.0),
+                Tom::new(165.0),
+            ],
+        }
+    }
+
+    /// Returns the drum for a General MIDI percussion note, if there is one.
+    pub fn drum(&self, note: u8) -> Option<Drum> {
+        let drum = match note {
+            35 | 36 => Drum::Kick(self.kick),
+            37 => Drum::Snare(Snare {
+                tone: self.snare.tone * 0.5,
+                snappy: self.snare.snappy * 0.3,
+                decay: self.snare.decay * 0.3,
+                ..self.snare
+            }),
+            38 | 40 => Drum::Snare(self.snare),
+            39 => Drum::Clap(self.clap),
+            41 => Drum::Tom(self.toms[0]),
+            43 => Drum::Tom(self.toms[1]),
+            45 => Drum::Tom(self.toms[2]),
+            47 => Drum::Tom(self.toms[3]),
+            48 => Drum::Tom(self.toms[4]),
+            50 => Drum::Tom(self.toms[5]),
+            42 => Drum::HiHat(self.closed_hihat),
+            44 => Drum::HiHat(self.pedal_hihat),
+            46 => Drum::HiHat(self.open_hihat),
+            49 | 52 | 55 | 57 => Drum::HiHat(self.crash),
+            51 | 53 | 59 => Drum::HiHat(self.ride),
+            _ => return None,
+        };
+
+        Some(drum)
+    }
+}
+
+impl Default for DrumKit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{magnitude_spectrum, rms};
+    use crate::synthesizer::make_samples;
+
+    fn render(drum: Drum, seed: u64) -> Vec<f64> {
+        make_samples(drum.length(), 44_100, drum.generator(44_100, seed))
+    }
+
+    fn zero_crossings(samples: &[f64]) -> usize {
+        samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count()
+    }
+
+    #[test]
+    fn test_kick_sweeps_down_and_decays() {
+        let mut kick = Kick::new();
+        kick.click = 0.0;
+        let samples = render(Drum::Kick(kick), 1);
+
+        // 20ms windows at the start and after the sweep has settled
+        let early = zero_crossings(&samples[..882]);
+        let late = zero_crossings(&samples[8820..9702]);
+        assert!(early > late);
+        assert!((late as f64 / 2.0 - 50.0 * 0.02).abs() <= 1.0);
+
+        let end = samples.len();
+        assert!(rms(&samples[end - 441..]) < 0.002);
+    }
+
+    #[test]
+    fn test_hihat_is_bright() {
+        let samples = render(Drum::HiHat(HiHat::open()), 1);
+        let spectrum = magnitude_spectrum(&samples[..8192]);
+        let cutoff = (5_000.0 * 8192.0 / 44_100.0) as usize;
+
+        let power = |bins: &[f64]| bins.iter().map(|m| m * m).sum::<f64>();
+        assert!(power(&spectrum[cutoff..]) > 4.0 * power(&spectrum[..cutoff]));
+    }
+
+    #[test]
+    fn test_clap_has_bursts() {
+        let clap = Clap::new();
+        let samples = render(Drum::Clap(clap), 1);
+
+        // Louder at the start of each burst than just before the next one
+        let level = |t: f64| {
+            let i = (t * 44_100.0) as usize;
+            rms(&samples[i..i + 100])
+        };
+        assert!(level(clap.spacing) > 2.0 * level(clap.spacing - 0.003));
+    }
+
+    #[test]
+    fn test_general_midi_mapping() {
+        let kit = DrumKit::new();
+        assert_eq!(kit.drum(36), Some(Drum::Kick(kit.kick)));
+        assert_eq!(kit.drum(38), Some(Drum::Snare(kit.snare)));
+        assert_eq!(kit.drum(39), Some(Drum::Clap(kit.clap)));
+        assert_eq!(kit.drum(42), Some(Drum::HiHat(kit.closed_hihat)));
+        assert_eq!(kit.drum(46), Some(Drum::HiHat(kit.open_hihat)));
+        assert_eq!(kit.drum(34), None);
+        assert_eq!(kit.drum(82), None);
+
+        let toms: Vec<f64> = [41, 43, 45, 47, 48, 50]
+            .iter()
+            .map(|&note| match kit.drum(note) {
+                Some(Drum::Tom(tom)) => tom.frequency,
+                _ => panic!("expected a tom"),
+            })
+            .collect();
+        assert!(toms.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_drums_are_seeded_and_bounded() {
+        let kit = DrumKit::new();
+        for note in 35..60 {
+            if let Some(drum) = kit.drum(note) {
+                let a = render(drum, 3);
+                let b = render(drum, 3);
+                assert_eq!(a, b);
+                assert!(a.iter().all(|sample| sample.abs() <= 1.0));
+                assert!(rms(&a) > 0.01);
+            }
+        }
+    }
+}
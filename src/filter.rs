@@ -27,14 +27,14 @@
 //! #### Common stateless filter arguments:
 //!
 //! * `cutoff`: as a fraction of sample rate, can be obtained from
-//!             `cutoff_from_frequency(cutoff, sample_rate)`. (eg. for a lowpass filter
-//!             frequencies below `sample_rate` / `cutoff` are preserved)
+//!   `cutoff_from_frequency(cutoff, sample_rate)`. (eg. for a lowpass filter
+//!   frequencies below `sample_rate` / `cutoff` are preserved)
 //! * `band`: transition band as a fraction of the sample rate. This determines how
-//!         the cutoff "blends", or how harsh a cutoff this is.
+//!   the cutoff "blends", or how harsh a cutoff this is.
 //!
 //! ### Stateful filters
 //!
-//! Stateful filters are structs which hold some state, such as `DelayLine` which has to
+//! Stateful filters are structs which hold some state, such as `DelayLine` or `Biquad` which have to
 //! keep in memory historical samples.
 //!
 //! They can be used to transform a bunch of samples using `map`.
@@ -105,6 +105,21 @@ pub fn blackman_window(size: usize) -> Vec<f64> {
         .collect()
 }
 
+/// Creates a Hann (raised cosine) window of a given size, falling to zero at both ends.
+pub fn hann_window(size: usize) -> Vec<f64> {
+    (0..size)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / (size as f64 - 1.0)).cos())
+        .collect()
+}
+
+/// Creates a Hamming window of a given size. Its ends stay at `0.08`, in exchange for lower
+/// sidelobes than the Hann window.
+pub fn hamming_window(size: usize) -> Vec<f64> {
+    (0..size)
+        .map(|i| 0.54 - 0.46 * (2.0 * PI * i as f64 / (size as f64 - 1.0)).cos())
+        .collect()
+}
+
 /// Creates a high-pass filter. Frequencies above the cutoff are preserved when
 /// samples are convolved with this filter.
 pub fn highpass_filter(cutoff: f64, band: f64) -> Vec<f64> {
@@ -192,6 +207,74 @@ pub fn envelope(relative_t: f64, attack: f64, decay: f64) -> f64 {
     0.0
 }
 
+/// Linear attack/decay/sustain/release envelope. Times are in seconds.
+///
+/// ```
+/// use synthrs::filter::Adsr;
+///
+/// let adsr = Adsr::new(0.1, 0.2, 0.5, 1.0);
+/// assert_eq!(adsr.value(0.05, None), 0.5);
+/// assert_eq!(adsr.value(10.0, None), 0.5);
+/// // Released at 10s, halfway through the release
+/// assert_eq!(adsr.value(10.5, Some(10.0)), 0.25);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Adsr {
+    pub attack: f64,
+    pub decay: f64,
+    /// Level held after the decay, from `0.0` to `1.0`
+    pub sustain: f64,
+    pub release: f64,
+}
+
+impl Adsr {
+    pub fn new(attack: f64, decay: f64, sustain: f64, release: f64) -> Adsr {
+        Adsr {
+            attack,
+            decay,
+            sustain,
+            release,
+        }
+    }
+
+    /// Returns the level `relative_t` seconds after the note started, for a note released
+    /// `released_at` seconds after it started (`None` while it is held).
+    pub fn value(&self, relative_t: f64, released_at: Option<f64>) -> f64 {
+        match released_at {
+            Some(released_at) if relative_t >= released_at => {
+                let released_for = relative_t - released_at;
+                if released_for >= self.release {
+                    0.0
+                } else {
+                    self.held_value(released_at) * (1.0 - released_for / self.release)
+                }
+            }
+            _ => self.held_value(relative_t),
+        }
+    }
+
+    /// Returns `true` once a note released at `released_at` has completely faded out.
+    pub fn is_finished(&self, relative_t: f64, released_at: Option<f64>) -> bool {
+        match released_at {
+            Some(released_at) => relative_t >= released_at + self.release,
+            None => false,
+        }
+    }
+
+    /// Level while the note is held
+    fn held_value(&self, relative_t: f64) -> f64 {
+        if relative_t < 0.0 {
+            0.0
+        } else if relative_t < self.attack {
+            relative_t / self.attack
+        } else if relative_t < self.attack + self.decay {
+            1.0 - (1.0 - self.sustain) * (relative_t - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
+}
+
 /// A stateful delay line. Samples are delayed for `delay_length` seconds.
 ///
 /// https://en.wikipedia.org/wiki/Analog_delay_line
@@ -229,6 +312,19 @@ impl DelayLine {
         }
     }
 
+    /// Creates a new delay line. Samples are delayed for `delay_samples` samples (at least one).
+    pub fn from_samples(delay_samples: usize, sample_rate: usize) -> DelayLine {
+        let delay_samples = delay_samples.max(1);
+
+        DelayLine {
+            buf: vec![0.0; delay_samples],
+            index: 0,
+            delay_length: delay_samples as f64 / sample_rate as f64,
+            delay_samples,
+            sample_rate,
+        }
+    }
+
     pub fn read(&self) -> f64 {
         self.buf[self.index]
     }
@@ -335,6 +431,448 @@ impl Comb {
     }
 }
 
+/// Normalized coefficients of a second-order IIR section
+/// (`a0` is divided out), designed from the
+/// [RBJ Audio EQ Cookbook](https://www.w3.org/TR/audio-eq-cookbook/).
+///
+/// `q` sets the resonance: `1/sqrt(2)` (`std::f64::consts::FRAC_1_SQRT_2`) gives a maximally flat
+/// (Butterworth) lowpass or highpass, and the steepest shelves without overshoot.
+///
+/// ```
+/// use synthrs::filter::BiquadCoefficients;
+///
+/// let lowpass = BiquadCoefficients::lowpass(1000.0, std::f64::consts::FRAC_1_SQRT_2, 44_100);
+///
+/// // -3dB at the cutoff
+/// assert!((lowpass.response(1000.0, 44_100) - 0.5f64.sqrt()).abs() < 1e-9);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BiquadCoefficients {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+
+impl BiquadCoefficients {
+    /// Creates coefficients from unnormalized feedforward (`b`) and feedback (`a`) terms.
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> BiquadCoefficients {
+        BiquadCoefficients {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+        }
+    }
+
+    /// Coefficients which pass the input through unchanged.
+    pub fn identity() -> BiquadCoefficients {
+        BiquadCoefficients::new([1.0, 0.0, 0.0], [1.0, 0.0, 0.0])
+    }
+
+    /// Second-order lowpass with unity gain at DC.
+    pub fn lowpass(frequency: f64, q: f64, sample_rate: usize) -> BiquadCoefficients {
+        let (cos, alpha) = cos_alpha(frequency, q, sample_rate);
+        BiquadCoefficients::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Second-order highpass with unity gain at Nyquist.
+    pub fn highpass(frequency: f64, q: f64, sample_rate: usize) -> BiquadCoefficients {
+        let (cos, alpha) = cos_alpha(frequency, q, sample_rate);
+        BiquadCoefficients::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Bandpass with unity gain at the centre `frequency`. A higher `q` narrows the band.
+    pub fn bandpass(frequency: f64, q: f64, sample_rate: usize) -> BiquadCoefficients {
+        let (cos, alpha) = cos_alpha(frequency, q, sample_rate);
+        BiquadCoefficients::new([alpha, 0.0, -alpha], [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    /// Notch (band-reject) removing the centre `frequency`. A higher `q` narrows the notch.
+    pub fn notch(frequency: f64, q: f64, sample_rate: usize) -> BiquadCoefficients {
+        let (cos, alpha) = cos_alpha(frequency, q, sample_rate);
+        BiquadCoefficients::new(
+            [1.0, -2.0 * cos, 1.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Allpass with unity gain at all frequencies, shifting phase by 180 degrees at `frequency`.
+    pub fn allpass(frequency: f64, q: f64, sample_rate: usize) -> BiquadCoefficients {
+        let (cos, alpha) = cos_alpha(frequency, q, sample_rate);
+        BiquadCoefficients::new(
+            [1.0 - alpha, -2.0 * cos, 1.0 + alpha],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Peaking EQ boosting (or cutting, for negative `gain` in dB) around `frequency`.
+    pub fn peaking(frequency: f64, q: f64, gain: f64, sample_rate: usize) -> BiquadCoefficients {
+        let (cos, alpha) = cos_alpha(frequency, q, sample_rate);
+        let a = 10.0f64.powf(gain / 40.0);
+        BiquadCoefficients::new(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    /// Low shelf applying `gain` dB below `frequency` (the shelf midpoint).
+    pub fn low_shelf(frequency: f64, q: f64, gain: f64, sample_rate: usize) -> BiquadCoefficients {
+        let (cos, alpha) = cos_alpha(frequency, q, sample_rate);
+        let a = 10.0f64.powf(gain / 40.0);
+        let beta = 2.0 * a.sqrt() * alpha;
+        BiquadCoefficients::new(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + beta),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - beta),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + beta,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - beta,
+            ],
+        )
+    }
+
+    /// High shelf applying `gain` dB above `frequency` (the shelf midpoint).
+    pub fn high_shelf(frequency: f64, q: f64, gain: f64, sample_rate: usize) -> BiquadCoefficients {
+        let (cos, alpha) = cos_alpha(frequency, q, sample_rate);
+        let a = 10.0f64.powf(gain / 40.0);
+        let beta = 2.0 * a.sqrt() * alpha;
+        BiquadCoefficients::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + beta),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - beta),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + beta,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - beta,
+            ],
+        )
+    }
+
+    /// Returns the magnitude response (linear gain) at `frequency`.
+    pub fn response(&self, frequency: f64, sample_rate: usize) -> f64 {
+        let w = 2.0 * PI * frequency / sample_rate as f64;
+        let (cos1, sin1, cos2, sin2) = (w.cos(), w.sin(), (2.0 * w).cos(), (2.0 * w).sin());
+        let numerator =
+            (self.b0 + self.b1 * cos1 + self.b2 * cos2).hypot(self.b1 * sin1 + self.b2 * sin2);
+        let denominator =
+            (1.0 + self.a1 * cos1 + self.a2 * cos2).hypot(self.a1 * sin1 + self.a2 * sin2);
+        numerator / denominator
+    }
+}
+
+/// Returns the cosine of the angular frequency and the RBJ `alpha` bandwidth term.
+fn cos_alpha(frequency: f64, q: f64, sample_rate: usize) -> (f64, f64) {
+    let w0 = 2.0 * PI * frequency / sample_rate as f64;
+    (w0.cos(), w0.sin() / (2.0 * q))
+}
+
+/// A stateful biquad (second-order IIR) filter.
+///
+/// https://en.wikipedia.org/wiki/Digital_biquad_filter
+///
+/// Unlike the FIR filters above, a biquad is cheap to redesign, so it can be swept while running.
+/// New coefficients from `set_coefficients` are ramped in linearly over `smoothing` samples to
+/// avoid zipper noise.
+///
+/// ```
+/// use synthrs::filter::{Biquad, BiquadCoefficients};
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::wave::sawtooth_wave;
+///
+/// let mut lowpass = Biquad::new(BiquadCoefficients::lowpass(400.0, 0.7, 44_100));
+/// let mut samples = make_samples(1.0, 44_100, sawtooth_wave(110.0));
+///
+/// // Per sample
+/// let filtered: Vec<f64> = samples.iter().map(|&sample| lowpass.tick(sample)).collect();
+///
+/// // Or in blocks, sweeping the cutoff up between them
+/// for (i, block) in samples.chunks_mut(512).enumerate() {
+///     let cutoff = 200.0 + 100.0 * i as f64;
+///     lowpass.set_coefficients(BiquadCoefficients::lowpass(cutoff, 0.7, 44_100));
+///     lowpass.process(block);
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Biquad {
+    current: BiquadCoefficients,
+    target: BiquadCoefficients,
+    remaining: usize,
+    x: [f64; 2],
+    y: [f64; 2],
+    /// Number of samples coefficient changes are spread over (`0` changes them immediately)
+    pub smoothing: usize,
+}
+
+impl Biquad {
+    /// Default number of samples coefficient changes are ramped over.
+    pub const DEFAULT_SMOOTHING: usize = 64;
+
+    /// Creates a new biquad filter with the given coefficients.
+    pub fn new(coefficients: BiquadCoefficients) -> Biquad {
+        Biquad {
+            current: coefficients,
+            target: coefficients,
+            remaining: 0,
+            x: [0.0; 2],
+            y: [0.0; 2],
+            smoothing: Biquad::DEFAULT_SMOOTHING,
+        }
+    }
+
+    /// Returns the coefficients the filter is using or ramping towards.
+    pub fn coefficients(&self) -> BiquadCoefficients {
+        self.target
+    }
+
+    /// Changes the filter's coefficients, ramping to them over `smoothing` samples.
+    pub fn set_coefficients(&mut self, coefficients: BiquadCoefficients) {
+        self.target = coefficients;
+        self.remaining = self.smoothing;
+
+        if self.remaining == 0 {
+            self.current = coefficients;
+        }
+    }
+
+    /// Clears the filter's history, and completes any coefficient ramp.
+    pub fn reset(&mut self) {
+        self.current = self.target;
+        self.remaining = 0;
+        self.x = [0.0; 2];
+        self.y = [0.0; 2];
+    }
+
+    pub fn tick(&mut self, input: f64) -> f64 {
+        if self.remaining > 0 {
+            let remaining = self.remaining as f64;
+            let step = |current: f64, target: f64| current + (target - current) / remaining;
+            let (current, target) = (self.current, self.target);
+            self.current = BiquadCoefficients {
+                b0: step(current.b0, target.b0),
+                b1: step(current.b1, target.b1),
+                b2: step(current.b2, target.b2),
+                a1: step(current.a1, target.a1),
+                a2: step(current.a2, target.a2),
+            };
+            self.remaining -= 1;
+        }
+
+        let c = &self.current;
+        let output = c.b0 * input + c.b1 * self.x[0] + c.b2 * self.x[1]
+            - c.a1 * self.y[0]
+            - c.a2 * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+
+    /// Filters a block of samples in place.
+    pub fn process(&mut self, samples: &mut [f64]) {
+        for sample in samples.iter_mut() {
+            *sample = self.tick(*sample);
+        }
+    }
+}
+
+/// Simultaneous outputs of a `StateVariable` filter.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StateVariableOutput {
+    pub lowpass: f64,
+    /// Bandpass, normalized to unity gain at the cutoff
+    pub bandpass: f64,
+    pub highpass: f64,
+    pub notch: f64,
+}
+
+/// A stateful, resonant 2-pole state-variable filter using the topology-preserving transform
+/// (TPT, or zero-delay feedback).
+///
+/// https://cytomic.com/files/dsp/SvfLinearTrapOptimised2.pdf
+///
+/// Each `tick` returns the lowpass, bandpass, highpass and notch outputs at once. The filter
+/// stays stable while `cutoff` and `q` change every sample, so they can be driven at audio rate,
+/// eg. by an envelope.
+///
+/// ```
+/// use synthrs::filter::{Adsr, StateVariable};
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::wave::sawtooth_wave;
+///
+/// let mut svf = StateVariable::new(200.0, 4.0, 44_100);
+/// let envelope = Adsr::new(0.01, 0.3, 0.2, 0.5);
+/// let samples = make_samples(1.0, 44_100, sawtooth_wave(110.0));
+///
+/// let filtered: Vec<f64> = samples
+///     .into_iter()
+///     .enumerate()
+///     .map(|(i, sample)| {
+///         // Sweep the cutoff between 200Hz and 5kHz with the envelope
+///         svf.cutoff = 200.0 + 4800.0 * envelope.value(i as f64 / 44_100.0, None);
+///         svf.tick(sample).lowpass
+///     })
+///     .collect();
+/// ```
+#[derive(Clone, Debug)]
+pub struct StateVariable {
+    ic1eq: f64,
+    ic2eq: f64,
+    sample_rate: usize,
+    /// Cutoff (or centre) frequency in Hz
+    pub cutoff: f64,
+    /// Resonance, `1/sqrt(2)` is flat, higher values ring at the cutoff
+    pub q: f64,
+}
+
+impl StateVariable {
+    /// Creates a new state-variable filter with a `cutoff` in Hz.
+    pub fn new(cutoff: f64, q: f64, sample_rate: usize) -> StateVariable {
+        StateVariable {
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+            sample_rate,
+            cutoff,
+            q,
+        }
+    }
+
+    /// Clears the filter's state.
+    pub fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+
+    pub fn tick(&mut self, input: f64) -> StateVariableOutput {
+        let g = prewarp(self.cutoff, self.sample_rate);
+        let k = 1.0 / self.q.max(1e-3);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let highpass = input - k * v1 - v2;
+        StateVariableOutput {
+            lowpass: v2,
+            bandpass: k * v1,
+            highpass,
+            notch: v2 + highpass,
+        }
+    }
+}
+
+/// A stateful 4-pole (24dB/octave) Moog-style transistor ladder lowpass filter, with `tanh`
+/// saturation at the input of the ladder.
+///
+/// https://www.native-instruments.com/fileadmin/ni_media/downloads/pdf/VAFilterDesign_2.1.0.pdf
+///
+/// Each of the four one-pole stages uses the topology-preserving transform and the resonance
+/// feedback is resolved without a unit delay, so the cutoff stays in tune at high frequencies
+/// and `cutoff` can be changed every sample. As on the original, raising the resonance thins
+/// out the bass. From a `resonance` of `1.0` the filter self-oscillates at the cutoff.
+///
+/// ```
+/// use synthrs::filter::{Adsr, MoogLadder};
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::wave::sawtooth_wave;
+///
+/// let mut ladder = MoogLadder::new(200.0, 0.7, 44_100);
+/// ladder.drive = 2.0;
+/// let envelope = Adsr::new(0.01, 0.3, 0.2, 0.5);
+/// let samples = make_samples(1.0, 44_100, sawtooth_wave(55.0));
+///
+/// let filtered: Vec<f64> = samples
+///     .into_iter()
+///     .enumerate()
+///     .map(|(i, sample)| {
+///         ladder.cutoff = 100.0 + 3000.0 * envelope.value(i as f64 / 44_100.0, None);
+///         ladder.tick(sample)
+///     })
+///     .collect();
+/// ```
+#[derive(Clone, Debug)]
+pub struct MoogLadder {
+    stages: [f64; 4],
+    sample_rate: usize,
+    /// Cutoff frequency in Hz
+    pub cutoff: f64,
+    /// Feedback, from `0.0` with `1.0` on the edge of self-oscillation
+    pub resonance: f64,
+    /// Input gain into the saturating ladder, `1.0` is mostly clean
+    pub drive: f64,
+}
+
+impl MoogLadder {
+    /// Creates a new ladder filter with a `cutoff` in Hz.
+    pub fn new(cutoff: f64, resonance: f64, sample_rate: usize) -> MoogLadder {
+        MoogLadder {
+            stages: [0.0; 4],
+            sample_rate,
+            cutoff,
+            resonance,
+            drive: 1.0,
+        }
+    }
+
+    /// Clears the filter's state.
+    pub fn reset(&mut self) {
+        self.stages = [0.0; 4];
+    }
+
+    pub fn tick(&mut self, input: f64) -> f64 {
+        let g = prewarp(self.cutoff, self.sample_rate);
+        let gain = g / (1.0 + g);
+        let k = 4.0 * self.resonance.max(0.0);
+
+        // Contribution of the stages' state to the output, to solve the feedback loop instantly
+        let state = self
+            .stages
+            .iter()
+            .fold(0.0, |sum, stage| sum * gain + stage / (1.0 + g));
+        let ladder_input = (self.drive * input - k * state) / (1.0 + k * gain.powi(4));
+
+        let mut output = ladder_input.tanh();
+        for stage in self.stages.iter_mut() {
+            let v = (output - *stage) * gain;
+            output = v + *stage;
+            *stage = output + v;
+        }
+
+        output
+    }
+
+    /// Filters a block of samples in place.
+    pub fn process(&mut self, samples: &mut [f64]) {
+        for sample in samples.iter_mut() {
+            *sample = self.tick(*sample);
+        }
+    }
+}
+
+/// Returns the prewarped integrator gain `tan(pi * cutoff / sample_rate)` for TPT filters,
+/// keeping the cutoff below Nyquist.
+fn prewarp(cutoff: f64, sample_rate: usize) -> f64 {
+    let rate = sample_rate as f64;
+    (PI * cutoff.max(0.0).min(0.49 * rate) / rate).tan()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -355,6 +893,24 @@ mod tests {
         assert_eq!(add(&a, &b), expected);
     }
 
+    #[test]
+    fn test_windows() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+        for window in [hann_window(9), hamming_window(9), blackman_window(9)].iter() {
+            // Symmetric, peaking at 1.0 in the middle
+            assert!(close(window[4], 1.0));
+            for i in 0..9 {
+                assert!(close(window[i], window[8 - i]));
+            }
+        }
+
+        assert!(close(hann_window(9)[0], 0.0));
+        assert!(close(hann_window(9)[2], 0.5));
+        assert!(close(hamming_window(9)[0], 0.08));
+        assert!(close(blackman_window(9)[0], 0.0));
+    }
+
     #[test]
     #[allow(clippy::float_cmp)]
     fn test_envelope() {
@@ -366,6 +922,28 @@ mod tests {
         assert_eq!(envelope(-0.5, 1.0, 1.0), 0.0);
     }
 
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn test_adsr() {
+        let adsr = Adsr::new(1.0, 1.0, 0.5, 2.0);
+        assert_eq!(adsr.value(-0.5, None), 0.0);
+        assert_eq!(adsr.value(0.5, None), 0.5);
+        assert_eq!(adsr.value(1.5, None), 0.75);
+        assert_eq!(adsr.value(5.0, None), 0.5);
+
+        // Released during the attack, fading from the level reached
+        assert_eq!(adsr.value(0.5, Some(0.5)), 0.5);
+        assert_eq!(adsr.value(1.5, Some(0.5)), 0.25);
+        assert_eq!(adsr.value(3.0, Some(0.5)), 0.0);
+        assert!(!adsr.is_finished(2.0, Some(0.5)));
+        assert!(adsr.is_finished(2.5, Some(0.5)));
+
+        // Zero-length stages jump straight to the next one
+        let gate = Adsr::new(0.0, 0.0, 1.0, 0.0);
+        assert_eq!(gate.value(0.0, None), 1.0);
+        assert_eq!(gate.value(1.0, Some(1.0)), 0.0);
+    }
+
     #[test]
     #[allow(clippy::float_cmp)]
     fn test_delay_line() {
@@ -386,4 +964,229 @@ mod tests {
         delay_line.write(17.0);
         assert_eq!(delay_line.read(), 7.0);
     }
+
+    #[test]
+    fn test_biquad_designs() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+        let db = |gain: f64| 10.0f64.powf(gain / 20.0);
+        let (q, rate) = (std::f64::consts::FRAC_1_SQRT_2, 44_100);
+
+        let lowpass = BiquadCoefficients::lowpass(1000.0, q, rate);
+        assert!(close(lowpass.response(0.0, rate), 1.0));
+        assert!(close(lowpass.response(1000.0, rate), q));
+        assert!(lowpass.response(10_000.0, rate) < db(-38.0));
+
+        let highpass = BiquadCoefficients::highpass(1000.0, q, rate);
+        assert!(close(highpass.response(22_050.0, rate), 1.0));
+        assert!(close(highpass.response(1000.0, rate), q));
+        assert!(highpass.response(100.0, rate) < db(-38.0));
+
+        let bandpass = BiquadCoefficients::bandpass(1000.0, 4.0, rate);
+        assert!(close(bandpass.response(1000.0, rate), 1.0));
+        assert!(bandpass.response(100.0, rate) < db(-30.0));
+        assert!(bandpass.response(10_000.0, rate) < db(-30.0));
+
+        let notch = BiquadCoefficients::notch(1000.0, 4.0, rate);
+        assert!(close(notch.response(1000.0, rate), 0.0));
+        assert!(close(notch.response(0.0, rate), 1.0));
+        assert!(notch.response(2000.0, rate) > db(-0.5));
+
+        let allpass = BiquadCoefficients::allpass(1000.0, q, rate);
+        for &frequency in [0.0, 100.0, 1000.0, 5000.0, 22_050.0].iter() {
+            assert!(close(allpass.response(frequency, rate), 1.0));
+        }
+
+        let peaking = BiquadCoefficients::peaking(1000.0, 2.0, 6.0, rate);
+        assert!(close(peaking.response(1000.0, rate), db(6.0)));
+        assert!(close(peaking.response(0.0, rate), 1.0));
+        let cut = BiquadCoefficients::peaking(1000.0, 2.0, -12.0, rate);
+        assert!(close(cut.response(1000.0, rate), db(-12.0)));
+
+        let low_shelf = BiquadCoefficients::low_shelf(300.0, q, 6.0, rate);
+        assert!(close(low_shelf.response(0.0, rate), db(6.0)));
+        assert!(close(low_shelf.response(300.0, rate), db(3.0)));
+        assert!(close(low_shelf.response(22_050.0, rate), 1.0));
+
+        let high_shelf = BiquadCoefficients::high_shelf(3000.0, q, -6.0, rate);
+        assert!(close(high_shelf.response(0.0, rate), 1.0));
+        assert!(close(high_shelf.response(3000.0, rate), db(-3.0)));
+        assert!(close(high_shelf.response(22_050.0, rate), db(-6.0)));
+
+        assert!(close(
+            BiquadCoefficients::identity().response(1234.0, rate),
+            1.0
+        ));
+    }
+
+    #[test]
+    fn test_biquad_tick() {
+        let rate = 44_100;
+        let input: Vec<f64> = (0..rate)
+            .map(|i| (2.0 * PI * 2000.0 * i as f64 / rate as f64).sin())
+            .collect();
+
+        for coefficients in [
+            BiquadCoefficients::lowpass(1000.0, 0.7, rate),
+            BiquadCoefficients::peaking(2000.0, 1.0, 9.0, rate),
+            BiquadCoefficients::high_shelf(4000.0, 0.7, -6.0, rate),
+        ]
+        .iter()
+        {
+            let mut biquad = Biquad::new(*coefficients);
+            let ticked: Vec<f64> = input.iter().map(|&sample| biquad.tick(sample)).collect();
+
+            // Steady state amplitude matches the designed response
+            let peak = ticked[rate / 2..]
+                .iter()
+                .fold(0.0f64, |a, b| a.max(b.abs()));
+            assert!((peak - coefficients.response(2000.0, rate)).abs() < 1e-3);
+
+            // Block processing matches ticking
+            let mut processed = input.clone();
+            let mut biquad = Biquad::new(*coefficients);
+            for block in processed.chunks_mut(100) {
+                biquad.process(block);
+            }
+            assert_eq!(processed, ticked);
+        }
+
+        let mut biquad = Biquad::new(BiquadCoefficients::lowpass(1000.0, 0.7, rate));
+        biquad.tick(1.0);
+        biquad.reset();
+        assert!(biquad.tick(0.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_biquad_smoothing() {
+        let rate = 44_100;
+        let flat = BiquadCoefficients::peaking(1000.0, 1.0, 0.0, rate);
+        let cut = BiquadCoefficients::peaking(1000.0, 1.0, -24.0, rate);
+
+        // Largest second difference (a click shows up as a kink) after cutting a 1kHz sine
+        let largest_kink = |smoothing: usize| {
+            let mut biquad = Biquad::new(flat);
+            biquad.smoothing = smoothing;
+            let outputs: Vec<f64> = (0..4410)
+                .map(|i| {
+                    if i == 2205 {
+                        biquad.set_coefficients(cut);
+                    }
+                    biquad.tick((2.0 * PI * 1000.0 * i as f64 / rate as f64).sin())
+                })
+                .collect();
+            assert_eq!(biquad.coefficients(), cut);
+            outputs
+                .windows(3)
+                .map(|w| (w[0] - 2.0 * w[1] + w[2]).abs())
+                .fold(0.0, f64::max)
+        };
+
+        // The input sine alone curves by up to (2pi * 1000 / 44100)^2 ~= 0.0203 per sample
+        assert!(largest_kink(0) > 0.04);
+        assert!(largest_kink(Biquad::DEFAULT_SMOOTHING) < 0.021);
+    }
+
+    /// Steady state peak amplitude of a sine at `frequency` through `filter`
+    fn sine_gain<F: FnMut(f64) -> f64>(frequency: f64, mut filter: F) -> f64 {
+        let rate = 44_100;
+        (0..rate)
+            .map(|i| filter((2.0 * PI * frequency * i as f64 / rate as f64).sin()))
+            .skip(rate / 2)
+            .fold(0.0, |peak, sample| sample.abs().max(peak))
+    }
+
+    #[test]
+    fn test_state_variable() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-2;
+        let output = |frequency: f64, q: f64| {
+            let mut outputs = Vec::new();
+            for i in 0..4 {
+                let mut svf = StateVariable::new(1000.0, q, 44_100);
+                outputs.push(sine_gain(frequency, |sample| {
+                    let output = svf.tick(sample);
+                    [
+                        output.lowpass,
+                        output.bandpass,
+                        output.highpass,
+                        output.notch,
+                    ][i]
+                }));
+            }
+            outputs
+        };
+
+        // At the cutoff: lowpass and highpass are boosted by q, the bandpass is unity and the
+        // notch removes it
+        let at_cutoff = output(1000.0, 2.0);
+        assert!(close(at_cutoff[0], 2.0));
+        assert!(close(at_cutoff[1], 1.0));
+        assert!(close(at_cutoff[2], 2.0));
+        assert!(at_cutoff[3] < 1e-2);
+
+        // 12dB/octave slopes, passing the other side through
+        let below = output(100.0, std::f64::consts::FRAC_1_SQRT_2);
+        assert!(close(below[0], 1.0));
+        assert!(below[2] < 0.011);
+        assert!(close(below[3], 0.99));
+        let above = output(10_000.0, std::f64::consts::FRAC_1_SQRT_2);
+        assert!(above[0] < 0.011);
+        assert!(close(above[2], 1.0));
+    }
+
+    #[test]
+    fn test_moog_ladder() {
+        let gain = |frequency: f64, resonance: f64| {
+            let mut ladder = MoogLadder::new(1000.0, resonance, 44_100);
+            ladder.drive = 0.01;
+            sine_gain(frequency, |sample| ladder.tick(sample) / 0.01)
+        };
+
+        // Unity in the passband and 24dB/octave above the cutoff, -12dB at the cutoff
+        assert!((gain(50.0, 0.0) - 1.0).abs() < 0.01);
+        assert!((gain(1000.0, 0.0) - 0.25).abs() < 0.01);
+        assert!(gain(10_000.0, 0.0) < 1e-3);
+
+        // Resonance peaks at the cutoff and thins the bass
+        assert!(gain(1000.0, 0.9) > 2.0 * gain(50.0, 0.9));
+        assert!(gain(50.0, 0.9) < 0.5);
+
+        // Saturation keeps a driven, self-oscillating ladder bounded, and it keeps ringing
+        let mut ladder = MoogLadder::new(1000.0, 1.1, 44_100);
+        ladder.drive = 10.0;
+        let mut outputs = vec![1.0; 100];
+        outputs.extend(vec![0.0; 44_100]);
+        ladder.process(&mut outputs);
+        assert!(outputs.iter().all(|sample| sample.abs() < 2.0));
+        let tail_peak = outputs[40_000..].iter().fold(0.0f64, |a, b| a.max(b.abs()));
+        assert!(tail_peak > 0.1);
+    }
+
+    #[test]
+    fn test_audio_rate_cutoff_modulation() {
+        let rate = 44_100;
+        let mut svf = StateVariable::new(1000.0, 10.0, rate);
+        let mut ladder = MoogLadder::new(1000.0, 0.95, rate);
+
+        for i in 0..rate {
+            let t = i as f64 / rate as f64;
+            // Cutoff swept between 50Hz and 15kHz by a 500Hz sine
+            let cutoff = 7525.0 + 7475.0 * (2.0 * PI * 500.0 * t).sin();
+            let input = (2.0 * PI * 220.0 * t).sin();
+
+            svf.cutoff = cutoff;
+            ladder.cutoff = cutoff;
+            let output = svf.tick(input);
+            for sample in [
+                output.lowpass,
+                output.bandpass,
+                output.highpass,
+                output.notch,
+            ]
+            .iter()
+            {
+                assert!(sample.is_finite() && sample.abs() < 100.0);
+            }
+            assert!(ladder.tick(input).abs() < 2.0);
+        }
+    }
 }
@@ -0,0 +1,470 @@
+//! DX7-style FM (phase modulation) synthesis.
+//!
+//! A `Patch` is a set of sine `Operator`s wired together by an `Algorithm`. Each operator runs at
+//! a ratio of the note frequency (or at a fixed frequency), is shaped by its own `Adsr` envelope
+//! and can modulate itself through feedback. Modulator outputs are added to the phase of the
+//! operators they modulate, with the modulator's `level` as the modulation index in radians.
+//! Carrier outputs are mixed into the patch output, with `level` as their amplitude.
+//!
+//! ```
+//! use synthrs::fm::Patch;
+//! use synthrs::synthesizer::{make_samples, make_stereo_samples_from_midi_file};
+//!
+//! // A patch's generator is a function of `t`, like the ones in `synthrs::wave`
+//! let bell = Patch::bell();
+//! let samples = make_samples(2.0, 44_100, bell.generator(440.0));
+//!
+//! // and can be used as a MIDI instrument with renderers that keep one generator per note, so
+//! // that operator feedback is kept
+//! let piano = Patch::electric_piano();
+//! let song = make_stereo_samples_from_midi_file(
+//!     |frequency: f64| piano.generator(frequency),
+//!     44_100,
+//!     false,
+//!     "tests/assets/test.mid",
+//! );
+//! ```
+//!
+//! Patches can also be built by hand.
+//!
+//! ```
+//! use synthrs::filter::Adsr;
+//! use synthrs::fm::{Algorithm, Operator, OperatorFrequency, Patch};
+//!
+//! let mut modulator = Operator::new(OperatorFrequency::Ratio(2.0), 3.0);
+//! modulator.envelope = Adsr::new(0.0, 0.5, 0.2, 0.3);
+//! modulator.feedback = 0.5;
+//!
+//! let carrier = Operator::new(OperatorFrequency::Ratio(1.0), 1.0);
+//!
+//! let patch = Patch::new(vec![carrier, modulator], Algorithm::stack(2));
+//! ```
+
+use std::cell::RefCell;
+use std::f64::consts::PI;
+
+use crate::filter::Adsr;
+
+/// Frequency of an operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OperatorFrequency {
+    /// Multiple of the note frequency
+    Ratio(f64),
+    /// Fixed frequency in hertz, regardless of the note
+    Fixed(f64),
+}
+
+/// A sine oscillator with its own envelope, level and feedback.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Operator {
+    pub frequency: OperatorFrequency,
+    /// Output amplitude for carriers, modulation index (in radians) for modulators
+    pub level: f64,
+    pub envelope: Adsr,
+    /// Self-modulation index (in radians), applied to the average of the last two outputs
+    pub feedback: f64,
+}
+
+impl Operator {
+    /// Creates an operator with no feedback and an envelope held at full level.
+    pub fn new(frequency: OperatorFrequency, level: f64) -> Operator {
+        Operator {
+            frequency,
+            level,
+            envelope: Adsr::new(0.0, 0.0, 1.0, 0.0),
+            feedback: 0.0,
+        }
+    }
+
+    fn frequency_for(&self, note_frequency: f64) -> f64 {
+        match self.frequency {
+            OperatorFrequency::Ratio(ratio) => ratio * note_frequency,
+            OperatorFrequency::Fixed(frequency) => frequency,
+        }
+    }
+}
+
+/// Routing between the operators of a patch.
+///
+/// Operators are numbered from `0`, and may only be modulated by operators with a higher
+/// number, so that the routing is a graph without cycles (feedback is set per operator).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Algorithm {
+    /// For each operator, the operators modulating it
+    pub modulators: Vec<Vec<usize>>,
+    /// Operators mixed into the output
+    pub carriers: Vec<usize>,
+}
+
+impl Algorithm {
+    /// Creates an algorithm from the modulators of each operator and the output carriers.
+    ///
+    /// Panics if an operator is modulated by itself or by a lower-numbered operator, or if an
+    /// operator does not exist.
+    ///
+    /// ```
+    /// use synthrs::fm::Algorithm;
+    ///
+    /// // DX7 algorithm 5: three pairs of one modulator and one carrier
+    /// let algorithm = Algorithm::new(
+    ///     vec![vec![1], vec![], vec![3], vec![], vec![5], vec![]],
+    ///     vec![0, 2, 4],
+    /// );
+    /// ```
+    pub fn new(modulators: Vec<Vec<usize>>, carriers: Vec<usize>) -> Algorithm {
+        let operators = modulators.len();
+
+        for (operator, sources) in modulators.iter().enumerate() {
+            for &source in sources {
+                assert!(
+                    source > operator && source < operators,
+                    "operator {} cannot be modulated by operator {}",
+                    operator,
+                    source
+                );
+            }
+        }
+        for &carrier in &carriers {
+            assert!(carrier < operators, "carrier {} does not exist", carrier);
+        }
+
+        Algorithm {
+            modulators,
+            carriers,
+        }
+    }
+
+    /// A single chain: operator `i` is modulated by operator `i + 1`, and operator `0` is the
+    /// only carrier.
+    pub fn stack(operators: usize) -> Algorithm {
+        let modulators = (0..operators)
+            .map(|i| {
+                if i + 1 < operators {
+                    vec![i + 1]
+                } else {
+                    vec![]
+                }
+            })
+            .collect();
+        Algorithm::new(modulators, vec![0])
+    }
+
+    /// Every operator is an unmodulated carrier, as in additive synthesis.
+    pub fn parallel(operators: usize) -> Algorithm {
+        Algorithm::new(vec![vec![]; operators], (0..operators).collect())
+    }
+
+    /// Modulator/carrier pairs: even operators are carriers modulated by the next operator.
+    pub fn pairs(operators: usize) -> Algorithm {
+        let modulators = (0..operators)
+            .map(|i| {
+                if i % 2 == 0 && i + 1 < operators {
+                    vec![i + 1]
+                } else {
+                    vec![]
+                }
+            })
+            .collect();
+        Algorithm::new(modulators, (0..operators).step_by(2).collect())
+    }
+
+    pub fn operator_count(&self) -> usize {
+        self.modulators.len()
+    }
+}
+
+/// A set of operators and their routing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Patch {
+    pub operators: Vec<Operator>,
+    pub algorithm: Algorithm,
+}
+
+impl Patch {
+    /// Panics if the number of operators does not match the algorithm.
+    pub fn new(operators: Vec<Operator>, algorithm: Algorithm) -> Patch {
+        assert_eq!(
+            operators.len(),
+            algorithm.operator_count(),
+            "patch needs one operator per algorithm slot"
+        );
+        Patch {
+            operators,
+            algorithm,
+        }
+    }
+
+    /// An inharmonic tubular bell: two pairs with non-integer modulator ratios and long decays.
+    pub fn bell() -> Patch {
+        let mut operators = vec![
+            Operator::new(OperatorFrequency::Ratio(1.0), 1.0),
+            Operator::new(OperatorFrequency::Ratio(3.5), 2.5),
+            Operator::new(OperatorFrequency::Ratio(2.0), 0.5),
+            Operator::new(OperatorFrequency::Ratio(5.19), 1.5),
+        ];
+        operators[0].envelope = Adsr::new(0.002, 6.0, 0.0, 1.0);
+        operators[1].envelope = Adsr::new(0.0, 4.0, 0.0, 1.0);
+        operators[2].envelope = Adsr::new(0.002, 3.0, 0.0, 1.0);
+        operators[3].envelope = Adsr::new(0.0, 1.5, 0.0, 1.0);
+
+        Patch::new(operators, Algorithm::pairs(4))
+    }
+
+    /// A DX7-style electric piano: a soft body pair and a quickly decaying high "tine" pair.
+    pub fn electric_piano() -> Patch {
+        let mut operators = vec![
+            Operator::new(OperatorFrequency::Ratio(1.0), 1.0),
+            Operator::new(OperatorFrequency::Ratio(1.0), 1.2),
+            Operator::new(OperatorFrequency::Ratio(1.0), 0.4),
+            Operator::new(OperatorFrequency::Ratio(14.0), 1.5),
+        ];
+        operators[0].envelope = Adsr::new(0.002, 2.5, 0.3, 0.3);
+        operators[1].envelope = Adsr::new(0.0, 1.5, 0.2, 0.3);
+        operators[2].envelope = Adsr::new(0.001, 0.6, 0.0, 0.2);
+        operators[3].envelope = Adsr::new(0.0, 0.15, 0.0, 0.1);
+        operators[1].feedback = 0.3;
+
+        Patch::new(operators, Algorithm::pairs(4))
+    }
+
+    /// A punchy bass: a three-operator stack with a decaying modulation index and feedback.
+    pub fn bass() -> Patch {
+        let mut operators = vec![
+            Operator::new(OperatorFrequency::Ratio(1.0), 1.0),
+            Operator::new(OperatorFrequency::Ratio(1.0), 2.5),
+            Operator::new(OperatorFrequency::Ratio(3.0), 1.0),
+        ];
+        operators[0].envelope = Adsr::new(0.002, 0.8, 0.6, 0.1);
+        operators[1].envelope = Adsr::new(0.0, 0.3, 0.3, 0.1);
+        operators[2].envelope = Adsr::new(0.0, 0.1, 0.0, 0.1);
+        operators[2].feedback = 0.8;
+
+        Patch::new(operators, Algorithm::stack(3))
+    }
+
+    /// Creates a stateful voice playing this patch at `frequency`.
+    pub fn voice(&self, frequency: f64, sample_rate: usize) -> FmVoice {
+        FmVoice {
+            patch: self.clone(),
+            frequency,
+            sample_rate,
+            samples: 0,
+            released_at: None,
+            state: OperatorState::new(self.operators.len()),
+        }
+    }
+
+    /// Returns a generator playing this patch at `frequency`, with `t` relative to the start of
+    /// the note. The note is held forever, so envelopes stay at their sustain level.
+    ///
+    /// The generator borrows the patch, and is stateful: it keeps the previous output of each
+    /// operator for feedback, and must be called in order once per sample. Create one generator
+    /// per note, as `make_stereo_samples_from_midi` and `realtime::PolySynth` do. Generators
+    /// recreated for every sample (as by `make_samples_from_midi`) play without feedback. For
+    /// notes which can be released, see `FmVoice`.
+    pub fn generator(&self, frequency: f64) -> impl Fn(f64) -> f64 + '_ {
+        let state = RefCell::new(OperatorState::new(self.operators.len()));
+
+        move |t| self.evaluate(frequency, t, None, &mut state.borrow_mut())
+    }
+
+    /// Computes all operators at `t` seconds into a note released at `released_at`
+    fn evaluate(
+        &self,
+        frequency: f64,
+        t: f64,
+        released_at: Option<f64>,
+        state: &mut OperatorState,
+    ) -> f64 {
+        // Modulators always have higher numbers than the operators they modulate
+        for index in (0..self.operators.len()).rev() {
+            let operator = &self.operators[index];
+
+            let modulation: f64 = self.algorithm.modulators[index]
+                .iter()
+                .map(|&source| state.outputs[source])
+                .sum();
+            let [previous, before_previous] = state.feedback[index];
+            let feedback = operator.feedback * (previous + before_previous) / 2.0;
+
+            let phase = t * operator.frequency_for(frequency);
+            let phase = phase - phase.floor();
+            let output = operator.level
+                * operator.envelope.value(t, released_at)
+                * (2.0 * PI * phase + modulation + feedback).sin();
+
+            state.outputs[index] = output;
+            state.feedback[index] = [output, previous];
+        }
+
+        let carriers = &self.algorithm.carriers;
+        if carriers.is_empty() {
+            return 0.0;
+        }
+
+        carriers
+            .iter()
+            .map(|&carrier| state.outputs[carrier])
+            .sum::<f64>()
+            / carriers.len() as f64
+    }
+
+    /// Returns `true` once every carrier envelope has finished releasing.
+    fn is_finished(&self, t: f64, released_at: Option<f64>) -> bool {
+        self.algorithm
+            .carriers
+            .iter()
+            .all(|&carrier| self.operators[carrier].envelope.is_finished(t, released_at))
+    }
+}
+
+/// Outputs of every operator, for modulation and feedback
+#[derive(Clone, Debug)]
+struct OperatorState {
+    outputs: Vec<f64>,
+    /// Last two outputs of each operator, newest first
+    feedback: Vec<[f64; 2]>,
+}
+
+impl OperatorState {
+    fn new(operators: usize) -> OperatorState {
+        OperatorState {
+            outputs: vec![0.0; operators],
+            feedback: vec![[0.0; 2]; operators],
+        }
+    }
+}
+
+/// A stateful note playing an FM `Patch`, which can be released.
+///
+/// ```
+/// use synthrs::fm::Patch;
+///
+/// let mut voice = Patch::bass().voice(55.0, 44_100);
+/// let held: Vec<f64> = (0..22_050).map(|_| voice.tick()).collect();
+///
+/// voice.release();
+/// while !voice.is_finished() {
+///     voice.tick();
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct FmVoice {
+    patch: Patch,
+    frequency: f64,
+    sample_rate: usize,
+    /// Samples since the start of the note
+    samples: usize,
+    released_at: Option<f64>,
+    state: OperatorState,
+}
+
+impl FmVoice {
+    /// Returns the next sample.
+    pub fn tick(&mut self) -> f64 {
+        let t = self.time();
+        self.samples += 1;
+        self.patch
+            .evaluate(self.frequency, t, self.released_at, &mut self.state)
+    }
+
+    /// Starts the release stage of every operator envelope.
+    pub fn release(&mut self) {
+        if self.released_at.is_none() {
+            self.released_at = Some(self.time());
+        }
+    }
+
+    /// Returns `true` once the voice has been released and faded out.
+    pub fn is_finished(&self) -> bool {
+        self.patch.is_finished(self.time(), self.released_at)
+    }
+
+    /// Seconds since the start of the note
+    fn time(&self) -> f64 {
+        self.samples as f64 / self.sample_rate as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wave::sine_wave;
+
+    #[test]
+    fn test_single_operator_is_a_sine() {
+        let patch = Patch::new(
+            vec![Operator::new(OperatorFrequency::Ratio(2.0), 1.0)],
+            Algorithm::stack(1),
+        );
+        let generator = patch.generator(220.0);
+
+        for i in 0..1000 {
+            let t = i as f64 / 44_100.0;
+            assert!((generator(t) - sine_wave(440.0)(t)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_two_operator_stack_matches_closed_form() {
+        let patch = Patch::new(
+            vec![
+                Operator::new(OperatorFrequency::Ratio(1.0), 1.0),
+                Operator::new(OperatorFrequency::Fixed(660.0), 2.0),
+            ],
+            Algorithm::stack(2),
+        );
+        let generator = patch.generator(440.0);
+
+        for i in 0..1000 {
+            let t = i as f64 / 44_100.0;
+            let expected = (2.0 * PI * 440.0 * t + 2.0 * (2.0 * PI * 660.0 * t).sin()).sin();
+            assert!((generator(t) - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_feedback_changes_output() {
+        let mut operator = Operator::new(OperatorFrequency::Ratio(1.0), 1.0);
+        let plain = Patch::new(vec![operator], Algorithm::stack(1)).voice(440.0, 44_100);
+        operator.feedback = 1.0;
+        let fed_back = Patch::new(vec![operator], Algorithm::stack(1)).voice(440.0, 44_100);
+
+        let plain: Vec<f64> = plain.take_samples(1000);
+        let fed_back: Vec<f64> = fed_back.take_samples(1000);
+
+        assert!(fed_back.iter().all(|sample| sample.abs() <= 1.0));
+        assert!(plain
+            .iter()
+            .zip(fed_back.iter())
+            .any(|(a, b)| (a - b).abs() > 0.1));
+    }
+
+    #[test]
+    fn test_voice_release() {
+        let mut voice = Patch::electric_piano().voice(440.0, 1000);
+        for _ in 0..1000 {
+            voice.tick();
+        }
+        assert!(!voice.is_finished());
+
+        voice.release();
+        for _ in 0..400 {
+            voice.tick();
+        }
+        assert!(voice.is_finished());
+        assert!(voice.tick().abs() < 1e-9);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_algorithm_rejects_cycles() {
+        Algorithm::new(vec![vec![1], vec![0]], vec![0]);
+    }
+
+    impl FmVoice {
+        fn take_samples(mut self, count: usize) -> Vec<f64> {
+            (0..count).map(|_| self.tick()).collect()
+        }
+    }
+}
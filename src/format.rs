@@ -0,0 +1,272 @@
+//! Sample formats used for quantization and for writing audio files.
+//!
+//! Integer formats map `[-1.0, 1.0)` onto their full range with a scale of `2^(bits - 1)`, so that
+//! `-1.0` is the most negative value and `1.0` clips to the most positive one. Conversions round
+//! to the nearest level and clip out-of-range input, and converting a quantized value back and
+//! forth is exact.
+//!
+//! * `u8`: offset binary, `128` is silence (8-bit WAV)
+//! * `i8`, `i16`, `I24`, `i32`: two's complement
+//! * `f32`, `f64`: passed through unscaled and unclipped
+//!
+//! ```
+//! use synthrs::format::{SampleFormat, I24};
+//!
+//! assert_eq!(u8::from_sample(0.0), 128);
+//! assert_eq!(i16::from_sample(-1.0), i16::MIN);
+//! assert_eq!(I24::from_sample(1.0), I24::MAX);
+//! assert_eq!(i16::from_sample(i16::MIN.to_sample()), i16::MIN);
+//! ```
+
+use std::io::{Result, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/// A format that samples can be quantized into and unquantized from.
+pub trait SampleFormat: Copy {
+    /// Bits used to store one sample
+    const BITS: u32;
+    /// Whether this is an IEEE floating point format (WAV format tag 3 rather than 1)
+    const IS_FLOAT: bool;
+
+    /// Converts a `f64` sample in `[-1.0, 1.0]` into this format, rounding to the nearest level
+    /// and clipping out-of-range input.
+    fn from_sample(sample: f64) -> Self;
+
+    /// Converts a value in this format back into a `f64` sample in `[-1.0, 1.0)`.
+    fn to_sample(self) -> f64;
+
+    /// Writes this sample as little-endian bytes, as stored in a WAV file.
+    fn write_le<W: Write>(self, writer: &mut W) -> Result<()>;
+}
+
+/// Scale between a `f64` sample and the signed levels of a `bits`-bit integer format
+fn integer_scale(bits: u32) -> f64 {
+    2.0f64.powi(bits as i32 - 1)
+}
+
+/// Rounds and clips a `f64` sample into the signed levels of a `bits`-bit integer format
+fn to_level(sample: f64, bits: u32) -> f64 {
+    let scale = integer_scale(bits);
+    if sample.is_nan() {
+        return 0.0;
+    }
+    (sample * scale).round().clamp(-scale, scale - 1.0)
+}
+
+macro_rules! signed_sample_format {
+    ($type:ty, $bits:expr, $write:ident) => {
+        impl SampleFormat for $type {
+            const BITS: u32 = $bits;
+            const IS_FLOAT: bool = false;
+
+            fn from_sample(sample: f64) -> Self {
+                to_level(sample, Self::BITS) as $type
+            }
+
+            fn to_sample(self) -> f64 {
+                f64::from(self) / integer_scale(Self::BITS)
+            }
+
+            fn write_le<W: Write>(self, writer: &mut W) -> Result<()> {
+                writer.$write::<LittleEndian>(self)
+            }
+        }
+    };
+}
+
+signed_sample_format!(i16, 16, write_i16);
+signed_sample_format!(i32, 32, write_i32);
+
+impl SampleFormat for i8 {
+    const BITS: u32 = 8;
+    const IS_FLOAT: bool = false;
+
+    fn from_sample(sample: f64) -> Self {
+        to_level(sample, Self::BITS) as i8
+    }
+
+    fn to_sample(self) -> f64 {
+        f64::from(self) / integer_scale(Self::BITS)
+    }
+
+    fn write_le<W: Write>(self, writer: &mut W) -> Result<()> {
+        writer.write_i8(self)
+    }
+}
+
+impl SampleFormat for u8 {
+    const BITS: u32 = 8;
+    const IS_FLOAT: bool = false;
+
+    fn from_sample(sample: f64) -> Self {
+        (to_level(sample, Self::BITS) + 128.0) as u8
+    }
+
+    fn to_sample(self) -> f64 {
+        (f64::from(self) - 128.0) / integer_scale(Self::BITS)
+    }
+
+    fn write_le<W: Write>(self, writer: &mut W) -> Result<()> {
+        writer.write_u8(self)
+    }
+}
+
+impl SampleFormat for f32 {
+    const BITS: u32 = 32;
+    const IS_FLOAT: bool = true;
+
+    fn from_sample(sample: f64) -> Self {
+        sample as f32
+    }
+
+    fn to_sample(self) -> f64 {
+        f64::from(self)
+    }
+
+    fn write_le<W: Write>(self, writer: &mut W) -> Result<()> {
+        writer.write_f32::<LittleEndian>(self)
+    }
+}
+
+impl SampleFormat for f64 {
+    const BITS: u32 = 64;
+    const IS_FLOAT: bool = true;
+
+    fn from_sample(sample: f64) -> Self {
+        sample
+    }
+
+    fn to_sample(self) -> f64 {
+        self
+    }
+
+    fn write_le<W: Write>(self, writer: &mut W) -> Result<()> {
+        writer.write_f64::<LittleEndian>(self)
+    }
+}
+
+/// A signed 24-bit sample, stored in the low 24 bits of an `i32`.
+///
+/// ```
+/// use synthrs::format::I24;
+///
+/// assert_eq!(I24::new(8_388_607), Some(I24::MAX));
+/// assert_eq!(I24::new(8_388_608), None);
+/// assert_eq!(I24::MIN.value(), -8_388_608);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct I24(i32);
+
+impl I24 {
+    pub const MIN: I24 = I24(-8_388_608);
+    pub const MAX: I24 = I24(8_388_607);
+
+    /// Creates a 24-bit sample, returning `None` if `value` is out of range.
+    pub fn new(value: i32) -> Option<I24> {
+        if (I24::MIN.0..=I24::MAX.0).contains(&value) {
+            Some(I24(value))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the sample value as an `i32`.
+    pub fn value(self) -> i32 {
+        self.0
+    }
+}
+
+impl SampleFormat for I24 {
+    const BITS: u32 = 24;
+    const IS_FLOAT: bool = false;
+
+    fn from_sample(sample: f64) -> Self {
+        I24(to_level(sample, Self::BITS) as i32)
+    }
+
+    fn to_sample(self) -> f64 {
+        f64::from(self.0) / integer_scale(Self::BITS)
+    }
+
+    fn write_le<W: Write>(self, writer: &mut W) -> Result<()> {
+        writer.write_i24::<LittleEndian>(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trips<T: SampleFormat + PartialEq + std::fmt::Debug>(value: T) {
+        assert_eq!(T::from_sample(value.to_sample()), value);
+    }
+
+    #[test]
+    fn test_full_scale() {
+        assert_eq!(u8::from_sample(1.0), u8::MAX);
+        assert_eq!(u8::from_sample(-1.0), u8::MIN);
+        assert_eq!(i8::from_sample(1.0), i8::MAX);
+        assert_eq!(i8::from_sample(-1.0), i8::MIN);
+        assert_eq!(i16::from_sample(1.0), i16::MAX);
+        assert_eq!(i16::from_sample(-1.0), i16::MIN);
+        assert_eq!(I24::from_sample(1.0), I24::MAX);
+        assert_eq!(I24::from_sample(-1.0), I24::MIN);
+        assert_eq!(i32::from_sample(1.0), i32::MAX);
+        assert_eq!(i32::from_sample(-1.0), i32::MIN);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn test_silence() {
+        assert_eq!(u8::from_sample(0.0), 128);
+        assert_eq!(i16::from_sample(0.0), 0);
+        assert_eq!(I24::from_sample(0.0), I24(0));
+        assert_eq!(128u8.to_sample(), 0.0);
+        assert_eq!(0i32.to_sample(), 0.0);
+    }
+
+    #[test]
+    fn test_clips_instead_of_zeroing() {
+        assert_eq!(u8::from_sample(2.0), u8::MAX);
+        assert_eq!(u8::from_sample(-2.0), u8::MIN);
+        assert_eq!(i16::from_sample(100.0), i16::MAX);
+        assert_eq!(i16::from_sample(-100.0), i16::MIN);
+        assert_eq!(I24::from_sample(1.5), I24::MAX);
+        assert_eq!(i32::from_sample(-1.5), i32::MIN);
+        assert_eq!(i16::from_sample(f64::NAN), 0);
+    }
+
+    #[test]
+    fn test_exact_round_trips() {
+        for value in u8::MIN..=u8::MAX {
+            assert_round_trips(value);
+        }
+        for value in i8::MIN..=i8::MAX {
+            assert_round_trips(value);
+        }
+        for value in i16::MIN..=i16::MAX {
+            assert_round_trips(value);
+        }
+        for value in (I24::MIN.0..=I24::MAX.0).step_by(97) {
+            assert_round_trips(I24(value));
+        }
+        assert_round_trips(I24::MAX);
+        for value in (i32::MIN..=i32::MAX).step_by(65_537) {
+            assert_round_trips(value);
+        }
+        assert_round_trips(i32::MAX);
+        for &value in &[-1.0f32, -0.123, 0.0, 0.5, 1.0] {
+            assert_round_trips(value);
+        }
+    }
+
+    #[test]
+    fn test_write_le() {
+        let mut buf: Vec<u8> = Vec::new();
+        I24::MIN.write_le(&mut buf).unwrap();
+        (-2i16).write_le(&mut buf).unwrap();
+        128u8.write_le(&mut buf).unwrap();
+        assert_eq!(buf, vec![0x00, 0x00, 0x80, 0xfe, 0xff, 0x80]);
+    }
+}
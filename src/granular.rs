@@ -0,0 +1,349 @@
+//! Granular synthesis over sample buffers.
+//!
+//! A granular engine plays a stream of short, overlapping grains, each a windowed snippet of a
+//! `Sample`. Grains are read from a position that moves through the sample at its own speed,
+//! independently of their pitch, which allows time-stretching (a speed below `1.0`), pitch
+//! shifting without changing length, and freezing a moment of the sound (a speed of `0.0`).
+//! Random jitter of each grain's position and pitch turns the stream into a cloud.
+//!
+//! ```
+//! use synthrs::granular::{Granular, Window};
+//! use synthrs::sample::Sample;
+//!
+//! let sample = Sample::from_wave_file("tests/assets/sine.wav", 440.0).unwrap();
+//!
+//! // Freeze the sound half a second in, as a shimmering cloud of grains
+//! let mut cloud = Granular::new(sample, 44_100, 1234);
+//! cloud.position = 0.5;
+//! cloud.speed = 0.0;
+//! cloud.grain_size = 0.08;
+//! cloud.density = 60.0;
+//! cloud.position_jitter = 0.02;
+//! cloud.pitch_jitter = 0.1;
+//! cloud.window = Window::Blackman;
+//! let frozen: Vec<f64> = cloud.take(44_100 * 4).collect();
+//! ```
+
+use std::cell::RefCell;
+
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
+
+use crate::filter;
+use crate::sample::Sample;
+
+/// Points in the lookup table grain windows are read from
+const WINDOW_TABLE_SIZE: usize = 1024;
+
+/// Shape of the amplitude envelope of every grain.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Window {
+    /// Raised cosine. Grains overlapping by half sum to a constant level.
+    #[default]
+    Hann,
+    /// Raised cosine on a pedestal, so grains start and end with a small step
+    Hamming,
+    /// Narrower than Hann, for smoother, softer grains
+    Blackman,
+}
+
+impl Window {
+    /// Creates a table of the window over `size` points, from `crate::filter`.
+    pub fn table(self, size: usize) -> Vec<f64> {
+        match self {
+            Window::Hann => filter::hann_window(size),
+            Window::Hamming => filter::hamming_window(size),
+            Window::Blackman => filter::blackman_window(size),
+        }
+    }
+}
+
+/// A single grain being played
+#[derive(Clone, Debug)]
+struct Grain {
+    /// Position in the sample the grain starts at, in samples of the sample
+    start: f64,
+    /// Samples of the sample read per output sample
+    step: f64,
+    /// Output samples played so far
+    age: usize,
+    /// Length in output samples
+    length: usize,
+}
+
+/// A granular synthesis engine playing grains of a `Sample`.
+///
+/// Parameters are public and can be changed between samples, for example to sweep the position
+/// or pitch while playing. The output is scaled by the amount grains overlap, so it stays at
+/// about the level of the sample however dense the grains are.
+///
+/// ```
+/// use synthrs::granular::Granular;
+/// use synthrs::sample::Sample;
+///
+/// let sample = Sample::from_wave_file("tests/assets/sine.wav", 440.0).unwrap();
+///
+/// // An octave up, at the original tempo
+/// let mut shifted = Granular::new(sample, 44_100, 1234);
+/// shifted.pitch = 12.0;
+/// let samples: Vec<f64> = shifted.take(44_100).collect();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Granular {
+    /// Length of every grain, in seconds
+    pub grain_size: f64,
+    /// Grains started per second
+    pub density: f64,
+    /// Position grains are read from, in seconds into the sample. It moves on by `speed` as the
+    /// engine plays, wrapping around at the ends of the sample.
+    pub position: f64,
+    /// Random offset of every grain's position, up to this many seconds either way
+    pub position_jitter: f64,
+    /// Rate `position` moves through the sample: `1.0` keeps the original tempo, `0.5` stretches
+    /// the sample to twice its length, and `0.0` freezes it
+    pub speed: f64,
+    /// Pitch shift of every grain, in semitones
+    pub pitch: f64,
+    /// Random pitch shift of every grain, up to this many semitones either way
+    pub pitch_jitter: f64,
+    pub window: Window,
+    sample: Sample,
+    sample_rate: usize,
+    rng: XorShiftRng,
+    grains: Vec<Grain>,
+    /// Output samples until the next grain starts
+    until_grain: f64,
+    /// Table of the window in use, and its mean
+    window_table: (Window, Vec<f64>, f64),
+}
+
+impl Granular {
+    /// Creates an engine playing grains of `sample` at `sample_rate`, with jitter seeded by
+    /// `seed`. Grains start 50ms long, 40 per second (each overlapping the next by half), at
+    /// the start of the sample, moving through it at the original tempo.
+    pub fn new(sample: Sample, sample_rate: usize, seed: u64) -> Granular {
+        Granular {
+            grain_size: 0.05,
+            density: 40.0,
+            position: 0.0,
+            position_jitter: 0.0,
+            speed: 1.0,
+            pitch: 0.0,
+            pitch_jitter: 0.0,
+            window: Window::Hann,
+            sample,
+            sample_rate,
+            rng: XorShiftRng::seed_from_u64(seed),
+            grains: Vec::new(),
+            until_grain: 0.0,
+            window_table: window_table(Window::Hann),
+        }
+    }
+
+    /// Random value between `-amount` and `amount`
+    fn jitter(&mut self, amount: f64) -> f64 {
+        if amount > 0.0 {
+            self.rng.gen_range(-amount, amount)
+        } else {
+            0.0
+        }
+    }
+
+    fn start_grain(&mut self) {
+        let position = self.position + self.jitter(self.position_jitter);
+        let semitones = self.pitch + self.jitter(self.pitch_jitter);
+
+        let source_rate = self.sample.sample_rate as f64;
+        let start = (position * source_rate).clamp(0.0, self.sample.len() as f64);
+        self.grains.push(Grain {
+            start,
+            step: 2.0f64.powf(semitones / 12.0) * source_rate / self.sample_rate as f64,
+            age: 0,
+            length: ((self.grain_size * self.sample_rate as f64).round() as usize).max(1),
+        });
+    }
+
+    /// Returns the next sample.
+    pub fn tick(&mut self) -> f64 {
+        if self.window_table.0 != self.window {
+            self.window_table = window_table(self.window);
+        }
+
+        if self.until_grain <= 0.0 {
+            self.start_grain();
+            self.until_grain += self.sample_rate as f64 / self.density.max(f64::EPSILON);
+        }
+        self.until_grain -= 1.0;
+
+        let (_, ref table, mean) = self.window_table;
+        let samples = self.sample.samples();
+        let interpolation = self.sample.interpolation;
+        let mut output = 0.0;
+
+        for grain in &mut self.grains {
+            let phase = grain.age as f64 / grain.length as f64 * (table.len() - 1) as f64;
+            let index = phase as usize;
+            let fraction = phase - index as f64;
+            let next = table[(index + 1).min(table.len() - 1)];
+            let window = table[index] + fraction * (next - table[index]);
+
+            let position = grain.start + grain.age as f64 * grain.step;
+            output += window * interpolation.value(samples, position, grain.step);
+            grain.age += 1;
+        }
+        self.grains.retain(|grain| grain.age < grain.length);
+
+        let duration = self.sample.duration();
+        self.position += self.speed / self.sample_rate as f64;
+        if duration > 0.0 {
+            self.position = self.position.rem_euclid(duration);
+        }
+
+        // Average number of grains sounding at once, weighted by their window
+        let overlap = self.density * self.grain_size * mean;
+        output / overlap.max(1.0)
+    }
+
+    /// Returns a generator of the engine's output, for use where a function of `t` is expected.
+    /// `t` is ignored: every call returns the next sample.
+    pub fn generator(self) -> impl Fn(f64) -> f64 {
+        let granular = RefCell::new(self);
+        move |_t| granular.borrow_mut().tick()
+    }
+}
+
+impl Iterator for Granular {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        Some(self.tick())
+    }
+}
+
+fn window_table(window: Window) -> (Window, Vec<f64>, f64) {
+    let table = window.table(WINDOW_TABLE_SIZE);
+    let mean = table.iter().sum::<f64>() / table.len() as f64;
+    (window, table, mean)
+}
+
+/// Time-stretches `sample` by `factor` (`2.0` being twice as long) without changing its pitch,
+/// using grains with the default size and density, output at `sample_rate`. Grains repeat the
+/// sample without lining up, which adds a grainy texture; `crate::timestretch` gives cleaner
+/// results on tonal material.
+///
+/// ```
+/// use synthrs::granular::stretch;
+/// use synthrs::sample::Sample;
+///
+/// let sample = Sample::from_wave_file("tests/assets/sine.wav", 440.0).unwrap();
+/// let slow = stretch(&sample, 2.0, 44_100);
+/// assert_eq!(slow.len(), 2 * sample.len());
+/// ```
+pub fn stretch(sample: &Sample, factor: f64, sample_rate: usize) -> Vec<f64> {
+    let length = (sample.duration() * factor * sample_rate as f64).round() as usize;
+    let mut granular = Granular::new(sample.clone(), sample_rate, 0);
+    granular.speed = 1.0 / factor;
+    granular.take(length).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{magnitude_spectrum, rms};
+    use crate::synthesizer::make_samples;
+    use crate::wave::sine_wave;
+
+    fn sine(frequency: f64, length: f64) -> Sample {
+        Sample::new(
+            make_samples(length, 44_100, sine_wave(frequency)),
+            44_100,
+            frequency,
+        )
+    }
+
+    /// Frequency of the loudest FFT bin
+    fn peak_frequency(samples: &[f64]) -> f64 {
+        let spectrum = magnitude_spectrum(samples);
+        let bin = (0..spectrum.len() / 2)
+            .max_by(|&a, &b| spectrum[a].partial_cmp(&spectrum[b]).unwrap())
+            .unwrap();
+        bin as f64 * 44_100.0 / samples.len() as f64
+    }
+
+    #[test]
+    fn test_plays_through_at_original_tempo() {
+        let sample = sine(440.0, 1.0);
+        let output: Vec<f64> = Granular::new(sample.clone(), 44_100, 1)
+            .take(44_100)
+            .collect();
+
+        // Once the first grain has faded in, overlapping Hann grains rebuild the sine
+        for (i, value) in output.iter().enumerate().skip(2_205).take(20_000) {
+            assert!((value - sample.samples()[i]).abs() < 0.02, "sample {}", i);
+        }
+    }
+
+    #[test]
+    fn test_pitch() {
+        let mut granular = Granular::new(sine(440.0, 1.0), 44_100, 1);
+        granular.pitch = 12.0;
+        let output: Vec<f64> = granular.skip(4_096).take(16_384).collect();
+        let frequency = peak_frequency(&output);
+        assert!((frequency - 880.0).abs() < 10.0, "{}", frequency);
+    }
+
+    #[test]
+    fn test_stretch() {
+        let sample = sine(440.0, 0.5);
+        let stretched = stretch(&sample, 2.0, 44_100);
+        assert_eq!(stretched.len(), 44_100);
+
+        // Twice as long and at the same pitch: grains repeating the sine out of phase add
+        // sidebands at the grain rate, but the energy stays close to 440Hz
+        let middle = &stretched[8_192..8_192 + 16_384];
+        let spectrum = magnitude_spectrum(middle);
+        let energy = |low: f64, high: f64| -> f64 {
+            (0..spectrum.len() / 2)
+                .filter(|&bin| {
+                    let frequency = bin as f64 * 44_100.0 / middle.len() as f64;
+                    frequency >= low && frequency < high
+                })
+                .map(|bin| spectrum[bin] * spectrum[bin])
+                .sum()
+        };
+        assert!(energy(360.0, 520.0) > 0.9 * energy(0.0, 22_050.0));
+        assert!(rms(middle) > 0.4);
+    }
+
+    #[test]
+    fn test_freeze() {
+        // A sine for half a second, then silence
+        let mut samples = make_samples(0.5, 44_100, sine_wave(440.0));
+        samples.extend(vec![0.0; 22_050]);
+        let sample = Sample::new(samples, 44_100, 440.0);
+
+        let frozen = |position: f64| {
+            let mut granular = Granular::new(sample.clone(), 44_100, 1);
+            granular.position = position;
+            granular.speed = 0.0;
+            granular.take(88_200).skip(4_410).collect::<Vec<f64>>()
+        };
+
+        // Holds the sine long past the end of the sample, and the silence stays silent
+        assert!(rms(&frozen(0.25)[70_000..]) > 0.5);
+        assert!(frozen(0.75).iter().all(|&value| value == 0.0));
+    }
+
+    #[test]
+    fn test_jitter_is_seeded() {
+        let cloud = |seed: u64| {
+            let mut granular = Granular::new(sine(440.0, 1.0), 44_100, seed);
+            granular.position_jitter = 0.1;
+            granular.pitch_jitter = 2.0;
+            granular.take(4_410).collect::<Vec<f64>>()
+        };
+
+        assert_eq!(cloud(1), cloud(1));
+        assert_ne!(cloud(1), cloud(2));
+    }
+}
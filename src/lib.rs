@@ -1,11 +1,28 @@
 #![feature(fn_traits, unboxed_closures)]
 #![allow(dead_code)]
 
+pub mod additive;
+pub mod analysis;
+pub mod dither;
+pub mod drums;
 pub mod errors;
 pub mod filter;
+pub mod fm;
+pub mod format;
+pub mod granular;
 pub mod midi;
 pub mod music;
+pub mod noise;
+pub mod oscillator;
+pub mod realtime;
+pub mod resample;
 pub mod sample;
+pub mod sf2;
+pub mod sfz;
 pub mod synthesizer;
+pub mod timestretch;
+pub mod unison;
 pub mod wave;
+pub mod waveguide;
+pub mod wavetable;
 pub mod writer;
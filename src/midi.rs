@@ -1,6 +1,7 @@
 //! MIDI parsing routines
 
 use std::cmp::max;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom};
 use std::path::Path;
@@ -128,6 +129,215 @@ pub struct MidiSong {
     pub bpm: f64,
 }
 
+impl MidiSong {
+    /// Converts a time in MIDI ticks into seconds using the song's tempo.
+    pub fn tick_to_seconds(&self, tick: usize) -> f64 {
+        tick as f64 * 60.0 / self.bpm / self.time_unit as f64
+    }
+
+    /// Length of the song in seconds.
+    pub fn length(&self) -> f64 {
+        self.tick_to_seconds(self.max_time)
+    }
+
+    /// Collects every note in the song, pairing each `NoteOn` with the first terminating event
+    /// for the same note on the same channel. Notes that are never terminated last until the end
+    /// of the song. Notes are ordered by track, then by start time.
+    ///
+    /// ```
+    /// use synthrs::midi::read_midi_file;
+    ///
+    /// let song = read_midi_file("tests/assets/test.mid").unwrap();
+    /// let notes = song.notes();
+    /// assert_eq!(notes[0].note, 57);
+    /// ```
+    pub fn notes(&self) -> Vec<MidiNote> {
+        let mut notes = Vec::new();
+
+        for track in &self.tracks {
+            for (i, event) in track.events.iter().enumerate() {
+                // Meta events carry the running status as their event type, so skip them
+                if event.event_type != EventType::NoteOn
+                    || event.system_event_type.is_some()
+                    || event.is_note_terminating()
+                {
+                    continue;
+                }
+
+                let end_tick = track.events[i + 1..]
+                    .iter()
+                    .find(|cursor| {
+                        cursor.system_event_type.is_none()
+                            && cursor.channel == event.channel
+                            && cursor.value1 == event.value1
+                            && cursor.is_note_terminating()
+                    })
+                    .map_or(self.max_time, |cursor| cursor.time);
+
+                notes.push(MidiNote {
+                    channel: event.channel,
+                    note: event.value1 as u8,
+                    velocity: event.value2.unwrap_or(0) as u8,
+                    start_tick: event.time,
+                    end_tick,
+                });
+            }
+        }
+
+        notes
+    }
+
+    /// Returns the most recent value of `controller` on `channel` at or before `tick`, across all
+    /// tracks. Returns `None` if the controller was never set. This scans the whole song, so use
+    /// a `ChannelTimeline` to look up controllers for many notes.
+    ///
+    /// ```
+    /// use synthrs::midi::{read_midi_file, CC_PAN};
+    ///
+    /// let song = read_midi_file("tests/assets/test.mid").unwrap();
+    /// let pan = song.controller_value_at(0, CC_PAN, 0).unwrap_or(64);
+    /// ```
+    pub fn controller_value_at(
+        &self,
+        channel: u8,
+        controller: usize,
+        tick: usize,
+    ) -> Option<usize> {
+        self.channel_timeline()
+            .controller_value_at(channel, controller, tick)
+    }
+
+    /// Returns the bank and program (instrument) most recently selected on `channel` at or before
+    /// `tick`, across all tracks. As in General MIDI, a bank select (CC0) only takes effect at the
+    /// next `ProgramChange`, so the bank is latched from the CC0 in effect when the program was
+    /// selected. Returns `None` if the channel never had a `ProgramChange`. Like
+    /// `controller_value_at`, this scans the whole song.
+    ///
+    /// ```
+    /// use synthrs::midi::read_midi_file;
+    ///
+    /// let song = read_midi_file("tests/assets/test.mid").unwrap();
+    /// assert_eq!(song.program_at(0, 0), Some((0, 0)));
+    /// assert_eq!(song.program_at(1, 0), None);
+    /// ```
+    pub fn program_at(&self, channel: u8, tick: usize) -> Option<(u16, u8)> {
+        self.channel_timeline().program_at(channel, tick)
+    }
+
+    /// Collects the control and program changes of every channel, across all tracks, so they
+    /// can be looked up for each note of a render without scanning the song again.
+    ///
+    /// ```
+    /// use synthrs::midi::{read_midi_file, CC_VOLUME};
+    ///
+    /// let song = read_midi_file("tests/assets/test.mid").unwrap();
+    /// let timeline = song.channel_timeline();
+    /// for note in song.notes() {
+    ///     let volume = timeline
+    ///         .controller_value_at(note.channel, CC_VOLUME, note.start_tick)
+    ///         .unwrap_or(100);
+    /// }
+    /// ```
+    pub fn channel_timeline(&self) -> ChannelTimeline {
+        let mut events: Vec<&MidiEvent> = self
+            .tracks
+            .iter()
+            .flat_map(|track| track.events.iter())
+            .filter(|event| {
+                (event.event_type == EventType::ControlChange
+                    || event.event_type == EventType::ProgramChange)
+                    && event.system_event_type.is_none()
+            })
+            .collect();
+        // Stable, so events on the same tick keep their order within a track
+        events.sort_by_key(|event| event.time);
+
+        let mut timeline = ChannelTimeline::default();
+        let mut banks: BTreeMap<u8, u16> = BTreeMap::new();
+        for event in events {
+            if event.event_type == EventType::ProgramChange {
+                let bank = banks.get(&event.channel).cloned().unwrap_or(0);
+                timeline
+                    .programs
+                    .entry(event.channel)
+                    .or_insert_with(Vec::new)
+                    .push((event.time, (bank, event.value1 as u8)));
+            } else if let Some(value) = event.value2 {
+                if event.value1 == CC_BANK_SELECT {
+                    banks.insert(event.channel, value as u16);
+                }
+                timeline
+                    .controllers
+                    .entry((event.channel, event.value1))
+                    .or_insert_with(Vec::new)
+                    .push((event.time, value));
+            }
+        }
+
+        timeline
+    }
+}
+
+/// Ticks of the changes to a value with the new value, ordered by tick
+type Changes<T> = Vec<(usize, T)>;
+
+/// The control and program changes of a `MidiSong` by channel, ordered by tick. Created with
+/// `MidiSong::channel_timeline`.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelTimeline {
+    controllers: BTreeMap<(u8, usize), Changes<usize>>,
+    programs: BTreeMap<u8, Changes<(u16, u8)>>,
+}
+
+impl ChannelTimeline {
+    /// Returns the most recent value of `controller` on `channel` at or before `tick`, as
+    /// `MidiSong::controller_value_at` does.
+    pub fn controller_value_at(
+        &self,
+        channel: u8,
+        controller: usize,
+        tick: usize,
+    ) -> Option<usize> {
+        latest_at(self.controllers.get(&(channel, controller))?, tick)
+    }
+
+    /// Returns the bank and program most recently selected on `channel` at or before `tick`, as
+    /// `MidiSong::program_at` does.
+    pub fn program_at(&self, channel: u8, tick: usize) -> Option<(u16, u8)> {
+        latest_at(self.programs.get(&channel)?, tick)
+    }
+}
+
+/// The value of the last change at or before `tick`, in changes ordered by tick
+fn latest_at<T: Copy>(changes: &[(usize, T)], tick: usize) -> Option<T> {
+    let count = changes.partition_point(|&(time, _)| time <= tick);
+    changes[..count].last().map(|&(_, value)| value)
+}
+
+/// Control change number for bank select (coarse), choosing the bank of the next program
+pub const CC_BANK_SELECT: usize = 0;
+/// Control change number for channel volume (coarse)
+pub const CC_VOLUME: usize = 7;
+/// Control change number for channel pan (coarse). 0 is hard left, 64 center and 127 hard right.
+pub const CC_PAN: usize = 10;
+/// Control change number to immediately silence all sound on a channel
+pub const CC_ALL_SOUND_OFF: usize = 120;
+/// Control change number to release all notes on a channel
+pub const CC_ALL_NOTES_OFF: usize = 123;
+
+/// Channel reserved for percussion in General MIDI ("channel 10", counting from 0 here)
+pub const DRUM_CHANNEL: u8 = 9;
+
+/// A single note extracted from a `MidiSong`. Times are in MIDI ticks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MidiNote {
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+    pub start_tick: usize,
+    pub end_tick: usize,
+}
+
 #[derive(Clone, Debug)]
 pub struct MidiTrack {
     pub events: Vec<MidiEvent>,
@@ -386,7 +596,7 @@ where
         let mut value = (octet & 0b0111_1111) as usize;
         while octet >= 0b1000_0000 {
             octet = self.reader.read_u8()?;
-            value = (value << 7) as usize + (octet & 0b0111_1111) as usize;
+            value = (value << 7) + (octet & 0b0111_1111) as usize;
         }
 
         Ok(value)
@@ -511,7 +721,7 @@ where
     for track in &song.tracks {
         for event in &track.events {
             if let Some(MetaEventType::TempoSetting) = event.meta_event_type {
-                song.bpm = (60_000_000.0 / event.value1 as f64) as f64;
+                song.bpm = 60_000_000.0 / event.value1 as f64;
                 break;
             }
         }
@@ -611,4 +821,41 @@ mod tests {
         let song = read_midi_file("tests/assets/running_status.mid").expect("failed");
         assert_eq!(song.bpm as usize, 160);
     }
+
+    #[test]
+    fn it_builds_a_channel_timeline() {
+        let mut song = read_midi_file("tests/assets/test.mid").expect("failed");
+        let event =
+            |time: usize, event_type: EventType, value1: usize, value2: Option<usize>| MidiEvent {
+                event_type,
+                system_event_type: None,
+                meta_event_type: None,
+                time,
+                channel: 0,
+                value1,
+                value2,
+            };
+        song.tracks[0]
+            .events
+            .push(event(480, EventType::ControlChange, CC_PAN, Some(0)));
+        song.tracks[1].events.extend(vec![
+            event(960, EventType::ControlChange, CC_BANK_SELECT, Some(1)),
+            event(960, EventType::ControlChange, CC_PAN, Some(127)),
+            event(1920, EventType::ProgramChange, 5, None),
+        ]);
+
+        let timeline = song.channel_timeline();
+        assert_eq!(timeline.controller_value_at(0, CC_PAN, 0), None);
+        assert_eq!(timeline.controller_value_at(0, CC_PAN, 959), Some(0));
+        assert_eq!(timeline.controller_value_at(0, CC_PAN, 960), Some(127));
+        assert_eq!(timeline.controller_value_at(1, CC_PAN, 960), None);
+
+        // The bank select only applies from the next program change
+        assert_eq!(timeline.program_at(0, 1919), Some((0, 0)));
+        assert_eq!(timeline.program_at(0, 1920), Some((1, 5)));
+
+        // The song's own lookups agree
+        assert_eq!(song.controller_value_at(0, CC_PAN, 959), Some(0));
+        assert_eq!(song.program_at(0, 1920), Some((1, 5)));
+    }
 }
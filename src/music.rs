@@ -30,6 +30,12 @@ pub fn note_midi(a4: f64, midi_note: usize) -> f64 {
     note(a4, semitone, octave)
 }
 
+/// Calculates the frequency ratio of an interval in cents (hundredths of an equal-tempered
+/// semitone). `cents_to_ratio(1200.0)` is an octave, `2.0`.
+pub fn cents_to_ratio(cents: f64) -> f64 {
+    2.0f64.powf(cents / 1200.0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,4 +68,12 @@ mod tests {
         assert!((note(a4, 9, 3) - note_midi(a4, a3_note)).abs() < threshold);
         assert!((note(a4, 9, 4) - note_midi(a4, c4_note)).abs() > threshold);
     }
+
+    #[test]
+    fn it_converts_cents_to_ratios() {
+        assert!((cents_to_ratio(0.0) - 1.0).abs() < 1e-12);
+        assert!((cents_to_ratio(1200.0) - 2.0).abs() < 1e-12);
+        assert!((cents_to_ratio(-1200.0) - 0.5).abs() < 1e-12);
+        assert!((cents_to_ratio(100.0) - note(440.0, 10, 4) / 440.0).abs() < 1e-9);
+    }
 }
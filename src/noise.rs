@@ -0,0 +1,308 @@
+//! Coloured and velvet noise generators.
+//!
+//! Every generator is built from an explicit seed, so renders are deterministic, and its output
+//! is zero-mean and within `[-1, 1]`.
+//!
+//! * White: uniform, flat spectrum
+//! * Pink: -3dB/octave (Voss-McCartney), equal energy per octave
+//! * Brown (red): -6dB/octave (leaky integration of white noise)
+//! * Blue: +3dB/octave (differentiated pink noise)
+//! * Violet: +6dB/octave (differentiated white noise)
+//!
+//! Coloured noises other than white and violet are scaled to an RMS of roughly `0.25` and clipped
+//! to `[-1, 1]`; clipping is very rare at that level.
+//!
+//! ```
+//! use synthrs::noise::{generator, Colour, Noise};
+//! use synthrs::synthesizer::make_samples;
+//!
+//! let mut pink = Noise::new(Colour::Pink, 1234);
+//! let samples: Vec<f64> = (0..44_100).map(|_| pink.tick()).collect();
+//!
+//! // Or as a generator of `t` (which ignores `t`)
+//! let brown = make_samples(1.0, 44_100, generator(Colour::Brown, 1234));
+//! ```
+
+use std::cell::RefCell;
+
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
+
+/// Spectral colour of a `Noise` generator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Colour {
+    White,
+    Pink,
+    Brown,
+    Blue,
+    Violet,
+}
+
+/// Number of Voss-McCartney rows, enough for a -3dB/octave slope down to a few hertz
+const PINK_ROWS: usize = 16;
+
+/// Gains bringing each colour to an RMS of roughly `0.25`
+const PINK_GAIN: f64 = 1.7;
+const BROWN_GAIN: f64 = 4.4;
+const BLUE_GAIN: f64 = 7.3;
+
+/// Leak of the brown noise integrator. Below about `(1 - BROWN_LEAK) * sample_rate / 2pi` hertz
+/// the spectrum is flat instead of rising forever (which would drift off to a DC offset).
+const BROWN_LEAK: f64 = 1.0 / 1.02;
+
+/// A seeded noise generator of a given colour.
+#[derive(Clone, Debug)]
+pub struct Noise {
+    pub colour: Colour,
+    rng: XorShiftRng,
+    /// Voss-McCartney rows, each updated half as often as the previous one
+    rows: [f64; PINK_ROWS],
+    counter: u32,
+    /// Previous white, pink or brown value, for differentiation and integration
+    previous: f64,
+}
+
+impl Noise {
+    pub fn new(colour: Colour, seed: u64) -> Noise {
+        let mut rng = XorShiftRng::seed_from_u64(seed);
+        let mut rows = [0.0; PINK_ROWS];
+        for row in rows.iter_mut() {
+            *row = rng.gen_range(-1.0, 1.0);
+        }
+
+        Noise {
+            colour,
+            rng,
+            rows,
+            counter: 0,
+            previous: 0.0,
+        }
+    }
+
+    /// Returns the next sample.
+    pub fn tick(&mut self) -> f64 {
+        match self.colour {
+            Colour::White => self.white(),
+            Colour::Pink => (self.pink() * PINK_GAIN).clamp(-1.0, 1.0),
+            Colour::Brown => {
+                let white = self.white();
+                self.previous = (self.previous + 0.02 * white) * BROWN_LEAK;
+                (self.previous * BROWN_GAIN).clamp(-1.0, 1.0)
+            }
+            Colour::Blue => {
+                let pink = self.pink();
+                let blue = (pink - self.previous) / 2.0;
+                self.previous = pink;
+                (blue * BLUE_GAIN).clamp(-1.0, 1.0)
+            }
+            Colour::Violet => {
+                let white = self.white();
+                let violet = (white - self.previous) / 2.0;
+                self.previous = white;
+                violet
+            }
+        }
+    }
+
+    fn white(&mut self) -> f64 {
+        self.rng.gen_range(-1.0, 1.0)
+    }
+
+    /// Unscaled Voss-McCartney pink noise, in `[-1, 1]`
+    fn pink(&mut self) -> f64 {
+        self.counter = self.counter.wrapping_add(1);
+
+        // Row `n` is updated every `2^n` samples, staggered so one row changes per sample
+        let row = self.counter.trailing_zeros() as usize;
+        if row < PINK_ROWS {
+            self.rows[row] = self.white();
+        }
+
+        let white = self.white();
+        (self.rows.iter().sum::<f64>() + white) / (PINK_ROWS + 1) as f64
+    }
+}
+
+impl Iterator for Noise {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        Some(self.tick())
+    }
+}
+
+/// Returns a generator of noise of `colour`, for use where a function of `t` is expected.
+/// `t` is ignored: every call returns the next sample.
+pub fn generator(colour: Colour, seed: u64) -> impl Fn(f64) -> f64 {
+    let noise = RefCell::new(Noise::new(colour, seed));
+    move |_t| noise.borrow_mut().tick()
+}
+
+/// Velvet noise: sparse impulses of `1.0` or `-1.0`, one at a random position in every period of
+/// `sample_rate / density` samples, and `0.0` everywhere else.
+///
+/// At densities around 1000-2000 impulses per second it sounds as smooth as white noise, and it
+/// is much cheaper to convolve with, which makes it useful for reverbs and decorrelation.
+///
+/// ```
+/// use synthrs::noise::VelvetNoise;
+///
+/// let mut velvet = VelvetNoise::new(2_000.0, 44_100, 1234);
+/// let samples: Vec<f64> = (0..44_100).map(|_| velvet.tick()).collect();
+///
+/// let impulses = samples.iter().filter(|&&sample| sample != 0.0).count();
+/// assert!(impulses >= 1_999 && impulses <= 2_001);
+/// ```
+#[derive(Clone, Debug)]
+pub struct VelvetNoise {
+    /// Period between impulses, in samples
+    period: f64,
+    rng: XorShiftRng,
+    /// Samples generated so far
+    sample: usize,
+    /// Number of the current period
+    periods: usize,
+    /// Sample index of the impulse in the current period, and its sign
+    impulse: (usize, f64),
+}
+
+impl VelvetNoise {
+    /// Creates velvet noise with `density` impulses per second.
+    pub fn new(density: f64, sample_rate: usize, seed: u64) -> VelvetNoise {
+        let mut velvet = VelvetNoise {
+            period: (sample_rate as f64 / density).max(1.0),
+            rng: XorShiftRng::seed_from_u64(seed),
+            sample: 0,
+            periods: 0,
+            impulse: (0, 1.0),
+        };
+        velvet.impulse = velvet.next_impulse();
+        velvet
+    }
+
+    /// First sample of period number `period`
+    fn period_start(&self, period: usize) -> usize {
+        (period as f64 * self.period).floor() as usize
+    }
+
+    /// Places the impulse of the current period
+    fn next_impulse(&mut self) -> (usize, f64) {
+        let start = self.period_start(self.periods);
+        let length = self.period_start(self.periods + 1) - start;
+        let offset = ((self.rng.gen::<f64>() * length as f64) as usize).min(length - 1);
+        let sign = if self.rng.gen::<bool>() { 1.0 } else { -1.0 };
+        (start + offset, sign)
+    }
+
+    /// Returns the next sample.
+    pub fn tick(&mut self) -> f64 {
+        let (position, sign) = self.impulse;
+        let output = if self.sample == position { sign } else { 0.0 };
+
+        self.sample += 1;
+        if self.sample >= self.period_start(self.periods + 1) {
+            self.periods += 1;
+            self.impulse = self.next_impulse();
+        }
+
+        output
+    }
+}
+
+impl Iterator for VelvetNoise {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        Some(self.tick())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{gain_to_db, magnitude_spectrum, rms};
+
+    const COLOURS: [Colour; 5] = [
+        Colour::White,
+        Colour::Pink,
+        Colour::Brown,
+        Colour::Blue,
+        Colour::Violet,
+    ];
+
+    /// Average power in the bins between `low` and `high` hertz, over several blocks
+    fn band_power(colour: Colour, low: f64, high: f64) -> f64 {
+        let size = 4096;
+        let mut noise = Noise::new(colour, 5);
+        let mut total = 0.0;
+        let mut count = 0;
+
+        for _ in 0..32 {
+            let block: Vec<f64> = noise.by_ref().take(size).collect();
+            let spectrum = magnitude_spectrum(&block);
+            for (bin, magnitude) in spectrum.iter().enumerate() {
+                let frequency = bin as f64 * 44_100.0 / size as f64;
+                if frequency >= low && frequency < high {
+                    total += magnitude * magnitude;
+                    count += 1;
+                }
+            }
+        }
+
+        total / count as f64
+    }
+
+    #[test]
+    fn test_spectral_slopes() {
+        // Power density change over the four octaves from 400-800Hz to 6.4-12.8kHz
+        let slope = |colour: Colour| -> f64 {
+            let ratio = band_power(colour, 6_400.0, 12_800.0) / band_power(colour, 400.0, 800.0);
+            gain_to_db(ratio.sqrt())
+        };
+
+        assert!(slope(Colour::White).abs() < 1.5);
+        assert!((slope(Colour::Pink) - -12.0).abs() < 2.5);
+        assert!((slope(Colour::Brown) - -24.0).abs() < 3.0);
+        assert!((slope(Colour::Blue) - 12.0).abs() < 3.0);
+        assert!((slope(Colour::Violet) - 24.0).abs() < 3.0);
+    }
+
+    #[test]
+    fn test_range_and_level() {
+        for &colour in COLOURS.iter() {
+            let samples: Vec<f64> = Noise::new(colour, 11).take(200_000).collect();
+            let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+            let level = rms(&samples);
+
+            assert!(samples.iter().all(|sample| sample.abs() <= 1.0));
+            // Pink noise has as much energy below 10Hz as in any other decade, so its mean
+            // wanders further over a few seconds
+            let mean_tolerance = if colour == Colour::Pink { 0.1 } else { 0.02 };
+            assert!(mean.abs() < mean_tolerance);
+            assert!(level > 0.15 && level < 0.6);
+        }
+    }
+
+    #[test]
+    fn test_seeded() {
+        for &colour in COLOURS.iter() {
+            let a: Vec<f64> = Noise::new(colour, 1).take(100).collect();
+            let b: Vec<f64> = Noise::new(colour, 1).take(100).collect();
+            let c: Vec<f64> = Noise::new(colour, 2).take(100).collect();
+            assert_eq!(a, b);
+            assert_ne!(a, c);
+        }
+
+        let a: Vec<f64> = VelvetNoise::new(1_000.0, 44_100, 1).take(1000).collect();
+        let b: Vec<f64> = VelvetNoise::new(1_000.0, 44_100, 1).take(1000).collect();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_velvet_has_one_impulse_per_period() {
+        let samples: Vec<f64> = VelvetNoise::new(1_000.0, 10_000, 3).take(10_000).collect();
+        for period in samples.chunks(10) {
+            assert_eq!(period.iter().filter(|&&sample| sample != 0.0).count(), 1);
+        }
+    }
+}
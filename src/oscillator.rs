@@ -0,0 +1,488 @@
+//! Stateful, phase-accumulating oscillators.
+//!
+//! The generators in `crate::wave` compute their output from absolute time, so changing their
+//! frequency between samples jumps to a different point in the cycle (a click), and precision
+//! drops as `t` grows. An `Oscillator` instead keeps a phase in `[0, 1)` and advances it by
+//! `frequency / sample_rate` every sample, so pitch glides and vibrato are continuous.
+//!
+//! ```
+//! use synthrs::oscillator::{Oscillator, Waveform};
+//!
+//! let mut oscillator = Oscillator::new(Waveform::Sine, 220.0, 44_100);
+//!
+//! // Glide from 220Hz to 440Hz over one second
+//! let samples: Vec<f64> = (0..44_100)
+//!     .map(|i| {
+//!         oscillator.set_frequency(220.0 + 220.0 * i as f64 / 44_100.0);
+//!         oscillator.tick()
+//!     })
+//!     .collect();
+//! ```
+//!
+//! Oscillators are also infinite iterators over their samples.
+//!
+//! ```
+//! use synthrs::oscillator::{Oscillator, Waveform};
+//!
+//! let samples: Vec<f64> = Oscillator::new(Waveform::BandlimitedSawtooth, 440.0, 44_100)
+//!     .take(44_100)
+//!     .collect();
+//! ```
+
+use std::f64::consts::PI;
+
+use crate::wave::{polyblamp, polyblep};
+
+/// Shape of one cycle of an oscillator. The naive variants match the generators in `crate::wave`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Square,
+    Sawtooth,
+    Triangle,
+    Tangent,
+    /// PolyBLEP square, see `crate::wave::bandlimited_square_wave`
+    BandlimitedSquare,
+    /// PolyBLEP sawtooth, see `crate::wave::bandlimited_sawtooth_wave`
+    BandlimitedSawtooth,
+    /// PolyBLAMP triangle, see `crate::wave::bandlimited_triangle_wave`
+    BandlimitedTriangle,
+    /// Pulse which is high for the given fraction of the cycle (the width, from `0.0` to `1.0`).
+    /// A width of `0.5` is a square wave.
+    Pulse(f64),
+    /// PolyBLEP pulse, see `crate::wave::bandlimited_pulse_wave`
+    BandlimitedPulse(f64),
+}
+
+impl Waveform {
+    /// Returns the amplitude at `phase` in `[0, 1)`. `phase_increment` (the phase advanced per
+    /// sample) is only used by the band-limited waveforms.
+    ///
+    /// ```
+    /// use synthrs::oscillator::Waveform;
+    ///
+    /// assert_eq!(Waveform::Square.value(0.25, 0.01), 1.0);
+    /// assert_eq!(Waveform::Triangle.value(0.5, 0.01), -1.0);
+    /// ```
+    pub fn value(self, phase: f64, phase_increment: f64) -> f64 {
+        match self {
+            Waveform::Sine => (phase * 2.0 * PI).sin(),
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sawtooth => phase - 0.5,
+            Waveform::Triangle => ((phase - 0.5).abs() - 0.25) * 4.0,
+            Waveform::Tangent => ((phase * PI - 0.5).tan() / 4.0).clamp(-1.0, 1.0),
+            Waveform::BandlimitedSquare => {
+                let falling_phase = (phase + 0.5).fract();
+                Waveform::Square.value(phase, phase_increment) + polyblep(phase, phase_increment)
+                    - polyblep(falling_phase, phase_increment)
+            }
+            Waveform::BandlimitedSawtooth => {
+                // The naive sawtooth drops by 1 at phase 0, half of the residual's step
+                phase - 0.5 - 0.5 * polyblep(phase, phase_increment)
+            }
+            Waveform::BandlimitedTriangle => {
+                // The slope changes by 8 per cycle (8 * phase_increment per sample) at the peak
+                // and the trough
+                let trough_phase = (phase + 0.5).fract();
+                Waveform::Triangle.value(phase, phase_increment)
+                    - 4.0 * phase_increment * polyblamp(phase, phase_increment)
+                    + 4.0 * phase_increment * polyblamp(trough_phase, phase_increment)
+            }
+            Waveform::Pulse(width) => {
+                if phase < width.clamp(0.0, 1.0) {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::BandlimitedPulse(width) => {
+                let width = width.clamp(0.0, 1.0);
+                if width == 0.0 || width == 1.0 {
+                    // No edges, only a constant level
+                    return Waveform::Pulse(width).value(phase, phase_increment);
+                }
+                let falling_phase = (phase - width + 1.0).fract();
+                Waveform::Pulse(width).value(phase, phase_increment)
+                    + polyblep(phase, phase_increment)
+                    - polyblep(falling_phase, phase_increment)
+            }
+        }
+    }
+
+    /// Returns the naive (aliasing) counterpart of a band-limited waveform, or the waveform itself.
+    ///
+    /// ```
+    /// use synthrs::oscillator::Waveform;
+    ///
+    /// assert_eq!(Waveform::BandlimitedPulse(0.25).naive(), Waveform::Pulse(0.25));
+    /// assert_eq!(Waveform::Sine.naive(), Waveform::Sine);
+    /// ```
+    pub fn naive(self) -> Waveform {
+        match self {
+            Waveform::BandlimitedSquare => Waveform::Square,
+            Waveform::BandlimitedSawtooth => Waveform::Sawtooth,
+            Waveform::BandlimitedTriangle => Waveform::Triangle,
+            Waveform::BandlimitedPulse(width) => Waveform::Pulse(width),
+            waveform => waveform,
+        }
+    }
+
+    pub fn is_bandlimited(self) -> bool {
+        self.naive() != self
+    }
+}
+
+/// A phase-accumulating source that can be retuned and restarted, such as an `Oscillator` or a
+/// `crate::wavetable::WavetableOscillator`. Used by wrappers such as `crate::unison::Unison`.
+pub trait Periodic {
+    /// Returns the current sample and advances by one sample
+    fn tick(&mut self) -> f64;
+    fn frequency(&self) -> f64;
+    fn set_frequency(&mut self, frequency: f64);
+    /// Jumps to `phase`, in cycles
+    fn set_phase(&mut self, phase: f64);
+}
+
+/// A phase-accumulating oscillator.
+#[derive(Clone, Debug)]
+pub struct Oscillator {
+    pub waveform: Waveform,
+    pub sample_rate: usize,
+    frequency: f64,
+    /// Position in the current cycle, in `[0, 1)`
+    phase: f64,
+}
+
+impl Oscillator {
+    /// Creates an oscillator starting at phase `0`.
+    pub fn new(waveform: Waveform, frequency: f64, sample_rate: usize) -> Oscillator {
+        Oscillator {
+            waveform,
+            sample_rate,
+            frequency,
+            phase: 0.0,
+        }
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Changes the frequency from the next sample on, without resetting the phase.
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+    }
+
+    pub fn phase(&self) -> f64 {
+        self.phase
+    }
+
+    /// Jumps to `phase`, wrapped into `[0, 1)`.
+    pub fn set_phase(&mut self, phase: f64) {
+        self.phase = phase - phase.floor();
+    }
+
+    /// Phase advanced per sample
+    pub fn phase_increment(&self) -> f64 {
+        self.frequency / self.sample_rate as f64
+    }
+
+    /// Returns the amplitude at the current phase, then advances the phase by one sample.
+    pub fn tick(&mut self) -> f64 {
+        let phase_increment = self.phase_increment();
+        let output = self.waveform.value(self.phase, phase_increment.abs());
+
+        self.phase += phase_increment;
+        self.phase -= self.phase.floor();
+        // A tiny negative phase wraps to exactly 1.0 when rounded
+        if self.phase >= 1.0 {
+            self.phase = 0.0;
+        }
+
+        output
+    }
+}
+
+impl Periodic for Oscillator {
+    fn tick(&mut self) -> f64 {
+        Oscillator::tick(self)
+    }
+
+    fn frequency(&self) -> f64 {
+        Oscillator::frequency(self)
+    }
+
+    fn set_frequency(&mut self, frequency: f64) {
+        Oscillator::set_frequency(self, frequency)
+    }
+
+    fn set_phase(&mut self, phase: f64) {
+        Oscillator::set_phase(self, phase)
+    }
+}
+
+impl Iterator for Oscillator {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        Some(self.tick())
+    }
+}
+
+/// How a `SyncedOscillator`'s master drives its slave.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sync {
+    /// The slave restarts its cycle every time the master completes one
+    Hard,
+    /// The slave reverses direction every time the master completes a cycle
+    Soft,
+}
+
+/// A slave oscillator synced to a master oscillator. Only the slave is heard: its timbre sweeps
+/// as its frequency changes while its pitch follows the master.
+///
+/// Hard sync resets of band-limited slave waveforms are themselves band-limited with PolyBLEP.
+///
+/// ```
+/// use synthrs::oscillator::{Oscillator, Sync, SyncedOscillator, Waveform};
+///
+/// let master = Oscillator::new(Waveform::Sine, 110.0, 44_100);
+/// let slave = Oscillator::new(Waveform::BandlimitedSawtooth, 110.0, 44_100);
+/// let mut synced = SyncedOscillator::new(master, slave, Sync::Hard);
+///
+/// // The classic sync sweep: the slave rises from one to four times the master's frequency
+/// let samples: Vec<f64> = (0..44_100)
+///     .map(|i| {
+///         synced.slave.set_frequency(110.0 * (1.0 + 3.0 * i as f64 / 44_100.0));
+///         synced.tick()
+///     })
+///     .collect();
+/// ```
+#[derive(Clone, Debug)]
+pub struct SyncedOscillator {
+    pub master: Oscillator,
+    pub slave: Oscillator,
+    pub sync: Sync,
+    /// `1.0` or `-1.0`, reversed by soft sync
+    direction: f64,
+    /// Band-limiting correction for the sample after a hard sync reset
+    correction: f64,
+    /// Whether the slave was reset just before the current sample
+    just_reset: bool,
+}
+
+impl SyncedOscillator {
+    pub fn new(master: Oscillator, slave: Oscillator, sync: Sync) -> SyncedOscillator {
+        SyncedOscillator {
+            master,
+            slave,
+            sync,
+            direction: 1.0,
+            correction: 0.0,
+            just_reset: false,
+        }
+    }
+
+    /// Returns the slave's amplitude at the current phase, then advances both oscillators.
+    pub fn tick(&mut self) -> f64 {
+        let waveform = self.slave.waveform;
+        let slave_increment = self.slave.phase_increment() * self.direction;
+
+        // Right after a reset the slave's phase is near 0 without having wrapped naturally, so
+        // the band-limited waveform's own correction would be wrong
+        let mut output = if self.just_reset {
+            waveform
+                .naive()
+                .value(self.slave.phase, slave_increment.abs())
+        } else {
+            waveform.value(self.slave.phase, slave_increment.abs())
+        } + self.correction;
+        self.correction = 0.0;
+        self.just_reset = false;
+
+        let master_increment = self.master.phase_increment();
+        let master_next = self.master.phase + master_increment;
+        self.master.tick();
+
+        let mut slave_next = self.slave.phase + slave_increment;
+
+        if master_increment != 0.0 && !(0.0..1.0).contains(&master_next) {
+            // Fraction of a sample between the master's wrap and the next sample
+            let wrapped = if master_next >= 1.0 {
+                master_next - 1.0
+            } else {
+                master_next
+            };
+            let after = (wrapped / master_increment).clamp(0.0, 1.0);
+
+            match self.sync {
+                Sync::Hard => {
+                    let at_reset = self.slave.phase + slave_increment * (1.0 - after);
+                    slave_next = slave_increment * after;
+
+                    if waveform.is_bandlimited() {
+                        let naive = waveform.naive();
+                        let at_reset = at_reset - at_reset.floor();
+                        let step = naive.value(0.0, 0.0) - naive.value(at_reset, 0.0);
+
+                        // PolyBLEP residuals on either side of the reset
+                        output += step / 2.0 * after * after;
+                        self.correction = step / 2.0 * (2.0 * after - after * after - 1.0);
+                        self.just_reset = true;
+                    }
+                }
+                Sync::Soft => {
+                    // Run forwards until the wrap, then backwards for the rest of the sample
+                    self.direction = -self.direction;
+                    slave_next = self.slave.phase + slave_increment * (1.0 - 2.0 * after);
+                }
+            }
+        }
+
+        self.slave.set_phase(slave_next);
+        if self.slave.phase >= 1.0 {
+            self.slave.phase = 0.0;
+        }
+
+        output
+    }
+}
+
+impl Iterator for SyncedOscillator {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        Some(self.tick())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wave::{sawtooth_wave, sine_wave, triangle_wave};
+
+    #[test]
+    fn test_matches_wave_generators() {
+        let mut sine = Oscillator::new(Waveform::Sine, 440.0, 44_100);
+        let mut sawtooth = Oscillator::new(Waveform::Sawtooth, 440.0, 44_100);
+        let mut triangle = Oscillator::new(Waveform::Triangle, 440.0, 44_100);
+
+        for i in 0..1000 {
+            let t = i as f64 / 44_100.0;
+            assert!((sine.tick() - sine_wave(440.0)(t)).abs() < 1e-9);
+            assert!((sawtooth.tick() - sawtooth_wave(440.0)(t)).abs() < 1e-9);
+            assert!((triangle.tick() - triangle_wave(440.0)(t)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_glide_is_continuous() {
+        let mut oscillator = Oscillator::new(Waveform::Sine, 100.0, 44_100);
+        let mut previous = oscillator.tick();
+
+        for i in 0..44_100 {
+            let frequency = 100.0 + 900.0 * i as f64 / 44_100.0;
+            oscillator.set_frequency(frequency);
+            let sample = oscillator.tick();
+
+            // A sine cannot move more than `2 * PI * frequency / sample_rate` in one sample
+            assert!((sample - previous).abs() <= 2.0 * PI * frequency / 44_100.0 + 1e-9);
+            previous = sample;
+        }
+    }
+
+    #[test]
+    fn test_pulse_width() {
+        for &width in &[0.1, 0.25, 0.5, 0.9] {
+            for &waveform in &[Waveform::Pulse(width), Waveform::BandlimitedPulse(width)] {
+                let samples: Vec<f64> =
+                    Oscillator::new(waveform, 100.0, 44_100).take(441).collect();
+                let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+                assert!((mean - (2.0 * width - 1.0)).abs() < 0.01);
+            }
+        }
+
+        assert!(
+            Oscillator::new(Waveform::BandlimitedPulse(0.0), 100.0, 44_100)
+                .take(441)
+                .all(|sample| sample == -1.0)
+        );
+    }
+
+    #[test]
+    fn test_hard_sync_follows_master_period() {
+        // 480 samples per master cycle, wrapping halfway between two samples
+        let mut master = Oscillator::new(Waveform::Sine, 100.0, 48_000);
+        master.set_phase(0.5 / 480.0);
+        let slave = Oscillator::new(Waveform::Sawtooth, 370.0, 48_000);
+        let samples: Vec<f64> = SyncedOscillator::new(master, slave, Sync::Hard)
+            .take(4800)
+            .collect();
+
+        // The first cycle starts before the first reset, so compare from the second one
+        for i in 960..4800 {
+            assert!((samples[i] - samples[i - 480]).abs() < 1e-6);
+        }
+        // The slave restarts at the start of every master cycle
+        assert!((samples[480] - (-0.5 + 0.5 * 370.0 / 48_000.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_bandlimited_hard_sync_aliases_less() {
+        use crate::analysis::aliasing_ratio;
+
+        // Ratio of power outside the master's harmonics
+        let aliasing = |waveform: Waveform| -> f64 {
+            let bin = 37;
+            let master = Oscillator::new(Waveform::Sine, bin as f64 * 44_100.0 / 8192.0, 44_100);
+            let slave = Oscillator::new(waveform, 1_234.5, 44_100);
+            let samples: Vec<f64> = SyncedOscillator::new(master, slave, Sync::Hard)
+                .take(8192)
+                .collect();
+            aliasing_ratio(&samples, bin)
+        };
+
+        assert!(aliasing(Waveform::BandlimitedSawtooth) < aliasing(Waveform::Sawtooth) / 10.0);
+    }
+
+    #[test]
+    fn test_soft_sync_is_continuous() {
+        let master = Oscillator::new(Waveform::Sine, 100.0, 44_100);
+        let slave = Oscillator::new(Waveform::Sine, 250.0, 44_100);
+        let samples: Vec<f64> = SyncedOscillator::new(master, slave, Sync::Soft)
+            .take(4410)
+            .collect();
+        let free: Vec<f64> = Oscillator::new(Waveform::Sine, 250.0, 44_100)
+            .take(4410)
+            .collect();
+
+        assert!(samples
+            .windows(2)
+            .all(|pair| (pair[1] - pair[0]).abs() <= 2.0 * PI * 250.0 / 44_100.0 + 1e-9));
+        assert!(samples
+            .iter()
+            .zip(free.iter())
+            .any(|(a, b)| (a - b).abs() > 0.5));
+    }
+
+    #[test]
+    fn test_phase_stays_wrapped() {
+        let mut oscillator = Oscillator::new(Waveform::Sawtooth, 7_919.0, 44_100);
+        for _ in 0..1_000_000 {
+            oscillator.tick();
+        }
+        assert!(oscillator.phase() >= 0.0 && oscillator.phase() < 1.0);
+
+        // Negative frequencies run the cycle backwards
+        oscillator.set_frequency(-440.0);
+        oscillator.set_phase(0.0);
+        oscillator.tick();
+        assert!((oscillator.phase() - (1.0 - 440.0 / 44_100.0)).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,649 @@
+//! Real-time synthesis for audio callbacks.
+//!
+//! A `Processor` fills blocks of interleaved `f32` samples, as requested by an audio device
+//! callback. Processors must not allocate, lock or block inside `process`, so that they can run
+//! on the audio thread.
+//!
+//! Notes and controller changes are sent from other threads through a lock-free, fixed-capacity,
+//! single-producer single-consumer queue created with `event_queue`. Each event carries the
+//! frame (sample) time at which it should take effect, and `PolySynth` applies it at that exact
+//! frame within the block being processed.
+//!
+//! ```
+//! use synthrs::realtime::{event_queue, Event, PolySynth, Processor};
+//! use synthrs::wave::sine_wave;
+//!
+//! let (mut sender, receiver) = event_queue(256);
+//! let mut synth = PolySynth::new(sine_wave, receiver, 16);
+//!
+//! // Control thread: play A4 from frame 64 until frame 512
+//! sender.send(Event::note_on(64, 0, 69, 100)).unwrap();
+//! sender.send(Event::note_off(512, 0, 69)).unwrap();
+//!
+//! // Audio thread: the device callback asks for blocks of stereo samples
+//! let mut block = [0.0f32; 2 * 128];
+//! for _ in 0..8 {
+//!     synth.process(&mut block, 2, 44_100);
+//! }
+//! ```
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::midi::{CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF, CC_PAN, CC_VOLUME};
+use crate::music;
+use crate::synthesizer::pan_gains;
+
+/// Something that can render audio into a device buffer on the audio thread.
+pub trait Processor {
+    /// Fills `out` with interleaved samples for `channels` channels at `sample_rate`.
+    /// `out.len()` is a multiple of `channels`. Implementations must not allocate or block.
+    fn process(&mut self, out: &mut [f32], channels: usize, sample_rate: usize);
+}
+
+/// The action of an `Event`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+}
+
+/// A timestamped note or controller event. `time` is the absolute frame at which the event takes
+/// effect, counted from the first frame the processor rendered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Event {
+    pub time: u64,
+    pub kind: EventKind,
+}
+
+impl Event {
+    pub fn note_on(time: u64, channel: u8, note: u8, velocity: u8) -> Event {
+        Event {
+            time,
+            kind: EventKind::NoteOn {
+                channel,
+                note,
+                velocity,
+            },
+        }
+    }
+
+    pub fn note_off(time: u64, channel: u8, note: u8) -> Event {
+        Event {
+            time,
+            kind: EventKind::NoteOff { channel, note },
+        }
+    }
+
+    pub fn control_change(time: u64, channel: u8, controller: u8, value: u8) -> Event {
+        Event {
+            time,
+            kind: EventKind::ControlChange {
+                channel,
+                controller,
+                value,
+            },
+        }
+    }
+}
+
+struct EventQueue {
+    buf: Box<[UnsafeCell<Event>]>,
+    /// Next slot to read, only written by the receiver
+    head: AtomicUsize,
+    /// Next slot to write, only written by the sender
+    tail: AtomicUsize,
+}
+
+// Slots are only written by the sender before publishing them with `tail`, and only read by the
+// receiver after observing `tail`; `head` hands them back. There is exactly one of each side.
+unsafe impl Sync for EventQueue {}
+
+/// The sending half of an event queue, used from a control (non-audio) thread.
+pub struct EventSender {
+    queue: Arc<EventQueue>,
+}
+
+/// The receiving half of an event queue, owned by a processor on the audio thread.
+pub struct EventReceiver {
+    queue: Arc<EventQueue>,
+}
+
+/// Creates a lock-free single-producer single-consumer event queue holding up to `capacity`
+/// events. All memory is allocated up front.
+pub fn event_queue(capacity: usize) -> (EventSender, EventReceiver) {
+    let empty = Event::note_off(0, 0, 0);
+    let queue = Arc::new(EventQueue {
+        // One slot is kept free to tell a full queue from an empty one
+        buf: (0..=capacity).map(|_| UnsafeCell::new(empty)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+
+    (
+        EventSender {
+            queue: queue.clone(),
+        },
+        EventReceiver { queue },
+    )
+}
+
+impl EventSender {
+    /// Queues an event. Events should be sent in time order. Returns the event back if the queue
+    /// is full.
+    pub fn send(&mut self, event: Event) -> Result<(), Event> {
+        let queue = &self.queue;
+        let tail = queue.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % queue.buf.len();
+
+        if next == queue.head.load(Ordering::Acquire) {
+            return Err(event);
+        }
+
+        unsafe {
+            *queue.buf[tail].get() = event;
+        }
+        queue.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl EventReceiver {
+    /// Returns the next event without removing it from the queue.
+    pub fn peek(&self) -> Option<Event> {
+        let queue = &self.queue;
+        let head = queue.head.load(Ordering::Relaxed);
+
+        if head == queue.tail.load(Ordering::Acquire) {
+            None
+        } else {
+            Some(unsafe { *queue.buf[head].get() })
+        }
+    }
+
+    /// Removes and returns the next event.
+    pub fn recv(&mut self) -> Option<Event> {
+        let event = self.peek()?;
+        let queue = &self.queue;
+        let head = queue.head.load(Ordering::Relaxed);
+        queue
+            .head
+            .store((head + 1) % queue.buf.len(), Ordering::Release);
+        Some(event)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Voice {
+    active: bool,
+    channel: u8,
+    note: u8,
+    gain: f64,
+    /// Seconds since note on
+    t: f64,
+    /// Seconds since note off, if released
+    released: Option<f64>,
+    /// Envelope level at note off, released voices fade out from here
+    release_level: f64,
+    /// Frame of note on, used to steal the oldest voice
+    started: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ChannelState {
+    volume: f64,
+    pan: f64,
+}
+
+/// A polyphonic synthesizer driven by a queue of timestamped events. Each voice plays
+/// `instrument(frequency)` as a generator of time since note on, with a short attack and a
+/// linear release to avoid clicks. `instrument` is called once per note on, inside `process`,
+/// and the generator it returns is kept for the whole note, so stateful generators work. For
+/// `process` to stay allocation-free, `instrument` must not allocate either. Handles CC7 (volume), CC10 (pan) and CC123 (all notes off).
+///
+/// Voices are allocated up front; when all are playing, the oldest voice is stolen.
+pub struct PolySynth<F1, F2>
+where
+    F1: Fn(f64) -> F2,
+    F2: Fn(f64) -> f64,
+{
+    instrument: F1,
+    events: EventReceiver,
+    voices: Vec<Voice>,
+    /// The generator playing each voice, created at note on
+    generators: Vec<Option<F2>>,
+    channels: [ChannelState; 16],
+    /// Absolute frame of the next sample to be rendered
+    frame: u64,
+    /// Attack time in seconds
+    pub attack: f64,
+    /// Release time in seconds
+    pub release: f64,
+    /// Linear output gain
+    pub gain: f64,
+}
+
+impl<F1, F2> PolySynth<F1, F2>
+where
+    F1: Fn(f64) -> F2,
+    F2: Fn(f64) -> f64,
+{
+    /// Creates a synthesizer playing at most `max_voices` notes at once.
+    pub fn new(instrument: F1, events: EventReceiver, max_voices: usize) -> PolySynth<F1, F2> {
+        let idle = Voice {
+            active: false,
+            channel: 0,
+            note: 0,
+            gain: 0.0,
+            t: 0.0,
+            released: None,
+            release_level: 0.0,
+            started: 0,
+        };
+
+        // General MIDI default channel volume of 100
+        let volume = 100.0 / 127.0;
+
+        PolySynth {
+            instrument,
+            events,
+            voices: vec![idle; max_voices.max(1)],
+            generators: (0..max_voices.max(1)).map(|_| None).collect(),
+            channels: [ChannelState {
+                volume: volume * volume,
+                pan: 0.0,
+            }; 16],
+            frame: 0,
+            attack: 0.005,
+            release: 0.05,
+            gain: 0.25,
+        }
+    }
+
+    /// Absolute frame of the next sample to be rendered.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Number of voices currently sounding.
+    pub fn active_voices(&self) -> usize {
+        self.voices.iter().filter(|voice| voice.active).count()
+    }
+
+    fn handle(&mut self, kind: EventKind) {
+        // Only the low nibble of a MIDI status byte is the channel
+        let kind = match kind {
+            EventKind::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => EventKind::NoteOn {
+                channel: channel & 0x0f,
+                note,
+                velocity,
+            },
+            EventKind::NoteOff { channel, note } => EventKind::NoteOff {
+                channel: channel & 0x0f,
+                note,
+            },
+            EventKind::ControlChange {
+                channel,
+                controller,
+                value,
+            } => EventKind::ControlChange {
+                channel: channel & 0x0f,
+                controller,
+                value,
+            },
+        };
+
+        match kind {
+            EventKind::NoteOn {
+                channel,
+                note,
+                velocity: 0,
+            }
+            | EventKind::NoteOff { channel, note } => {
+                let envelope = (self.attack, self.release);
+                for voice in &mut self.voices {
+                    if voice.active
+                        && voice.released.is_none()
+                        && voice.channel == channel
+                        && voice.note == note
+                    {
+                        voice.release_level = envelope_level(voice, envelope);
+                        voice.released = Some(0.0);
+                    }
+                }
+            }
+
+            EventKind::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => {
+                let index = match self.voices.iter().position(|voice| !voice.active) {
+                    Some(index) => index,
+                    None => self
+                        .voices
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, voice)| voice.started)
+                        .map_or(0, |(index, _)| index),
+                };
+
+                let frequency = music::note_midi(440.0, note as usize);
+                self.generators[index] = Some((self.instrument)(frequency));
+                self.voices[index] = Voice {
+                    active: true,
+                    channel,
+                    note,
+                    gain: f64::from(velocity) / 127.0,
+                    t: 0.0,
+                    released: None,
+                    release_level: 0.0,
+                    started: self.frame,
+                };
+            }
+
+            EventKind::ControlChange {
+                channel,
+                controller,
+                value,
+            } => {
+                let state = &mut self.channels[channel as usize];
+                match controller as usize {
+                    CC_VOLUME => {
+                        let volume = f64::from(value.min(127)) / 127.0;
+                        state.volume = volume * volume;
+                    }
+                    CC_PAN => state.pan = ((f64::from(value) - 64.0) / 63.0).clamp(-1.0, 1.0),
+                    CC_ALL_SOUND_OFF => {
+                        for voice in &mut self.voices {
+                            if voice.channel == channel {
+                                voice.active = false;
+                            }
+                        }
+                    }
+                    CC_ALL_NOTES_OFF => {
+                        let envelope = (self.attack, self.release);
+                        for voice in &mut self.voices {
+                            if voice.active && voice.released.is_none() && voice.channel == channel
+                            {
+                                voice.release_level = envelope_level(voice, envelope);
+                                voice.released = Some(0.0);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Renders one frame into `out`
+    fn render_frame(&mut self, out: &mut [f32], sample_rate: usize) {
+        let dt = 1.0 / sample_rate as f64;
+        let envelope = (self.attack, self.release);
+        let (mut left, mut right) = (0.0, 0.0);
+
+        for (voice, generator) in self.voices.iter_mut().zip(self.generators.iter()) {
+            let generator = match generator {
+                Some(generator) if voice.active => generator,
+                _ => continue,
+            };
+
+            let level = envelope_level(voice, envelope);
+            let state = self.channels[voice.channel as usize];
+            let sample = generator(voice.t) * level * voice.gain * state.volume;
+            let (pan_left, pan_right) = pan_gains(state.pan);
+            left += sample * pan_left;
+            right += sample * pan_right;
+
+            voice.t += dt;
+            if let Some(released) = voice.released {
+                voice.released = Some(released + dt);
+                if released >= envelope.1 {
+                    voice.active = false;
+                }
+            }
+        }
+
+        left *= self.gain;
+        right *= self.gain;
+
+        match out.len() {
+            0 => {}
+            // Centered sources should have the same level in mono as on each stereo side
+            1 => out[0] = ((left + right) * std::f64::consts::FRAC_1_SQRT_2) as f32,
+            _ => {
+                out[0] = left as f32;
+                out[1] = right as f32;
+                for sample in &mut out[2..] {
+                    *sample = 0.0;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the current attack/release envelope level of a voice
+fn envelope_level(voice: &Voice, (attack, release): (f64, f64)) -> f64 {
+    match voice.released {
+        Some(released) => voice.release_level * (1.0 - released / release).max(0.0),
+        None if voice.t < attack => voice.t / attack,
+        None => 1.0,
+    }
+}
+
+impl<F1, F2> Processor for PolySynth<F1, F2>
+where
+    F1: Fn(f64) -> F2,
+    F2: Fn(f64) -> f64,
+{
+    fn process(&mut self, out: &mut [f32], channels: usize, sample_rate: usize) {
+        let channels = channels.max(1);
+
+        for frame in out.chunks_mut(channels) {
+            // Apply every event due at or before this frame. Late events are applied right away.
+            while let Some(event) = self.events.peek() {
+                if event.time > self.frame {
+                    break;
+                }
+                self.events.recv();
+                self.handle(event.kind);
+            }
+
+            self.render_frame(frame, sample_rate);
+            self.frame += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wave::sine_wave;
+    use std::cell::Cell;
+
+    /// Runs a fake audio host loop, returning the interleaved output of `blocks` blocks
+    fn run_host<P: Processor>(processor: &mut P, blocks: usize, block_frames: usize) -> Vec<f32> {
+        let mut block = vec![0.0f32; block_frames * 2];
+        let mut output = Vec::new();
+        for _ in 0..blocks {
+            processor.process(&mut block, 2, 44_100);
+            output.extend_from_slice(&block);
+        }
+        output
+    }
+
+    #[test]
+    fn test_event_queue() {
+        let (mut sender, mut receiver) = event_queue(2);
+        assert_eq!(receiver.recv(), None);
+
+        sender.send(Event::note_on(1, 0, 60, 100)).unwrap();
+        sender.send(Event::note_off(2, 0, 60)).unwrap();
+        assert_eq!(
+            sender.send(Event::note_on(3, 0, 60, 100)),
+            Err(Event::note_on(3, 0, 60, 100))
+        );
+
+        assert_eq!(receiver.peek(), Some(Event::note_on(1, 0, 60, 100)));
+        assert_eq!(receiver.recv(), Some(Event::note_on(1, 0, 60, 100)));
+        assert_eq!(receiver.recv(), Some(Event::note_off(2, 0, 60)));
+        assert_eq!(receiver.recv(), None);
+    }
+
+    #[test]
+    fn test_event_queue_across_threads() {
+        let (mut sender, mut receiver) = event_queue(16);
+
+        let producer = std::thread::spawn(move || {
+            for time in 0..1000 {
+                let mut event = Event::note_on(time, 0, 60, 100);
+                while let Err(rejected) = sender.send(event) {
+                    event = rejected;
+                    std::thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < 1000 {
+            if let Some(event) = receiver.recv() {
+                assert_eq!(event.time, expected);
+                expected += 1;
+            }
+        }
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn test_sample_accurate_events() {
+        let (mut sender, receiver) = event_queue(16);
+        let mut synth = PolySynth::new(sine_wave, receiver, 4);
+        sender.send(Event::note_on(100, 0, 69, 127)).unwrap();
+        sender.send(Event::note_off(300, 0, 69)).unwrap();
+
+        let output = run_host(&mut synth, 8, 64);
+        let left: Vec<f32> = output.iter().step_by(2).copied().collect();
+
+        assert!(left[..=100].iter().all(|&sample| sample == 0.0));
+        assert!(left[101..300].iter().any(|&sample| sample != 0.0));
+
+        // Released voices fade out and are freed
+        let release_frames = (0.05 * 44_100.0) as usize;
+        let output = run_host(&mut synth, release_frames / 64 + 1, 64);
+        assert!(output[output.len() - 64..].iter().all(|&s| s == 0.0));
+        assert_eq!(synth.active_voices(), 0);
+    }
+
+    #[test]
+    fn test_pan_and_voice_stealing() {
+        let (mut sender, receiver) = event_queue(16);
+        let mut synth = PolySynth::new(sine_wave, receiver, 2);
+        sender.send(Event::control_change(0, 0, 10, 0)).unwrap();
+        sender.send(Event::note_on(0, 0, 60, 100)).unwrap();
+        sender.send(Event::note_on(10, 0, 64, 100)).unwrap();
+        sender.send(Event::note_on(20, 0, 67, 100)).unwrap();
+
+        let output = run_host(&mut synth, 4, 64);
+        assert_eq!(synth.active_voices(), 2);
+
+        // Hard left
+        assert!(output.iter().step_by(2).any(|&sample| sample != 0.0));
+        assert!(output
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .all(|&sample| sample.abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_channels_are_masked() {
+        let (mut sender, receiver) = event_queue(16);
+        let mut synth = PolySynth::new(sine_wave, receiver, 4);
+
+        // Channel 17 is channel 1, whether the note is released on 17 or 1
+        sender.send(Event::note_on(0, 17, 60, 100)).unwrap();
+        sender.send(Event::note_on(0, 17, 64, 100)).unwrap();
+        sender.send(Event::note_on(0, 1, 67, 100)).unwrap();
+        sender.send(Event::note_off(10, 17, 60)).unwrap();
+        sender.send(Event::note_off(10, 1, 64)).unwrap();
+        sender.send(Event::control_change(10, 17, 123, 0)).unwrap();
+
+        run_host(&mut synth, 1, 64);
+        let release_frames = (0.05 * 44_100.0) as usize;
+        run_host(&mut synth, release_frames / 64 + 1, 64);
+        assert_eq!(synth.active_voices(), 0);
+    }
+
+    #[test]
+    fn test_generators_are_created_once_per_note() {
+        let (mut sender, receiver) = event_queue(16);
+        let created = Cell::new(0);
+        let instrument = |_frequency: f64| {
+            created.set(created.get() + 1);
+
+            // A stateful generator counting its own calls
+            let calls = Cell::new(0.0);
+            move |_t: f64| {
+                calls.set(calls.get() + 1.0);
+                calls.get()
+            }
+        };
+        let mut synth = PolySynth::new(instrument, receiver, 4);
+        synth.attack = 0.0;
+        synth.gain = 1.0;
+        sender.send(Event::note_on(0, 0, 60, 127)).unwrap();
+        sender.send(Event::note_on(0, 1, 64, 127)).unwrap();
+
+        let output = run_host(&mut synth, 4, 64);
+        let left: Vec<f32> = output.iter().step_by(2).copied().collect();
+        assert_eq!(created.get(), 2);
+        assert!(left.windows(2).all(|pair| pair[1] > pair[0]));
+    }
+
+    #[test]
+    fn test_all_notes_off_releases_and_all_sound_off_cuts() {
+        let (mut sender, receiver) = event_queue(16);
+        let mut synth = PolySynth::new(|_frequency: f64| |_t: f64| 1.0, receiver, 4);
+        synth.attack = 0.0;
+        sender.send(Event::note_on(0, 0, 60, 127)).unwrap();
+        sender.send(Event::control_change(64, 0, 123, 0)).unwrap();
+        sender.send(Event::note_on(64, 1, 60, 127)).unwrap();
+        sender.send(Event::control_change(128, 1, 120, 0)).unwrap();
+
+        let release_frames = (synth.release * 44_100.0) as usize;
+        let output = run_host(&mut synth, (release_frames + 64) / 64 + 2, 64);
+        let left: Vec<f32> = output.iter().step_by(2).copied().collect();
+        let full = left[63];
+
+        // Channel 1 plays along until it is cut at frame 128
+        assert!((left[64] - 2.0 * full).abs() < 1e-3);
+        assert!(left[128] < full);
+
+        // Channel 0 fades out linearly over the release
+        let halfway = left[64 + release_frames / 2];
+        assert!((halfway - full / 2.0).abs() < 0.01 * full);
+        assert!(left[65..64 + release_frames]
+            .windows(2)
+            .all(|pair| pair[1] < pair[0]));
+        assert_eq!(left[64 + release_frames + 1], 0.0);
+        assert_eq!(synth.active_voices(), 0);
+    }
+}
@@ -0,0 +1,448 @@
+//! Sample-rate conversion.
+//!
+//! Samples recorded at one rate must be converted before they are mixed into a render at
+//! another: a 48kHz sample played as if it were 44.1kHz plays flat and slow. The converter is a
+//! polyphase windowed-sinc (Kaiser window) low-pass filter, sampled at the fractional input
+//! positions of every output sample. The filter cuts off below the lower of the two Nyquist
+//! frequencies, so downsampling does not alias and upsampling does not image.
+//!
+//! Rates are integers, so every conversion is a rational ratio `up / down` (160 / 147 from
+//! 44.1kHz to 48kHz). When `up` is small enough, the filter is tabulated at every one of its
+//! phases and conversion is exact; otherwise the closest tabulated phases are interpolated.
+//!
+//! ```
+//! use synthrs::resample::{resample, Quality};
+//! use synthrs::sample::samples_from_wave_file;
+//!
+//! // Convert a 44.1kHz recording for a 48kHz render
+//! let (samples, _) = samples_from_wave_file("tests/assets/sine.wav").unwrap();
+//! let converted = resample(&samples, 44_100, 48_000, Quality::High);
+//! assert_eq!(converted.len(), 96_000);
+//! ```
+
+/// Most phases tabulated, after which neighbouring phases are interpolated
+const MAX_PHASES: usize = 1024;
+
+/// Trade-off between conversion quality and speed. Every preset keeps aliasing below its
+/// stopband attenuation; better presets use longer filters for a flat passband closer to the
+/// Nyquist frequency and deeper attenuation.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Quality {
+    /// 60dB attenuation, passband to 77% of Nyquist
+    Fast,
+    /// 90dB attenuation, passband to 82% of Nyquist
+    #[default]
+    Medium,
+    /// 120dB attenuation, passband to 88% of Nyquist
+    High,
+    /// 140dB attenuation, passband to 93% of Nyquist
+    Best,
+}
+
+impl Quality {
+    /// Zero crossings of the sinc on each side, at the lower of the two rates
+    fn zero_crossings(self) -> usize {
+        match self {
+            Quality::Fast => 16,
+            Quality::Medium => 32,
+            Quality::High => 64,
+            Quality::Best => 128,
+        }
+    }
+
+    /// Stopband attenuation, in dB
+    pub fn attenuation(self) -> f64 {
+        match self {
+            Quality::Fast => 60.0,
+            Quality::Medium => 90.0,
+            Quality::High => 120.0,
+            Quality::Best => 140.0,
+        }
+    }
+
+    /// Width of the transition band from passband to stopband, as a fraction of the Nyquist
+    /// frequency (from Kaiser's filter length estimate)
+    fn transition(self) -> f64 {
+        let taps = 2.0 * self.zero_crossings() as f64;
+        (self.attenuation() - 7.95) / (2.285 * taps * std::f64::consts::PI)
+    }
+
+    /// End of the passband, as a fraction of the lower Nyquist frequency. The stopband starts at
+    /// the Nyquist frequency.
+    pub fn passband(self) -> f64 {
+        1.0 - self.transition()
+    }
+
+    /// Kaiser window shape parameter for the attenuation
+    fn beta(self) -> f64 {
+        0.1102 * (self.attenuation() - 8.7)
+    }
+}
+
+/// Zeroth-order modified Bessel function of the first kind
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    let mut k = 1.0;
+
+    while term > sum * 1e-21 {
+        term *= (half / k) * (half / k);
+        sum += term;
+        k += 1.0;
+    }
+
+    sum
+}
+
+/// Normalized sinc, `sin(pi x) / (pi x)`
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-12 {
+        1.0
+    } else {
+        (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+    }
+}
+
+fn greatest_common_divisor(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        greatest_common_divisor(b, a % b)
+    }
+}
+
+/// A streaming sample-rate converter. Input can be given in blocks of any size, and output is
+/// produced as soon as enough input has arrived for it. Call `flush` at the end of the stream
+/// for the remaining output.
+///
+/// ```
+/// use synthrs::resample::{Quality, Resampler};
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::wave::sine_wave;
+///
+/// let input = make_samples(1.0, 48_000, sine_wave(440.0));
+///
+/// let mut resampler = Resampler::new(48_000, 44_100, Quality::Medium);
+/// let mut output = Vec::new();
+/// for block in input.chunks(512) {
+///     output.extend(resampler.process(block));
+/// }
+/// output.extend(resampler.flush());
+/// assert_eq!(output.len(), 44_100);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Resampler {
+    /// Output samples per `down` input samples
+    up: u64,
+    down: u64,
+    /// Phases in `table`, not counting the extra last row
+    phases: usize,
+    /// Filter taps on each side of an output sample
+    taps: usize,
+    /// Filter taps at every phase, `2 * taps` per phase, with an extra row for phase `phases`
+    /// (the next input sample) to interpolate towards
+    table: Vec<f64>,
+    /// Input still needed, starting at input sample `buffer_start`
+    buffer: Vec<f64>,
+    buffer_start: u64,
+    /// Input samples given so far
+    input_length: u64,
+    /// Output samples produced so far
+    output_length: u64,
+}
+
+impl Resampler {
+    /// Creates a converter from sample rate `from` to `to`.
+    pub fn new(from: usize, to: usize, quality: Quality) -> Resampler {
+        assert!(from > 0 && to > 0, "sample rates must be positive");
+        let divisor = greatest_common_divisor(from as u64, to as u64);
+        let (up, down) = (to as u64 / divisor, from as u64 / divisor);
+
+        // The filter runs at the input rate, so when downsampling it is narrowed in frequency
+        // (and widened in time) to cut off below the output's Nyquist frequency
+        let scale = (up as f64 / down as f64).min(1.0);
+        let cutoff = scale * (1.0 - quality.transition() / 2.0);
+        let half_width = quality.zero_crossings() as f64 / scale;
+        let taps = half_width.ceil() as usize;
+        let phases = (up as usize).min(MAX_PHASES);
+
+        let (beta, i0_beta) = (quality.beta(), bessel_i0(quality.beta()));
+        let kaiser = |x: f64| {
+            let u = x / half_width;
+            if u.abs() >= 1.0 {
+                0.0
+            } else {
+                bessel_i0(beta * (1.0 - u * u).sqrt()) / i0_beta
+            }
+        };
+
+        // Row `phase`, tap `j` weighs input sample `j - taps + 1` from the sample before the
+        // output, whose distance from the output is `phase / phases - (j - taps + 1)`
+        let mut table = Vec::with_capacity((phases + 1) * 2 * taps);
+        for phase in 0..=phases {
+            let fraction = phase as f64 / phases as f64;
+            for j in 0..2 * taps {
+                let x = fraction - (j as f64 - taps as f64 + 1.0);
+                table.push(cutoff * sinc(cutoff * x) * kaiser(x));
+            }
+        }
+
+        Resampler {
+            up,
+            down,
+            phases,
+            taps,
+            table,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            input_length: 0,
+            output_length: 0,
+        }
+    }
+
+    /// Input samples of latency: output samples wait for this much later input.
+    pub fn latency(&self) -> usize {
+        self.taps
+    }
+
+    fn input(&self, index: i64) -> f64 {
+        if index < self.buffer_start as i64 {
+            0.0
+        } else {
+            self.buffer
+                .get((index - self.buffer_start as i64) as usize)
+                .cloned()
+                .unwrap_or(0.0)
+        }
+    }
+
+    /// Computes output sample `n`, treating input that has not arrived as silence
+    fn output(&self, n: u64) -> f64 {
+        if self.up == self.down {
+            return self.input(n as i64);
+        }
+
+        let position = n * self.down;
+        let index = (position / self.up) as i64;
+        let remainder = position % self.up;
+
+        let phase = remainder as f64 * self.phases as f64 / self.up as f64;
+        let row = phase.floor() as usize;
+        let fraction = phase - row as f64;
+
+        let width = 2 * self.taps;
+        let first = index - self.taps as i64 + 1;
+        let current = &self.table[row * width..(row + 1) * width];
+
+        if fraction == 0.0 {
+            (0..width)
+                .map(|j| self.input(first + j as i64) * current[j])
+                .sum()
+        } else {
+            let next = &self.table[(row + 1) * width..(row + 2) * width];
+            (0..width)
+                .map(|j| {
+                    let tap = current[j] + fraction * (next[j] - current[j]);
+                    self.input(first + j as i64) * tap
+                })
+                .sum()
+        }
+    }
+
+    /// Input sample before output sample `n`
+    fn input_index(&self, n: u64) -> u64 {
+        n * self.down / self.up
+    }
+
+    /// Converts the next block of input, returning the output it completes.
+    pub fn process(&mut self, input: &[f64]) -> Vec<f64> {
+        self.buffer.extend_from_slice(input);
+        self.input_length += input.len() as u64;
+
+        let mut output = Vec::new();
+        // Output needs input up to `taps` samples after its position
+        while self.input_index(self.output_length) + (self.taps as u64) < self.input_length {
+            output.push(self.output(self.output_length));
+            self.output_length += 1;
+        }
+
+        // Drop input that no later output needs
+        let needed = (self.input_index(self.output_length) + 1).saturating_sub(self.taps as u64);
+        if needed > self.buffer_start {
+            let drop = ((needed - self.buffer_start) as usize).min(self.buffer.len());
+            self.buffer.drain(..drop);
+            self.buffer_start += drop as u64;
+        }
+
+        output
+    }
+
+    /// Ends the stream, returning the remaining output (as if the input were followed by
+    /// silence). In all, the stream's output is `input * to / from` samples long, rounded up.
+    /// The converter can then be used for a new stream.
+    pub fn flush(&mut self) -> Vec<f64> {
+        let total = (self.input_length * self.up).div_ceil(self.down);
+        let output = (self.output_length..total)
+            .map(|n| self.output(n))
+            .collect();
+
+        self.buffer.clear();
+        self.buffer_start = 0;
+        self.input_length = 0;
+        self.output_length = 0;
+        output
+    }
+}
+
+/// Converts `samples` from sample rate `from` to `to`. The output is `samples.len() * to / from`
+/// samples long, rounded up.
+pub fn resample(samples: &[f64], from: usize, to: usize, quality: Quality) -> Vec<f64> {
+    let mut resampler = Resampler::new(from, to, quality);
+    let mut output = resampler.process(samples);
+    output.extend(resampler.flush());
+    output
+}
+
+/// Converts interleaved `samples` of `channels` channels from sample rate `from` to `to`.
+///
+/// ```
+/// use synthrs::resample::{resample_interleaved, Quality};
+///
+/// let stereo = vec![0.0; 2 * 48_000];
+/// let converted = resample_interleaved(&stereo, 2, 48_000, 44_100, Quality::Fast);
+/// assert_eq!(converted.len(), 2 * 44_100);
+/// ```
+pub fn resample_interleaved(
+    samples: &[f64],
+    channels: usize,
+    from: usize,
+    to: usize,
+    quality: Quality,
+) -> Vec<f64> {
+    let converted: Vec<Vec<f64>> = (0..channels)
+        .map(|channel| {
+            let channel: Vec<f64> = samples
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .cloned()
+                .collect();
+            resample(&channel, from, to, quality)
+        })
+        .collect();
+
+    let frames = converted.first().map_or(0, |channel| channel.len());
+    (0..frames)
+        .flat_map(|frame| converted.iter().map(move |channel| channel[frame]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{gain_to_db, rms};
+    use crate::synthesizer::make_samples;
+    use crate::wave::sine_wave;
+
+    const QUALITIES: [Quality; 4] = [Quality::Fast, Quality::Medium, Quality::High, Quality::Best];
+
+    /// Amplitude of the `frequency` component of `samples`, away from the ends. The projection
+    /// is Hann windowed, so that partial periods at the ends do not leak into it.
+    fn amplitude(samples: &[f64], frequency: f64, sample_rate: usize) -> f64 {
+        let samples = &samples[1_024..samples.len() - 1_024];
+        let window = crate::filter::hann_window(samples.len());
+        let (mut sine, mut cosine) = (0.0, 0.0);
+        for (i, (sample, w)) in samples.iter().zip(window.iter()).enumerate() {
+            let phase = 2.0 * std::f64::consts::PI * frequency * i as f64 / sample_rate as f64;
+            sine += w * sample * phase.sin();
+            cosine += w * sample * phase.cos();
+        }
+        2.0 * (sine * sine + cosine * cosine).sqrt() / window.iter().sum::<f64>()
+    }
+
+    #[test]
+    fn test_passband_ripple() {
+        for &quality in QUALITIES.iter() {
+            for &(from, to) in [(48_000, 44_100), (22_050, 44_100)].iter() {
+                let nyquist = from.min(to) as f64 / 2.0;
+                for &step in [0.1, 0.4, 0.7, 1.0].iter() {
+                    let frequency = nyquist * quality.passband() * step;
+                    let input = make_samples(0.25, from, sine_wave(frequency));
+                    let output = resample(&input, from, to, quality);
+
+                    let gain = gain_to_db(amplitude(&output, frequency, to));
+                    assert!(
+                        gain.abs() < 0.01,
+                        "{:?} {} -> {}: {}Hz at {}dB",
+                        quality,
+                        from,
+                        to,
+                        frequency,
+                        gain
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stopband_attenuation() {
+        for &quality in QUALITIES.iter() {
+            // Above 22.05kHz, these would alias back into the output
+            for &frequency in [23_000.0, 30_000.0, 40_000.0].iter() {
+                let input = make_samples(0.1, 96_000, sine_wave(frequency));
+                let output = resample(&input, 96_000, 44_100, quality);
+
+                let middle = &output[1_024..output.len() - 1_024];
+                let level = gain_to_db(rms(middle) * 2.0f64.sqrt());
+                assert!(
+                    level < -quality.attenuation() + 6.0,
+                    "{:?}: {}Hz at {}dB",
+                    quality,
+                    frequency,
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_matches_offline() {
+        let input = make_samples(0.25, 44_100, sine_wave(1_000.0));
+        // 44.1kHz to 44.101kHz has too many phases to tabulate, so phases are interpolated
+        for &to in [48_000, 32_000, 44_101].iter() {
+            let offline = resample(&input, 44_100, to, Quality::Medium);
+            assert_eq!(
+                offline.len(),
+                (input.len() as f64 * to as f64 / 44_100.0).ceil() as usize
+            );
+
+            let mut resampler = Resampler::new(44_100, to, Quality::Medium);
+            let mut streamed = Vec::new();
+            let mut rest = &input[..];
+            let mut size = 1;
+            while !rest.is_empty() {
+                let (block, next) = rest.split_at(size.min(rest.len()));
+                streamed.extend(resampler.process(block));
+                rest = next;
+                size = size * 3 % 1_000 + 1;
+            }
+            streamed.extend(resampler.flush());
+            assert_eq!(streamed, offline);
+
+            // Reusable after a flush
+            assert_eq!(
+                resampler.process(&input).len() + resampler.flush().len(),
+                offline.len()
+            );
+        }
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn test_same_rate_is_unchanged() {
+        let input = make_samples(0.1, 44_100, sine_wave(1_000.0));
+        assert_eq!(resample(&input, 44_100, 44_100, Quality::Best), input);
+    }
+}
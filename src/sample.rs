@@ -1,7 +1,28 @@
 //! Functions for dealing with creating samples for sample-synthesis generators
+//!
+//! A `Sample` owns its audio behind an `Arc`, so it can be cloned cheaply and shared between any
+//! number of `sampler` generators, which can outlive the original `Sample`.
+//!
+//! ```
+//! use synthrs::sample::Sample;
+//! use synthrs::synthesizer::make_samples;
+//!
+//! // A recording of a 440Hz sine
+//! let sine = Sample::from_wave_file("tests/assets/sine.wav", 440.0).unwrap();
+//!
+//! // Play it an octave higher
+//! let samples = make_samples(0.5, 44_100, sine.sampler(880.0, 44_100));
+//! ```
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::f64::consts::PI;
 use std::io::{Cursor, Result};
+use std::sync::Arc;
 
+use crate::filter::Adsr;
+use crate::music::{cents_to_ratio, note_midi};
+use crate::resample::{resample, Quality};
 use crate::synthesizer::unquantize_samples;
 use crate::writer::{read_wav, read_wav_file, Wave};
 
@@ -55,3 +76,777 @@ pub fn samples_from_wave_file(filepath: &str) -> Result<(Vec<f64>, usize)> {
     let wave = read_wav_file(filepath)?;
     Ok(samples_from_wave(wave))
 }
+
+/// How a `Sample` is read between its samples when it is pitch shifted or played at a different
+/// sample rate.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Interpolation {
+    /// Nearest sample. Cheap, but gritty and aliased.
+    Nearest,
+    /// Straight line between neighbouring samples. Dulls high frequencies.
+    Linear,
+    /// 4-point, 3rd-order Hermite (Catmull-Rom) cubic. A good default.
+    #[default]
+    Hermite,
+    /// Blackman-windowed sinc with the given number of zero crossings on each side (8 to 32 is
+    /// typical). When playing faster than the output sample rate, the sinc is widened to low-pass
+    /// the sample below the output's Nyquist frequency, so pitched-up notes do not alias. The
+    /// widening, and so the cost of each read, is capped at `MAX_SINC_WIDENING` times: beyond
+    /// that the window keeps fewer zero crossings and the filter's transition gets wider. For
+    /// larger ratios, such as a sample far above the output sample rate, convert it first with
+    /// `Sample::resample`.
+    Sinc(usize),
+}
+
+/// Most an `Interpolation::Sinc` is widened when playing faster than the output sample rate, two
+/// octaves
+pub const MAX_SINC_WIDENING: f64 = 4.0;
+
+impl Interpolation {
+    /// Reads `samples` at fractional `position`, moving `step` samples per output sample.
+    /// Samples outside of `samples` are `0.0`.
+    pub fn value(self, samples: &[f64], position: f64, step: f64) -> f64 {
+        let at = |index: isize| -> f64 {
+            if index < 0 {
+                0.0
+            } else {
+                samples.get(index as usize).cloned().unwrap_or(0.0)
+            }
+        };
+
+        let index = position.floor();
+        let fraction = position - index;
+        let index = index as isize;
+
+        match self {
+            Interpolation::Nearest => at(position.round() as isize),
+            Interpolation::Linear => at(index) + fraction * (at(index + 1) - at(index)),
+            Interpolation::Hermite => {
+                let (xm1, x0, x1, x2) = (at(index - 1), at(index), at(index + 1), at(index + 2));
+                let c1 = 0.5 * (x1 - xm1);
+                let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+                let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+                ((c3 * fraction + c2) * fraction + c1) * fraction + x0
+            }
+            Interpolation::Sinc(zero_crossings) => {
+                // Cutoff as a fraction of the source's Nyquist frequency
+                let cutoff = if step > 1.0 { 1.0 / step } else { 1.0 };
+                let half_width =
+                    zero_crossings.max(1) as f64 * (1.0 / cutoff).min(MAX_SINC_WIDENING);
+
+                let first = (position - half_width).ceil() as isize;
+                let last = (position + half_width).floor() as isize;
+                (first..=last)
+                    .map(|k| {
+                        let x = position - k as f64;
+                        let window = blackman(x / half_width);
+                        at(k) * cutoff * sinc(cutoff * x) * window
+                    })
+                    .sum()
+            }
+        }
+    }
+}
+
+/// Normalized sinc, `sin(pi x) / (pi x)`
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-12 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window over `[-1, 1]`
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        0.0
+    } else {
+        0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+    }
+}
+
+/// A mono sample with its sample rate and root pitch, for sample-synthesis generators.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    samples: Arc<[f64]>,
+    /// Sample rate of the recording, in hertz
+    pub sample_rate: usize,
+    /// Frequency of the recorded note, in hertz. The sample plays unshifted at this frequency.
+    pub root_frequency: f64,
+    /// How the sample is read between samples, `Interpolation::Hermite` by default
+    pub interpolation: Interpolation,
+}
+
+impl Sample {
+    /// Creates a sample from mono samples (such as a `Vec<f64>`) or an already shared `Arc<[f64]>`.
+    ///
+    /// ```
+    /// use synthrs::sample::Sample;
+    ///
+    /// let sample = Sample::new(vec![0.0, 0.5, 1.0], 44_100, 440.0);
+    /// assert_eq!(sample.len(), 3);
+    /// ```
+    pub fn new<S: Into<Arc<[f64]>>>(samples: S, sample_rate: usize, root_frequency: f64) -> Sample {
+        Sample {
+            samples: samples.into(),
+            sample_rate,
+            root_frequency,
+            interpolation: Interpolation::default(),
+        }
+    }
+
+    /// Creates a sample from a `crate::writer::Wave`, taking its sample rate from the wave.
+    /// Multichannel waves are mixed down to mono.
+    pub fn from_wave(wave: Wave, root_frequency: f64) -> Sample {
+        let channels = (wave.num_channels.max(1)) as usize;
+        let sample_rate = wave.sample_rate as usize;
+        let (samples, _) = samples_from_wave(wave);
+
+        let mono: Vec<f64> = samples
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f64>() / channels as f64)
+            .collect();
+
+        Sample::new(mono, sample_rate, root_frequency)
+    }
+
+    /// Loads a sample from a wave file. See `from_wave`.
+    pub fn from_wave_file(filepath: &str, root_frequency: f64) -> Result<Sample> {
+        let wave = read_wav_file(filepath)?;
+        Ok(Sample::from_wave(wave, root_frequency))
+    }
+
+    /// Converts the sample to `sample_rate` with `crate::resample`, so that it can be played at
+    /// its root frequency without interpolation.
+    ///
+    /// ```
+    /// use synthrs::resample::Quality;
+    /// use synthrs::sample::Sample;
+    ///
+    /// let sine = Sample::from_wave_file("tests/assets/sine.wav", 440.0).unwrap();
+    /// let converted = sine.resample(48_000, Quality::High);
+    /// assert_eq!(converted.sample_rate, 48_000);
+    /// assert_eq!(converted.len(), 96_000);
+    /// ```
+    pub fn resample(&self, sample_rate: usize, quality: Quality) -> Sample {
+        Sample {
+            samples: resample(&self.samples, self.sample_rate, sample_rate, quality).into(),
+            sample_rate,
+            root_frequency: self.root_frequency,
+            interpolation: self.interpolation,
+        }
+    }
+
+    pub fn samples(&self) -> &[f64] {
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Duration of the sample at its root frequency, in seconds
+    pub fn duration(&self) -> f64 {
+        self.samples.len() as f64 / self.sample_rate as f64
+    }
+
+    /// Value of the sample at `t` seconds, pitch shifted to `frequency`, for output at
+    /// `sample_rate`. `0.0` past the end.
+    pub fn value(&self, frequency: f64, sample_rate: usize, t: f64) -> f64 {
+        let speed = frequency / self.root_frequency;
+        let position = t * self.sample_rate as f64 * speed;
+        let step = speed * self.sample_rate as f64 / sample_rate as f64;
+
+        self.interpolation.value(&self.samples, position, step)
+    }
+
+    /// Creates a generator playing the sample pitch shifted to `frequency`, for output at
+    /// `sample_rate`. Pitch is shifted by resampling, so the sample also plays faster at higher
+    /// frequencies. Samples recorded at a different rate than `sample_rate` are converted. The
+    /// generator shares the sample's data and can outlive it.
+    ///
+    /// ```
+    /// use synthrs::sample::{Interpolation, Sample};
+    ///
+    /// let mut sample = Sample::new(vec![0.0, 0.25, 0.5, 0.75], 4, 1.0);
+    /// sample.interpolation = Interpolation::Linear;
+    ///
+    /// // Twice as fast an octave up
+    /// let sampler = sample.sampler(2.0, 4);
+    /// assert_eq!(sampler(0.25), 0.5);
+    /// assert_eq!(sampler(0.3125), 0.625);
+    /// assert_eq!(sampler(2.0), 0.0);
+    /// ```
+    pub fn sampler(&self, frequency: f64, sample_rate: usize) -> impl Fn(f64) -> f64 {
+        let sample = self.clone();
+        move |t| sample.value(frequency, sample_rate, t)
+    }
+}
+
+/// How a `SampleZone` plays its sample.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LoopMode {
+    /// Play the sample once, stopping at the end of the note
+    #[default]
+    NoLoop,
+    /// Play the whole sample once, whatever the length of the note
+    OneShot,
+    /// Repeat the loop for as long as the note sounds
+    Continuous,
+    /// Repeat the loop while the note is held, then play on past the loop after its release
+    Sustain,
+}
+
+/// A sample mapped to a range of keys and velocities in a `SampleInstrument`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleZone {
+    /// The sample. Its `root_frequency` is ignored in favour of `root_key`.
+    pub sample: Sample,
+    /// MIDI note the sample was recorded at
+    pub root_key: u8,
+    /// Lowest and highest MIDI notes the zone plays (inclusive)
+    pub keys: (u8, u8),
+    /// Lowest and highest note velocities the zone plays (inclusive)
+    pub velocities: (u8, u8),
+    /// Tuning correction in cents, added to every note the zone plays
+    pub tune: f64,
+    /// Linear gain
+    pub gain: f64,
+    pub loop_mode: LoopMode,
+    /// First sample of the loop and the sample after its end. Looping modes play straight
+    /// through without loop points.
+    pub loop_points: Option<(usize, usize)>,
+    /// Amplitude envelope, or `None` to play the sample as is
+    pub envelope: Option<Adsr>,
+}
+
+impl SampleZone {
+    /// Creates a zone playing `sample`, recorded at `root_key`, over all keys and velocities.
+    pub fn new(sample: Sample, root_key: u8) -> SampleZone {
+        SampleZone {
+            sample,
+            root_key,
+            keys: (0, 127),
+            velocities: (0, 127),
+            tune: 0.0,
+            gain: 1.0,
+            loop_mode: LoopMode::NoLoop,
+            loop_points: None,
+            envelope: None,
+        }
+    }
+
+    fn has_key(&self, note: u8) -> bool {
+        self.keys.0 <= note && note <= self.keys.1
+    }
+
+    fn has_velocity(&self, velocity: u8) -> bool {
+        self.velocities.0 <= velocity && velocity <= self.velocities.1
+    }
+
+    /// Playback speed of the sample when playing `frequency`
+    fn speed(&self, frequency: f64) -> f64 {
+        frequency * cents_to_ratio(self.tune) / note_midi(440.0, self.root_key as usize)
+    }
+
+    /// Time taken to play through the whole sample at `frequency`, in seconds
+    pub fn duration(&self, frequency: f64) -> f64 {
+        self.sample.duration() / self.speed(frequency)
+    }
+
+    /// Time the zone keeps sounding after a note is released, in seconds
+    pub fn release(&self) -> f64 {
+        self.envelope.map_or(0.0, |envelope| envelope.release)
+    }
+
+    /// Creates a generator playing the zone's sample at `frequency`, for output at `sample_rate`,
+    /// for a note that is never released.
+    pub fn sampler(&self, frequency: f64, sample_rate: usize) -> impl Fn(f64) -> f64 {
+        self.note_sampler(frequency, sample_rate, None)
+    }
+
+    /// Creates a generator playing the zone's sample at `frequency`, for output at `sample_rate`,
+    /// for a note released `released_at` seconds after it starts. The release ends sustain loops
+    /// and starts the release of the envelope.
+    ///
+    /// ```
+    /// use synthrs::filter::Adsr;
+    /// use synthrs::sample::{LoopMode, Sample, SampleZone};
+    ///
+    /// let mut zone = SampleZone::new(Sample::new(vec![0.0, 0.25, 0.5, 0.75], 4, 0.0), 69);
+    /// zone.loop_mode = LoopMode::Continuous;
+    /// zone.loop_points = Some((2, 4));
+    /// zone.envelope = Some(Adsr::new(0.0, 0.0, 1.0, 1.0));
+    ///
+    /// let sampler = zone.note_sampler(440.0, 4, Some(2.0));
+    /// let samples: Vec<f64> = (0..8).map(|i| sampler(i as f64 / 4.0)).collect();
+    /// assert_eq!(&samples[..6], &[0.0, 0.25, 0.5, 0.75, 0.5, 0.75]);
+    ///
+    /// // Fading out after the release
+    /// assert!((sampler(2.5) - 0.5 * 0.5).abs() < 1e-9);
+    /// ```
+    pub fn note_sampler(
+        &self,
+        frequency: f64,
+        sample_rate: usize,
+        released_at: Option<f64>,
+    ) -> impl Fn(f64) -> f64 {
+        let sample = self.sample.clone();
+        let speed = self.speed(frequency);
+        let step = speed * sample.sample_rate as f64 / sample_rate as f64;
+        let (gain, envelope, loop_mode) = (self.gain, self.envelope, self.loop_mode);
+        let loop_points = match self.loop_points {
+            Some((start, end)) if end > start => Some((start as f64, end as f64)),
+            _ => None,
+        };
+
+        // Wraps positions past the end of the loop back into it
+        let wrap = move |position: f64| match loop_points {
+            Some((start, end)) if position >= end => start + (position - start) % (end - start),
+            _ => position,
+        };
+
+        move |t| {
+            if t < 0.0 {
+                return 0.0;
+            }
+
+            let position = t * sample.sample_rate as f64 * speed;
+            let position = match (loop_mode, released_at) {
+                (LoopMode::Continuous, _) => wrap(position),
+                (LoopMode::Sustain, Some(released_at)) if t >= released_at => {
+                    // Carry on from wherever the loop was at the release
+                    let released = released_at * sample.sample_rate as f64 * speed;
+                    wrap(released) + position - released
+                }
+                (LoopMode::Sustain, _) => wrap(position),
+                _ => position,
+            };
+
+            let level = envelope.map_or(1.0, |envelope| envelope.value(t, released_at));
+            if level == 0.0 {
+                return 0.0;
+            }
+
+            gain * level * sample.interpolation.value(sample.samples(), position, step)
+        }
+    }
+}
+
+/// Velocity used by `SampleInstrument::frequency_generator`, where there is no note velocity
+const DEFAULT_VELOCITY: u8 = 100;
+
+/// Root key and velocity layer shared by zones played in turn with round-robin
+type ZoneGroup = (u8, (u8, u8));
+
+/// A multi-sampled instrument: samples mapped by key range and velocity layer.
+///
+/// To play a note, the zones for its velocity are considered, preferring those whose key range
+/// includes the note. Of those, the zones with the root key closest to the note are used, which
+/// keeps pitch shifting to a minimum. When several zones share the same closest root (and the
+/// same velocity layer), they are played in turn if `round_robin` is set, and the first is used
+/// otherwise.
+///
+/// ```
+/// use synthrs::sample::{Sample, SampleInstrument, SampleZone};
+/// use synthrs::synthesizer::make_samples;
+///
+/// let sine = Sample::from_wave_file("tests/assets/sine.wav", 440.0).unwrap();
+///
+/// // A4 for soft notes, and the same sample twice as loud for hard ones
+/// let mut soft = SampleZone::new(sine.clone(), 69);
+/// soft.velocities = (0, 63);
+/// let mut hard = SampleZone::new(sine, 69);
+/// hard.velocities = (64, 127);
+/// hard.gain = 2.0;
+///
+/// let instrument = SampleInstrument::new(vec![soft, hard]);
+///
+/// // Middle C played hard
+/// let generator = instrument.generator(60, 100, 44_100).unwrap();
+/// let samples = make_samples(0.5, 44_100, generator);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SampleInstrument {
+    pub zones: Vec<SampleZone>,
+    /// Cycle through zones with the same root and velocity layer on every note
+    pub round_robin: bool,
+    /// Notes played so far from each group of round-robin zones, by root key and velocity layer
+    played: RefCell<HashMap<ZoneGroup, usize>>,
+}
+
+impl SampleInstrument {
+    pub fn new(zones: Vec<SampleZone>) -> SampleInstrument {
+        SampleInstrument {
+            zones,
+            round_robin: false,
+            played: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the zone to play for MIDI `note` at `velocity`, if any zone has that velocity.
+    pub fn zone(&self, note: u8, velocity: u8) -> Option<&SampleZone> {
+        let layer: Vec<&SampleZone> = self
+            .zones
+            .iter()
+            .filter(|zone| zone.has_velocity(velocity))
+            .collect();
+
+        let in_range: Vec<&SampleZone> = layer
+            .iter()
+            .cloned()
+            .filter(|zone| zone.has_key(note))
+            .collect();
+        let candidates = if in_range.is_empty() { layer } else { in_range };
+
+        let distance = |zone: &SampleZone| (i16::from(zone.root_key) - i16::from(note)).abs();
+        let nearest = candidates.iter().map(|zone| distance(zone)).min()?;
+        let closest: Vec<&SampleZone> = candidates
+            .into_iter()
+            .filter(|zone| distance(zone) == nearest)
+            .collect();
+
+        if self.round_robin && closest.len() > 1 {
+            let mut played = self.played.borrow_mut();
+            let count = played
+                .entry((closest[0].root_key, closest[0].velocities))
+                .or_insert(0);
+            let zone = closest[*count % closest.len()];
+            *count = count.wrapping_add(1);
+            Some(zone)
+        } else {
+            Some(closest[0])
+        }
+    }
+
+    /// Creates a generator for MIDI `note` at `velocity`, for output at `sample_rate`. Returns
+    /// `None` if no zone has that velocity.
+    pub fn generator(
+        &self,
+        note: u8,
+        velocity: u8,
+        sample_rate: usize,
+    ) -> Option<impl Fn(f64) -> f64> {
+        let zone = self.zone(note, velocity)?;
+        Some(zone.sampler(note_midi(440.0, note as usize), sample_rate))
+    }
+
+    /// Creates a generator playing `frequency`, for use as the instrument of MIDI renderers that
+    /// only give a frequency. The zone is chosen for the nearest MIDI note at a velocity of 100,
+    /// and is then pitched to `frequency` exactly. Silent if no zone has that velocity.
+    ///
+    /// The zone is chosen when the generator is created, so this should only be used with
+    /// renderers which create one generator per note, such as `make_stereo_samples_from_midi`.
+    /// `make_samples_from_midi` creates generators on every sample, which is slow and picks a
+    /// different zone on every sample with `round_robin`. To also play velocity layers, use
+    /// `make_stereo_samples_from_midi_with_sampler`.
+    ///
+    /// ```
+    /// use synthrs::sample::{Sample, SampleInstrument, SampleZone};
+    /// use synthrs::synthesizer::make_stereo_samples_from_midi_file;
+    ///
+    /// let sine = Sample::from_wave_file("tests/assets/sine.wav", 440.0).unwrap();
+    /// let instrument = SampleInstrument::new(vec![SampleZone::new(sine, 69)]);
+    ///
+    /// let samples = make_stereo_samples_from_midi_file(
+    ///     |frequency| instrument.frequency_generator(frequency, 44_100),
+    ///     44_100,
+    ///     false,
+    ///     "tests/assets/test.mid",
+    /// ).unwrap();
+    /// ```
+    pub fn frequency_generator(&self, frequency: f64, sample_rate: usize) -> impl Fn(f64) -> f64 {
+        let note = (69.0 + 12.0 * (frequency / 440.0).log2())
+            .round()
+            .clamp(0.0, 127.0) as u8;
+        let sampler = self
+            .zone(note, DEFAULT_VELOCITY)
+            .map(|zone| zone.sampler(frequency, sample_rate));
+
+        move |t| sampler.as_ref().map_or(0.0, |sampler| sampler(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::rms;
+
+    #[test]
+    fn test_sample_from_wave_file() {
+        let sample = Sample::from_wave_file("./tests/assets/sine.wav", 440.0).unwrap();
+        let (samples, length) = samples_from_wave_file("./tests/assets/sine.wav").unwrap();
+
+        assert_eq!(sample.len(), length);
+        assert_eq!(sample.samples(), &samples[..]);
+        assert_eq!(sample.sample_rate, 44_100);
+    }
+
+    #[test]
+    fn test_sampler_outlives_sample() {
+        let sampler = {
+            let mut sample = Sample::new(vec![0.1, 0.2, 0.3, 0.4], 4, 100.0);
+            sample.interpolation = Interpolation::Nearest;
+            sample.sampler(100.0, 4)
+        };
+
+        assert_eq!(sampler(0.0), 0.1);
+        assert_eq!(sampler(0.5), 0.3);
+        assert_eq!(sampler(1.0), 0.0);
+        assert_eq!(sampler(-1.0), 0.0);
+    }
+
+    #[test]
+    fn test_sampler_pitch_shifts() {
+        let sample = Sample::new((0..100).map(f64::from).collect::<Vec<f64>>(), 100, 50.0);
+        let down = sample.sampler(25.0, 100);
+        let up = sample.sampler(100.0, 100);
+
+        assert_eq!(down(0.2), 10.0);
+        assert_eq!(up(0.2), 40.0);
+    }
+
+    /// Largest error reading a sine of `frequency` (relative to the sample rate) at fractional
+    /// positions
+    fn interpolation_error(interpolation: Interpolation, frequency: f64) -> f64 {
+        let sine: Vec<f64> = (0..400)
+            .map(|i| (2.0 * PI * frequency * i as f64).sin())
+            .collect();
+
+        (0..1000)
+            .map(|i| {
+                let position = 100.0 + i as f64 * 0.2 + 0.037;
+                let expected = (2.0 * PI * frequency * position).sin();
+                (interpolation.value(&sine, position, 1.0) - expected).abs()
+            })
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn test_interpolation_accuracy() {
+        let nearest = interpolation_error(Interpolation::Nearest, 0.05);
+        let linear = interpolation_error(Interpolation::Linear, 0.05);
+        let hermite = interpolation_error(Interpolation::Hermite, 0.05);
+        let sinc = interpolation_error(Interpolation::Sinc(16), 0.05);
+
+        assert!(linear < nearest / 2.0);
+        assert!(hermite < linear / 2.0);
+        assert!(sinc < hermite);
+        assert!(sinc < 1e-3);
+
+        // Every interpolation passes through the samples themselves
+        let samples = [0.3, -0.2, 0.7, 0.1, -0.5];
+        for &interpolation in &[
+            Interpolation::Nearest,
+            Interpolation::Linear,
+            Interpolation::Hermite,
+            Interpolation::Sinc(8),
+        ] {
+            for (i, &sample) in samples.iter().enumerate() {
+                assert!((interpolation.value(&samples, i as f64, 1.0) - sample).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sinc_prevents_aliasing() {
+        // A sine at 0.4 of the sample rate, played 1.5 times faster, is above Nyquist and should
+        // be filtered out instead of folding back down
+        let mut sample = Sample::new(
+            (0..44_100)
+                .map(|i| (2.0 * PI * 0.4 * i as f64).sin())
+                .collect::<Vec<f64>>(),
+            44_100,
+            100.0,
+        );
+
+        let level = |sample: &Sample| {
+            let sampler = sample.sampler(150.0, 44_100);
+            let samples: Vec<f64> = (0..8192)
+                .map(|i| sampler(i as f64 / 44_100.0))
+                .skip(100)
+                .collect();
+            rms(&samples)
+        };
+
+        sample.interpolation = Interpolation::Linear;
+        let linear = level(&sample);
+        sample.interpolation = Interpolation::Sinc(16);
+        let sinc = level(&sample);
+
+        assert!(linear > 0.1);
+        assert!(sinc < 0.01);
+    }
+
+    #[test]
+    fn test_sinc_widening_is_capped() {
+        let mut samples = vec![0.0; 4096];
+        let reach = 8.0 * MAX_SINC_WIDENING;
+        samples[2048 + reach as usize] = 1.0;
+
+        // Within the capped window however fast the sample is played, but not beyond it
+        let interpolation = Interpolation::Sinc(8);
+        assert!(interpolation.value(&samples, 2048.5, 1000.0).abs() > 0.0);
+        assert_eq!(interpolation.value(&samples, 2047.5, 1000.0), 0.0);
+    }
+
+    #[test]
+    fn test_sample_rate_conversion() {
+        // A 441Hz sine recorded at 22.05kHz, played back at its root pitch at 44.1kHz
+        let sample = Sample::new(
+            (0..22_050)
+                .map(|i| (2.0 * PI * 441.0 * i as f64 / 22_050.0).sin())
+                .collect::<Vec<f64>>(),
+            22_050,
+            441.0,
+        );
+        let sampler = sample.sampler(441.0, 44_100);
+
+        for i in 1_000..2_000 {
+            let t = i as f64 / 44_100.0;
+            assert!((sampler(t) - (2.0 * PI * 441.0 * t).sin()).abs() < 1e-3);
+        }
+    }
+
+    /// A constant sample, so that zones can be told apart by their output
+    fn constant_zone(value: f64, root_key: u8) -> SampleZone {
+        SampleZone::new(Sample::new(vec![value; 1000], 44_100, 440.0), root_key)
+    }
+
+    #[test]
+    fn test_zone_key_and_velocity_splits() {
+        let mut low = constant_zone(0.1, 48);
+        low.keys = (0, 59);
+        let mut high_soft = constant_zone(0.2, 72);
+        high_soft.keys = (60, 127);
+        high_soft.velocities = (0, 63);
+        let mut high_hard = constant_zone(0.3, 72);
+        high_hard.keys = (60, 127);
+        high_hard.velocities = (64, 127);
+        let instrument = SampleInstrument::new(vec![low, high_soft, high_hard]);
+
+        let played =
+            |note: u8, velocity: u8| instrument.generator(note, velocity, 44_100).unwrap()(0.0);
+        assert_eq!(played(40, 100), 0.1);
+        assert_eq!(played(70, 30), 0.2);
+        assert_eq!(played(70, 100), 0.3);
+    }
+
+    #[test]
+    fn test_nearest_root_is_chosen() {
+        let instrument = SampleInstrument::new(vec![
+            constant_zone(0.1, 48),
+            constant_zone(0.2, 60),
+            constant_zone(0.3, 72),
+        ]);
+
+        assert_eq!(instrument.zone(50, 100).unwrap().root_key, 48);
+        assert_eq!(instrument.zone(57, 100).unwrap().root_key, 60);
+        assert_eq!(instrument.zone(100, 100).unwrap().root_key, 72);
+
+        let mut soft_only = constant_zone(0.1, 60);
+        soft_only.velocities = (0, 63);
+        let instrument = SampleInstrument::new(vec![soft_only]);
+        assert!(instrument.zone(60, 100).is_none());
+        assert!(instrument.generator(60, 100, 44_100).is_none());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut instrument =
+            SampleInstrument::new(vec![constant_zone(0.1, 60), constant_zone(0.2, 60)]);
+        assert_eq!(instrument.zone(60, 100).unwrap().sample.samples()[0], 0.1);
+        assert_eq!(instrument.zone(60, 100).unwrap().sample.samples()[0], 0.1);
+
+        instrument.round_robin = true;
+        let cycle: Vec<f64> = (0..4)
+            .map(|_| instrument.zone(60, 100).unwrap().sample.samples()[0])
+            .collect();
+        assert_eq!(cycle, vec![0.1, 0.2, 0.1, 0.2]);
+    }
+
+    #[test]
+    fn test_round_robin_per_key() {
+        let mut instrument = SampleInstrument::new(vec![
+            constant_zone(0.1, 60),
+            constant_zone(0.2, 60),
+            constant_zone(0.3, 72),
+            constant_zone(0.4, 72),
+            constant_zone(0.5, 84),
+        ]);
+        instrument.round_robin = true;
+
+        // Alternating keys each cycle through their own zones, and single zones don't count
+        let cycle: Vec<f64> = [60, 72, 84, 60, 72, 84, 60, 72]
+            .iter()
+            .map(|&note| instrument.zone(note, 100).unwrap().sample.samples()[0])
+            .collect();
+        assert_eq!(cycle, vec![0.1, 0.3, 0.5, 0.2, 0.4, 0.5, 0.1, 0.3]);
+    }
+
+    #[test]
+    fn test_zone_pitch_tune_and_gain() {
+        // A4 recorded at 44.1kHz, played two semitones up
+        let sine = Sample::new(
+            (0..44_100)
+                .map(|i| (2.0 * PI * 440.0 * i as f64 / 44_100.0).sin())
+                .collect::<Vec<f64>>(),
+            44_100,
+            0.0,
+        );
+        let mut zone = SampleZone::new(sine, 69);
+        zone.gain = 0.5;
+        let instrument = SampleInstrument::new(vec![zone.clone()]);
+        let generator = instrument.generator(71, 100, 44_100).unwrap();
+        let b4 = note_midi(440.0, 71);
+
+        for i in 100..1000 {
+            let t = i as f64 / 44_100.0;
+            assert!((generator(t) - 0.5 * (2.0 * PI * b4 * t).sin()).abs() < 1e-3);
+        }
+
+        // Tuned down a semitone
+        zone.tune = -100.0;
+        let generator = zone.sampler(b4, 44_100);
+        let a_sharp4 = note_midi(440.0, 70);
+        for i in 100..1000 {
+            let t = i as f64 / 44_100.0;
+            assert!((generator(t) - 0.5 * (2.0 * PI * a_sharp4 * t).sin()).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_loop_modes() {
+        let ramp: Vec<f64> = (0..10).map(f64::from).collect();
+        let mut zone = SampleZone::new(Sample::new(ramp, 10, 0.0), 69);
+        zone.sample.interpolation = Interpolation::Nearest;
+        zone.loop_points = Some((4, 8));
+
+        let play = |zone: &SampleZone, released_at: Option<f64>| -> Vec<f64> {
+            let sampler = zone.note_sampler(440.0, 10, released_at);
+            (0..16).map(|i| sampler(i as f64 / 10.0)).collect()
+        };
+
+        // Straight through and then silent
+        assert_eq!(play(&zone, None)[8..12], [8.0, 9.0, 0.0, 0.0]);
+
+        zone.loop_mode = LoopMode::Continuous;
+        assert_eq!(
+            play(&zone, Some(0.5))[6..14],
+            [6.0, 7.0, 4.0, 5.0, 6.0, 7.0, 4.0, 5.0]
+        );
+
+        // Loops until the release at sample 9 (in the loop at 5), then plays on out of the loop
+        zone.loop_mode = LoopMode::Sustain;
+        assert_eq!(
+            play(&zone, Some(0.9))[6..14],
+            [6.0, 7.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]
+        );
+        assert_eq!(play(&zone, None)[10..14], [6.0, 7.0, 4.0, 5.0]);
+    }
+}
@@ -0,0 +1,833 @@
+//! Loads SoundFont 2 (`.sf2`) banks for sample-based General MIDI playback.
+//!
+//! A SoundFont is a RIFF file holding 16-bit (or 24-bit) sample data and a hierarchy of
+//! presets, instruments and samples. Presets are chosen by MIDI bank and program, and are made
+//! of zones that each play an instrument over a key and velocity range. Instruments are in turn
+//! made of zones playing a sample. Zones set their parameters with generators and modulators,
+//! and the first zone of a preset or instrument may be a global zone with defaults for the
+//! others.
+//!
+//! `SoundFont::instrument` flattens a preset into a `SampleInstrument`, with one `SampleZone` per
+//! instrument zone. The following generators are supported, combined across preset and
+//! instrument zones as the SoundFont 2.04 specification describes:
+//!
+//! * `keyRange`, `velRange`
+//! * `overridingRootKey`, `coarseTune`, `fineTune` (and the sample's pitch correction)
+//! * `initialAttenuation`
+//! * `sampleModes`, and the sample, loop start and loop end address offsets
+//! * `attackVolEnv`, `decayVolEnv`, `sustainVolEnv`, `releaseVolEnv`
+//!
+//! Other generators, such as filters, pan, LFOs and the modulation envelope, are read but not
+//! played, as are modulators: note velocity and channel volume are applied by the MIDI renderers
+//! instead. A `SampleInstrument` plays a single zone per note, so layered zones (including both
+//! sides of stereo samples) play only the first matching zone.
+//!
+//! ```
+//! use synthrs::sf2::read_sf2_file;
+//! use synthrs::synthesizer::make_stereo_samples_from_midi_with_soundfont;
+//! use synthrs::midi;
+//!
+//! let soundfont = read_sf2_file("tests/assets/test.sf2").unwrap();
+//! assert_eq!(soundfont.preset(0, 80).unwrap().name, "Lead");
+//!
+//! // Every channel plays the preset selected by its bank select and program changes
+//! let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+//! let samples = make_stereo_samples_from_midi_with_soundfont(&soundfont, 44_100, false, song)
+//!     .unwrap();
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::errors::{Result, SynthrsError};
+use crate::filter::Adsr;
+use crate::music::note_midi;
+use crate::sample::{LoopMode, Sample, SampleInstrument, SampleZone};
+
+/// Bank holding percussion presets, played on the General MIDI drum channel
+pub const PERCUSSION_BANK: u16 = 128;
+
+/// Generator operators, as numbered by the SoundFont 2 specification
+pub mod generator {
+    pub const START_ADDRS_OFFSET: u16 = 0;
+    pub const END_ADDRS_OFFSET: u16 = 1;
+    pub const STARTLOOP_ADDRS_OFFSET: u16 = 2;
+    pub const ENDLOOP_ADDRS_OFFSET: u16 = 3;
+    pub const START_ADDRS_COARSE_OFFSET: u16 = 4;
+    pub const END_ADDRS_COARSE_OFFSET: u16 = 12;
+    pub const PAN: u16 = 17;
+    pub const DELAY_VOL_ENV: u16 = 33;
+    pub const ATTACK_VOL_ENV: u16 = 34;
+    pub const HOLD_VOL_ENV: u16 = 35;
+    pub const DECAY_VOL_ENV: u16 = 36;
+    pub const SUSTAIN_VOL_ENV: u16 = 37;
+    pub const RELEASE_VOL_ENV: u16 = 38;
+    pub const INSTRUMENT: u16 = 41;
+    pub const KEY_RANGE: u16 = 43;
+    pub const VEL_RANGE: u16 = 44;
+    pub const STARTLOOP_ADDRS_COARSE_OFFSET: u16 = 45;
+    pub const INITIAL_ATTENUATION: u16 = 48;
+    pub const ENDLOOP_ADDRS_COARSE_OFFSET: u16 = 50;
+    pub const COARSE_TUNE: u16 = 51;
+    pub const FINE_TUNE: u16 = 52;
+    pub const SAMPLE_ID: u16 = 53;
+    pub const SAMPLE_MODES: u16 = 54;
+    pub const OVERRIDING_ROOT_KEY: u16 = 58;
+}
+
+/// Timecents of the shortest envelope stages (about a millisecond), the default for envelopes
+const MINIMUM_TIMECENTS: i32 = -12000;
+
+/// Sample types of samples held in ROM rather than in the file, which cannot be played
+const ROM_SAMPLE: u16 = 0x8000;
+
+/// A modulator, routing a controller (such as note velocity or a MIDI CC) to a generator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Modulator {
+    pub source: u16,
+    /// Generator operator modulated
+    pub destination: u16,
+    pub amount: i16,
+    /// Source scaling `amount`
+    pub amount_source: u16,
+    pub transform: u16,
+}
+
+/// A zone of a preset or instrument: its generators, keyed by operator, and its modulators.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Zone {
+    pub generators: BTreeMap<u16, i16>,
+    pub modulators: Vec<Modulator>,
+}
+
+impl Zone {
+    /// Returns the amount of generator `operator`, if the zone sets it.
+    pub fn generator(&self, operator: u16) -> Option<i16> {
+        self.generators.get(&operator).cloned()
+    }
+
+    /// Returns the lowest and highest values (inclusive) of a range generator, such as
+    /// `generator::KEY_RANGE`, if the zone sets it.
+    pub fn range(&self, operator: u16) -> Option<(u8, u8)> {
+        self.generator(operator).map(split_range)
+    }
+}
+
+/// A preset, played by selecting its bank and program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u8,
+    /// Defaults for the other zones
+    pub global_zone: Option<Zone>,
+    /// Zones, each playing an instrument
+    pub zones: Vec<Zone>,
+}
+
+/// An instrument, played by preset zones.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instrument {
+    pub name: String,
+    /// Defaults for the other zones
+    pub global_zone: Option<Zone>,
+    /// Zones, each playing a sample
+    pub zones: Vec<Zone>,
+}
+
+/// Where a sample is in the sample data, and how it was recorded. Positions are in sample frames
+/// from the start of the sample data, ends being exclusive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleHeader {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub sample_rate: usize,
+    /// MIDI note the sample was recorded at, or 255 for unpitched samples
+    pub original_pitch: u8,
+    /// Pitch correction in cents, applied on playback
+    pub pitch_correction: i8,
+    /// Index of the other sample of a stereo pair
+    pub sample_link: u16,
+    pub sample_type: u16,
+}
+
+/// A SoundFont 2 bank.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoundFont {
+    pub name: String,
+    pub presets: Vec<Preset>,
+    pub instruments: Vec<Instrument>,
+    pub samples: Vec<SampleHeader>,
+    /// Sample data of every sample, from -1.0 to 1.0
+    pub sample_data: Vec<f64>,
+}
+
+impl SoundFont {
+    /// Returns the preset for MIDI `bank` and `program`, if the bank has one.
+    pub fn preset(&self, bank: u16, program: u8) -> Option<&Preset> {
+        self.presets
+            .iter()
+            .find(|preset| preset.bank == bank && preset.program == program)
+    }
+
+    /// Creates a `SampleInstrument` playing the preset for MIDI `bank` and `program`. Like
+    /// General MIDI players, missing presets fall back to the same program in bank 0, or for
+    /// percussion to the standard kit (program 0 of `PERCUSSION_BANK`). Returns `None` if there
+    /// is no preset to fall back to either.
+    ///
+    /// ```
+    /// use synthrs::sf2::read_sf2_file;
+    ///
+    /// let soundfont = read_sf2_file("tests/assets/test.sf2").unwrap();
+    ///
+    /// // Middle C, played hard
+    /// let sine = soundfont.instrument(0, 0).unwrap();
+    /// let generator = sine.generator(60, 100, 44_100).unwrap();
+    /// ```
+    pub fn instrument(&self, bank: u16, program: u8) -> Option<SampleInstrument> {
+        let fallback = if bank == PERCUSSION_BANK {
+            (PERCUSSION_BANK, 0)
+        } else {
+            (0, program)
+        };
+        let preset = self
+            .preset(bank, program)
+            .or_else(|| self.preset(fallback.0, fallback.1))?;
+
+        // Samples shared by several zones are only copied out of the sample data once
+        let mut samples: HashMap<(usize, usize, usize), Sample> = HashMap::new();
+        let mut zones = Vec::new();
+
+        for preset_zone in &preset.zones {
+            let preset_generators = merge_zones(preset.global_zone.as_ref(), preset_zone);
+            let instrument = match preset_generators
+                .get(&generator::INSTRUMENT)
+                .and_then(|index| self.instruments.get(*index as u16 as usize))
+            {
+                Some(instrument) => instrument,
+                None => continue,
+            };
+
+            for instrument_zone in &instrument.zones {
+                let instrument_generators =
+                    merge_zones(instrument.global_zone.as_ref(), instrument_zone);
+                if let Some(zone) =
+                    self.sample_zone(&preset_generators, &instrument_generators, &mut samples)
+                {
+                    zones.push(zone);
+                }
+            }
+        }
+
+        Some(SampleInstrument::new(zones))
+    }
+
+    /// Creates the `SampleZone` for an instrument zone played by a preset zone, or `None` if the
+    /// zone cannot be played (its ranges do not overlap, or its sample is missing)
+    fn sample_zone(
+        &self,
+        preset: &BTreeMap<u16, i16>,
+        instrument: &BTreeMap<u16, i16>,
+        samples: &mut HashMap<(usize, usize, usize), Sample>,
+    ) -> Option<SampleZone> {
+        let header = instrument
+            .get(&generator::SAMPLE_ID)
+            .and_then(|index| self.samples.get(*index as u16 as usize))?;
+        if header.sample_type & ROM_SAMPLE != 0 || header.sample_rate == 0 {
+            return None;
+        }
+
+        let keys = intersect_ranges(
+            range(preset, generator::KEY_RANGE),
+            range(instrument, generator::KEY_RANGE),
+        )?;
+        let velocities = intersect_ranges(
+            range(preset, generator::VEL_RANGE),
+            range(instrument, generator::VEL_RANGE),
+        )?;
+
+        // Address offsets are only allowed in instrument zones
+        let offset = |fine: u16, coarse: u16| {
+            i64::from(amount(instrument, fine, 0))
+                + 32768 * i64::from(amount(instrument, coarse, 0))
+        };
+        let address = |position: usize, offset: i64| (position as i64 + offset).max(0) as usize;
+        let start = address(
+            header.start,
+            offset(
+                generator::START_ADDRS_OFFSET,
+                generator::START_ADDRS_COARSE_OFFSET,
+            ),
+        );
+        let end = address(
+            header.end,
+            offset(
+                generator::END_ADDRS_OFFSET,
+                generator::END_ADDRS_COARSE_OFFSET,
+            ),
+        )
+        .min(self.sample_data.len());
+        if start >= end {
+            return None;
+        }
+
+        let loop_start = address(
+            header.loop_start,
+            offset(
+                generator::STARTLOOP_ADDRS_OFFSET,
+                generator::STARTLOOP_ADDRS_COARSE_OFFSET,
+            ),
+        );
+        let loop_end = address(
+            header.loop_end,
+            offset(
+                generator::ENDLOOP_ADDRS_OFFSET,
+                generator::ENDLOOP_ADDRS_COARSE_OFFSET,
+            ),
+        );
+
+        let root_key = match amount(instrument, generator::OVERRIDING_ROOT_KEY, -1) {
+            key @ 0..=127 => key as u8,
+            _ if header.original_pitch <= 127 => header.original_pitch,
+            _ => 60,
+        };
+
+        let sample = samples
+            .entry((start, end, header.sample_rate))
+            .or_insert_with(|| {
+                Sample::new(
+                    &self.sample_data[start..end],
+                    header.sample_rate,
+                    note_midi(440.0, root_key as usize),
+                )
+            })
+            .clone();
+
+        // Preset zones add to the instrument zone's values
+        let sum = |operator: u16, default: i16| {
+            i32::from(amount(instrument, operator, default))
+                + i32::from(amount(preset, operator, 0))
+        };
+
+        let mut zone = SampleZone::new(sample, root_key);
+        zone.keys = keys;
+        zone.velocities = velocities;
+        zone.tune = f64::from(
+            100 * sum(generator::COARSE_TUNE, 0)
+                + sum(generator::FINE_TUNE, 0)
+                + i32::from(header.pitch_correction),
+        );
+        zone.gain = centibels_to_gain(sum(generator::INITIAL_ATTENUATION, 0).max(0));
+        zone.loop_mode = match amount(instrument, generator::SAMPLE_MODES, 0) & 3 {
+            1 => LoopMode::Continuous,
+            3 => LoopMode::Sustain,
+            _ => LoopMode::NoLoop,
+        };
+        if zone.loop_mode != LoopMode::NoLoop && start <= loop_start && loop_start < loop_end {
+            zone.loop_points = Some((loop_start - start, (loop_end - start).min(end - start)));
+        }
+        zone.envelope = Some(Adsr::new(
+            timecents_to_seconds(sum(generator::ATTACK_VOL_ENV, MINIMUM_TIMECENTS as i16)),
+            timecents_to_seconds(sum(generator::DECAY_VOL_ENV, MINIMUM_TIMECENTS as i16)),
+            centibels_to_gain(sum(generator::SUSTAIN_VOL_ENV, 0).clamp(0, 1440)),
+            timecents_to_seconds(sum(generator::RELEASE_VOL_ENV, MINIMUM_TIMECENTS as i16)),
+        ));
+
+        Some(zone)
+    }
+}
+
+/// Generators of a zone, with those it does not set taken from the global zone
+fn merge_zones(global: Option<&Zone>, zone: &Zone) -> BTreeMap<u16, i16> {
+    let mut generators = global.map_or_else(BTreeMap::new, |global| global.generators.clone());
+    generators.extend(
+        zone.generators
+            .iter()
+            .map(|(&operator, &value)| (operator, value)),
+    );
+    generators
+}
+
+fn amount(generators: &BTreeMap<u16, i16>, operator: u16, default: i16) -> i16 {
+    generators.get(&operator).cloned().unwrap_or(default)
+}
+
+/// Splits the amount of a range generator into its lowest and highest values
+fn split_range(amount: i16) -> (u8, u8) {
+    let amount = amount as u16;
+    ((amount & 0xff) as u8, (amount >> 8) as u8)
+}
+
+/// Range of a range generator, covering everything if it is not set
+fn range(generators: &BTreeMap<u16, i16>, operator: u16) -> (u8, u8) {
+    generators
+        .get(&operator)
+        .map_or((0, 127), |&amount| split_range(amount))
+}
+
+/// Overlap of two inclusive ranges, if any
+fn intersect_ranges(a: (u8, u8), b: (u8, u8)) -> Option<(u8, u8)> {
+    let range = (a.0.max(b.0), a.1.min(b.1));
+    if range.0 <= range.1 {
+        Some(range)
+    } else {
+        None
+    }
+}
+
+/// Converts an attenuation in centibels into a linear gain
+fn centibels_to_gain(centibels: i32) -> f64 {
+    10.0f64.powf(-f64::from(centibels) / 200.0)
+}
+
+/// Converts an envelope time in timecents (1200ths of an octave of time) into seconds
+fn timecents_to_seconds(timecents: i32) -> f64 {
+    if timecents <= MINIMUM_TIMECENTS {
+        0.0
+    } else {
+        2.0f64.powf(f64::from(timecents) / 1200.0)
+    }
+}
+
+fn parse_error(message: &str) -> SynthrsError {
+    SynthrsError::Parse(format!("invalid SoundFont: {}", message))
+}
+
+/// A RIFF chunk's four-character ID and data
+struct Chunk<'a> {
+    id: &'a [u8],
+    data: &'a [u8],
+}
+
+/// Splits RIFF data into its chunks
+fn chunks(mut data: &[u8]) -> Result<Vec<Chunk<'_>>> {
+    let mut chunks = Vec::new();
+
+    while data.len() >= 8 {
+        let size = LittleEndian::read_u32(&data[4..8]) as usize;
+        if data.len() < 8 + size {
+            return Err(parse_error("truncated chunk"));
+        }
+        chunks.push(Chunk {
+            id: &data[..4],
+            data: &data[8..8 + size],
+        });
+
+        // Chunks are padded to an even size
+        data = &data[(8 + size + size % 2).min(data.len())..];
+    }
+
+    Ok(chunks)
+}
+
+/// Returns the subchunks of the `LIST` chunk of type `kind`
+fn list<'a>(chunks: &[Chunk<'a>], kind: &[u8]) -> Result<Vec<Chunk<'a>>> {
+    match chunks
+        .iter()
+        .find(|chunk| chunk.id == b"LIST" && chunk.data.len() >= 4 && &chunk.data[..4] == kind)
+    {
+        Some(list) => self::chunks(&list.data[4..]),
+        None => Err(parse_error(&format!(
+            "missing {} list",
+            String::from_utf8_lossy(kind)
+        ))),
+    }
+}
+
+/// Returns the data of chunk `id`
+fn find<'a>(chunks: &[Chunk<'a>], id: &[u8]) -> Option<&'a [u8]> {
+    chunks
+        .iter()
+        .find(|chunk| chunk.id == id)
+        .map(|chunk| chunk.data)
+}
+
+/// Splits the data of chunk `id` into records of `size` bytes
+fn records<'a>(chunks: &[Chunk<'a>], id: &str, size: usize) -> Result<Vec<&'a [u8]>> {
+    let data =
+        find(chunks, id.as_bytes()).ok_or_else(|| parse_error(&format!("missing {}", id)))?;
+    if data.len() % size != 0 {
+        return Err(parse_error(&format!("{} has a partial record", id)));
+    }
+    Ok(data.chunks(size).collect())
+}
+
+/// Reads a null-terminated name
+fn name(data: &[u8]) -> String {
+    let end = data
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+/// Reads the zones of a preset or instrument: the bags `bags[start..end]`, whose generators and
+/// modulators are in `generators` and `modulators`. `terminal` is the generator ending a zone
+/// (the instrument of a preset zone, or the sample of an instrument zone), without which the
+/// first zone is the global zone.
+fn read_zones(
+    bags: &[(usize, usize)],
+    (start, end): (usize, usize),
+    generators: &[(u16, i16)],
+    modulators: &[Modulator],
+    terminal: u16,
+) -> Result<(Option<Zone>, Vec<Zone>)> {
+    let mut global_zone = None;
+    let mut zones = Vec::new();
+
+    for index in start..end {
+        let (bag, next) = match (bags.get(index), bags.get(index + 1)) {
+            (Some(bag), Some(next)) => (bag, next),
+            _ => return Err(parse_error("zone index out of range")),
+        };
+        let (zone_generators, zone_modulators) =
+            match (generators.get(bag.0..next.0), modulators.get(bag.1..next.1)) {
+                (Some(generators), Some(modulators)) => (generators, modulators),
+                _ => return Err(parse_error("generator or modulator index out of range")),
+            };
+
+        let mut zone = Zone {
+            generators: BTreeMap::new(),
+            modulators: zone_modulators.to_vec(),
+        };
+        // Generators after the terminal generator are ignored
+        for &(operator, value) in zone_generators {
+            zone.generators.insert(operator, value);
+            if operator == terminal {
+                break;
+            }
+        }
+
+        if zone.generators.contains_key(&terminal) {
+            zones.push(zone);
+        } else if index == start {
+            global_zone = Some(zone);
+        }
+    }
+
+    Ok((global_zone, zones))
+}
+
+/// Reads a SoundFont 2 bank.
+///
+/// ```
+/// use std::fs::File;
+/// use std::io::BufReader;
+/// use synthrs::sf2::read_sf2;
+///
+/// let mut reader = BufReader::new(File::open("tests/assets/test.sf2").unwrap());
+/// let soundfont = read_sf2(&mut reader).unwrap();
+/// assert_eq!(soundfont.name, "synthrs test");
+/// ```
+pub fn read_sf2<R>(reader: &mut R) -> Result<SoundFont>
+where
+    R: Read,
+{
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"sfbk" {
+        return Err(parse_error("file is not a SoundFont 2 bank"));
+    }
+    let size = (LittleEndian::read_u32(&bytes[4..8]) as usize).min(bytes.len() - 8);
+    if size < 4 {
+        return Err(parse_error("RIFF chunk is too small"));
+    }
+    let chunks = chunks(&bytes[12..8 + size])?;
+
+    let info = list(&chunks, b"INFO")?;
+    let sdta = list(&chunks, b"sdta")?;
+    let pdta = list(&chunks, b"pdta")?;
+
+    let name = find(&info, b"INAM").map_or_else(String::new, self::name);
+
+    // 16-bit samples, with the low byte of 24-bit samples in `sm24`
+    let smpl = find(&sdta, b"smpl").unwrap_or(&[]);
+    let sample_count = smpl.len() / 2;
+    let sample_data = match find(&sdta, b"sm24") {
+        Some(sm24) if sm24.len() >= sample_count => (0..sample_count)
+            .map(|i| {
+                let high = i32::from(LittleEndian::read_i16(&smpl[2 * i..]));
+                f64::from((high << 8) | i32::from(sm24[i])) / 8_388_608.0
+            })
+            .collect(),
+        _ => (0..sample_count)
+            .map(|i| f64::from(LittleEndian::read_i16(&smpl[2 * i..])) / 32_768.0)
+            .collect(),
+    };
+
+    let bag = |record: &[u8]| {
+        (
+            LittleEndian::read_u16(&record[0..2]) as usize,
+            LittleEndian::read_u16(&record[2..4]) as usize,
+        )
+    };
+    let generator = |record: &[u8]| {
+        (
+            LittleEndian::read_u16(&record[0..2]),
+            LittleEndian::read_i16(&record[2..4]),
+        )
+    };
+    let modulator = |record: &[u8]| Modulator {
+        source: LittleEndian::read_u16(&record[0..2]),
+        destination: LittleEndian::read_u16(&record[2..4]),
+        amount: LittleEndian::read_i16(&record[4..6]),
+        amount_source: LittleEndian::read_u16(&record[6..8]),
+        transform: LittleEndian::read_u16(&record[8..10]),
+    };
+
+    let preset_bags: Vec<(usize, usize)> =
+        records(&pdta, "pbag", 4)?.into_iter().map(bag).collect();
+    let preset_modulators: Vec<Modulator> = records(&pdta, "pmod", 10)?
+        .into_iter()
+        .map(modulator)
+        .collect();
+    let preset_generators: Vec<(u16, i16)> = records(&pdta, "pgen", 4)?
+        .into_iter()
+        .map(generator)
+        .collect();
+    let instrument_bags: Vec<(usize, usize)> =
+        records(&pdta, "ibag", 4)?.into_iter().map(bag).collect();
+    let instrument_modulators: Vec<Modulator> = records(&pdta, "imod", 10)?
+        .into_iter()
+        .map(modulator)
+        .collect();
+    let instrument_generators: Vec<(u16, i16)> = records(&pdta, "igen", 4)?
+        .into_iter()
+        .map(generator)
+        .collect();
+
+    // Every list ends with a terminal record, which only marks where the last entry's bags end
+    let preset_headers = records(&pdta, "phdr", 38)?;
+    let mut presets = Vec::new();
+    for pair in preset_headers.windows(2) {
+        let (header, next) = (pair[0], pair[1]);
+        let (global_zone, zones) = read_zones(
+            &preset_bags,
+            (
+                LittleEndian::read_u16(&header[24..26]) as usize,
+                LittleEndian::read_u16(&next[24..26]) as usize,
+            ),
+            &preset_generators,
+            &preset_modulators,
+            generator::INSTRUMENT,
+        )?;
+        presets.push(Preset {
+            name: self::name(&header[..20]),
+            program: LittleEndian::read_u16(&header[20..22]).min(127) as u8,
+            bank: LittleEndian::read_u16(&header[22..24]),
+            global_zone,
+            zones,
+        });
+    }
+
+    let instrument_headers = records(&pdta, "inst", 22)?;
+    let mut instruments = Vec::new();
+    for pair in instrument_headers.windows(2) {
+        let (header, next) = (pair[0], pair[1]);
+        let (global_zone, zones) = read_zones(
+            &instrument_bags,
+            (
+                LittleEndian::read_u16(&header[20..22]) as usize,
+                LittleEndian::read_u16(&next[20..22]) as usize,
+            ),
+            &instrument_generators,
+            &instrument_modulators,
+            generator::SAMPLE_ID,
+        )?;
+        instruments.push(Instrument {
+            name: self::name(&header[..20]),
+            global_zone,
+            zones,
+        });
+    }
+
+    let sample_headers = records(&pdta, "shdr", 46)?;
+    let samples = sample_headers[..sample_headers.len().saturating_sub(1)]
+        .iter()
+        .map(|header| SampleHeader {
+            name: self::name(&header[..20]),
+            start: LittleEndian::read_u32(&header[20..24]) as usize,
+            end: LittleEndian::read_u32(&header[24..28]) as usize,
+            loop_start: LittleEndian::read_u32(&header[28..32]) as usize,
+            loop_end: LittleEndian::read_u32(&header[32..36]) as usize,
+            sample_rate: LittleEndian::read_u32(&header[36..40]) as usize,
+            original_pitch: header[40],
+            pitch_correction: header[41] as i8,
+            sample_link: LittleEndian::read_u16(&header[42..44]),
+            sample_type: LittleEndian::read_u16(&header[44..46]),
+        })
+        .collect();
+
+    Ok(SoundFont {
+        name,
+        presets,
+        instruments,
+        samples,
+        sample_data,
+    })
+}
+
+/// Reads a SoundFont 2 bank from a file.
+///
+/// ```
+/// use synthrs::sf2::read_sf2_file;
+///
+/// let soundfont = read_sf2_file("tests/assets/test.sf2").unwrap();
+/// assert_eq!(soundfont.presets.len(), 3);
+/// ```
+pub fn read_sf2_file<P: AsRef<Path>>(path: P) -> Result<SoundFont> {
+    let mut reader = BufReader::new(File::open(path)?);
+    read_sf2(&mut reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soundfont() -> SoundFont {
+        read_sf2_file("tests/assets/test.sf2").unwrap()
+    }
+
+    #[test]
+    fn test_read_sf2() {
+        let soundfont = soundfont();
+        assert_eq!(soundfont.name, "synthrs test");
+
+        let names: Vec<(&str, u16, u8)> = soundfont
+            .presets
+            .iter()
+            .map(|preset| (preset.name.as_str(), preset.bank, preset.program))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("Sine", 0, 0),
+                ("Lead", 0, 80),
+                ("Standard", PERCUSSION_BANK, 0)
+            ]
+        );
+
+        // The first zone of "Sine" has no instrument, so it is the global zone
+        let sine = &soundfont.presets[0];
+        assert_eq!(
+            sine.global_zone
+                .as_ref()
+                .unwrap()
+                .generator(generator::INITIAL_ATTENUATION),
+            Some(40)
+        );
+        assert_eq!(sine.zones.len(), 1);
+        assert_eq!(sine.zones[0].range(generator::KEY_RANGE), Some((21, 108)));
+
+        let instrument = &soundfont.instruments[0];
+        assert_eq!(instrument.name, "Sine");
+        assert_eq!(
+            instrument.global_zone.as_ref().unwrap().modulators,
+            vec![Modulator {
+                source: 0x0502,
+                destination: generator::INITIAL_ATTENUATION,
+                amount: 960,
+                amount_source: 0,
+                transform: 0,
+            }]
+        );
+        assert_eq!(soundfont.instruments[1].global_zone, None);
+
+        assert_eq!(soundfont.samples.len(), 2);
+        let header = &soundfont.samples[0];
+        assert_eq!(header.name, "sine");
+        assert_eq!((header.start, header.end), (0, 4410));
+        assert_eq!((header.loop_start, header.loop_end), (2200, 4400));
+        assert_eq!(header.sample_rate, 44_100);
+        assert_eq!((header.original_pitch, header.pitch_correction), (69, -4));
+
+        assert_eq!(soundfont.sample_data.len(), 4410 + 46 + 2205 + 46);
+        let peak = soundfont.sample_data[..4410]
+            .iter()
+            .fold(0.0f64, |peak, sample| peak.max(sample.abs()));
+        assert!((peak - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_instrument_combines_preset_and_instrument_zones() {
+        let instrument = soundfont().instrument(0, 0).unwrap();
+        assert_eq!(instrument.zones.len(), 1);
+
+        let zone = &instrument.zones[0];
+        assert_eq!(zone.sample.len(), 4410);
+        assert_eq!(zone.sample.sample_rate, 44_100);
+        assert_eq!(zone.root_key, 69);
+        // Preset key range within the instrument's
+        assert_eq!(zone.keys, (21, 108));
+        assert_eq!(zone.velocities, (0, 127));
+        // Fine tune and the sample's pitch correction
+        assert!((zone.tune - 6.0).abs() < 1e-9);
+        // Instrument and preset attenuation add up to 10 dB
+        assert!((zone.gain - 10.0f64.powf(-0.5)).abs() < 1e-9);
+        assert_eq!(zone.loop_mode, LoopMode::Continuous);
+        assert_eq!(zone.loop_points, Some((2200, 4400)));
+
+        let envelope = zone.envelope.unwrap();
+        assert!((envelope.release - 1.0).abs() < 1e-9);
+        assert!(envelope.attack < 1e-9);
+        assert!((envelope.sustain - 1.0).abs() < 1e-9);
+
+        // "Lead" plays the same instrument an octave up
+        let lead = soundfont().instrument(0, 80).unwrap();
+        assert!((lead.zones[0].tune - 1206.0).abs() < 1e-9);
+        assert!((lead.zones[0].gain - 10.0f64.powf(-0.3)).abs() < 1e-9);
+
+        // Unpitched samples use the overriding root key
+        let kit = soundfont().instrument(PERCUSSION_BANK, 0).unwrap();
+        let zone = &kit.zones[0];
+        assert_eq!(zone.root_key, 60);
+        assert_eq!(zone.sample.sample_rate, 22_050);
+        assert_eq!(zone.loop_mode, LoopMode::NoLoop);
+        assert!((zone.envelope.unwrap().decay - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_instrument_falls_back_to_general_midi_presets() {
+        let soundfont = soundfont();
+        let lead = soundfont.instrument(0, 80).unwrap();
+
+        // A missing bank falls back to bank 0, and missing kits to the standard kit
+        assert_eq!(soundfont.instrument(8, 80).unwrap().zones, lead.zones);
+        assert_eq!(
+            soundfont.instrument(PERCUSSION_BANK, 16).unwrap().zones,
+            soundfont.instrument(PERCUSSION_BANK, 0).unwrap().zones
+        );
+        assert!(soundfont.instrument(0, 1).is_none());
+        assert!(soundfont.preset(8, 80).is_none());
+    }
+
+    #[test]
+    fn test_read_sf2_errors() {
+        let mut wave = File::open("tests/assets/sine.wav").unwrap();
+        assert!(read_sf2(&mut wave).is_err());
+
+        // Truncated in the middle of the preset data
+        let mut bytes = Vec::new();
+        File::open("tests/assets/test.sf2")
+            .unwrap()
+            .read_to_end(&mut bytes)
+            .unwrap();
+        let mut truncated = bytes.clone();
+        truncated.truncate(bytes.len() - 100);
+        assert!(read_sf2(&mut truncated.as_slice()).is_err());
+
+        // RIFF size too small to hold the form type
+        for size in 0..4u32 {
+            LittleEndian::write_u32(&mut bytes[4..8], size);
+            assert!(read_sf2(&mut bytes.as_slice()).is_err());
+        }
+    }
+}
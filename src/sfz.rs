@@ -0,0 +1,569 @@
+//! Loads SFZ instrument files into multi-sampled `SampleInstrument`s.
+//!
+//! SFZ is a plain-text format of headers (`<region>`, `<group>`, ...) each followed by
+//! `opcode=value` pairs. Every `<region>` becomes a `SampleZone`, taking any opcode it does not
+//! set itself from the enclosing `<group>`, `<master>` and `<global>` headers. Samples are loaded
+//! from WAV files relative to the SFZ file (and `default_path` in `<control>`), and each file is
+//! only loaded once, however many regions use it.
+//!
+//! Supported opcodes:
+//!
+//! * `sample`
+//! * `lokey`, `hikey`, `key`, `pitch_keycenter` (as numbers or note names, `c4` being 60)
+//! * `lovel`, `hivel`
+//! * `tune` (cents), `transpose` (semitones), `volume` (dB), `amplitude` (percent)
+//! * `loop_mode` (`no_loop`, `one_shot`, `loop_continuous`, `loop_sustain`), `loop_start` and
+//!   `loop_end` (also `loopstart` and `loopend`)
+//! * `ampeg_attack`, `ampeg_decay`, `ampeg_sustain` (percent), `ampeg_release`
+//! * `default_path`, `note_offset` and `octave_offset` in `<control>`
+//! * `#define $NAME value`
+//!
+//! Other opcodes are ignored, as SFZ players do with opcodes they do not support.
+//!
+//! ```
+//! use std::collections::BTreeMap;
+//! use std::path::Path;
+//! use synthrs::sfz::read_sfz;
+//! use synthrs::synthesizer::make_stereo_samples_from_midi_with_samplers;
+//! use synthrs::midi;
+//!
+//! let sfz = "
+//!     <group> ampeg_release=0.2
+//!     <region> sample=sine.wav pitch_keycenter=a4 lokey=0 hikey=127
+//! ";
+//! let instrument = read_sfz(sfz, Path::new("tests/assets")).unwrap();
+//!
+//! // Play MIDI channel 1 with the SFZ instrument
+//! let mut instruments = BTreeMap::new();
+//! instruments.insert(0, instrument);
+//! let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+//! let samples =
+//!     make_stereo_samples_from_midi_with_samplers(&instruments, 44_100, false, song).unwrap();
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::errors::{Result, SynthrsError};
+use crate::filter::Adsr;
+use crate::sample::{LoopMode, Sample, SampleInstrument, SampleZone};
+use crate::writer::read_wav;
+
+/// Opcodes of a single header
+pub type Opcodes = BTreeMap<String, String>;
+
+/// A `<region>` of an SFZ file, with the opcodes it inherits from its enclosing headers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Region {
+    pub opcodes: Opcodes,
+}
+
+impl Region {
+    fn get(&self, opcode: &str) -> Option<&str> {
+        self.opcodes.get(opcode).map(String::as_str)
+    }
+
+    fn number(&self, opcode: &str) -> Result<Option<f64>> {
+        self.get(opcode)
+            .map(|value| value.parse::<f64>().map_err(|_| parse_error(opcode, value)))
+            .transpose()
+    }
+
+    fn key(&self, opcode: &str) -> Result<Option<i32>> {
+        self.get(opcode)
+            .map(|value| parse_key(value).ok_or_else(|| parse_error(opcode, value)))
+            .transpose()
+    }
+}
+
+fn parse_error(opcode: &str, value: &str) -> SynthrsError {
+    SynthrsError::Parse(format!("invalid SFZ value {}={}", opcode, value))
+}
+
+/// Parses a MIDI note number (`60`) or note name (`c4`, `c#4`, `db4`), middle C being `c4`
+fn parse_key(value: &str) -> Option<i32> {
+    if let Ok(number) = value.parse::<i32>() {
+        return Some(number);
+    }
+
+    let value = value.to_lowercase();
+    let mut chars = value.chars();
+    let semitone = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+
+    let rest = chars.as_str();
+    let (accidental, octave) = if let Some(octave) = rest.strip_prefix('#') {
+        (1, octave)
+    } else if let Some(octave) = rest.strip_prefix('b') {
+        (-1, octave)
+    } else {
+        (0, rest)
+    };
+
+    let octave = octave.parse::<i32>().ok()?;
+    Some((octave + 1) * 12 + semitone + accidental)
+}
+
+/// Removes `//` line comments and `/* */` block comments
+fn strip_comments(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("//") {
+            rest = after.find('\n').map_or("", |end| &after[end..]);
+        } else if let Some(after) = rest.strip_prefix("/*") {
+            rest = after.find("*/").map_or("", |end| &after[end + 2..]);
+            output.push(' ');
+        } else {
+            let next = rest.chars().next().unwrap();
+            output.push(next);
+            rest = &rest[next.len_utf8()..];
+        }
+    }
+
+    output
+}
+
+/// Applies `#define $NAME value` definitions, removing them from the text
+fn apply_defines(text: &str) -> Result<String> {
+    let mut defines: Vec<(String, String)> = Vec::new();
+    let mut lines = Vec::new();
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if let Some(definition) = trimmed.strip_prefix("#define") {
+            let mut parts = definition.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.starts_with('$') => {
+                    defines.push((name.to_string(), value.to_string()));
+                }
+                _ => return Err(SynthrsError::Parse(format!("invalid SFZ {}", trimmed))),
+            }
+        } else if trimmed.starts_with('#') {
+            return Err(SynthrsError::Parse(format!(
+                "unsupported SFZ directive {}",
+                trimmed
+            )));
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+
+    // Longest names first, so that `$A` does not replace the start of `$AB`
+    defines.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+    Ok(lines
+        .into_iter()
+        .map(|line| {
+            defines
+                .iter()
+                .fold(line, |line, (name, value)| line.replace(name, value))
+        })
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+/// A header or opcode in an SFZ file
+#[derive(Debug, PartialEq)]
+enum Token {
+    Header(String),
+    Opcode(String, String),
+}
+
+/// Splits SFZ text (without comments) into headers and opcodes. Values of `sample` opcodes run
+/// until the next opcode, header or end of line, so that file names can contain spaces.
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+
+    for line in text.lines() {
+        let mut rest = line.trim_start();
+
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('<') {
+                let end = after
+                    .find('>')
+                    .ok_or_else(|| SynthrsError::Parse(format!("unclosed SFZ header {}", rest)))?;
+                tokens.push(Token::Header(after[..end].trim().to_string()));
+                rest = after[end + 1..].trim_start();
+                continue;
+            }
+
+            let equals = rest.find('=').ok_or_else(|| {
+                SynthrsError::Parse(format!("expected an SFZ opcode, found {}", rest))
+            })?;
+            let opcode = rest[..equals].trim().to_string();
+            let after = &rest[equals + 1..];
+
+            let end = if opcode == "sample" {
+                value_end(after)
+            } else {
+                after
+                    .find(|c: char| c.is_whitespace() || c == '<')
+                    .unwrap_or(after.len())
+            };
+
+            tokens.push(Token::Opcode(opcode, after[..end].trim().to_string()));
+            rest = after[end..].trim_start();
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// End of a value that may contain spaces: the start of the next `opcode=` or header
+fn value_end(text: &str) -> usize {
+    let mut end = text.len();
+
+    if let Some(header) = text.find('<') {
+        end = header;
+    }
+
+    // The last whitespace-separated word before an `=` starts the next opcode
+    if let Some(equals) = text[..end].find('=') {
+        if let Some(space) = text[..equals].rfind(char::is_whitespace) {
+            end = space;
+        }
+    }
+
+    end
+}
+
+/// Parses SFZ text into its regions, along with the opcodes of the `<control>` header.
+///
+/// ```
+/// use synthrs::sfz::parse_sfz;
+///
+/// let (control, regions) = parse_sfz("
+///     <control> default_path=samples/
+///     <group> lovel=64 // loud layer
+///     <region> sample=piano c4.wav key=60
+///     <region> sample=piano c5.wav key=72 lovel=100
+/// ").unwrap();
+///
+/// assert_eq!(control["default_path"], "samples/");
+/// assert_eq!(regions[0].opcodes["sample"], "piano c4.wav");
+/// assert_eq!(regions[0].opcodes["lovel"], "64");
+/// assert_eq!(regions[1].opcodes["lovel"], "100");
+/// ```
+pub fn parse_sfz(text: &str) -> Result<(Opcodes, Vec<Region>)> {
+    let text = apply_defines(&strip_comments(text))?;
+
+    let mut control = Opcodes::new();
+    let mut global = Opcodes::new();
+    let mut master = Opcodes::new();
+    let mut group = Opcodes::new();
+    let mut regions: Vec<Region> = Vec::new();
+    let mut header = String::new();
+
+    for token in tokenize(&text)? {
+        match token {
+            Token::Header(name) => {
+                match name.as_str() {
+                    "global" => {
+                        global.clear();
+                        master.clear();
+                        group.clear();
+                    }
+                    "master" => {
+                        master.clear();
+                        group.clear();
+                    }
+                    "group" => group.clear(),
+                    "region" => {
+                        let mut opcodes = global.clone();
+                        opcodes.extend(master.clone());
+                        opcodes.extend(group.clone());
+                        regions.push(Region { opcodes });
+                    }
+                    _ => {}
+                }
+                header = name;
+            }
+            Token::Opcode(opcode, value) => {
+                let opcodes = match header.as_str() {
+                    "control" => &mut control,
+                    "global" => &mut global,
+                    "master" => &mut master,
+                    "group" => &mut group,
+                    "region" => &mut regions.last_mut().unwrap().opcodes,
+                    // Opcodes of unsupported headers (such as <curve> and <effect>) are ignored
+                    _ => continue,
+                };
+                opcodes.insert(opcode, value);
+            }
+        }
+    }
+
+    Ok((control, regions))
+}
+
+/// Builds a `SampleInstrument` from SFZ text, loading samples relative to `directory`.
+pub fn read_sfz(text: &str, directory: &Path) -> Result<SampleInstrument> {
+    let (control, regions) = parse_sfz(text)?;
+
+    let control = Region { opcodes: control };
+    let default_path = control.get("default_path").unwrap_or("").replace('\\', "/");
+    let note_offset = control.number("note_offset")?.unwrap_or(0.0) as i32
+        + 12 * control.number("octave_offset")?.unwrap_or(0.0) as i32;
+
+    let mut samples: HashMap<PathBuf, Sample> = HashMap::new();
+    let mut zones = Vec::new();
+
+    for region in regions {
+        let name = match region.get("sample") {
+            Some(name) => name.replace('\\', "/"),
+            None => continue,
+        };
+        let path = directory.join(&default_path).join(name);
+
+        let sample = match samples.get(&path) {
+            Some(sample) => sample.clone(),
+            None => {
+                let sample = load_sample(&path)?;
+                samples.insert(path, sample.clone());
+                sample
+            }
+        };
+
+        zones.push(region_zone(&region, sample, note_offset)?);
+    }
+
+    Ok(SampleInstrument::new(zones))
+}
+
+/// Loads an SFZ file into a `SampleInstrument`. Samples are loaded relative to the file.
+pub fn read_sfz_file<P: AsRef<Path>>(path: P) -> Result<SampleInstrument> {
+    let path = path.as_ref();
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+
+    read_sfz(&text, path.parent().unwrap_or_else(|| Path::new("")))
+}
+
+fn load_sample(path: &Path) -> Result<Sample> {
+    let file = File::open(path)?;
+    let wave = read_wav(&mut BufReader::new(file))?;
+    // The root pitch is set by each zone
+    Ok(Sample::from_wave(wave, 0.0))
+}
+
+fn region_zone(region: &Region, sample: Sample, note_offset: i32) -> Result<SampleZone> {
+    // `note_offset` is added to incoming notes before they are matched against regions, so
+    // regions are mapped to the played notes `note_offset` below them. The root key moves with
+    // them, which transposes the sound by `note_offset`.
+    let played = |key: i32| key - note_offset;
+    let key = region.key("key")?;
+    let root_key = played(region.key("pitch_keycenter")?.or(key).unwrap_or(60));
+
+    let mut zone = SampleZone::new(sample, root_key.clamp(0, 127) as u8);
+    zone.keys = (
+        played(region.key("lokey")?.or(key).unwrap_or(0)).clamp(0, 127) as u8,
+        played(region.key("hikey")?.or(key).unwrap_or(127)).clamp(0, 127) as u8,
+    );
+
+    let velocity = |opcode: &str, default: f64| -> Result<u8> {
+        Ok(region.number(opcode)?.unwrap_or(default).clamp(0.0, 127.0) as u8)
+    };
+    zone.velocities = (velocity("lovel", 0.0)?, velocity("hivel", 127.0)?);
+
+    // Root keys moved out of the MIDI range are made up for by tuning
+    zone.tune = region.number("tune")?.unwrap_or(0.0)
+        + 100.0 * region.number("transpose")?.unwrap_or(0.0)
+        + 100.0 * f64::from(i32::from(zone.root_key) - root_key);
+    zone.gain = 10.0f64.powf(region.number("volume")?.unwrap_or(0.0) / 20.0)
+        * region.number("amplitude")?.unwrap_or(100.0)
+        / 100.0;
+
+    let loop_start = region.number("loop_start")?.or(region.number("loopstart")?);
+    let loop_end = region.number("loop_end")?.or(region.number("loopend")?);
+    if let (Some(start), Some(end)) = (loop_start, loop_end) {
+        // SFZ loop ends are inclusive
+        zone.loop_points = Some((start.max(0.0) as usize, end.max(0.0) as usize + 1));
+    }
+
+    zone.loop_mode = match region.get("loop_mode").or_else(|| region.get("loopmode")) {
+        None if zone.loop_points.is_some() => LoopMode::Continuous,
+        None | Some("no_loop") => LoopMode::NoLoop,
+        Some("one_shot") => LoopMode::OneShot,
+        Some("loop_continuous") => LoopMode::Continuous,
+        Some("loop_sustain") => LoopMode::Sustain,
+        Some(value) => return Err(parse_error("loop_mode", value)),
+    };
+
+    let envelope_opcodes = [
+        "ampeg_attack",
+        "ampeg_decay",
+        "ampeg_sustain",
+        "ampeg_release",
+    ];
+    if envelope_opcodes
+        .iter()
+        .any(|opcode| region.get(opcode).is_some())
+    {
+        zone.envelope = Some(Adsr::new(
+            region.number("ampeg_attack")?.unwrap_or(0.0),
+            region.number("ampeg_decay")?.unwrap_or(0.0),
+            region.number("ampeg_sustain")?.unwrap_or(100.0) / 100.0,
+            region.number("ampeg_release")?.unwrap_or(0.0),
+        ));
+    }
+
+    Ok(zone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("60"), Some(60));
+        assert_eq!(parse_key("c4"), Some(60));
+        assert_eq!(parse_key("C#4"), Some(61));
+        assert_eq!(parse_key("db4"), Some(61));
+        assert_eq!(parse_key("a4"), Some(69));
+        assert_eq!(parse_key("c-1"), Some(0));
+        assert_eq!(parse_key("h4"), None);
+        assert_eq!(parse_key("c"), None);
+    }
+
+    #[test]
+    fn test_parse_headers_and_inheritance() {
+        let (control, regions) = parse_sfz(
+            "
+            /* A block
+               comment */
+            #define $LOUD 100
+            <control> default_path=samples\\ note_offset=12
+            <global> volume=-6
+            <group> lovel=$LOUD hivel=127 ampeg_release=0.5
+            <region> sample=a b.wav lokey=c4 hikey=b4 pitch_keycenter=60 // soft
+            <region>sample=c.wav key=72 volume=0
+            <group> lovel=0
+            <region> sample=d.wav
+            <curve> v000=0
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(control["default_path"], "samples\\");
+        assert_eq!(regions.len(), 3);
+
+        let first = &regions[0].opcodes;
+        assert_eq!(first["sample"], "a b.wav");
+        assert_eq!(first["lovel"], "100");
+        assert_eq!(first["volume"], "-6");
+        assert_eq!(first["ampeg_release"], "0.5");
+        assert_eq!(first["hikey"], "b4");
+
+        assert_eq!(regions[1].opcodes["volume"], "0");
+        assert_eq!(regions[1].opcodes["key"], "72");
+
+        // A new group replaces the previous group's opcodes
+        assert_eq!(regions[2].opcodes["lovel"], "0");
+        assert!(!regions[2].opcodes.contains_key("hivel"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_sfz("<region sample=a.wav").is_err());
+        assert!(parse_sfz("<region> sample").is_err());
+        assert!(parse_sfz("#include \"other.sfz\"").is_err());
+        assert!(read_sfz(
+            "<region> sample=sine.wav lokey=x9",
+            Path::new("tests/assets")
+        )
+        .is_err());
+        assert!(read_sfz("<region> sample=missing.wav", Path::new("tests/assets")).is_err());
+    }
+
+    #[test]
+    fn test_read_sfz() {
+        let instrument = read_sfz(
+            "
+            <control> octave_offset=-1
+            <group> lovel=0 hivel=63 ampeg_attack=0.01 ampeg_sustain=50 ampeg_release=0.3
+            <region> sample=sine.wav lokey=c5 hikey=b5 pitch_keycenter=a5 tune=-10 volume=-6
+            <group> lovel=64 hivel=127
+            <region> sample=sine.wav key=a5 loop_start=100 loop_end=199 loop_mode=loop_sustain
+            <region> sample=sine.wav key=a6 loop_mode=one_shot transpose=-1 amplitude=50
+            ",
+            Path::new("tests/assets"),
+        )
+        .unwrap();
+
+        assert_eq!(instrument.zones.len(), 3);
+
+        // Samples used by several regions are only loaded once
+        let first = &instrument.zones[0];
+        let second = &instrument.zones[1];
+        assert_eq!(first.sample, second.sample);
+        assert!(std::ptr::eq(
+            first.sample.samples().as_ptr(),
+            second.sample.samples().as_ptr()
+        ));
+
+        // An octave offset of -1 moves c5 to b5 up to be played by c6 to b6 (84 to 95), and
+        // they sound an octave lower
+        assert_eq!(first.keys, (84, 95));
+        assert_eq!(first.root_key, 93);
+        assert_eq!(first.velocities, (0, 63));
+        assert!((first.tune - -10.0).abs() < 1e-12);
+        assert!((first.gain - 10.0f64.powf(-6.0 / 20.0)).abs() < 1e-12);
+        assert_eq!(first.loop_mode, LoopMode::NoLoop);
+        assert_eq!(first.envelope, Some(Adsr::new(0.01, 0.0, 0.5, 0.3)));
+
+        assert_eq!(second.keys, (93, 93));
+        assert_eq!(second.velocities, (64, 127));
+        assert_eq!(second.loop_mode, LoopMode::Sustain);
+        assert_eq!(second.loop_points, Some((100, 200)));
+        assert_eq!(second.envelope, None);
+
+        let third = &instrument.zones[2];
+        assert_eq!(third.root_key, 105);
+        assert_eq!(third.loop_mode, LoopMode::OneShot);
+        assert!((third.tune - -100.0).abs() < 1e-12);
+        assert!((third.gain - 0.5).abs() < 1e-12);
+
+        assert_eq!(instrument.zone(93, 100).unwrap().root_key, 93);
+        assert_eq!(instrument.zone(89, 30).unwrap().root_key, 93);
+
+        // Root keys shifted past the MIDI range are tuned instead
+        let instrument = read_sfz(
+            "<control> note_offset=-10 <region> sample=sine.wav pitch_keycenter=120",
+            Path::new("tests/assets"),
+        )
+        .unwrap();
+        assert_eq!(instrument.zones[0].root_key, 127);
+        assert!((instrument.zones[0].tune - -300.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_read_sfz_file() {
+        let path = std::env::temp_dir().join("synthrs_test_read_sfz_file.sfz");
+        let assets = std::env::current_dir().unwrap().join("tests/assets");
+        std::fs::write(
+            &path,
+            format!(
+                "<control> default_path={}/\n<region> sample=sine.wav",
+                assets.display()
+            ),
+        )
+        .unwrap();
+
+        let instrument = read_sfz_file(&path).unwrap();
+        assert_eq!(instrument.zones.len(), 1);
+        assert_eq!(instrument.zones[0].root_key, 60);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -17,56 +17,64 @@
 //!
 //! See: `examples/simple.rs`
 
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
 use std::iter::Iterator;
-use std::mem::size_of;
-
-use num::traits::{Bounded, FromPrimitive, Num, ToPrimitive, Zero};
-use num::Float;
 
+use crate::analysis::{self, Normalization};
+use crate::dither::{Dither, Ditherer, NoiseShaping};
+use crate::drums::DrumKit;
 use crate::errors::SynthrsError;
 use crate::filter;
+use crate::format::SampleFormat;
 use crate::midi;
 use crate::music;
+use crate::sample::{LoopMode, SampleInstrument};
+use crate::sf2::{SoundFont, PERCUSSION_BANK};
 
 /// Quantizes a `f64` sample into `T`.
-/// Convert from [-1.0f64, 1.0] to take up full quantization range of type `T`.
-/// Behaviour is not guaranteed for `T` where `T` is unsigned.
+/// Convert from [-1.0f64, 1.0] to take up full quantization range of type `T`, rounding to the
+/// nearest level. Out-of-range input is clipped. See `crate::format` for the supported formats.
 ///
 /// ```
 /// use synthrs::synthesizer::quantize;
+/// use synthrs::format::I24;
 ///
 /// assert_eq!(quantize::<i8>(1.0f64), 127i8);
+/// assert_eq!(quantize::<i8>(-1.0f64), -128i8);
 /// assert_eq!(quantize::<f32>(0.0f64), 0.0f32);
-/// assert_eq!(quantize::<u8>(1.0f64), 127u8); // half of available quantization space of a u8 (255)
-/// // quantize::<u8>(-1.0f64); // panics
+/// assert_eq!(quantize::<u8>(1.0f64), 255u8); // offset binary
+/// assert_eq!(quantize::<u8>(-1.0f64), 0u8);
+/// assert_eq!(quantize::<I24>(2.0f64), I24::MAX); // clipped
 /// ```
 pub fn quantize<T>(input: f64) -> T
 where
-    T: Num + FromPrimitive + Bounded + Zero,
+    T: SampleFormat,
 {
-    let quantization_levels = 2.0.powf(size_of::<T>() as f64 * 8.0) - 1.0;
-    // defaults to 0 on quantization failure for whatever reason
-    T::from_f64(input * (quantization_levels / 2.0)).unwrap_or_else(T::zero)
+    T::from_sample(input)
 }
 
 /// Reverses a quantization from `T` into `f64`.
-/// Convert from `T` to take up full quantization range of `f64`.
+/// Convert from `T` to take up full quantization range of `f64`. `quantize` is the exact inverse
+/// of this function.
 ///
 /// Note: This does not attempt to recover any loss of information from prior quantization processes.
 ///
 /// ```
-/// use synthrs::synthesizer::unquantize;
+/// use synthrs::synthesizer::{quantize, unquantize};
 ///
 /// assert_eq!(unquantize(&0.0f32), 0.0f64);
-/// assert_eq!(unquantize(&0u8), 0.0f64);
+/// assert_eq!(unquantize(&128u8), 0.0f64);
 /// assert_eq!(unquantize(&0i16), 0.0f64);
+/// assert_eq!(unquantize(&i16::MIN), -1.0f64);
+/// assert_eq!(quantize::<i16>(unquantize(&1234i16)), 1234i16);
 /// ```
 pub fn unquantize<T>(input: &T) -> f64
 where
-    T: Num + ToPrimitive + Bounded + Zero,
+    T: SampleFormat,
 {
-    let quantization_levels = 2.0.powf(size_of::<T>() as f64 * 8.0 - 1.0);
-    T::to_f64(&input).unwrap_or(0.0) * (quantization_levels / 2.0)
+    input.to_sample()
 }
 
 /// Quantizes a `Vec<f64>` of samples into `Vec<T>`.
@@ -81,11 +89,53 @@ where
 /// ```
 pub fn quantize_samples<T>(input: &[f64]) -> Vec<T>
 where
-    T: Num + FromPrimitive + Bounded + Zero,
+    T: SampleFormat,
 {
     input.iter().map(|s| quantize::<T>(*s)).collect()
 }
 
+/// Quantizes a `Vec<f64>` of samples into `Vec<T>` like `quantize_samples`, adding `dither`
+/// noise and shaping the quantization noise before rounding. Floating point formats are not
+/// dithered.
+///
+/// The dither noise is generated from `seed`, so the same input and seed always give the same
+/// output. See `crate::dither`.
+///
+/// ```
+/// use synthrs::dither::{Dither, NoiseShaping};
+/// use synthrs::wave::sine_wave;
+/// use synthrs::synthesizer::{quantize_samples_dithered, make_samples};
+///
+/// // A quiet fade-out that would otherwise turn into distortion at 8 bits
+/// let fade = make_samples(1.0, 44_100, |t| (1.0 - t) * 0.01 * sine_wave(440.0)(t));
+/// let quantized = quantize_samples_dithered::<i8>(&fade, Dither::Triangular, NoiseShaping::None, 0);
+/// ```
+pub fn quantize_samples_dithered<T>(
+    input: &[f64],
+    dither: Dither,
+    noise_shaping: NoiseShaping,
+    seed: u64,
+) -> Vec<T>
+where
+    T: SampleFormat,
+{
+    // Floating point formats have no quantization levels to dither
+    if T::IS_FLOAT {
+        return quantize_samples(input);
+    }
+
+    let scale = 2.0f64.powi(T::BITS as i32 - 1);
+    let mut ditherer = Ditherer::new(dither, noise_shaping, seed);
+
+    input
+        .iter()
+        .map(|&sample| {
+            let level = ditherer.tick(sample * scale, -scale, scale - 1.0);
+            T::from_sample(level / scale)
+        })
+        .collect()
+}
+
 /// Reverses quantization of `Vec<T>` into a `Vec<f64>`.
 ///
 /// Note: This does not attempt to recover any loss of information from prior quantization processes.
@@ -97,9 +147,9 @@ where
 /// ```
 pub fn unquantize_samples<T>(input: &[T]) -> Vec<f64>
 where
-    T: Num + ToPrimitive + Bounded + Zero,
+    T: SampleFormat,
 {
-    input.iter().map(|s| unquantize::<T>(&*s)).collect()
+    input.iter().map(|s| unquantize::<T>(s)).collect()
 }
 
 /// Invokes the waveform function `f` at time `t` to return the amplitude at that time.
@@ -160,12 +210,15 @@ where
 pub struct SamplesIter {
     i: u64,
     sample_rate: u64,
-    waveform: Box<Fn(f64) -> f64 + Send + 'static>,
+    waveform: Box<dyn Fn(f64) -> f64 + Send + 'static>,
 }
 
 impl SamplesIter {
     /// Returns an iterator that generates samples for the waveform at the given sample rate
-    pub fn new(sample_rate: u64, waveform: Box<Fn(f64) -> f64 + Send + 'static>) -> SamplesIter {
+    pub fn new(
+        sample_rate: u64,
+        waveform: Box<dyn Fn(f64) -> f64 + Send + 'static>,
+    ) -> SamplesIter {
         SamplesIter {
             i: 0,
             sample_rate,
@@ -186,6 +239,9 @@ impl Iterator for SamplesIter {
 
 /// Peak normalizes a `Vec<f64>` of samples such that the maximum and minimum amplitudes of the
 /// `Vec<f64>` samples are within the range [-1.0, 1.0]
+/// Silent input is returned unchanged.
+///
+/// For other level targets, such as RMS or loudness, see `crate::analysis`.
 ///
 /// ```
 /// use synthrs::synthesizer::{make_samples, peak_normalize};
@@ -199,9 +255,190 @@ pub fn peak_normalize(samples: &[f64]) -> Vec<f64> {
         .iter()
         .fold(0.0f64, |acc, &sample| acc.max(sample).max(-sample));
 
+    if peak == 0.0 {
+        return samples.to_vec();
+    }
+
     samples.iter().map(|&sample| sample / peak).collect()
 }
 
+/// Returns the `(left, right)` gains for a constant-power pan. `pan` ranges from -1.0 (hard left)
+/// through 0.0 (center) to 1.0 (hard right), and is clamped to that range.
+///
+/// ```
+/// use synthrs::synthesizer::pan_gains;
+///
+/// let (left, right) = pan_gains(0.0);
+/// assert!((left - right).abs() < 1e-12);
+/// assert!((left * left + right * right - 1.0).abs() < 1e-12);
+/// ```
+pub fn pan_gains(pan: f64) -> (f64, f64) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * PI / 4.0;
+    (angle.cos(), angle.sin())
+}
+
+/// A mono source on a `Mixer`, such as a rendered instrument track.
+pub struct MixerSource {
+    /// Mono samples to be mixed
+    pub samples: Vec<f64>,
+    /// Linear gain applied before panning and sends (1.0 is unity)
+    pub gain: f64,
+    /// Constant-power pan from -1.0 (left) to 1.0 (right), see `pan_gains`
+    pub pan: f64,
+    pub mute: bool,
+    /// If any source is soloed, only soloed sources are heard
+    pub solo: bool,
+    /// `(bus index, send level)` pairs. Sends are taken post-gain and pre-pan.
+    pub sends: Vec<(usize, f64)>,
+}
+
+impl MixerSource {
+    /// Creates a centered source at unity gain with no sends.
+    pub fn new(samples: Vec<f64>) -> MixerSource {
+        MixerSource {
+            samples,
+            gain: 1.0,
+            pan: 0.0,
+            mute: false,
+            solo: false,
+            sends: Vec::new(),
+        }
+    }
+}
+
+/// An effect bus on a `Mixer`. Sources feed the bus through sends; the bus runs the summed mono
+/// send signal through its effect and returns it to the stereo mix.
+pub struct MixerBus {
+    effect: Box<dyn FnMut(f64) -> f64>,
+    /// Linear return gain
+    pub gain: f64,
+    /// Constant-power pan of the bus return
+    pub pan: f64,
+    pub mute: bool,
+}
+
+/// Mixes mono sources down into an interleaved stereo buffer (`[l0, r0, l1, r1, ...]`).
+///
+/// Each source has gain, constant-power pan, mute/solo and send levels to any number of effect
+/// buses. Any stateful filter can be used as a bus effect.
+///
+/// ```
+/// use synthrs::filter::Comb;
+/// use synthrs::synthesizer::{make_samples, Mixer};
+/// use synthrs::wave::{sine_wave, square_wave};
+///
+/// let mut mixer = Mixer::new();
+///
+/// let mut comb = Comb::new(0.2, 44_100, 0.5, 0.5, 0.5);
+/// let reverb = mixer.add_bus(Box::new(move |sample| comb.tick(sample)));
+///
+/// let lead = mixer.add_source(make_samples(0.1, 44_100, square_wave(440.0)));
+/// mixer.sources[lead].gain = 0.5;
+/// mixer.sources[lead].pan = -0.5;
+/// mixer.sources[lead].sends.push((reverb, 0.3));
+///
+/// let bass = mixer.add_source(make_samples(0.1, 44_100, sine_wave(110.0)));
+/// mixer.sources[bass].pan = 0.25;
+///
+/// let stereo = mixer.render();
+/// assert_eq!(stereo.len(), 2 * 4410);
+/// ```
+pub struct Mixer {
+    pub sources: Vec<MixerSource>,
+    pub buses: Vec<MixerBus>,
+    /// Linear gain applied to the final stereo mix
+    pub master_gain: f64,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mixer {
+    /// Creates an empty mixer at unity master gain.
+    pub fn new() -> Mixer {
+        Mixer {
+            sources: Vec::new(),
+            buses: Vec::new(),
+            master_gain: 1.0,
+        }
+    }
+
+    /// Adds a centered source at unity gain and returns its index into `sources`.
+    pub fn add_source(&mut self, samples: Vec<f64>) -> usize {
+        self.sources.push(MixerSource::new(samples));
+        self.sources.len() - 1
+    }
+
+    /// Adds a centered effect bus at unity gain and returns its index into `buses`.
+    pub fn add_bus(&mut self, effect: Box<dyn FnMut(f64) -> f64>) -> usize {
+        self.buses.push(MixerBus {
+            effect,
+            gain: 1.0,
+            pan: 0.0,
+            mute: false,
+        });
+        self.buses.len() - 1
+    }
+
+    /// Renders all sources and buses into an interleaved stereo buffer as long as the longest
+    /// source. Bus effects keep their state between calls.
+    pub fn render(&mut self) -> Vec<f64> {
+        let length = self
+            .sources
+            .iter()
+            .map(|source| source.samples.len())
+            .max()
+            .unwrap_or(0);
+        let any_solo = self.sources.iter().any(|source| source.solo);
+
+        let mut output = vec![0.0; length * 2];
+        let mut bus_inputs = vec![vec![0.0; length]; self.buses.len()];
+
+        for source in &self.sources {
+            if source.mute || (any_solo && !source.solo) {
+                continue;
+            }
+
+            let (left, right) = pan_gains(source.pan);
+
+            for (i, &sample) in source.samples.iter().enumerate() {
+                let sample = sample * source.gain;
+                output[2 * i] += sample * left;
+                output[2 * i + 1] += sample * right;
+
+                for &(bus, level) in &source.sends {
+                    if let Some(bus_input) = bus_inputs.get_mut(bus) {
+                        bus_input[i] += sample * level;
+                    }
+                }
+            }
+        }
+
+        for (bus, bus_input) in self.buses.iter_mut().zip(bus_inputs.iter()) {
+            let (left, right) = pan_gains(bus.pan);
+
+            // Effects are stateful so they are always run, even when the return is muted
+            for (i, &sample) in bus_input.iter().enumerate() {
+                let processed = (bus.effect)(sample) * bus.gain;
+
+                if !bus.mute {
+                    output[2 * i] += processed * left;
+                    output[2 * i + 1] += processed * right;
+                }
+            }
+        }
+
+        for sample in &mut output {
+            *sample *= self.master_gain;
+        }
+
+        output
+    }
+}
+
 // This is really awful, is there a more elegant way to do this?
 /// Generates samples from a MIDI file
 ///
@@ -263,6 +500,60 @@ pub fn make_samples_from_midi<F1, F2>(
     use_envelope: bool,
     song: midi::MidiSong,
 ) -> Result<Vec<f64>, SynthrsError>
+where
+    F1: Fn(f64) -> F2,
+    F2: Fn(f64) -> f64,
+{
+    Ok(peak_normalize(&render_midi(
+        instrument,
+        sample_rate,
+        use_envelope,
+        song,
+    )))
+}
+
+/// Generates samples from a MIDI song like `make_samples_from_midi`, but normalizes the output
+/// to a level target instead of peak normalizing it. Loudness targets keep renders at a
+/// consistent perceived level regardless of their loudest transient.
+///
+/// ```
+/// use synthrs::analysis::Normalization;
+/// use synthrs::synthesizer::make_samples_from_midi_normalized;
+/// use synthrs::midi;
+/// use synthrs::wave;
+///
+/// let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+///
+/// let samples = make_samples_from_midi_normalized(
+///     wave::sine_wave,
+///     44_100,
+///     true,
+///     song,
+///     Normalization::Loudness(-14.0),
+/// ).unwrap();
+/// ```
+pub fn make_samples_from_midi_normalized<F1, F2>(
+    instrument: F1,
+    sample_rate: usize,
+    use_envelope: bool,
+    song: midi::MidiSong,
+    normalization: Normalization,
+) -> Result<Vec<f64>, SynthrsError>
+where
+    F1: Fn(f64) -> F2,
+    F2: Fn(f64) -> f64,
+{
+    let samples = render_midi(instrument, sample_rate, use_envelope, song);
+    Ok(analysis::normalize(&samples, 1, sample_rate, normalization))
+}
+
+/// Renders a MIDI song into raw (unnormalized) samples
+fn render_midi<F1, F2>(
+    instrument: F1,
+    sample_rate: usize,
+    use_envelope: bool,
+    song: midi::MidiSong,
+) -> Vec<f64>
 where
     F1: Fn(f64) -> F2,
     F2: Fn(f64) -> f64,
@@ -319,10 +610,9 @@ where
                 &notes_on_for_ticks[tick]
             {
                 let frequency = music::note_midi(440.0, note as usize);
-                // TODO: split loudness into a util module
-                let loudness = (6.908 * (f64::from(velocity) / 255.0)).exp() / 1000.0;
+                let loudness = velocity_loudness(velocity);
 
-                let start_t = start_tick as f64 * 60.0 / song.bpm as f64 / song.time_unit as f64;
+                let start_t = start_tick as f64 * 60.0 / song.bpm / song.time_unit as f64;
                 let relative_t = t - start_t;
 
                 out += loudness * (instrument)(frequency)(relative_t);
@@ -361,14 +651,418 @@ where
         samples.push(midi_frequency_function(t));
     }
 
-    Ok(peak_normalize(&samples))
+    samples
+}
+
+/// Generates interleaved stereo samples (`[l0, r0, l1, r1, ...]`) from a MIDI file.
+/// See `make_stereo_samples_from_midi`.
+///
+/// ```
+/// use synthrs::synthesizer::make_stereo_samples_from_midi_file;
+/// use synthrs::wave;
+///
+/// let samples = make_stereo_samples_from_midi_file(
+///     wave::sine_wave,
+///     44_100,
+///     true,
+///     "tests/assets/multitrack.mid",
+/// ).unwrap();
+/// ```
+pub fn make_stereo_samples_from_midi_file<F1, F2>(
+    instrument: F1,
+    sample_rate: usize,
+    use_envelope: bool,
+    path: &str,
+) -> Result<Vec<f64>, SynthrsError>
+where
+    F1: Fn(f64) -> F2,
+    F2: Fn(f64) -> f64,
+{
+    let song = midi::read_midi_file(path)?;
+    make_stereo_samples_from_midi(instrument, sample_rate, use_envelope, song)
+}
+
+/// Generates interleaved stereo samples (`[l0, r0, l1, r1, ...]`) from a MIDI song, placing each
+/// MIDI channel in the stereo field using its CC10 (pan) and CC7 (volume) controllers.
+///
+/// Controller values are read at the start of each note, so pan and volume changes during the
+/// song are followed. Channels without a CC7 use the General MIDI default volume of 100, and
+/// channels without a CC10 are centered. The result is peak normalized.
+///
+/// `instrument` is the waveform generator
+/// `use_envelope` decide whether to use a basic attack/decay envelope when generating samples
+///
+/// ```
+/// use synthrs::synthesizer::make_stereo_samples_from_midi;
+/// use synthrs::midi;
+/// use synthrs::wave;
+///
+/// let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+/// let samples = make_stereo_samples_from_midi(wave::square_wave, 44_100, true, song).unwrap();
+/// ```
+pub fn make_stereo_samples_from_midi<F1, F2>(
+    instrument: F1,
+    sample_rate: usize,
+    use_envelope: bool,
+    song: midi::MidiSong,
+) -> Result<Vec<f64>, SynthrsError>
+where
+    F1: Fn(f64) -> F2,
+    F2: Fn(f64) -> f64,
+{
+    Ok(render_stereo_midi(
+        instrument,
+        None,
+        sample_rate,
+        use_envelope,
+        song,
+    ))
+}
+
+/// Generates interleaved stereo samples from a MIDI song like `make_stereo_samples_from_midi`,
+/// playing notes on the General MIDI percussion channel (`midi::DRUM_CHANNEL`) with `drums`.
+///
+/// Drum hits always ring for their full length, whatever the length of their note, and are not
+/// affected by `use_envelope`. Notes without a drum in the kit are skipped.
+///
+/// ```
+/// use synthrs::drums::DrumKit;
+/// use synthrs::synthesizer::make_stereo_samples_from_midi_with_drums;
+/// use synthrs::midi;
+/// use synthrs::wave;
+///
+/// let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+/// let samples =
+///     make_stereo_samples_from_midi_with_drums(wave::sine_wave, &DrumKit::new(), 44_100, true, song)
+///         .unwrap();
+/// ```
+pub fn make_stereo_samples_from_midi_with_drums<F1, F2>(
+    instrument: F1,
+    drums: &DrumKit,
+    sample_rate: usize,
+    use_envelope: bool,
+    song: midi::MidiSong,
+) -> Result<Vec<f64>, SynthrsError>
+where
+    F1: Fn(f64) -> F2,
+    F2: Fn(f64) -> f64,
+{
+    Ok(render_stereo_midi(
+        instrument,
+        Some(drums),
+        sample_rate,
+        use_envelope,
+        song,
+    ))
+}
+
+/// Generates interleaved stereo samples from a MIDI song like `make_stereo_samples_from_midi`,
+/// playing every note with a multi-sampled instrument. Unlike frequency-based instruments, the
+/// sampler is given each note's key and velocity, so key splits, velocity layers and round-robin
+/// all apply. Notes with no zone for their velocity are skipped.
+///
+/// ```
+/// use synthrs::sample::{Sample, SampleInstrument, SampleZone};
+/// use synthrs::synthesizer::make_stereo_samples_from_midi_with_sampler;
+/// use synthrs::midi;
+///
+/// let sine = Sample::from_wave_file("tests/assets/sine.wav", 440.0).unwrap();
+/// let instrument = SampleInstrument::new(vec![SampleZone::new(sine, 69)]);
+///
+/// let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+/// let samples =
+///     make_stereo_samples_from_midi_with_sampler(&instrument, 44_100, true, song).unwrap();
+/// ```
+pub fn make_stereo_samples_from_midi_with_sampler(
+    instrument: &SampleInstrument,
+    sample_rate: usize,
+    use_envelope: bool,
+    song: midi::MidiSong,
+) -> Result<Vec<f64>, SynthrsError> {
+    Ok(render_stereo_notes(
+        |song, note, _| sampler_voice(instrument, song, note, sample_rate),
+        sample_rate,
+        use_envelope,
+        song,
+    ))
+}
+
+/// Generates interleaved stereo samples from a MIDI song, playing each MIDI channel with its own
+/// multi-sampled instrument (such as one loaded with `crate::sfz::read_sfz_file`). See
+/// `make_stereo_samples_from_midi_with_sampler`. Notes on channels without an instrument are
+/// skipped.
+///
+/// ```
+/// use std::collections::BTreeMap;
+/// use synthrs::sample::{Sample, SampleInstrument, SampleZone};
+/// use synthrs::synthesizer::make_stereo_samples_from_midi_with_samplers;
+/// use synthrs::midi;
+///
+/// let sine = Sample::from_wave_file("tests/assets/sine.wav", 440.0).unwrap();
+/// let mut instruments = BTreeMap::new();
+/// instruments.insert(0, SampleInstrument::new(vec![SampleZone::new(sine, 69)]));
+///
+/// let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+/// let samples =
+///     make_stereo_samples_from_midi_with_samplers(&instruments, 44_100, false, song).unwrap();
+/// ```
+pub fn make_stereo_samples_from_midi_with_samplers(
+    instruments: &BTreeMap<u8, SampleInstrument>,
+    sample_rate: usize,
+    use_envelope: bool,
+    song: midi::MidiSong,
+) -> Result<Vec<f64>, SynthrsError> {
+    Ok(render_stereo_notes(
+        |song, note, _| {
+            let instrument = instruments.get(&note.channel)?;
+            sampler_voice(instrument, song, note, sample_rate)
+        },
+        sample_rate,
+        use_envelope,
+        song,
+    ))
+}
+
+/// Generates samples from a MIDI song with a General MIDI SoundFont, mixed down to mono. See
+/// `make_stereo_samples_from_midi_with_soundfont`.
+///
+/// ```
+/// use synthrs::sf2::read_sf2_file;
+/// use synthrs::synthesizer::make_samples_from_midi_with_soundfont;
+/// use synthrs::midi;
+///
+/// let soundfont = read_sf2_file("tests/assets/test.sf2").unwrap();
+/// let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+/// let samples = make_samples_from_midi_with_soundfont(&soundfont, 44_100, false, song).unwrap();
+/// ```
+pub fn make_samples_from_midi_with_soundfont(
+    soundfont: &SoundFont,
+    sample_rate: usize,
+    use_envelope: bool,
+    song: midi::MidiSong,
+) -> Result<Vec<f64>, SynthrsError> {
+    let stereo =
+        make_stereo_samples_from_midi_with_soundfont(soundfont, sample_rate, use_envelope, song)?;
+    let mono: Vec<f64> = stereo
+        .chunks(2)
+        .map(|frame| frame.iter().sum::<f64>() / 2.0)
+        .collect();
+    Ok(peak_normalize(&mono))
+}
+
+/// Generates interleaved stereo samples from a MIDI song with a General MIDI SoundFont (see
+/// `crate::sf2`). Every note plays the preset selected on its channel by the most recent
+/// `ProgramChange`, from the bank selected (CC0) when that program was changed, and notes on the
+/// drum channel play the kits of the percussion bank. Presets missing from the SoundFont fall
+/// back as `SoundFont::instrument` describes, and notes without a preset are skipped. Otherwise
+/// this plays notes as
+/// `make_stereo_samples_from_midi_with_sampler` does.
+///
+/// ```
+/// use synthrs::sf2::read_sf2_file;
+/// use synthrs::synthesizer::make_stereo_samples_from_midi_with_soundfont;
+/// use synthrs::midi;
+///
+/// let soundfont = read_sf2_file("tests/assets/test.sf2").unwrap();
+/// let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+/// let samples =
+///     make_stereo_samples_from_midi_with_soundfont(&soundfont, 44_100, false, song).unwrap();
+/// ```
+pub fn make_stereo_samples_from_midi_with_soundfont(
+    soundfont: &SoundFont,
+    sample_rate: usize,
+    use_envelope: bool,
+    song: midi::MidiSong,
+) -> Result<Vec<f64>, SynthrsError> {
+    // Presets are only flattened into instruments once, when they are first played
+    let instruments: RefCell<BTreeMap<(u16, u8), Option<SampleInstrument>>> =
+        RefCell::new(BTreeMap::new());
+
+    let timeline = song.channel_timeline();
+
+    Ok(render_stereo_notes(
+        |song, note, _| {
+            let (bank, program) = timeline
+                .program_at(note.channel, note.start_tick)
+                .unwrap_or((0, 0));
+            let bank = if note.channel == midi::DRUM_CHANNEL {
+                PERCUSSION_BANK
+            } else {
+                bank
+            };
+
+            let mut instruments = instruments.borrow_mut();
+            let instrument = instruments
+                .entry((bank, program))
+                .or_insert_with(|| soundfont.instrument(bank, program))
+                .as_ref()?;
+            sampler_voice(instrument, song, note, sample_rate)
+        },
+        sample_rate,
+        use_envelope,
+        song,
+    ))
+}
+
+/// The voice of a sampled note: one-shot zones play through, and other zones stop at the note
+/// off, after their envelope's release
+fn sampler_voice<'a>(
+    instrument: &SampleInstrument,
+    song: &midi::MidiSong,
+    note: &midi::MidiNote,
+    sample_rate: usize,
+) -> Option<NoteVoice<'a>> {
+    let zone = instrument.zone(note.note, note.velocity)?;
+    let frequency = music::note_midi(440.0, note.note as usize);
+
+    let voice = if zone.loop_mode == LoopMode::OneShot {
+        NoteVoice {
+            generator: Box::new(zone.note_sampler(frequency, sample_rate, None)),
+            length: Some(zone.duration(frequency)),
+            release: 0.0,
+        }
+    } else {
+        let held = song.tick_to_seconds(note.end_tick) - song.tick_to_seconds(note.start_tick);
+        NoteVoice {
+            generator: Box::new(zone.note_sampler(frequency, sample_rate, Some(held))),
+            length: None,
+            release: zone.release(),
+        }
+    };
+
+    Some(voice)
+}
+
+/// Renders a MIDI song into peak normalized, interleaved stereo samples. Notes on the drum
+/// channel are played by `drums` when there is a kit, and by `instrument` otherwise.
+fn render_stereo_midi<'a, F1, F2>(
+    instrument: F1,
+    drums: Option<&DrumKit>,
+    sample_rate: usize,
+    use_envelope: bool,
+    song: midi::MidiSong,
+) -> Vec<f64>
+where
+    F1: Fn(f64) -> F2,
+    F2: Fn(f64) -> f64 + 'a,
+{
+    render_stereo_notes(
+        |_, note, index| match drums {
+            Some(kit) if note.channel == midi::DRUM_CHANNEL => {
+                let drum = kit.drum(note.note)?;
+                // Drum hits are seeded by their position in the song, so renders are repeatable
+                Some(NoteVoice {
+                    generator: drum.generator(sample_rate, index as u64),
+                    length: Some(drum.length()),
+                    release: 0.0,
+                })
+            }
+            _ => Some(NoteVoice {
+                generator: Box::new(instrument(music::note_midi(440.0, note.note as usize))),
+                length: None,
+                release: 0.0,
+            }),
+        },
+        sample_rate,
+        use_envelope,
+        song,
+    )
+}
+
+/// The sound of a single MIDI note
+struct NoteVoice<'a> {
+    generator: Box<dyn Fn(f64) -> f64 + 'a>,
+    /// Fixed length in seconds that the note rings for regardless of its note off (for one-shot
+    /// sounds such as drums, which are also left without an envelope), or `None` to stop at the
+    /// note off
+    length: Option<f64>,
+    /// Time the note keeps sounding after its note off, in seconds, if it has no fixed length
+    release: f64,
+}
+
+/// Renders a MIDI song into peak normalized, interleaved stereo samples, with `voice` creating
+/// the sound of every note from the song, the note and its index in the song. Notes without a
+/// voice are skipped.
+fn render_stereo_notes<'a, V>(
+    voice: V,
+    sample_rate: usize,
+    use_envelope: bool,
+    song: midi::MidiSong,
+) -> Vec<f64>
+where
+    V: Fn(&midi::MidiSong, &midi::MidiNote, usize) -> Option<NoteVoice<'a>>,
+{
+    let num_samples = (sample_rate as f64 * song.length()).floor() as usize;
+
+    let mut mixer = Mixer::new();
+    // One mixer source per channel and pan position, so pan changes mid-song are respected
+    let mut sources: BTreeMap<(u8, usize), usize> = BTreeMap::new();
+    let timeline = song.channel_timeline();
+
+    for (index, note) in song.notes().into_iter().enumerate() {
+        let voice = match voice(&song, &note, index) {
+            Some(voice) => voice,
+            None => continue,
+        };
+
+        let pan = timeline
+            .controller_value_at(note.channel, midi::CC_PAN, note.start_tick)
+            .unwrap_or(64);
+        let volume = timeline
+            .controller_value_at(note.channel, midi::CC_VOLUME, note.start_tick)
+            .unwrap_or(100);
+
+        let source = *sources.entry((note.channel, pan)).or_insert_with(|| {
+            let source = mixer.add_source(vec![0.0; num_samples]);
+            mixer.sources[source].pan = midi_pan(pan);
+            source
+        });
+
+        let start_t = song.tick_to_seconds(note.start_tick);
+        let start = (start_t * sample_rate as f64).ceil() as usize;
+        let end_t = match voice.length {
+            Some(length) => start_t + length,
+            None => song.tick_to_seconds(note.end_tick) + voice.release,
+        };
+        let end = ((end_t * sample_rate as f64).ceil() as usize).min(num_samples);
+
+        let gain = velocity_loudness(note.velocity) * midi_volume(volume);
+        let samples = &mut mixer.sources[source].samples;
+
+        for (i, sample) in samples.iter_mut().enumerate().take(end).skip(start) {
+            let relative_t = i as f64 / sample_rate as f64 - start_t;
+            let mut out = gain * (voice.generator)(relative_t);
+
+            if use_envelope && voice.length.is_none() {
+                out *= filter::envelope(relative_t, 0.01, 1.0);
+            }
+
+            *sample += out;
+        }
+    }
+
+    peak_normalize(&mixer.render())
+}
+
+/// Loudness multiplier for a MIDI note velocity
+fn velocity_loudness(velocity: u8) -> f64 {
+    (6.908 * (f64::from(velocity) / 255.0)).exp() / 1000.0
+}
+
+/// Converts a CC7 channel volume into a linear gain, using the General MIDI curve
+/// (40 * log10(volume / 127) dB)
+fn midi_volume(volume: usize) -> f64 {
+    let volume = volume.min(127) as f64 / 127.0;
+    volume * volume
+}
+
+/// Converts a CC10 pan value into a pan position for `pan_gains`
+fn midi_pan(pan: usize) -> f64 {
+    ((pan as f64 - 64.0) / 63.0).clamp(-1.0, 1.0)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::i16;
-    use std::i8;
-
     use super::*;
     use crate::wave::sine_wave;
 
@@ -380,17 +1074,64 @@ mod tests {
 
         let input_positive = vec![2.0f64, 1.0, -1.0];
         let output_positive = peak_normalize(&input_positive);
-        assert_eq!(output_positive, vec![1.0f64, 0.5, -0.5])
+        assert_eq!(output_positive, vec![1.0f64, 0.5, -0.5]);
+
+        let silence = vec![0.0f64; 3];
+        assert_eq!(peak_normalize(&silence), silence);
     }
 
     #[test]
     #[allow(clippy::float_cmp)]
     fn test_quantize() {
         assert_eq!(i8::MAX, quantize::<i8>(1.0));
-        // assert_eq!(i8::MIN + 1, quantize::<i8>(-1.0)); // Bad quantization behaviour?
+        assert_eq!(i8::MIN, quantize::<i8>(-1.0));
         assert_eq!(i16::MAX, quantize::<i16>(1.0));
         assert_eq!(0.0f32, quantize::<f32>(0.0));
-        // assert_eq!(u8::MAX, quantize::<u8>(1.0)); // TODO: Make quantization work for unsigned types
+        assert_eq!(u8::MAX, quantize::<u8>(1.0));
+        assert_eq!(u8::MIN, quantize::<u8>(-1.0));
+    }
+
+    #[test]
+    fn test_quantize_round_trip() {
+        let samples: Vec<i16> = (i16::MIN..=i16::MAX).collect();
+        assert_eq!(
+            quantize_samples::<i16>(&unquantize_samples(&samples)),
+            samples
+        );
+
+        let samples: Vec<u8> = (u8::MIN..=u8::MAX).collect();
+        assert_eq!(
+            quantize_samples::<u8>(&unquantize_samples(&samples)),
+            samples
+        );
+    }
+
+    #[test]
+    fn test_quantize_samples_dithered() {
+        let quiet = make_samples(0.1, 44_100, |t| 0.002 * sine_wave(440.0)(t));
+
+        // Without dither this is below half an 8-bit LSB and quantizes to silence
+        let undithered =
+            quantize_samples_dithered::<i8>(&quiet, Dither::None, NoiseShaping::None, 0);
+        assert!(undithered.iter().all(|&sample| sample == 0));
+
+        let dithered =
+            quantize_samples_dithered::<i8>(&quiet, Dither::Triangular, NoiseShaping::None, 0);
+        assert!(dithered.iter().any(|&sample| sample != 0));
+        assert!(dithered.iter().all(|&sample| sample.abs() <= 2));
+        assert_eq!(
+            dithered,
+            quantize_samples_dithered::<i8>(&quiet, Dither::Triangular, NoiseShaping::None, 0)
+        );
+
+        // Clipped rather than wrapped or zeroed
+        let loud = quantize_samples_dithered::<i16>(
+            &[1.5, -1.5],
+            Dither::Triangular,
+            NoiseShaping::Lipshitz,
+            0,
+        );
+        assert_eq!(loud, vec![i16::MAX, i16::MIN]);
     }
 
     #[test]
@@ -402,6 +1143,257 @@ mod tests {
         assert_eq!(iter.next().unwrap(), 0.978_580_904_325_472_5);
     }
 
+    #[test]
+    fn test_pan_gains() {
+        let (left, right) = pan_gains(-1.0);
+        assert!((left - 1.0).abs() < 1e-12 && right.abs() < 1e-12);
+
+        let (left, right) = pan_gains(1.0);
+        assert!(left.abs() < 1e-12 && (right - 1.0).abs() < 1e-12);
+
+        for &pan in &[-0.75, -0.2, 0.0, 0.4, 0.9] {
+            let (left, right) = pan_gains(pan);
+            assert!((left * left + right * right - 1.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_mixer_gain_pan_mute_solo() {
+        let mut mixer = Mixer::new();
+        let a = mixer.add_source(vec![1.0, 1.0]);
+        let b = mixer.add_source(vec![0.5]);
+        mixer.sources[a].pan = -1.0;
+        mixer.sources[a].gain = 0.5;
+        mixer.sources[b].pan = 1.0;
+
+        let output = mixer.render();
+        assert_eq!(output.len(), 4);
+        assert!((output[0] - 0.5).abs() < 1e-12);
+        assert!((output[1] - 0.5).abs() < 1e-12);
+        assert!((output[2] - 0.5).abs() < 1e-12);
+        assert!(output[3].abs() < 1e-12);
+
+        mixer.sources[a].mute = true;
+        let output = mixer.render();
+        assert!(output[0].abs() < 1e-12);
+        assert!((output[1] - 0.5).abs() < 1e-12);
+
+        mixer.sources[a].mute = false;
+        mixer.sources[a].solo = true;
+        let output = mixer.render();
+        assert!((output[0] - 0.5).abs() < 1e-12);
+        assert!(output[1].abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_mixer_sends() {
+        let mut mixer = Mixer::new();
+        let bus = mixer.add_bus(Box::new(|sample| sample * 2.0));
+        mixer.buses[bus].pan = 1.0;
+
+        let source = mixer.add_source(vec![1.0]);
+        mixer.sources[source].pan = -1.0;
+        mixer.sources[source].sends.push((bus, 0.25));
+
+        let output = mixer.render();
+        assert!((output[0] - 1.0).abs() < 1e-12);
+        assert!((output[1] - 0.5).abs() < 1e-12);
+
+        mixer.buses[bus].mute = true;
+        let output = mixer.render();
+        assert!(output[1].abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_make_stereo_samples_from_midi() {
+        let mut song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+        let mono = make_samples_from_midi(sine_wave, 8_000, false, song.clone()).unwrap();
+        let stereo = make_stereo_samples_from_midi(sine_wave, 8_000, false, song.clone()).unwrap();
+        assert_eq!(stereo.len(), mono.len() * 2);
+
+        // Without a CC10 every channel is centered
+        assert!(stereo
+            .chunks(2)
+            .all(|frame| (frame[0] - frame[1]).abs() < 1e-12));
+
+        // Hard left pan at the start of the song
+        song.tracks[1].events.insert(
+            0,
+            midi::MidiEvent {
+                event_type: midi::EventType::ControlChange,
+                system_event_type: None,
+                meta_event_type: None,
+                time: 0,
+                channel: 0,
+                value1: midi::CC_PAN,
+                value2: Some(0),
+            },
+        );
+        let stereo = make_stereo_samples_from_midi(sine_wave, 8_000, false, song).unwrap();
+        assert!(stereo.chunks(2).any(|frame| frame[0].abs() > 0.5));
+        assert!(stereo.chunks(2).all(|frame| frame[1].abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_drum_channel_plays_drums() {
+        let mut song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+        for track in song.tracks.iter_mut() {
+            for event in track.events.iter_mut() {
+                event.channel = midi::DRUM_CHANNEL;
+                if event.event_type == midi::EventType::NoteOn
+                    || event.event_type == midi::EventType::NoteOff
+                {
+                    // Kick drum
+                    event.value1 = 36;
+                }
+            }
+        }
+
+        let kit = DrumKit::new();
+        let tonal = make_stereo_samples_from_midi(sine_wave, 8_000, false, song.clone()).unwrap();
+        let drums =
+            make_stereo_samples_from_midi_with_drums(sine_wave, &kit, 8_000, false, song.clone())
+                .unwrap();
+        assert_eq!(tonal.len(), drums.len());
+        assert_ne!(tonal, drums);
+        assert!(drums.iter().any(|sample| sample.abs() > 0.5));
+
+        // Renders are repeatable
+        let again =
+            make_stereo_samples_from_midi_with_drums(sine_wave, &kit, 8_000, false, song).unwrap();
+        assert_eq!(drums, again);
+    }
+
+    #[test]
+    fn test_soundfont_follows_program_changes() {
+        let soundfont = crate::sf2::read_sf2_file("tests/assets/test.sf2").unwrap();
+        let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+        let with_program = |channel: u8, program: usize| {
+            let mut song = song.clone();
+            for track in song.tracks.iter_mut() {
+                for event in track.events.iter_mut() {
+                    if event.system_event_type.is_none() {
+                        event.channel = channel;
+                    }
+                    if event.event_type == midi::EventType::ProgramChange {
+                        event.value1 = program;
+                    }
+                }
+            }
+            make_stereo_samples_from_midi_with_soundfont(&soundfont, 8_000, false, song).unwrap()
+        };
+
+        let sine = with_program(0, 0);
+        assert!(sine.iter().any(|sample| sample.abs() > 0.5));
+
+        let lead = with_program(0, 80);
+        assert_eq!(sine.len(), lead.len());
+        assert_ne!(sine, lead);
+
+        // Program 1 is not in the SoundFont, and has no fallback
+        assert!(with_program(0, 1).iter().all(|&sample| sample == 0.0));
+
+        // The drum channel plays the standard kit, whatever its program
+        let drums = with_program(midi::DRUM_CHANNEL, 16);
+        assert!(drums.iter().any(|sample| sample.abs() > 0.5));
+        assert_ne!(drums, sine);
+
+        let mono = make_samples_from_midi_with_soundfont(&soundfont, 8_000, false, song).unwrap();
+        assert_eq!(mono.len() * 2, sine.len());
+    }
+
+    #[test]
+    fn test_soundfont_latches_bank_at_program_change() {
+        let soundfont = crate::sf2::read_sf2_file("tests/assets/test.sf2").unwrap();
+        let mut song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+        for event in song.tracks[1].events.iter_mut() {
+            if event.event_type == midi::EventType::ProgramChange {
+                event.value1 = 80;
+            }
+        }
+        let lead =
+            make_stereo_samples_from_midi_with_soundfont(&soundfont, 8_000, false, song.clone())
+                .unwrap();
+
+        // The percussion bank has no program 80 and falls back to the standard kit
+        let with_bank_select = |index: usize| {
+            let mut song = song.clone();
+            song.tracks[1].events.insert(
+                index,
+                midi::MidiEvent {
+                    event_type: midi::EventType::ControlChange,
+                    system_event_type: None,
+                    meta_event_type: None,
+                    time: 0,
+                    channel: 0,
+                    value1: midi::CC_BANK_SELECT,
+                    value2: Some(usize::from(PERCUSSION_BANK)),
+                },
+            );
+            make_stereo_samples_from_midi_with_soundfont(&soundfont, 8_000, false, song).unwrap()
+        };
+
+        // Selected before the program change, the bank is used
+        let drums = with_bank_select(0);
+        assert!(drums.iter().any(|sample| sample.abs() > 0.5));
+        assert_ne!(drums, lead);
+
+        // Selected after it, the bank waits for the next program change
+        assert_eq!(with_bank_select(1), lead);
+    }
+
+    #[test]
+    fn test_sampler_uses_velocity_layers() {
+        use crate::sample::{Sample, SampleZone};
+
+        let song = midi::read_midi_file("tests/assets/test.mid").unwrap();
+        let sine = |frequency: f64| {
+            Sample::new(
+                make_samples(2.0, 8_000, sine_wave(frequency)),
+                8_000,
+                frequency,
+            )
+        };
+
+        let single = SampleInstrument::new(vec![SampleZone::new(sine(440.0), 69)]);
+        let expected =
+            make_stereo_samples_from_midi_with_sampler(&single, 8_000, false, song.clone())
+                .unwrap();
+        assert!(expected.iter().any(|sample| sample.abs() > 0.5));
+
+        // Only the zone for the notes' velocities is played, not the detuned one on another layer
+        let velocities: Vec<u8> = song.notes().iter().map(|note| note.velocity).collect();
+        let (low, high) = (
+            *velocities.iter().min().unwrap(),
+            *velocities.iter().max().unwrap(),
+        );
+        let mut played = SampleZone::new(sine(440.0), 69);
+        played.velocities = (low, high);
+        let mut other = SampleZone::new(sine(470.0), 69);
+        other.velocities = if low > 0 {
+            (0, low - 1)
+        } else {
+            (high + 1, 127)
+        };
+        let layered = SampleInstrument::new(vec![other, played]);
+        let samples =
+            make_stereo_samples_from_midi_with_sampler(&layered, 8_000, false, song.clone())
+                .unwrap();
+        assert_eq!(samples, expected);
+
+        // No zone has the notes' velocities, so nothing is played
+        let mut zone = SampleZone::new(sine(440.0), 69);
+        zone.velocities = if low > 0 {
+            (0, low - 1)
+        } else {
+            (high + 1, 127)
+        };
+        let silent = SampleInstrument::new(vec![zone]);
+        let samples =
+            make_stereo_samples_from_midi_with_sampler(&silent, 8_000, false, song).unwrap();
+        assert!(samples.iter().all(|&sample| sample == 0.0));
+    }
+
     #[test]
     #[allow(clippy::approx_constant)]
     fn test_make_samples() {
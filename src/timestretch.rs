@@ -0,0 +1,482 @@
+//! Time-stretching and pitch-shifting of sample buffers.
+//!
+//! Playing a sample faster (as `crate::wave::sampler` does to raise its pitch) shortens it too.
+//! These offline processors change one without the other:
+//!
+//! * `Wsola`: waveform similarity overlap-add. Stretches time by overlapping frames of the input,
+//!   each nudged to line up with the last. Clean on speech, monophonic and percussive material.
+//! * `PhaseVocoder`: stretches time in the frequency domain, with identity phase locking
+//!   (Laroche-Dolson) to keep partials coherent, which suits polyphonic material better. Pitch is
+//!   shifted by stretching, then resampling back to the original length. Formants (the spectral
+//!   envelope, which gives voices their character) can be kept in place while the pitch moves,
+//!   so vocals do not take on a "chipmunk" sound.
+//!
+//! ```
+//! use synthrs::synthesizer::make_samples;
+//! use synthrs::timestretch::{pitch_shift, time_stretch, PhaseVocoder};
+//! use synthrs::wave::sine_wave;
+//!
+//! let samples = make_samples(0.5, 44_100, sine_wave(440.0));
+//!
+//! // Half the tempo, at the same pitch
+//! let slower = time_stretch(&samples, 2.0, 44_100);
+//! assert_eq!(slower.len(), 2 * samples.len());
+//!
+//! // A fifth up, at the same length
+//! let fifth = pitch_shift(&samples, 7.0, 44_100);
+//! assert_eq!(fifth.len(), samples.len());
+//!
+//! // An octave down, for a vocal sample
+//! let mut vocoder = PhaseVocoder::new(44_100);
+//! vocoder.preserve_formants = true;
+//! let lower = vocoder.pitch_shift(&samples, -12.0);
+//! ```
+
+use std::f64::consts::PI;
+
+use num::Complex;
+
+use crate::analysis::{fft, ifft};
+use crate::filter::hann_window;
+use crate::sample::Interpolation;
+
+/// Zero crossings of the sinc used to resample pitch-shifted audio
+const RESAMPLING_ZERO_CROSSINGS: usize = 16;
+
+/// Periodic Hann window, whose copies overlapping by half (or a quarter) sum to a constant
+fn periodic_hann(size: usize) -> Vec<f64> {
+    let mut window = hann_window(size + 1);
+    window.truncate(size);
+    window
+}
+
+/// Sample `index` of `samples`, `0.0` outside of it
+fn at(samples: &[f64], index: isize) -> f64 {
+    if index < 0 {
+        0.0
+    } else {
+        samples.get(index as usize).cloned().unwrap_or(0.0)
+    }
+}
+
+/// Divides overlap-added output by the overlap-added windows
+fn normalize_overlap(output: &mut [f64], weights: &[f64]) {
+    for (sample, &weight) in output.iter_mut().zip(weights.iter()) {
+        if weight > 1e-6 {
+            *sample /= weight;
+        }
+    }
+}
+
+/// Waveform similarity overlap-add (WSOLA) time-stretching.
+///
+/// ```
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::timestretch::Wsola;
+/// use synthrs::wave::sine_wave;
+///
+/// let samples = make_samples(0.5, 44_100, sine_wave(440.0));
+///
+/// // Conform a loop at 120 BPM to 100 BPM
+/// let conformed = Wsola::new(44_100).stretch(&samples, 120.0 / 100.0);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Wsola {
+    /// Length of the overlapping frames, in samples. Frames should span a few periods of the
+    /// lowest pitch.
+    pub frame_size: usize,
+    /// Furthest a frame is moved to line up with the previous one, in samples
+    pub tolerance: usize,
+}
+
+impl Wsola {
+    /// Creates a stretcher with 40ms frames and a 10ms tolerance at `sample_rate`.
+    pub fn new(sample_rate: usize) -> Wsola {
+        Wsola {
+            frame_size: (sample_rate as f64 * 0.04) as usize,
+            tolerance: (sample_rate as f64 * 0.01) as usize,
+        }
+    }
+
+    /// Stretches `samples` in time by `factor` (`2.0` being twice as long) without changing
+    /// their pitch.
+    pub fn stretch(&self, samples: &[f64], factor: f64) -> Vec<f64> {
+        let length = (samples.len() as f64 * factor).round() as usize;
+        let size = self.frame_size.max(2);
+        let hop = size / 2;
+        let window = periodic_hann(size);
+        let tolerance = self.tolerance as isize;
+
+        let mut output = vec![0.0; length + size];
+        let mut weights = vec![0.0; length + size];
+        let mut previous: Option<isize> = None;
+
+        for frame in 0..=length / hop {
+            let ideal = (frame as f64 * hop as f64 / factor).round() as isize;
+
+            let start = match previous {
+                None => ideal,
+                Some(previous) => {
+                    // The frame that would naturally follow the previous one in the input. The
+                    // chosen frame is the one near `ideal` most similar to it, which lines up
+                    // their waveforms where they overlap.
+                    let natural = previous + hop as isize;
+                    let similarity = |candidate: isize| {
+                        let (mut correlation, mut energy) = (0.0, 0.0);
+                        for i in 0..hop as isize {
+                            let value = at(samples, candidate + i);
+                            correlation += value * at(samples, natural + i);
+                            energy += value * value;
+                        }
+                        correlation / energy.sqrt().max(1e-9)
+                    };
+
+                    (ideal - tolerance..=ideal + tolerance)
+                        .map(|candidate| (candidate, similarity(candidate)))
+                        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                        .map_or(ideal, |best| best.0)
+                }
+            };
+
+            let offset = frame * hop;
+            for i in 0..size {
+                output[offset + i] += window[i] * at(samples, start + i as isize);
+                weights[offset + i] += window[i];
+            }
+            previous = Some(start);
+        }
+
+        normalize_overlap(&mut output, &weights);
+        output.truncate(length);
+        output
+    }
+}
+
+/// Wraps a phase into `[-pi, pi]`
+fn wrap_phase(phase: f64) -> f64 {
+    phase - 2.0 * PI * (phase / (2.0 * PI)).round()
+}
+
+/// Estimates the spectral envelope of `magnitudes` (bins `0` to `n / 2`) by cepstral liftering:
+/// smoothing the log spectrum by keeping only its first `lifter` cepstral coefficients.
+fn spectral_envelope(magnitudes: &[f64], lifter: usize) -> Vec<f64> {
+    let n = (magnitudes.len() - 1) * 2;
+    let mut buffer: Vec<Complex<f64>> = (0..n)
+        .map(|k| Complex::new((magnitudes[k.min(n - k)] + 1e-9).ln(), 0.0))
+        .collect();
+
+    ifft(&mut buffer);
+    let lifter = lifter.clamp(1, n / 2);
+    for coefficient in buffer.iter_mut().take(n - lifter + 1).skip(lifter) {
+        *coefficient = Complex::new(0.0, 0.0);
+    }
+    fft(&mut buffer);
+
+    buffer[..magnitudes.len()]
+        .iter()
+        .map(|bin| bin.re.exp())
+        .collect()
+}
+
+/// Phase vocoder time-stretching and pitch-shifting, with identity phase locking.
+///
+/// ```
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::timestretch::PhaseVocoder;
+/// use synthrs::wave::sine_wave;
+///
+/// let samples = make_samples(0.5, 44_100, sine_wave(440.0));
+///
+/// let vocoder = PhaseVocoder::new(44_100);
+/// let longer = vocoder.stretch(&samples, 1.5);
+/// let higher = vocoder.pitch_shift(&samples, 3.0);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhaseVocoder {
+    /// FFT size, a power of two. Larger frames resolve low notes better, but smear transients.
+    pub frame_size: usize,
+    /// Frames overlapping every sample, at least 4
+    pub overlap: usize,
+    /// Keep the spectral envelope in place when pitch shifting
+    pub preserve_formants: bool,
+    /// Cepstral coefficients kept when estimating the spectral envelope for
+    /// `preserve_formants`. Must be fewer than the samples in a pitch period of the voice.
+    pub formant_lifter: usize,
+}
+
+impl PhaseVocoder {
+    /// Creates a phase vocoder with frames of about 46ms and an overlap of 4 at `sample_rate`,
+    /// and a formant lifter of 1.5ms, which suits voices below about 600Hz.
+    pub fn new(sample_rate: usize) -> PhaseVocoder {
+        PhaseVocoder {
+            frame_size: ((sample_rate as f64 * 0.046) as usize).next_power_of_two(),
+            overlap: 4,
+            preserve_formants: false,
+            formant_lifter: (sample_rate as f64 * 0.0015) as usize,
+        }
+    }
+
+    /// Stretches `samples` in time by `factor` (`2.0` being twice as long) without changing
+    /// their pitch.
+    pub fn stretch(&self, samples: &[f64], factor: f64) -> Vec<f64> {
+        self.vocode(samples, factor, None)
+    }
+
+    /// Shifts the pitch of `samples` by `semitones` without changing their length.
+    pub fn pitch_shift(&self, samples: &[f64], semitones: f64) -> Vec<f64> {
+        let ratio = 2.0f64.powf(semitones / 12.0);
+        let formants = if self.preserve_formants {
+            Some(ratio)
+        } else {
+            None
+        };
+
+        // Stretching by the pitch ratio and then playing back `ratio` times faster gives the
+        // original length, with every frequency scaled by `ratio`
+        let stretched = self.vocode(samples, ratio, formants);
+        let interpolation = Interpolation::Sinc(RESAMPLING_ZERO_CROSSINGS);
+        (0..samples.len())
+            .map(|i| interpolation.value(&stretched, i as f64 * ratio, ratio))
+            .collect()
+    }
+
+    /// Stretches `samples` by `factor`. With a `formant_ratio`, frequencies are about to be
+    /// scaled by it, so the spectral envelope is shifted the other way to end up unchanged.
+    fn vocode(&self, samples: &[f64], factor: f64, formant_ratio: Option<f64>) -> Vec<f64> {
+        let length = (samples.len() as f64 * factor).round() as usize;
+        let size = self.frame_size.next_power_of_two().max(4);
+        let bins = size / 2 + 1;
+        let hop = (size / self.overlap.max(4)).max(1);
+        let window = periodic_hann(size);
+        let half = (size / 2) as isize;
+
+        // Frames are centred on their positions, so output starts `size / 2` early
+        let mut output = vec![0.0; length + 2 * size];
+        let mut weights = vec![0.0; length + 2 * size];
+
+        let mut previous_position: isize = 0;
+        let mut previous_phases = vec![0.0; bins];
+        let mut phases = vec![0.0; bins];
+        let mut buffer = vec![Complex::new(0.0, 0.0); size];
+
+        for frame in 0..=length / hop + 1 {
+            let position = (frame as f64 * hop as f64 / factor).round() as isize;
+            for (i, bin) in buffer.iter_mut().enumerate() {
+                *bin = Complex::new(at(samples, position - half + i as isize) * window[i], 0.0);
+            }
+            fft(&mut buffer);
+
+            let mut magnitudes: Vec<f64> = buffer[..bins].iter().map(|bin| bin.norm()).collect();
+            let analysis_phases: Vec<f64> = buffer[..bins].iter().map(|bin| bin.arg()).collect();
+
+            if frame == 0 {
+                phases.copy_from_slice(&analysis_phases);
+            } else {
+                let analysis_hop = (position - previous_position) as f64;
+                let frequency = |k: usize| -> f64 {
+                    let expected = 2.0 * PI * k as f64 / size as f64;
+                    if analysis_hop > 0.0 {
+                        let deviation =
+                            analysis_phases[k] - previous_phases[k] - expected * analysis_hop;
+                        expected + wrap_phase(deviation) / analysis_hop
+                    } else {
+                        expected
+                    }
+                };
+
+                // Peaks (louder than two bins on either side) advance at their own frequency,
+                // and the bins around them keep their phase relative to the peak
+                let peaks: Vec<usize> = (0..bins)
+                    .filter(|&k| {
+                        (k.saturating_sub(2)..(k + 3).min(bins))
+                            .all(|other| other == k || magnitudes[k] > magnitudes[other])
+                    })
+                    .collect();
+
+                if peaks.is_empty() {
+                    for (k, phase) in phases.iter_mut().enumerate() {
+                        *phase += hop as f64 * frequency(k);
+                    }
+                } else {
+                    let peak_phases: Vec<f64> = peaks
+                        .iter()
+                        .map(|&peak| phases[peak] + hop as f64 * frequency(peak))
+                        .collect();
+
+                    let distance = |a: usize, b: usize| (a as isize - b as isize).abs();
+                    let mut nearest = 0;
+                    for (k, phase) in phases.iter_mut().enumerate() {
+                        while nearest + 1 < peaks.len()
+                            && distance(peaks[nearest + 1], k) < distance(peaks[nearest], k)
+                        {
+                            nearest += 1;
+                        }
+                        let peak = peaks[nearest];
+                        *phase = peak_phases[nearest] + analysis_phases[k] - analysis_phases[peak];
+                    }
+                }
+            }
+
+            if let Some(ratio) = formant_ratio {
+                let envelope = spectral_envelope(&magnitudes, self.formant_lifter);
+                for (k, magnitude) in magnitudes.iter_mut().enumerate() {
+                    // Bin `k` ends up at `k * ratio`, where it should have that bin's envelope
+                    let target = k as f64 * ratio;
+                    let index = target.floor() as usize;
+                    *magnitude *= if index + 1 < bins {
+                        let fraction = target - index as f64;
+                        let shifted =
+                            envelope[index] + fraction * (envelope[index + 1] - envelope[index]);
+                        shifted / envelope[k]
+                    } else {
+                        0.0
+                    };
+                }
+            }
+
+            for k in 0..bins {
+                buffer[k] = Complex::from_polar(&magnitudes[k], &phases[k]);
+                if k > 0 && k < size / 2 {
+                    buffer[size - k] = buffer[k].conj();
+                }
+            }
+            ifft(&mut buffer);
+
+            // Output frame `frame` starts `size / 2` before `frame * hop`, so that output sample
+            // `i` is at `i + size / 2` in `output`
+            let offset = frame * hop;
+            for i in 0..size {
+                if offset + i < output.len() {
+                    output[offset + i] += buffer[i].re * window[i];
+                    weights[offset + i] += window[i] * window[i];
+                }
+            }
+
+            previous_position = position;
+            previous_phases = analysis_phases;
+        }
+
+        normalize_overlap(&mut output, &weights);
+        output.drain(..size / 2);
+        output.truncate(length);
+        output
+    }
+}
+
+/// Stretches `samples` in time by `factor` (`2.0` being twice as long) without changing their
+/// pitch, using `Wsola` with its default settings for `sample_rate`.
+pub fn time_stretch(samples: &[f64], factor: f64, sample_rate: usize) -> Vec<f64> {
+    Wsola::new(sample_rate).stretch(samples, factor)
+}
+
+/// Shifts the pitch of `samples` by `semitones` without changing their length, using
+/// `PhaseVocoder` with its default settings for `sample_rate`.
+pub fn pitch_shift(samples: &[f64], semitones: f64, sample_rate: usize) -> Vec<f64> {
+    PhaseVocoder::new(sample_rate).pitch_shift(samples, semitones)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{magnitude_spectrum, rms};
+    use crate::synthesizer::make_samples;
+    use crate::wave::sine_wave;
+
+    /// Frequency of the loudest FFT bin of 16384 samples from the middle of `samples`
+    fn peak_frequency(samples: &[f64]) -> f64 {
+        let start = (samples.len() - 16_384) / 2;
+        let spectrum = magnitude_spectrum(&samples[start..start + 16_384]);
+        let bin = (0..spectrum.len())
+            .max_by(|&a, &b| spectrum[a].partial_cmp(&spectrum[b]).unwrap())
+            .unwrap();
+        bin as f64 * 44_100.0 / 16_384.0
+    }
+
+    /// Largest jump between neighbouring samples, away from the ends
+    fn largest_step(samples: &[f64]) -> f64 {
+        samples[2_048..samples.len() - 2_048]
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn test_wsola() {
+        let samples = make_samples(0.5, 44_100, sine_wave(440.0));
+        // A sine moves at most this far between samples
+        let sine_step = 2.0 * (PI * 440.0 / 44_100.0).sin();
+
+        for &factor in [0.75, 1.5, 2.0].iter() {
+            let stretched = time_stretch(&samples, factor, 44_100);
+            assert_eq!(
+                stretched.len(),
+                (samples.len() as f64 * factor).round() as usize
+            );
+            assert!((peak_frequency(&stretched) - 440.0).abs() < 3.0);
+
+            // Frames line up, so there are no clicks or cancellation
+            assert!(largest_step(&stretched) < 1.1 * sine_step);
+            let middle = &stretched[2_048..stretched.len() - 2_048];
+            assert!((rms(middle) - 0.5f64.sqrt()).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn test_phase_vocoder_stretch() {
+        let samples = make_samples(0.5, 44_100, sine_wave(440.0));
+        let vocoder = PhaseVocoder::new(44_100);
+
+        for &factor in [0.75, 2.0].iter() {
+            let stretched = vocoder.stretch(&samples, factor);
+            assert_eq!(
+                stretched.len(),
+                (samples.len() as f64 * factor).round() as usize
+            );
+            assert!((peak_frequency(&stretched) - 440.0).abs() < 3.0);
+
+            let middle = &stretched[4_096..stretched.len() - 4_096];
+            assert!((rms(middle) - 0.5f64.sqrt()).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn test_pitch_shift() {
+        let samples = make_samples(0.75, 44_100, sine_wave(440.0));
+
+        for &semitones in [7.0, -12.0].iter() {
+            let shifted = pitch_shift(&samples, semitones, 44_100);
+            assert_eq!(shifted.len(), samples.len());
+
+            let expected = 440.0 * 2.0f64.powf(semitones / 12.0);
+            assert!((peak_frequency(&shifted) - expected).abs() < 3.0);
+
+            let middle = &shifted[4_096..shifted.len() - 4_096];
+            assert!((rms(middle) - 0.5f64.sqrt()).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn test_pitch_shift_preserves_formants() {
+        // A vowel-like tone: 150Hz harmonics under a formant at 1kHz
+        let vowel = make_samples(0.75, 44_100, |t| {
+            (1..60)
+                .map(|harmonic| {
+                    let frequency = 150.0 * harmonic as f64;
+                    let formant = (-((frequency - 1_000.0) / 250.0).powi(2)).exp();
+                    formant * sine_wave(frequency)(t)
+                })
+                .sum::<f64>()
+                / 3.0
+        });
+        assert!((peak_frequency(&vowel) - 1_050.0).abs() < 3.0);
+
+        // Up a fourth, the harmonics are 200Hz apart and the formant would move to 1335Hz
+        let mut vocoder = PhaseVocoder::new(44_100);
+        let shifted = vocoder.pitch_shift(&vowel, 5.0);
+        assert!((peak_frequency(&shifted) - 1_401.0).abs() < 10.0);
+
+        vocoder.preserve_formants = true;
+        let preserved = vocoder.pitch_shift(&vowel, 5.0);
+        assert!((peak_frequency(&preserved) - 1_000.0).abs() < 10.0);
+    }
+}
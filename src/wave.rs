@@ -13,7 +13,10 @@
 use std::collections::VecDeque;
 use std::f64::consts::PI;
 
-use crate::filter::envelope;
+use crate::additive::{additive, drawbar_organ, Partial};
+use crate::filter::{envelope, Adsr};
+use crate::oscillator::Waveform;
+use crate::sample::{Interpolation, Sample};
 
 pub fn sine_wave(frequency: f64) -> impl Fn(f64) -> f64 {
     move |t| (t * frequency * 2.0 * PI).sin()
@@ -45,45 +48,142 @@ pub fn triangle_wave(frequency: f64) -> impl Fn(f64) -> f64 {
 }
 
 pub fn tangent_wave(frequency: f64) -> impl Fn(f64) -> f64 {
-    move |t| {
-        (((t * frequency * PI) - 0.5).tan() / 4.0)
-            .max(-1.0)
-            .min(1.0)
+    move |t| (((t * frequency * PI) - 0.5).tan() / 4.0).clamp(-1.0, 1.0)
+}
+
+/// PolyBLEP residual for a step of height `2` at phase `0`, where `phase` is in `[0, 1)` and
+/// `phase_increment` is the phase advanced per sample (`frequency / sample_rate`).
+///
+/// Subtracting the scaled residual from a naive waveform rounds off its discontinuities over one
+/// sample on either side, removing most of the aliasing.
+pub fn polyblep(phase: f64, phase_increment: f64) -> f64 {
+    if phase < phase_increment {
+        let x = phase / phase_increment;
+        2.0 * x - x * x - 1.0
+    } else if phase > 1.0 - phase_increment {
+        let x = (phase - 1.0) / phase_increment;
+        x * x + 2.0 * x + 1.0
+    } else {
+        0.0
     }
 }
 
-pub fn bell(frequency: f64, attack: f64, decay: f64) -> impl Fn(f64) -> f64 {
+/// PolyBLAMP residual for a change in slope of `2` per sample at phase `0`, the integral of
+/// `polyblep`. Used to round off the corners of waveforms such as triangles.
+pub fn polyblamp(phase: f64, phase_increment: f64) -> f64 {
+    if phase < phase_increment {
+        let x = phase / phase_increment - 1.0;
+        -x * x * x / 3.0
+    } else if phase > 1.0 - phase_increment {
+        let x = (phase - 1.0) / phase_increment + 1.0;
+        x * x * x / 3.0
+    } else {
+        0.0
+    }
+}
+
+/// Evaluates `waveform` at time `t` for a given `frequency` and `sample_rate`
+fn waveform_at(waveform: Waveform, t: f64, frequency: f64, sample_rate: usize) -> f64 {
+    let t_factor = t * frequency;
+    let phase = t_factor - t_factor.floor();
+    waveform.value(phase, frequency / sample_rate as f64)
+}
+
+/// Band-limited (PolyBLEP) version of `square_wave`, for rendering at `sample_rate`.
+///
+/// ```
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::wave::bandlimited_square_wave;
+///
+/// let samples = make_samples(0.1, 44_100, bandlimited_square_wave(3_000.0, 44_100));
+/// assert!(samples.iter().all(|s| s.abs() <= 1.0));
+/// ```
+pub fn bandlimited_square_wave(frequency: f64, sample_rate: usize) -> impl Fn(f64) -> f64 {
+    move |t| waveform_at(Waveform::BandlimitedSquare, t, frequency, sample_rate)
+}
+
+/// Band-limited (PolyBLEP) version of `sawtooth_wave`, for rendering at `sample_rate`.
+pub fn bandlimited_sawtooth_wave(frequency: f64, sample_rate: usize) -> impl Fn(f64) -> f64 {
+    move |t| waveform_at(Waveform::BandlimitedSawtooth, t, frequency, sample_rate)
+}
+
+/// Band-limited (PolyBLAMP) version of `triangle_wave`, for rendering at `sample_rate`.
+pub fn bandlimited_triangle_wave(frequency: f64, sample_rate: usize) -> impl Fn(f64) -> f64 {
+    move |t| waveform_at(Waveform::BandlimitedTriangle, t, frequency, sample_rate)
+}
+
+/// Pulse wave which is high for `width` (`0.0` to `1.0`) of each cycle. `width` can be a
+/// function of `t` to modulate it (PWM).
+///
+/// ```
+/// use synthrs::synthesizer::make_samples;
+/// use synthrs::wave::{pulse_wave, sine_wave};
+///
+/// // Width swept by a slow sine
+/// let pwm = make_samples(1.0, 44_100, pulse_wave(110.0, |t| 0.5 + 0.4 * sine_wave(0.5)(t)));
+/// let fixed = make_samples(1.0, 44_100, pulse_wave(110.0, |_| 0.25));
+/// ```
+pub fn pulse_wave<W: Fn(f64) -> f64>(frequency: f64, width: W) -> impl Fn(f64) -> f64 {
     move |t| {
-        // TODO: lazy-static this table
-        // Frequency, amplitude, decay
-        let harmonics_table: [(f64, f64, f64); 9] = [
-            (0.56, 1.5, 1.0),
-            (0.92, 0.5, 2.0),
-            (1.19, 0.25, 4.0),
-            (1.71, 0.125, 6.0),
-            (2.00, 0.062_5, 8.4),
-            (2.74, 0.031_25, 10.8),
-            (3.00, 0.015_625, 13.6),
-            (3.76, 0.007_812_5, 16.4),
-            (4.07, 0.003_906_25, 19.6),
-        ];
-
-        harmonics_table.iter().fold(0.0, |acc, h| {
-            acc + sine_wave(frequency * h.0)(t) * h.1 * envelope(t, attack, decay * h.2)
-        }) / 2.0
+        let t_factor = t * frequency;
+        Waveform::Pulse(width(t)).value(t_factor - t_factor.floor(), 0.0)
     }
 }
 
-pub fn organ(frequency: f64) -> impl Fn(f64) -> f64 {
+/// Band-limited (PolyBLEP) version of `pulse_wave`, for rendering at `sample_rate`.
+pub fn bandlimited_pulse_wave<W: Fn(f64) -> f64>(
+    frequency: f64,
+    width: W,
+    sample_rate: usize,
+) -> impl Fn(f64) -> f64 {
     move |t| {
-        let frequency_2 = (frequency / 2.0) * 3.0;
-        sine_wave(frequency)(t) + 0.2 * sine_wave(frequency_2)(t)
+        waveform_at(
+            Waveform::BandlimitedPulse(width(t)),
+            t,
+            frequency,
+            sample_rate,
+        )
     }
 }
 
+/// Bell partials: frequency ratio, amplitude and decay multiplier
+const BELL_PARTIALS: [(f64, f64, f64); 9] = [
+    (0.56, 1.5, 1.0),
+    (0.92, 0.5, 2.0),
+    (1.19, 0.25, 4.0),
+    (1.71, 0.125, 6.0),
+    (2.00, 0.062_5, 8.4),
+    (2.74, 0.031_25, 10.8),
+    (3.00, 0.015_625, 13.6),
+    (3.76, 0.007_812_5, 16.4),
+    (4.07, 0.003_906_25, 19.6),
+];
+
+/// An inharmonic bell made of nine partials, higher partials decaying faster.
+/// See `crate::additive` for building other additive instruments, and `crate::fm::Patch::bell`.
+pub fn bell(frequency: f64, attack: f64, decay: f64) -> impl Fn(f64) -> f64 {
+    let mut partials = [Partial::new(0.0, 0.0); 9];
+    for (partial, &(ratio, amplitude, decay_multiplier)) in
+        partials.iter_mut().zip(BELL_PARTIALS.iter())
+    {
+        *partial = Partial::new(ratio, amplitude / 2.0);
+        partial.envelope = Some(Adsr::new(attack, decay * decay_multiplier, 0.0, 0.0));
+    }
+
+    additive(frequency, partials, 0.0)
+}
+
+/// A simple organ: the fundamental (8') with a quieter fifth above it (5 1/3'), drawn out to
+/// `"038000000"` on a drawbar organ. See `crate::additive::drawbar_organ` for other registrations.
+pub fn organ(frequency: f64) -> impl Fn(f64) -> f64 {
+    drawbar_organ(frequency, [0, 3, 8, 0, 0, 0, 0, 0, 0])
+}
+
 /// Bastardised and butchered generic Karplus-Strong synthesis.
 /// Try a Sawtooth, or even a Bell wave.
 ///
+/// For a real, stateful plucked-string model see `waveguide::PluckedString`.
+///
 /// This is an example of a generator function using another generator function.
 /// In this case, `karplus_strong` wraps around a generator function and
 /// applies a poor emulation of a real-world object over it.
@@ -120,48 +220,75 @@ pub fn karplus_strong<F: Fn(f64) -> f64>(
     }
 }
 
+/// Unseeded white noise in `[-1, 1)`. Output differs on every run; use `crate::noise` for seeded,
+/// deterministic white and coloured noise.
 pub fn noise() -> impl Fn(f64) -> f64 {
-    |_t| rand::random::<f64>()
+    |_t| rand::random::<f64>() * 2.0 - 1.0
 }
 
-/// `sampler` creates a a generator function given a bunch of samples. Different frequencies are
-/// generated using a simple pitch shift. This function is unsafe because we want to share
-/// `sample` without resorting to a `'static` lifetime (making it easier to use with bindgen).
+/// `sampler` creates a generator function playing a `crate::sample::Sample`. Different
+/// frequencies are generated using a simple pitch shift. See `Sample::sampler`.
 ///
 /// * `frequency`: The frequency passed in to the generator
-/// * `samples`: The raw pointer to the samples to be used
-/// * `sample_length`: Length of `samples`
-/// * `sample_frequency`: The frequency of the sample provided. This is used to calculate how much to shift the pitch.
-/// * `sample_rate`: The sample rate of the given sample
-/// ```compile_fail
-/// use synthrs::sampler;
+/// * `sample`: The sample to play. Its data is shared with the generator, not copied.
+/// * `sample_rate`: The output sample rate. Samples recorded at other rates are converted.
+///
+/// ```
+/// use synthrs::sample::Sample;
 /// use synthrs::wave;
 ///
-/// let (piano_sample, sample_length) =
-///        sample::samples_from_wave_file("examples/assets/piano110hz.wav").unwrap();
+/// let sine = Sample::from_wave_file("tests/assets/sine.wav", 440.0).unwrap();
+/// let sine_sampler = |frequency: f64| wave::sampler(frequency, &sine, 44_100);
+/// ```
+pub fn sampler(frequency: f64, sample: &Sample, sample_rate: usize) -> impl Fn(f64) -> f64 {
+    sample.sampler(frequency, sample_rate)
+}
+
+/// Like `sampler`, but reads samples through a raw pointer and length. This is for samples owned
+/// outside Rust, such as a buffer shared over FFI or wasm-bindgen, which cannot be wrapped in a
+/// `Sample` without copying.
+///
+/// * `frequency`: The frequency passed in to the generator
+/// * `samples`: Pointer to the first of `sample_length` samples
+/// * `sample_length`: Number of samples
+/// * `sample_frequency`: The frequency of the sample provided. This is used to calculate how much to shift the pitch.
+/// * `sample_rate`: The sample rate of the given sample, and of the output
+/// * `interpolation`: How to read between samples
+///
+/// # Safety
+///
+/// `samples` must point to `sample_length` valid, initialized `f64`s that are not mutated for as
+/// long as the returned generator is alive.
 ///
-/// let frequency_to_generate = 110.0;
-/// let sampler = wave::sampler(frequency, &piano_sample, sample_length, 110.0, 44_100)
 /// ```
-pub fn sampler(
+/// use synthrs::sample::Interpolation;
+/// use synthrs::wave;
+///
+/// let samples = vec![0.0, 0.5, 1.0];
+/// let sampler = unsafe {
+///     wave::sampler_from_raw_parts(
+///         440.0,
+///         samples.as_ptr(),
+///         samples.len(),
+///         440.0,
+///         3,
+///         Interpolation::Nearest,
+///     )
+/// };
+/// assert_eq!(sampler(1.0 / 3.0), 0.5);
+/// ```
+pub unsafe fn sampler_from_raw_parts(
     frequency: f64,
-    samples: *const Vec<f64>,
+    samples: *const f64,
     sample_length: usize,
     sample_frequency: f64,
     sample_rate: usize,
+    interpolation: Interpolation,
 ) -> impl Fn(f64) -> f64 {
+    let speed = frequency / sample_frequency;
     move |t| {
-        let multiplier = frequency / sample_frequency;
-        let original_index = sample_rate as f64 * t;
-        let adjusted_index = (multiplier * original_index).round() as usize;
-
-        // We have no intention of copying sample, and we don't want to use &'static Vec<f64> for wasm interop
-        let raw_samples = unsafe { &*samples };
-        if adjusted_index >= sample_length {
-            0.0
-        } else {
-            raw_samples[adjusted_index]
-        }
+        let samples = unsafe { std::slice::from_raw_parts(samples, sample_length) };
+        interpolation.value(samples, t * sample_rate as f64 * speed, speed)
     }
 }
 
@@ -210,18 +337,30 @@ pub fn delay_line_generator<F: Fn(f64) -> f64>(
 ///
 /// This is mainly an example on how to do stateful generator functions.
 /// This is achieved using interior mutability. See the source for details on how this is achieved.
+///
+/// The phase is accumulated from the time elapsed between calls, so the sweep has no clicks
+/// when the frequency changes. See `crate::oscillator::Oscillator` for a phase-accumulating
+/// oscillator which does not need `t` at all.
 pub fn rising_linear(
     start_frequency: f64,
     end_frequency: f64,
     increment_per_sample: f64,
 ) -> impl Fn(f64) -> f64 {
     // Our state! You can use a `RefCell` or a `Cell` for a start.
-    // This example uses a `RefCell`, but a `Cell` will be simpler and
-    // suffice for this simple state. An example using `Cell` is provided below.
-    let cell = std::cell::RefCell::new(start_frequency);
+    // This example uses a `RefCell` as the state (frequency, phase and the previous `t`) is
+    // more than a single `Copy` value. For a single value, a `Cell` will be simpler and suffice.
+    let cell = std::cell::RefCell::new((start_frequency, 0.0f64, None::<f64>));
 
     move |t| {
-        let mut current_frequency = cell.borrow_mut();
+        let mut state = cell.borrow_mut();
+        let (ref mut current_frequency, ref mut phase, ref mut last_t) = *state;
+
+        // Advance the phase by the time since the previous call at the previous frequency
+        if let Some(last_t) = *last_t {
+            *phase += (t - last_t) * *current_frequency;
+            *phase -= phase.floor();
+        }
+        *last_t = Some(t);
 
         *current_frequency += increment_per_sample;
 
@@ -229,28 +368,8 @@ pub fn rising_linear(
             *current_frequency = start_frequency;
         }
 
-        sine_wave(*current_frequency)(t)
+        Waveform::Sine.value(*phase, 0.0)
     }
-
-    // The below is an example using `Cell` instead of `RefCell`.
-    //
-    // ```
-    // let mut cell = std::cell::Cell::new(start_frequency);
-    //
-    // move |t| {
-    //     let current_frequency = cell.get();
-    //
-    //     let new_frequency = if current_frequency > end_frequency {
-    //         start_frequency
-    //     } else {
-    //         current_frequency + increment_per_sample
-    //     };
-    //
-    //     cell.set(new_frequency);
-    //
-    //     sine_wave(new_frequency)(t)
-    // }
-    // ```
 }
 
 mod tests {
@@ -272,4 +391,65 @@ mod tests {
         assert_eq!(delayed(17.0), 7.0);
         assert_eq!(delayed(19.0), 11.0);
     }
+
+    #[test]
+    fn test_additive_instruments() {
+        let old_bell = |t: f64| {
+            BELL_PARTIALS.iter().fold(0.0, |acc, h| {
+                acc + sine_wave(220.0 * h.0)(t) * h.1 * envelope(t, 0.003, 0.5 * h.2)
+            }) / 2.0
+        };
+        // The fifth is 15dB (five drawbar steps) below the fundamental, scaled to stay in [-1, 1]
+        let fifth = 10.0f64.powf(-15.0 / 20.0);
+        let registration =
+            |t: f64| (sine_wave(220.0)(t) + fifth * sine_wave(330.0)(t)) / (1.0 + fifth);
+
+        for i in 0..44_100 {
+            let t = i as f64 / 44_100.0;
+            assert!((bell(220.0, 0.003, 0.5)(t) - old_bell(t)).abs() < 1e-9);
+            assert!((organ(220.0)(t) - registration(t)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_rising_linear_is_continuous() {
+        let generator = rising_linear(100.0, 2_000.0, 0.05);
+        let mut previous = generator(0.0);
+
+        for i in 1..44_100 {
+            let sample = generator(i as f64 / 44_100.0);
+            // Never faster than a 2kHz sine
+            assert!((sample - previous).abs() <= 2.0 * PI * 2_000.0 / 44_100.0 + 1e-9);
+            previous = sample;
+        }
+    }
+
+    /// Ratio of power between the harmonics of a fundamental at `bin` to the power on them, in dB
+    #[cfg(test)]
+    fn aliasing_db<F: Fn(f64) -> f64>(generator: F, bin: usize) -> f64 {
+        use crate::analysis::{aliasing_ratio, gain_to_db};
+
+        let samples: Vec<f64> = (0..8192).map(|i| generator(i as f64 / 44_100.0)).collect();
+        gain_to_db(aliasing_ratio(&samples, bin).sqrt())
+    }
+
+    #[test]
+    fn test_bandlimited_waves_alias_less() {
+        // An exact bin frequency, about 2.6kHz, so that harmonics land on bin centres
+        let bin = 487;
+        let frequency = bin as f64 * 44_100.0 / 8192.0;
+
+        let naive_square = aliasing_db(square_wave(frequency), bin);
+        let naive_sawtooth = aliasing_db(sawtooth_wave(frequency), bin);
+        let naive_triangle = aliasing_db(triangle_wave(frequency), bin);
+        let square = aliasing_db(bandlimited_square_wave(frequency, 44_100), bin);
+        let sawtooth = aliasing_db(bandlimited_sawtooth_wave(frequency, 44_100), bin);
+        let triangle = aliasing_db(bandlimited_triangle_wave(frequency, 44_100), bin);
+        let naive_pulse = aliasing_db(pulse_wave(frequency, |_| 0.3), bin);
+        let pulse = aliasing_db(bandlimited_pulse_wave(frequency, |_| 0.3, 44_100), bin);
+        assert!(square < -24.0 && square < naive_square - 10.0);
+        assert!(sawtooth < -24.0 && sawtooth < naive_sawtooth - 10.0);
+        assert!(triangle < -42.0 && triangle < naive_triangle - 10.0);
+        assert!(pulse < -24.0 && pulse < naive_pulse - 10.0);
+    }
 }
@@ -0,0 +1,497 @@
+//! Digital waveguide (physical modelling) instruments.
+//!
+//! Each model is a loop of delay lines holding the travelling waves of a vibrating string or air
+//! column, with filters modelling losses at the ends. The length of the loop sets the pitch, and
+//! fractional delays are tuned with first-order allpass interpolation.
+//!
+//! * `PluckedString`: extended Karplus-Strong (Jaffe-Smith) plucked string
+//! * `BowedString`: bowed string with a bow-string friction table
+//! * `Clarinet`: single-reed wind instrument
+//!
+//! ```
+//! use synthrs::waveguide::PluckedString;
+//!
+//! // A2, ringing for 3 seconds, plucked near the bridge
+//! let mut string = PluckedString::new(110.0, 44_100, 3.0, 0.5, 0.1, 1234);
+//! let samples: Vec<f64> = string.by_ref().take(44_100 * 3).collect();
+//!
+//! // Pluck it again
+//! string.pluck(0.8);
+//! ```
+//!
+//! The bowed string and clarinet keep sounding while they are driven.
+//!
+//! ```
+//! use synthrs::waveguide::{BowedString, Clarinet};
+//!
+//! let mut cello = BowedString::new(130.81, 44_100, 0.13);
+//! cello.bow_velocity = 0.8;
+//! let bowed: Vec<f64> = cello.by_ref().take(44_100).collect();
+//!
+//! // Lift the bow and let the string ring out
+//! cello.bow_velocity = 0.0;
+//! let released: Vec<f64> = cello.by_ref().take(44_100).collect();
+//!
+//! let mut clarinet = Clarinet::new(220.0, 44_100, 1234);
+//! clarinet.breath_pressure = 0.7;
+//! let blown: Vec<f64> = clarinet.take(44_100).collect();
+//! ```
+
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
+
+use crate::filter::DelayLine;
+
+/// A delay line with a fractional length, tuned with a first-order allpass.
+#[derive(Clone, Debug)]
+struct FractionalDelay {
+    line: DelayLine,
+    /// Allpass coefficient `(1 - d) / (1 + d)` for a fractional delay `d`
+    coefficient: f64,
+    previous_input: f64,
+    previous_output: f64,
+}
+
+impl FractionalDelay {
+    /// Creates a delay of `delay` samples. The fractional part is kept between `0.1` and `1.1`
+    /// samples, where the allpass has a flat phase delay at low frequencies.
+    fn new(delay: f64, sample_rate: usize) -> FractionalDelay {
+        let integer = (delay - 0.1).floor().max(1.0);
+        let fraction = (delay - integer).max(0.1);
+
+        FractionalDelay {
+            line: DelayLine::from_samples(integer as usize, sample_rate),
+            coefficient: (1.0 - fraction) / (1.0 + fraction),
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    /// Pushes `input` into the delay and returns the sample leaving it.
+    fn tick(&mut self, input: f64) -> f64 {
+        let delayed = self.line.read();
+        self.line.write(input);
+
+        let output = self.coefficient * delayed + self.previous_input
+            - self.coefficient * self.previous_output;
+        self.previous_input = delayed;
+        self.previous_output = output;
+        output
+    }
+
+    /// The sample that last left the delay
+    fn last(&self) -> f64 {
+        self.previous_output
+    }
+}
+
+/// Magnitude of the two-point lowpass `(1 - stretch) * x[n] + stretch * x[n - 1]` at `frequency`
+fn stretch_gain(stretch: f64, frequency: f64, sample_rate: usize) -> f64 {
+    let omega = 2.0 * std::f64::consts::PI * frequency / sample_rate as f64;
+    ((1.0 - stretch).powi(2) + stretch * stretch + 2.0 * stretch * (1.0 - stretch) * omega.cos())
+        .sqrt()
+}
+
+/// A plucked string: the extended Karplus-Strong algorithm.
+///
+/// A burst of noise circulates in a delay loop, losing high frequencies on every trip through a
+/// two-point lowpass. The loop is tuned to any frequency with an allpass fractional delay.
+#[derive(Clone, Debug)]
+pub struct PluckedString {
+    delay: FractionalDelay,
+    /// Weight of the previous sample in the loop lowpass, see `new`
+    stretch: f64,
+    /// Gain applied on every trip around the loop, for the requested decay time
+    loss: f64,
+    lowpass_previous: f64,
+    /// Loop length in samples
+    period: f64,
+    pick_position: f64,
+    rng: XorShiftRng,
+    /// Excitation still to be fed into the loop
+    excitation: Vec<f64>,
+}
+
+impl PluckedString {
+    /// Creates a string and plucks it at full amplitude.
+    ///
+    /// * `decay`: time in seconds for the fundamental to decay by 60dB
+    /// * `stretch`: `0.5` for the classic Karplus-Strong loop filter. Lower values keep the upper
+    ///   harmonics ringing longer (a brighter, stiffer string), higher values dampen them faster
+    ///   (`0.0` to `1.0`)
+    /// * `pick_position`: where the string is plucked, as a fraction of its length from the
+    ///   bridge (`0.5` is the middle, which cancels the even harmonics)
+    /// * `seed`: seed for the noise burst used for every pluck
+    pub fn new(
+        frequency: f64,
+        sample_rate: usize,
+        decay: f64,
+        stretch: f64,
+        pick_position: f64,
+        seed: u64,
+    ) -> PluckedString {
+        let stretch = stretch.clamp(0.0, 1.0);
+        let period = sample_rate as f64 / frequency;
+
+        // Reading the delay's `last` output adds a sample to the loop, and the lowpass delays it
+        // by `stretch` samples at low frequencies
+        let delay = FractionalDelay::new(period - 1.0 - stretch, sample_rate);
+
+        // Loop gain per period so that the fundamental reaches -60dB after `decay` seconds,
+        // compensating for what the lowpass already removes at the fundamental
+        let gain_per_period = 0.001f64.powf(1.0 / (frequency * decay));
+        let loss = (gain_per_period / stretch_gain(stretch, frequency, sample_rate)).min(1.0);
+
+        let mut string = PluckedString {
+            delay,
+            stretch,
+            loss,
+            lowpass_previous: 0.0,
+            period,
+            pick_position: pick_position.clamp(0.0, 1.0),
+            rng: XorShiftRng::seed_from_u64(seed),
+            excitation: Vec::new(),
+        };
+        string.pluck(1.0);
+        string
+    }
+
+    /// Plucks the string again with a noise burst of `amplitude`, adding to whatever it is
+    /// already playing.
+    pub fn pluck(&mut self, amplitude: f64) {
+        let length = self.period.round().max(1.0) as usize;
+        let noise: Vec<f64> = (0..length).map(|_| self.rng.gen_range(-1.0, 1.0)).collect();
+
+        // Plucking at a fraction of the string's length cancels the harmonics with a node there:
+        // a comb filter with a delay of that fraction of the period
+        let pick_delay = (self.pick_position * length as f64).round() as usize;
+        let burst: Vec<f64> = (0..length)
+            .map(|i| {
+                let delayed = if i >= pick_delay && pick_delay > 0 {
+                    noise[i - pick_delay]
+                } else {
+                    0.0
+                };
+                amplitude * (noise[i] - delayed) / 2.0
+            })
+            .collect();
+
+        // Mix into any remaining excitation, stored in reverse so that it can be popped
+        let mut pending: Vec<f64> = self.excitation.drain(..).rev().collect();
+        pending.resize(pending.len().max(burst.len()), 0.0);
+        for (sample, added) in pending.iter_mut().zip(burst.iter()) {
+            *sample += added;
+        }
+        self.excitation = pending.into_iter().rev().collect();
+    }
+
+    /// Returns the next sample.
+    pub fn tick(&mut self) -> f64 {
+        let delayed = self.delay.last();
+
+        let lowpass = (1.0 - self.stretch) * delayed + self.stretch * self.lowpass_previous;
+        self.lowpass_previous = delayed;
+
+        let output = self.loss * lowpass + self.excitation.pop().unwrap_or(0.0);
+        self.delay.tick(output);
+
+        output
+    }
+}
+
+impl Iterator for PluckedString {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        Some(self.tick())
+    }
+}
+
+/// Bow-string friction: how much of the velocity difference between bow and string is passed on
+/// to the string. The string sticks to the bow at small differences and slips at large ones.
+fn bow_table(velocity_difference: f64, slope: f64) -> f64 {
+    ((velocity_difference * slope).abs() + 0.75)
+        .powi(-4)
+        .clamp(0.01, 0.98)
+}
+
+/// One-pole smoothing towards a target, so that control changes do not click
+fn smooth(current: f64, target: f64) -> f64 {
+    current + (target - current) * 0.002
+}
+
+/// A bowed string, after the STK bowed string model.
+///
+/// The bow divides the string into a neck side and a bridge side. Every sample, the bow's
+/// friction adds the difference between the bow's and the string's velocity to both sides.
+#[derive(Clone, Debug)]
+pub struct BowedString {
+    /// Bow speed, from `0.0` (bow lifted) to `1.0`. Changes are smoothed.
+    pub bow_velocity: f64,
+    /// Bow pressure, from `0.0` (light, airy) to `1.0` (heavy, scratchy)
+    pub bow_pressure: f64,
+    neck: FractionalDelay,
+    bridge: FractionalDelay,
+    /// One-pole lowpass modelling losses at the bridge
+    string_filter: f64,
+    string_pole: f64,
+    velocity: f64,
+}
+
+impl BowedString {
+    /// Creates a silent string. `bow_position` is the bow's distance from the bridge as a fraction
+    /// of the string's length (around `0.1` to `0.2` is typical).
+    pub fn new(frequency: f64, sample_rate: usize, bow_position: f64) -> BowedString {
+        let string_pole = 0.75 - 0.2 * 22_050.0 / sample_rate as f64;
+
+        // Each side's `last` output adds a sample, and the bridge filter delays the loop by
+        // `pole / (1 - pole)` samples at low frequencies
+        let period = sample_rate as f64 / frequency - 2.0 - string_pole / (1.0 - string_pole);
+        let bow_position = bow_position.clamp(0.01, 0.99);
+
+        BowedString {
+            bow_velocity: 0.0,
+            bow_pressure: 0.5,
+            neck: FractionalDelay::new(period * (1.0 - bow_position), sample_rate),
+            bridge: FractionalDelay::new(period * bow_position, sample_rate),
+            string_filter: 0.0,
+            string_pole,
+            velocity: 0.0,
+        }
+    }
+
+    /// Returns the next sample.
+    pub fn tick(&mut self) -> f64 {
+        self.velocity = smooth(self.velocity, self.bow_velocity.clamp(0.0, 1.0));
+        let bow_velocity = if self.velocity > 1e-4 {
+            0.03 + 0.2 * self.velocity
+        } else {
+            0.0
+        };
+
+        self.string_filter = 0.95 * (1.0 - self.string_pole) * self.bridge.last()
+            + self.string_pole * self.string_filter;
+        let bridge_reflection = -self.string_filter;
+        let nut_reflection = -self.neck.last();
+
+        let string_velocity = bridge_reflection + nut_reflection;
+        let difference = bow_velocity - string_velocity;
+        let slope = 5.0 - 4.0 * self.bow_pressure.clamp(0.0, 1.0);
+        let added = if bow_velocity > 0.0 {
+            difference * bow_table(difference, slope)
+        } else {
+            0.0
+        };
+
+        self.neck.tick(bridge_reflection + added);
+        self.bridge.tick(nut_reflection + added);
+
+        // The string's velocity at the bridge drives the body
+        (4.0 * self.bridge.last()).clamp(-1.0, 1.0)
+    }
+}
+
+impl Iterator for BowedString {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        Some(self.tick())
+    }
+}
+
+/// A clarinet, after the STK clarinet model.
+///
+/// A cylindrical bore closed at the reed end is modelled with a single delay loop, with an
+/// inverting, lowpassed reflection at the open end. The reed lets air in depending on the
+/// pressure difference across it. Like a real closed pipe, it mostly sounds odd harmonics.
+#[derive(Clone, Debug)]
+pub struct Clarinet {
+    /// Breath pressure, from `0.0` (silent) to `1.0`. Changes are smoothed.
+    pub breath_pressure: f64,
+    /// Amount of breath noise, `0.2` by default
+    pub noise: f64,
+    bore: FractionalDelay,
+    reflection_previous: f64,
+    pressure: f64,
+    rng: XorShiftRng,
+}
+
+impl Clarinet {
+    /// Creates a silent clarinet. `seed` seeds the breath noise.
+    pub fn new(frequency: f64, sample_rate: usize, seed: u64) -> Clarinet {
+        // The inverting reflection makes the period two trips around the loop. Each trip is
+        // lengthened by the bore's `last` output and the reflection lowpass.
+        let delay = sample_rate as f64 / frequency / 2.0 - 1.5;
+
+        Clarinet {
+            breath_pressure: 0.0,
+            noise: 0.2,
+            bore: FractionalDelay::new(delay, sample_rate),
+            reflection_previous: 0.0,
+            pressure: 0.0,
+            rng: XorShiftRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Returns the next sample.
+    pub fn tick(&mut self) -> f64 {
+        let target = if self.breath_pressure > 0.0 {
+            0.55 + 0.3 * self.breath_pressure.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        self.pressure = smooth(self.pressure, target);
+        let breath = self.pressure * (1.0 + self.noise * self.rng.gen_range(-1.0, 1.0));
+
+        let bore = self.bore.last();
+        let reflection = -0.95 * (bore + self.reflection_previous) / 2.0;
+        self.reflection_previous = bore;
+
+        let difference = reflection - breath;
+        let reed = (0.7 - 0.3 * difference).clamp(-1.0, 1.0);
+        self.bore.tick(breath + difference * reed);
+
+        self.bore.last().clamp(-1.0, 1.0)
+    }
+}
+
+impl Iterator for Clarinet {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        Some(self.tick())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{gain_to_db, magnitude_spectrum, rms};
+
+    /// Estimates the strongest frequency between `low` and `high`, with parabolic interpolation of
+    /// the log magnitudes
+    fn peak_frequency(samples: &[f64], low: f64, high: f64) -> f64 {
+        let size = 32_768;
+        let spectrum = magnitude_spectrum(&samples[..size]);
+        let bin_width = 44_100.0 / size as f64;
+
+        let start = (low / bin_width) as usize;
+        let end = (high / bin_width) as usize;
+        let peak = (start..end)
+            .max_by(|&a, &b| spectrum[a].partial_cmp(&spectrum[b]).unwrap())
+            .unwrap();
+
+        let (a, b, c) = (
+            spectrum[peak - 1].ln(),
+            spectrum[peak].ln(),
+            spectrum[peak + 1].ln(),
+        );
+        let offset = 0.5 * (a - c) / (a - 2.0 * b + c);
+        (peak as f64 + offset) * bin_width
+    }
+
+    fn cents(frequency: f64, expected: f64) -> f64 {
+        1200.0 * (frequency / expected).log2()
+    }
+
+    #[test]
+    fn test_plucked_string_is_tuned() {
+        for &frequency in &[82.41, 261.63, 440.0, 987.77] {
+            let samples: Vec<f64> = PluckedString::new(frequency, 44_100, 4.0, 0.5, 0.3, 1)
+                .take(32_768)
+                .collect();
+            let measured = peak_frequency(&samples, frequency * 0.9, frequency * 1.1);
+            assert!(cents(measured, frequency).abs() < 1.0);
+        }
+    }
+
+    #[test]
+    fn test_plucked_string_decay_time() {
+        let samples: Vec<f64> = PluckedString::new(220.0, 44_100, 1.0, 0.5, 0.3, 2)
+            .take(44_100 + 4410)
+            .collect();
+
+        // The fundamental outlasts the upper harmonics, so after a second the level has dropped
+        // by at least 60dB but not much more
+        let start = rms(&samples[2205..6615]);
+        let end = rms(&samples[44_100 - 2205..44_100 + 2205]);
+        let drop = gain_to_db(end / start);
+        assert!(drop < -55.0 && drop > -75.0);
+    }
+
+    #[test]
+    fn test_pick_position_shapes_harmonics() {
+        // Relative level of the second harmonic
+        let second_harmonic = |pick_position: f64| -> f64 {
+            let samples: Vec<f64> = PluckedString::new(220.0, 44_100, 4.0, 0.5, pick_position, 3)
+                .take(32_768)
+                .collect();
+            let spectrum = magnitude_spectrum(&samples);
+            let bin = |frequency: f64| (frequency * 32_768.0 / 44_100.0).round() as usize;
+            let level = |frequency: f64| {
+                spectrum[bin(frequency) - 2..=bin(frequency) + 2]
+                    .iter()
+                    .cloned()
+                    .fold(0.0, f64::max)
+            };
+            level(440.0) / level(220.0)
+        };
+
+        // Plucking in the middle cancels even harmonics
+        assert!(second_harmonic(0.5) < second_harmonic(0.2) / 10.0);
+    }
+
+    #[test]
+    fn test_pluck_is_deterministic() {
+        let a: Vec<f64> = PluckedString::new(440.0, 44_100, 1.0, 0.5, 0.2, 7)
+            .take(1000)
+            .collect();
+        let b: Vec<f64> = PluckedString::new(440.0, 44_100, 1.0, 0.5, 0.2, 7)
+            .take(1000)
+            .collect();
+        assert_eq!(a, b);
+        assert!(a.iter().all(|sample| sample.abs() <= 1.0));
+    }
+
+    #[test]
+    fn test_bowed_string_sustains_and_releases() {
+        let mut string = BowedString::new(196.0, 44_100, 0.13);
+        string.bow_velocity = 0.8;
+        let bowed: Vec<f64> = string.by_ref().take(44_100 * 2).collect();
+
+        let sustained = &bowed[44_100..];
+        assert!(rms(sustained) > 0.05);
+        let measured = peak_frequency(sustained, 196.0 * 0.8, 196.0 * 1.2);
+        assert!(cents(measured, 196.0).abs() < 25.0);
+
+        string.bow_velocity = 0.0;
+        let released: Vec<f64> = string.by_ref().take(44_100 * 2).collect();
+        assert!(rms(&released[44_100..]) < rms(sustained) / 100.0);
+    }
+
+    #[test]
+    fn test_clarinet_sustains_with_odd_harmonics() {
+        let mut clarinet = Clarinet::new(220.0, 44_100, 5);
+        clarinet.breath_pressure = 0.7;
+        let blown: Vec<f64> = clarinet.by_ref().take(44_100 * 2).collect();
+
+        let sustained = &blown[44_100..];
+        assert!(rms(sustained) > 0.05);
+        let measured = peak_frequency(sustained, 220.0 * 0.8, 220.0 * 1.2);
+        assert!(cents(measured, 220.0).abs() < 25.0);
+
+        let spectrum = magnitude_spectrum(&sustained[..32_768]);
+        let level = |harmonic: f64| {
+            let bin = (measured * harmonic * 32_768.0 / 44_100.0).round() as usize;
+            spectrum[bin - 3..=bin + 3]
+                .iter()
+                .cloned()
+                .fold(0.0, f64::max)
+        };
+        assert!(level(2.0) < level(3.0));
+
+        clarinet.breath_pressure = 0.0;
+        let stopped: Vec<f64> = clarinet.take(44_100 * 2).collect();
+        assert!(rms(&stopped[44_100..]) < 0.001);
+    }
+}
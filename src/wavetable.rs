@@ -0,0 +1,364 @@
+//! Wavetable synthesis with band-limited mip-maps and frame morphing.
+//!
+//! A `Wavetable` holds one or more single-cycle frames. Each frame is resampled to
+//! `TABLE_SIZE` samples and stored as a set of mip-levels, one per octave: level `k` only keeps
+//! the harmonics below `TABLE_SIZE / 2 >> k`. A `WavetableOscillator` picks the level whose
+//! highest harmonic stays below Nyquist at the current frequency, so tables can be played at any
+//! pitch without aliasing.
+//!
+//! `position` (`0.0` to `1.0`) selects a point between the first and the last frame, crossfading
+//! between neighbouring frames. It can be changed every sample to morph the timbre.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use synthrs::wavetable::{Wavetable, WavetableOscillator};
+//!
+//! // Morph from a sine to a bright sawtooth-like frame
+//! let sawtooth: Vec<f64> = (1..64).map(|harmonic| 1.0 / harmonic as f64).collect();
+//! let table = Arc::new(Wavetable::from_harmonic_frames(&[vec![1.0], sawtooth]));
+//!
+//! let mut oscillator = WavetableOscillator::new(table, 220.0, 44_100);
+//! let samples: Vec<f64> = (0..44_100)
+//!     .map(|i| {
+//!         oscillator.position = i as f64 / 44_100.0;
+//!         oscillator.tick()
+//!     })
+//!     .collect();
+//! ```
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use num::Complex;
+
+use crate::analysis::{fft, ifft};
+use crate::errors::{Result, SynthrsError};
+use crate::oscillator::Periodic;
+use crate::sample::samples_from_wave_file;
+
+/// Number of samples in each stored frame
+pub const TABLE_SIZE: usize = 2048;
+
+/// Number of mip-levels per frame, the last one being a pure sine
+const LEVELS: usize = 11;
+
+/// A multi-frame, mip-mapped wavetable.
+#[derive(Clone, Debug)]
+pub struct Wavetable {
+    /// `frames[frame][level][sample]`
+    frames: Vec<Vec<Vec<f64>>>,
+}
+
+impl Wavetable {
+    /// Creates a wavetable from single-cycle frames of any length. Frames are resampled to
+    /// `TABLE_SIZE` with linear interpolation before being band-limited.
+    ///
+    /// Panics if there are no frames or any frame is empty.
+    ///
+    /// ```
+    /// use synthrs::wavetable::Wavetable;
+    ///
+    /// let square: Vec<f64> = (0..600).map(|i| if i < 300 { 1.0 } else { -1.0 }).collect();
+    /// let table = Wavetable::from_frames(&[square]);
+    /// assert_eq!(table.frame_count(), 1);
+    /// ```
+    pub fn from_frames(frames: &[Vec<f64>]) -> Wavetable {
+        assert!(!frames.is_empty(), "a wavetable needs at least one frame");
+
+        Wavetable {
+            frames: frames
+                .iter()
+                .map(|frame| mip_levels(&resample_cycle(frame)))
+                .collect(),
+        }
+    }
+
+    /// Creates a single-frame wavetable from the amplitudes of its harmonics, starting with the
+    /// fundamental. Each harmonic is a sine starting at phase `0`.
+    ///
+    /// ```
+    /// use synthrs::wavetable::Wavetable;
+    ///
+    /// // Odd harmonics only, a square-like wave
+    /// let table = Wavetable::from_harmonics(&[1.0, 0.0, 1.0 / 3.0, 0.0, 1.0 / 5.0]);
+    /// ```
+    pub fn from_harmonics(harmonics: &[f64]) -> Wavetable {
+        Wavetable::from_harmonic_frames(&[harmonics.to_vec()])
+    }
+
+    /// Creates a multi-frame wavetable, each frame given by the amplitudes of its harmonics.
+    pub fn from_harmonic_frames(frames: &[Vec<f64>]) -> Wavetable {
+        let frames: Vec<Vec<f64>> = frames
+            .iter()
+            .map(|harmonics| {
+                (0..TABLE_SIZE)
+                    .map(|i| {
+                        let phase = i as f64 / TABLE_SIZE as f64;
+                        harmonics
+                            .iter()
+                            .take(TABLE_SIZE / 2 - 1)
+                            .enumerate()
+                            .map(|(k, amplitude)| {
+                                amplitude * (2.0 * PI * (k + 1) as f64 * phase).sin()
+                            })
+                            .sum()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Wavetable::from_frames(&frames)
+    }
+
+    /// Loads a wavetable from a WAV file made of consecutive single-cycle frames of
+    /// `frame_size` samples each. A trailing partial frame is ignored.
+    ///
+    /// ```
+    /// use synthrs::wavetable::Wavetable;
+    ///
+    /// let table = Wavetable::from_wave_file("./tests/assets/sine.wav", 256).unwrap();
+    /// assert!(table.frame_count() > 1);
+    /// ```
+    pub fn from_wave_file(filepath: &str, frame_size: usize) -> Result<Wavetable> {
+        let (samples, _) = samples_from_wave_file(filepath)?;
+
+        if frame_size == 0 || samples.len() < frame_size {
+            return Err(SynthrsError::Parse(format!(
+                "wavetable needs at least one frame of {} samples, found {} samples",
+                frame_size,
+                samples.len()
+            )));
+        }
+
+        let frames: Vec<Vec<f64>> = samples
+            .chunks_exact(frame_size)
+            .map(|frame| frame.to_vec())
+            .collect();
+
+        Ok(Wavetable::from_frames(&frames))
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns the mip-level to use at `frequency`: the most detailed one whose highest harmonic
+    /// is below Nyquist.
+    pub fn level_for(&self, frequency: f64, sample_rate: usize) -> usize {
+        let harmonics = (sample_rate as f64 / 2.0) / frequency.abs();
+        let top_harmonic = (TABLE_SIZE / 2 - 1) as f64;
+
+        if harmonics >= top_harmonic {
+            0
+        } else if harmonics < 1.0 {
+            LEVELS - 1
+        } else {
+            ((top_harmonic / harmonics).log2().ceil() as usize).min(LEVELS - 1)
+        }
+    }
+
+    /// Returns the amplitude at `phase` in `[0, 1)` of mip-level `level`, at `position` between
+    /// the first (`0.0`) and last (`1.0`) frame.
+    pub fn value(&self, position: f64, phase: f64, level: usize) -> f64 {
+        let level = level.min(LEVELS - 1);
+        let last = self.frames.len() - 1;
+        let frame_position = position.clamp(0.0, 1.0) * last as f64;
+        let index = (frame_position.floor() as usize).min(last);
+        let fraction = frame_position - index as f64;
+
+        let a = read_table(&self.frames[index][level], phase);
+        if fraction == 0.0 || index == last {
+            a
+        } else {
+            let b = read_table(&self.frames[index + 1][level], phase);
+            a + (b - a) * fraction
+        }
+    }
+}
+
+/// Linearly interpolated lookup of a cyclic table at `phase` in `[0, 1)`
+fn read_table(table: &[f64], phase: f64) -> f64 {
+    let position = phase * table.len() as f64;
+    let index = position.floor() as usize % table.len();
+    let fraction = position - position.floor();
+    let a = table[index];
+    let b = table[(index + 1) % table.len()];
+    a + (b - a) * fraction
+}
+
+/// Resamples one cycle of any length to `TABLE_SIZE` samples
+fn resample_cycle(frame: &[f64]) -> Vec<f64> {
+    assert!(!frame.is_empty(), "wavetable frames cannot be empty");
+
+    if frame.len() == TABLE_SIZE {
+        return frame.to_vec();
+    }
+
+    (0..TABLE_SIZE)
+        .map(|i| read_table(frame, i as f64 / TABLE_SIZE as f64))
+        .collect()
+}
+
+/// Builds the band-limited mip-levels of one `TABLE_SIZE` frame, removing its DC offset
+fn mip_levels(frame: &[f64]) -> Vec<Vec<f64>> {
+    let mut spectrum: Vec<Complex<f64>> = frame
+        .iter()
+        .map(|&sample| Complex::new(sample, 0.0))
+        .collect();
+    fft(&mut spectrum);
+
+    (0..LEVELS)
+        .map(|level| {
+            let top_harmonic = ((TABLE_SIZE / 2) >> level).max(2) - 1;
+            let mut bins: Vec<Complex<f64>> = spectrum
+                .iter()
+                .enumerate()
+                .map(|(bin, &value)| {
+                    let harmonic = bin.min(TABLE_SIZE - bin);
+                    if harmonic == 0 || harmonic > top_harmonic {
+                        Complex::new(0.0, 0.0)
+                    } else {
+                        value
+                    }
+                })
+                .collect();
+            ifft(&mut bins);
+            bins.iter().map(|bin| bin.re).collect()
+        })
+        .collect()
+}
+
+/// A phase-accumulating oscillator playing a `Wavetable`.
+///
+/// The table is shared, so many voices can play the same table without copying it.
+#[derive(Clone, Debug)]
+pub struct WavetableOscillator {
+    pub table: Arc<Wavetable>,
+    pub sample_rate: usize,
+    /// Position between the first (`0.0`) and last (`1.0`) frame
+    pub position: f64,
+    frequency: f64,
+    level: usize,
+    phase: f64,
+}
+
+impl WavetableOscillator {
+    pub fn new(table: Arc<Wavetable>, frequency: f64, sample_rate: usize) -> WavetableOscillator {
+        let level = table.level_for(frequency, sample_rate);
+        WavetableOscillator {
+            table,
+            sample_rate,
+            position: 0.0,
+            frequency,
+            level,
+            phase: 0.0,
+        }
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Changes the frequency from the next sample on, selecting a new mip-level.
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.level = self.table.level_for(frequency, self.sample_rate);
+    }
+
+    pub fn phase(&self) -> f64 {
+        self.phase
+    }
+
+    /// Jumps to `phase`, wrapped into `[0, 1)`.
+    pub fn set_phase(&mut self, phase: f64) {
+        self.phase = phase - phase.floor();
+    }
+
+    /// Returns the amplitude at the current phase and position, then advances the phase.
+    pub fn tick(&mut self) -> f64 {
+        let output = self.table.value(self.position, self.phase, self.level);
+
+        self.phase += self.frequency / self.sample_rate as f64;
+        self.phase -= self.phase.floor();
+        if self.phase >= 1.0 {
+            self.phase = 0.0;
+        }
+
+        output
+    }
+}
+
+impl Periodic for WavetableOscillator {
+    fn tick(&mut self) -> f64 {
+        WavetableOscillator::tick(self)
+    }
+
+    fn frequency(&self) -> f64 {
+        WavetableOscillator::frequency(self)
+    }
+
+    fn set_frequency(&mut self, frequency: f64) {
+        WavetableOscillator::set_frequency(self, frequency)
+    }
+
+    fn set_phase(&mut self, phase: f64) {
+        WavetableOscillator::set_phase(self, phase)
+    }
+}
+
+impl Iterator for WavetableOscillator {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        Some(self.tick())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{aliasing_ratio, gain_to_db};
+    use crate::wave::sine_wave;
+
+    #[test]
+    fn test_sine_table_matches_sine() {
+        let table = Arc::new(Wavetable::from_harmonics(&[1.0]));
+        let mut oscillator = WavetableOscillator::new(table, 440.0, 44_100);
+
+        for i in 0..1000 {
+            let expected = sine_wave(440.0)(i as f64 / 44_100.0);
+            assert!((oscillator.tick() - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_levels_remove_harmonics_above_nyquist() {
+        let table = Wavetable::from_harmonics(&[1.0; 1000]);
+
+        assert_eq!(table.level_for(20.0, 44_100), 0);
+        assert_eq!(table.level_for(30_000.0, 44_100), LEVELS - 1);
+
+        // An exact bin frequency so that harmonics land on bin centres
+        let bin = 487;
+        let frequency = bin as f64 * 44_100.0 / 8192.0;
+        let mut oscillator = WavetableOscillator::new(Arc::new(table), frequency, 44_100);
+        let samples: Vec<f64> = oscillator.by_ref().take(8192).collect();
+        assert!(gain_to_db(aliasing_ratio(&samples, bin).sqrt()) < -60.0);
+    }
+
+    #[test]
+    fn test_position_morphs_between_frames() {
+        // Two-sample frames are resampled into opposite triangles
+        let table = Wavetable::from_frames(&[vec![1.0, -1.0], vec![-1.0, 1.0]]);
+
+        assert!(table.value(0.0, 0.0, 0) > 0.9);
+        assert!(table.value(1.0, 0.0, 0) < -0.9);
+        assert!(table.value(0.5, 0.0, 0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_from_wave_file_rejects_short_frames() {
+        assert!(Wavetable::from_wave_file("./tests/assets/sine.wav", 0).is_err());
+        assert!(Wavetable::from_wave_file("./tests/assets/sine.wav", 100_000_000).is_err());
+    }
+}
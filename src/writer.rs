@@ -14,6 +14,8 @@ use std::path::Path;
 
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 
+use crate::format::SampleFormat;
+
 /// Creates a file at `filename` and writes a bunch of `&[i16]` samples to it as a PCM file.
 /// See module documentation for PCM settings.
 ///
@@ -33,7 +35,7 @@ pub fn write_pcm_file(filename: &str, samples: &[i16]) -> Result<()> {
         .write(true)
         .truncate(true)
         .create(true)
-        .open(&path)?;
+        .open(path)?;
     write_pcm(&mut f, samples)
 }
 
@@ -86,7 +88,7 @@ pub fn write_wav_file(filename: &str, sample_rate: usize, samples: &[i16]) -> Re
         .write(true)
         .truncate(true)
         .create(true)
-        .open(&path)?;
+        .open(path)?;
     write_wav(&mut f, sample_rate, samples)
 }
 
@@ -110,12 +112,108 @@ pub fn write_wav_file(filename: &str, sample_rate: usize, samples: &[i16]) -> Re
 pub fn write_wav<W>(writer: &mut W, sample_rate: usize, samples: &[i16]) -> Result<()>
 where
     W: Write,
+{
+    write_multichannel_wav(writer, sample_rate, 1, samples)
+}
+
+/// Creates a file at `filename` and writes a bunch of interleaved `&[i16]` samples with `channels`
+/// channels to it as a WAVE file. For stereo, samples are ordered `[l0, r0, l1, r1, ...]`.
+/// ```
+/// use synthrs::wave::sine_wave;
+/// use synthrs::writer::write_multichannel_wav_file;
+/// use synthrs::synthesizer::{quantize_samples, make_samples, Mixer};
+///
+/// let mut mixer = Mixer::new();
+/// let source = mixer.add_source(make_samples(0.1, 44_100, sine_wave(440.0)));
+/// mixer.sources[source].pan = -1.0;
+///
+/// write_multichannel_wav_file(
+///     "out/sine_left.wav",
+///     44_100,
+///     2,
+///     &quantize_samples::<i16>(&mixer.render()),
+/// ).expect("failed to write wav");
+/// ```
+pub fn write_multichannel_wav_file(
+    filename: &str,
+    sample_rate: usize,
+    channels: usize,
+    samples: &[i16],
+) -> Result<()> {
+    let path = Path::new(filename);
+    let mut f = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(path)?;
+    write_multichannel_wav(&mut f, sample_rate, channels, samples)
+}
+
+/// Writes a bunch of interleaved `&[i16]` samples with `channels` channels to a `Write`.
+/// Also see `synthrs::writer::write_multichannel_wav_file`.
+pub fn write_multichannel_wav<W>(
+    writer: &mut W,
+    sample_rate: usize,
+    channels: usize,
+    samples: &[i16],
+) -> Result<()>
+where
+    W: Write,
+{
+    write_wav_samples(writer, sample_rate, channels, samples)
+}
+
+/// Creates a file at `filename` and writes interleaved samples of any `SampleFormat` to it as a
+/// WAVE file. The bit depth and encoding of the file follow `T`: 8-bit unsigned, 16, 24 or
+/// 32-bit signed integer, or 32/64-bit IEEE float.
+/// ```
+/// use synthrs::format::I24;
+/// use synthrs::wave::sine_wave;
+/// use synthrs::writer::write_wav_samples_file;
+/// use synthrs::synthesizer::{quantize_samples, make_samples};
+///
+/// write_wav_samples_file(
+///     "out/sine_24bit.wav",
+///     44_100,
+///     1,
+///     &quantize_samples::<I24>(&make_samples(0.1, 44_100, sine_wave(440.0))),
+/// ).expect("failed to write wav");
+/// ```
+pub fn write_wav_samples_file<T>(
+    filename: &str,
+    sample_rate: usize,
+    channels: usize,
+    samples: &[T],
+) -> Result<()>
+where
+    T: SampleFormat,
+{
+    let path = Path::new(filename);
+    let mut f = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(path)?;
+    write_wav_samples(&mut f, sample_rate, channels, samples)
+}
+
+/// Writes interleaved samples of any `SampleFormat` to a `Write` as a WAVE file.
+/// Also see `synthrs::writer::write_wav_samples_file`.
+pub fn write_wav_samples<W, T>(
+    writer: &mut W,
+    sample_rate: usize,
+    channels: usize,
+    samples: &[T],
+) -> Result<()>
+where
+    W: Write,
+    T: SampleFormat,
 {
     // See: http://www-mmsp.ece.mcgill.ca/Documents/AudioFormats/WAVE/WAVE.html
     // Some WAV header fields
-    let channels = 1;
-    let bit_depth = 16;
-    let subchunk_2_size = samples.len() * channels * bit_depth / 8;
+    let bit_depth = T::BITS as usize;
+    let audio_format = if T::IS_FLOAT { 3 } else { 1 };
+    let subchunk_2_size = samples.len() * bit_depth / 8;
     let chunk_size = 36 + subchunk_2_size as i32;
     let byte_rate = (sample_rate * channels * bit_depth / 8) as i32;
     let block_align = (channels * bit_depth / 8) as i16;
@@ -126,7 +224,7 @@ where
 
     writer.write_i32::<BigEndian>(0x666d_7420)?; // Subchunk1ID, fmt
     writer.write_i32::<LittleEndian>(16)?; // Subchunk1Size, 16 for PCM
-    writer.write_i16::<LittleEndian>(1)?; // AudioFormat, PCM = 1 (linear quantization)
+    writer.write_i16::<LittleEndian>(audio_format)?; // AudioFormat, PCM = 1 (linear quantization), IEEE float = 3
     writer.write_i16::<LittleEndian>(channels as i16)?; // NumChannels
     writer.write_i32::<LittleEndian>(sample_rate as i32)?; // SampleRate
     writer.write_i32::<LittleEndian>(byte_rate)?; // ByteRate
@@ -137,7 +235,7 @@ where
     writer.write_i32::<LittleEndian>(subchunk_2_size as i32)?; // Subchunk2Size, number of bytes in the data
 
     for sample in samples {
-        writer.write_i16::<LittleEndian>(*sample)?
+        sample.write_le(writer)?
     }
 
     Ok(())
@@ -175,7 +273,7 @@ pub struct Wave {
 /// ```
 pub fn read_wav_file(filename: &str) -> Result<Wave> {
     let path = Path::new(filename);
-    let file = OpenOptions::new().read(true).open(&path)?;
+    let file = OpenOptions::new().read(true).open(path)?;
     let mut reader = BufReader::new(file);
     read_wav(&mut reader)
 }
@@ -345,4 +443,37 @@ mod tests {
         assert_eq!(wave.subchunk_2_size, 8820);
         assert_eq!(wave.pcm.len(), 8820);
     }
+
+    #[test]
+    fn test_write_read_stereo_wav() {
+        use std::io::{Cursor, Seek, SeekFrom};
+
+        let output_buffer: Vec<u8> = Vec::new();
+        let mut output_writer = Cursor::new(output_buffer);
+
+        write_multichannel_wav(&mut output_writer, 44_100, 2, &[1, -1, 2, -2]).unwrap();
+
+        let _ = output_writer.seek(SeekFrom::Start(0));
+        let wave = read_wav(&mut output_writer).unwrap();
+        assert_eq!(wave.num_channels, 2);
+        assert_eq!(wave.byte_rate, 176_400);
+        assert_eq!(wave.block_align, 4);
+        assert_eq!(wave.subchunk_2_size, 8);
+    }
+
+    #[test]
+    fn test_write_wav_samples_formats() {
+        use crate::format::I24;
+
+        let mut buf: Vec<u8> = Vec::new();
+        write_wav_samples(&mut buf, 48_000, 2, &[I24::MIN, I24::MAX]).unwrap();
+        assert_eq!(buf.len(), 44 + 6);
+        assert_eq!(&buf[20..22], &[1, 0]); // AudioFormat, PCM
+        assert_eq!(&buf[32..36], &[6, 0, 24, 0]); // BlockAlign, BitsPerSample
+
+        let mut buf: Vec<u8> = Vec::new();
+        write_wav_samples(&mut buf, 48_000, 1, &[0.5f32]).unwrap();
+        assert_eq!(buf.len(), 44 + 4);
+        assert_eq!(&buf[20..22], &[3, 0]); // AudioFormat, IEEE float
+    }
 }